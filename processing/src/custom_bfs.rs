@@ -1,49 +1,91 @@
-use std::collections::{HashMap, VecDeque};
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{BinaryHeap, HashMap, HashSet},
+    sync::Arc,
+};
 
 use fixedbitset::FixedBitSet;
 use petgraph::{
-    graph::NodeIndex,
+    graph::{EdgeIndex, NodeIndex},
     stable_graph::StableDiGraph,
     visit::{EdgeRef, VisitMap, Visitable},
     Direction::{Incoming, Outgoing},
 };
 
-use crate::parse::Point;
+use crate::{math::dist, parse::Point};
+
+/// A distance function that is also given the edge being measured, so it can use costs that are
+/// not stored in the graph
+pub type EdgeDistanceFn<N, E> = Arc<dyn Fn(EdgeIndex, &N, &N, &E) -> f64 + Send + Sync>;
 
 #[derive(Clone)]
 pub struct StackNode<N> {
     idx: NodeIndex,
     distance: f64,
+    /// The cumulative distance plus the heuristic estimate to the target
+    priority: f64,
     data: N,
     path: Vec<NodeIndex>,
 }
 
 impl<N> StackNode<N> {
-    fn new(node: NodeIndex, distance: f64, data: N, path: Vec<NodeIndex>) -> Self {
+    fn new(node: NodeIndex, distance: f64, priority: f64, data: N, path: Vec<NodeIndex>) -> Self {
         StackNode {
             idx: node,
             distance,
+            priority,
             data,
             path,
         }
     }
 }
 
+// Nodes are ordered by their priority only, so the heap pops the most promising node first
+impl<N> PartialEq for StackNode<N> {
+    fn eq(&self, other: &Self) -> bool {
+        self.priority.total_cmp(&other.priority) == Ordering::Equal
+    }
+}
+
+impl<N> Eq for StackNode<N> {}
+
+impl<N> PartialOrd for StackNode<N> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<N> Ord for StackNode<N> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority.total_cmp(&other.priority)
+    }
+}
+
 #[derive(Clone)]
 pub struct CustomBfs<N, E> {
-    /// The queue of nodes to visit
-    pub stack: VecDeque<StackNode<N>>,
+    /// The queue of nodes to visit, closest first
+    pub stack: BinaryHeap<Reverse<StackNode<N>>>,
     /// The map of discovered nodes
     pub discovered: FixedBitSet,
     pub distances: HashMap<NodeIndex, f64>,
     pub paths: HashMap<NodeIndex, Vec<NodeIndex>>,
     pub distance_fn: fn(&N, &N, &E) -> f64,
+    /// Used instead of **distance_fn** if set
+    pub edge_distance_fn: Option<EdgeDistanceFn<N, E>>,
+    /// The target and the factor to scale the straight line distance to it by, if searching with A*
+    pub heuristic: Option<(Point, f64)>,
+    /// Edges the search is not allowed to traverse
+    pub excluded_edges: HashSet<EdgeIndex>,
+    /// Allow turning back along the same road right after arriving at a node
+    pub allow_uturns: bool,
+    /// The node the start was arrived at from, so the search does not turn back there either
+    pub arrived_from: Option<NodeIndex>,
 }
 
 impl<N, E> CustomBfs<N, E>
 where
     N: PartialEq + Copy + Positionable,
-    E: Clone,
+    E: Clone + RoadSegment,
 {
     /// Create a new **Bfs**, using the graph's visitor map, and put **start**
     /// in the stack of nodes to visit.
@@ -56,9 +98,15 @@ where
         N: PartialEq + Copy + Positionable,
     {
         let discovered = graph.visit_map();
-        let mut stack = VecDeque::new();
+        let mut stack = BinaryHeap::new();
         let start_data = graph.node_weight(start).unwrap();
-        stack.push_front(StackNode::new(start, 0.0, *start_data, vec![]));
+        stack.push(Reverse(StackNode::new(
+            start,
+            0.0,
+            0.0,
+            *start_data,
+            vec![],
+        )));
         let distances = HashMap::new();
         let paths = HashMap::new();
         CustomBfs {
@@ -67,6 +115,60 @@ where
             distances,
             paths,
             distance_fn,
+            edge_distance_fn: None,
+            heuristic: None,
+            excluded_edges: HashSet::new(),
+            allow_uturns: false,
+            arrived_from: None,
+        }
+    }
+
+    /// Guide the search towards **target** like A*, estimating the remaining distance of a node as
+    /// its straight line distance to the target in meters times **scale**.
+    ///
+    /// The estimate must never exceed the real remaining distance, otherwise the distance of the
+    /// target is not guaranteed to be the shortest.
+    pub fn with_heuristic(mut self, target: Point, scale: f64) -> Self {
+        self.heuristic = Some((target, scale));
+        self
+    }
+
+    /// Measure every edge with **distance_fn** instead of the function the search was created with
+    pub fn with_edge_distance(mut self, distance_fn: EdgeDistanceFn<N, E>) -> Self {
+        self.edge_distance_fn = Some(distance_fn);
+        self
+    }
+
+    fn distance(&self, edge: EdgeIndex, from: &N, to: &N, data: &E) -> f64 {
+        match &self.edge_distance_fn {
+            Some(distance_fn) => distance_fn(edge, from, to, data),
+            None => (self.distance_fn)(from, to, data),
+        }
+    }
+
+    /// Whether taking **edge** to **to** turns straight back along the same road, when the search
+    /// arrived from **previous** over an edge from **arrival.0** to **arrival.1**
+    pub fn is_uturn(
+        &self,
+        graph: &StableDiGraph<N, E>,
+        previous: Option<&NodeIndex>,
+        to: NodeIndex,
+        arrival: (NodeIndex, NodeIndex),
+        edge: &E,
+    ) -> bool {
+        let previous = previous.or(self.arrived_from.as_ref());
+        if self.allow_uturns || previous != Some(&to) {
+            return false;
+        }
+        graph
+            .edges_connecting(arrival.0, arrival.1)
+            .any(|other| other.weight().road_id() == edge.road_id())
+    }
+
+    fn estimate(&self, data: &N) -> f64 {
+        match self.heuristic {
+            Some((target, scale)) => dist(data.point(), target) * scale,
+            None => 0.0,
         }
     }
 
@@ -75,7 +177,7 @@ where
     where
         N: PartialEq + Copy + Positionable,
     {
-        while let Some(node) = self.stack.pop_front() {
+        while let Some(Reverse(node)) = self.stack.pop() {
             if self.discovered.visit(node.idx) {
                 // First time visiting node, add its distance
                 self.distances.insert(node.idx, node.distance);
@@ -88,15 +190,79 @@ where
                     let to = edge.target();
                     let to_data = graph.node_weight(to).unwrap();
 
+                    let arrival = (to, node.idx);
+                    if self.excluded_edges.contains(&edge.id())
+                        || self.is_uturn(graph, node.path.last(), to, arrival, edge.weight())
+                    {
+                        continue;
+                    }
+                    let edge_data = edge.weight();
+
+                    let distance = self.distance(edge.id(), &node.data, to_data, edge_data);
+                    if distance.is_infinite() {
+                        continue;
+                    }
+
+                    let distance = node.distance + distance;
+                    let priority = distance + self.estimate(to_data);
+                    self.stack.push(Reverse(StackNode::new(
+                        to,
+                        distance,
+                        priority,
+                        *to_data,
+                        path.clone(),
+                    )));
+                }
+                return Some((node.idx, node.distance, node.path));
+            }
+        }
+        None
+    }
+
+    /// Like **next**, but traverse the incoming edges of each node, searching backwards from the
+    /// start. The distance of an edge is still measured in its own direction.
+    pub fn next_reverse(
+        &mut self,
+        graph: &StableDiGraph<N, E>,
+    ) -> Option<(NodeIndex, f64, Vec<NodeIndex>)>
+    where
+        N: PartialEq + Copy + Positionable,
+    {
+        while let Some(Reverse(node)) = self.stack.pop() {
+            if self.discovered.visit(node.idx) {
+                // First time visiting node, add its distance
+                self.distances.insert(node.idx, node.distance);
+                // Add the path to the node
+                let mut path = node.path.clone();
+                self.paths.insert(node.idx, path.clone());
+                path.push(node.idx);
+
+                for edge in graph.edges_directed(node.idx, Incoming) {
+                    let to = edge.source();
+                    let to_data = graph.node_weight(to).unwrap();
+
+                    let arrival = (node.idx, to);
+                    if self.excluded_edges.contains(&edge.id())
+                        || self.is_uturn(graph, node.path.last(), to, arrival, edge.weight())
+                    {
+                        continue;
+                    }
                     let edge_data = edge.weight();
 
-                    let distance = (self.distance_fn)(&node.data, to_data, edge_data);
+                    let distance = self.distance(edge.id(), to_data, &node.data, edge_data);
                     if distance.is_infinite() {
                         continue;
                     }
 
                     let distance = node.distance + distance;
-                    self.insert_sorted(StackNode::new(to, distance, *to_data, path.clone()));
+                    let priority = distance + self.estimate(to_data);
+                    self.stack.push(Reverse(StackNode::new(
+                        to,
+                        distance,
+                        priority,
+                        *to_data,
+                        path.clone(),
+                    )));
                 }
                 return Some((node.idx, node.distance, node.path));
             }
@@ -104,6 +270,83 @@ where
         None
     }
 
+    /// Search until **end** is reached and return its distance and the path to it, like calling
+    /// **next** until it returns **end**, but keeping only the node each visited node was reached
+    /// from instead of cloning the full path to every queued node. The search is consumed, so the
+    /// visited nodes are tracked locally instead of in **discovered** and **distances**.
+    pub fn path_to(
+        mut self,
+        graph: &StableDiGraph<N, E>,
+        end: NodeIndex,
+    ) -> Option<(f64, Vec<NodeIndex>)> {
+        let mut came_from: HashMap<NodeIndex, NodeIndex> = HashMap::new();
+        let mut stack = self
+            .stack
+            .drain()
+            .map(|Reverse(node)| {
+                Reverse(Frontier {
+                    idx: node.idx,
+                    from: node.path.last().copied(),
+                    distance: node.distance,
+                    priority: node.priority,
+                })
+            })
+            .collect::<BinaryHeap<_>>();
+
+        let mut visited = graph.visit_map();
+        while let Some(Reverse(node)) = stack.pop() {
+            if !visited.visit(node.idx) {
+                continue;
+            }
+            if let Some(from) = node.from {
+                came_from.insert(node.idx, from);
+            }
+
+            if node.idx == end {
+                let mut path = vec![end];
+                let mut current = end;
+                while let Some(previous) = came_from.get(&current) {
+                    path.push(*previous);
+                    current = *previous;
+                }
+                path.reverse();
+                return Some((node.distance, path));
+            }
+
+            let data = graph.node_weight(node.idx).unwrap();
+            for edge in graph.edges(node.idx) {
+                let to = edge.target();
+                let to_data = graph.node_weight(to).unwrap();
+
+                let arrival = (to, node.idx);
+                if self.excluded_edges.contains(&edge.id())
+                    || self.is_uturn(graph, node.from.as_ref(), to, arrival, edge.weight())
+                {
+                    continue;
+                }
+
+                let distance = self.distance(edge.id(), data, to_data, edge.weight());
+                if distance.is_infinite() {
+                    continue;
+                }
+
+                let distance = node.distance + distance;
+                stack.push(Reverse(Frontier {
+                    idx: to,
+                    from: Some(node.idx),
+                    distance,
+                    priority: distance + self.estimate(to_data),
+                }));
+            }
+        }
+        None
+    }
+
+    /// The distance of the next node in the queue, which no unvisited node can be closer than
+    pub fn peek_distance(&self) -> Option<f64> {
+        self.stack.peek().map(|Reverse(node)| node.distance)
+    }
+
     pub fn next_undirected(
         &mut self,
         graph: &StableDiGraph<N, E>,
@@ -111,7 +354,7 @@ where
     where
         N: PartialEq + Copy + Positionable,
     {
-        while let Some(node) = self.stack.pop_front() {
+        while let Some(Reverse(node)) = self.stack.pop() {
             if self.discovered.visit(node.idx) {
                 // First time visiting node, add its distance
                 self.distances.insert(node.idx, node.distance);
@@ -132,34 +375,217 @@ where
                     };
                     let to_data = graph.node_weight(to).unwrap();
 
+                    if self.excluded_edges.contains(&edge.id()) {
+                        continue;
+                    }
                     let edge_data = edge.weight();
 
-                    let distance = (self.distance_fn)(&node.data, to_data, edge_data);
+                    let distance = self.distance(edge.id(), &node.data, to_data, edge_data);
                     if distance.is_infinite() {
                         continue;
                     }
 
                     let distance = node.distance + distance;
-                    self.insert_sorted(StackNode::new(to, distance, *to_data, path.clone()));
+                    let priority = distance + self.estimate(to_data);
+                    self.stack.push(Reverse(StackNode::new(
+                        to,
+                        distance,
+                        priority,
+                        *to_data,
+                        path.clone(),
+                    )));
                 }
                 return Some((node.idx, node.distance, node.path));
             }
         }
         None
     }
+}
 
-    fn insert_sorted(&mut self, node: StackNode<N>) {
-        match self.stack.binary_search_by(|n| {
-            n.distance
-                .partial_cmp(&node.distance)
-                .expect(format!("{} {}", n.distance, node.distance).as_str())
-        }) {
-            Ok(i) => self.stack.insert(i, node),
-            Err(i) => self.stack.insert(i, node),
-        }
+/// A node in the queue of **CustomBfs::path_to**, remembering only the node it was reached from
+struct Frontier {
+    idx: NodeIndex,
+    from: Option<NodeIndex>,
+    distance: f64,
+    priority: f64,
+}
+
+impl PartialEq for Frontier {
+    fn eq(&self, other: &Self) -> bool {
+        self.priority.total_cmp(&other.priority) == Ordering::Equal
+    }
+}
+
+impl Eq for Frontier {}
+
+impl PartialOrd for Frontier {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Frontier {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority.total_cmp(&other.priority)
     }
 }
 
 pub trait Positionable {
     fn point(&self) -> Point;
 }
+
+pub trait RoadSegment {
+    /// The id of the road the segment was created from
+    fn road_id(&self) -> i32;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+    use crate::{
+        processing::{EdgeData, NodeData},
+        test_graphs::{add_road, grid, node, TestGraph},
+    };
+
+    fn edge_distance(_from: &NodeData, _to: &NodeData, edge: &EdgeData) -> f64 {
+        edge.distance
+    }
+
+    #[test]
+    fn path_to_finds_the_same_path_as_searching_node_by_node() {
+        let (mut graph, nodes) = grid(4, 4);
+        // Lengthen every edge by a different amount so no two paths are equally short
+        for edge in graph.edge_indices().collect::<Vec<_>>() {
+            graph[edge].distance += (edge.index() as f64 + 2.0).sqrt();
+        }
+        let start = nodes[0][0];
+
+        for end in nodes.concat() {
+            let mut search = CustomBfs::new(&graph, start, edge_distance);
+            let (distance, mut path) = std::iter::from_fn(|| search.next(&graph))
+                .find(|(idx, _, _)| *idx == end)
+                .map(|(_, distance, path)| (distance, path))
+                .unwrap();
+            path.push(end);
+
+            let found = CustomBfs::new(&graph, start, edge_distance).path_to(&graph, end);
+            assert_eq!(found, Some((distance, path)));
+        }
+    }
+
+    #[test]
+    fn path_to_matches_the_node_by_node_search_on_a_20k_node_grid() {
+        let (mut graph, nodes) = grid(100, 200);
+        for edge in graph.edge_indices().collect::<Vec<_>>() {
+            graph[edge].distance += (edge.index() as f64 + 2.0).sqrt();
+        }
+        let (start, end) = (nodes[0][0], nodes[99][199]);
+
+        let mut search = CustomBfs::new(&graph, start, edge_distance);
+        let (distance, mut path) = std::iter::from_fn(|| search.next(&graph))
+            .find(|(idx, _, _)| *idx == end)
+            .map(|(_, distance, path)| (distance, path))
+            .unwrap();
+        path.push(end);
+        // Every visited node keeps its full path, where path_to keeps a single predecessor
+        let stored = search.paths.values().map(Vec::len).sum::<usize>();
+        assert!(stored > 100 * graph.node_count());
+
+        let found = CustomBfs::new(&graph, start, edge_distance).path_to(&graph, end);
+        assert_eq!(found, Some((distance, path)));
+    }
+
+    /// The distances the search found before the heap, popping the closest node off a queue
+    /// kept sorted by inserting every node where a binary search puts it
+    fn sorted_queue_distances(graph: &TestGraph, start: NodeIndex) -> HashMap<NodeIndex, f64> {
+        let mut queue = VecDeque::from([(start, 0.0)]);
+        let mut distances = HashMap::new();
+        while let Some((node, distance)) = queue.pop_front() {
+            if distances.contains_key(&node) {
+                continue;
+            }
+            distances.insert(node, distance);
+            for edge in graph.edges(node) {
+                let next = distance + edge.weight().distance;
+                let (Ok(idx) | Err(idx)) =
+                    queue.binary_search_by(|(_, queued)| queued.partial_cmp(&next).unwrap());
+                queue.insert(idx, (edge.target(), next));
+            }
+        }
+        distances
+    }
+
+    #[test]
+    fn heap_matches_the_sorted_queue_on_a_long_line() {
+        // 10 000 nodes on a two-way line of uneven edges, with shortcuts skipping 100 nodes at a
+        // time, so the closest queued node is not always the last one added
+        let mut graph = TestGraph::default();
+        let nodes = (0..10_000)
+            .map(|idx| graph.add_node(node(0.0, idx as f64 * 0.0001)))
+            .collect::<Vec<_>>();
+        let mut add_two_way = |a, b, road_id, distance| {
+            for (from, to) in [(a, b), (b, a)] {
+                let edge = add_road(&mut graph, from, to, road_id, 50.0);
+                graph[edge].distance = distance;
+            }
+        };
+        for (idx, pair) in nodes.windows(2).enumerate() {
+            add_two_way(pair[0], pair[1], 1, 10.0 + (idx % 7) as f64);
+        }
+        for idx in (0..nodes.len() - 100).step_by(50) {
+            add_two_way(
+                nodes[idx],
+                nodes[idx + 100],
+                2,
+                1000.0 + (idx % 13) as f64 * 20.0,
+            );
+        }
+        let start = nodes[nodes.len() / 3];
+
+        let mut search = CustomBfs::new(&graph, start, edge_distance);
+        while search.next(&graph).is_some() {}
+
+        assert_eq!(search.distances.len(), nodes.len());
+        assert_eq!(search.distances, sorted_queue_distances(&graph, start));
+    }
+
+    #[test]
+    fn search_does_not_turn_back_along_a_two_way_road() {
+        // a and b share a two-way road, and a one-way road leads on from b to c
+        let mut graph = TestGraph::default();
+        let [a, b, c] = [0.0, 0.001, 0.002].map(|longitude| graph.add_node(node(0.0, longitude)));
+        add_road(&mut graph, a, b, 1, 50.0);
+        add_road(&mut graph, b, a, 1, 50.0);
+        add_road(&mut graph, b, c, 2, 50.0);
+        let queued = |search: &CustomBfs<NodeData, EdgeData>, node| {
+            search
+                .stack
+                .iter()
+                .any(|Reverse(queued)| queued.idx == node)
+        };
+
+        let mut search = CustomBfs::new(&graph, a, edge_distance);
+        let mut visited = Vec::new();
+        while let Some((node, _, path)) = search.next(&graph) {
+            // Nothing ever steps back onto the start
+            assert!(!queued(&search, a));
+            visited.push((node, path));
+        }
+        assert_eq!(visited, vec![(a, vec![]), (b, vec![a]), (c, vec![a, b])]);
+
+        let mut search = CustomBfs::new(&graph, a, edge_distance);
+        search.allow_uturns = true;
+        search.next(&graph);
+        search.next(&graph);
+        assert!(queued(&search, a));
+
+        // Continuing from b after arriving from a only goes on to c
+        let mut search = CustomBfs::new(&graph, b, edge_distance);
+        search.arrived_from = Some(a);
+        search.next(&graph);
+        assert!(!queued(&search, a));
+        assert!(queued(&search, c));
+    }
+}
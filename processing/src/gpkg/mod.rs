@@ -1,9 +1,14 @@
 mod model;
 
+pub use model::ColumnMapping;
+
 use std::time::Instant;
 
+use anyhow::Context;
 use console::style;
+use futures::TryStreamExt;
 use geo::CoordsIter;
+use indicatif::ProgressBar;
 use proj4rs::Proj;
 use rayon::iter::{IntoParallelIterator, ParallelIterator};
 use sqlx::{sqlite::SqlitePoolOptions, Pool, Sqlite};
@@ -14,29 +19,173 @@ use crate::{
     progress::eta_bar,
 };
 
-pub fn read_database(path: &str, query: Option<String>) -> Vec<RoadData> {
-    let runtime = tokio::runtime::Runtime::new().unwrap();
+/// SWEREF 99 TM, which the Sverigepaket data is in
+const FALLBACK_SOURCE_CRS: &str =
+    "+proj=utm +zone=33 +ellps=GRS80 +towgs84=0,0,0,0,0,0,0 +units=m +no_defs +type=crs";
+
+/// Read the roads from **table**, or the only features table if not set, transforming them from
+/// **source_crs** if set, or else from the CRS declared in the GeoPackage
+pub fn read_database(
+    path: &str,
+    query: Option<String>,
+    source_crs: Option<String>,
+    table: Option<String>,
+    columns: &ColumnMapping,
+) -> anyhow::Result<Vec<RoadData>> {
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        let (pool, table, source_crs) = open_database(path, source_crs, table).await?;
+        fetch_all_roads(&pool, query, &source_crs, &table, columns).await
+    })
+}
+
+/// Read the roads like [`read_database`], but hand them to **on_chunk** at most **chunk_size** at
+/// a time as they are read, so only one chunk is held in memory. Returns the number of roads read.
+pub fn stream_database(
+    path: &str,
+    query: Option<String>,
+    source_crs: Option<String>,
+    table: Option<String>,
+    columns: &ColumnMapping,
+    chunk_size: usize,
+    on_chunk: impl FnMut(Vec<RoadData>) -> anyhow::Result<()>,
+) -> anyhow::Result<usize> {
+    let runtime = tokio::runtime::Runtime::new()?;
     runtime.block_on(async {
-        let pool = create_connection_pool(path).await;
-        fetch_all_roads(&pool, query).await
+        let (pool, table, source_crs) = open_database(path, source_crs, table).await?;
+        stream_roads(
+            &pool,
+            query,
+            &source_crs,
+            &table,
+            columns,
+            chunk_size,
+            on_chunk,
+        )
+        .await
     })
 }
 
-async fn create_connection_pool(path: &str) -> Pool<Sqlite> {
-    let mut path = path.to_string();
-    if !path.starts_with("sqlite://") {
-        path = format!("sqlite://{}", path);
+/// Connect to the GeoPackage, and pick the table and source CRS to read the roads with
+async fn open_database(
+    path: &str,
+    source_crs: Option<String>,
+    table: Option<String>,
+) -> anyhow::Result<(Pool<Sqlite>, String, String)> {
+    let pool = create_connection_pool(path).await?;
+    let table = match table {
+        Some(table) => table,
+        None => detect_table(&pool).await?,
+    };
+    let source_crs = match source_crs {
+        Some(source_crs) => source_crs,
+        None => detect_source_crs(&pool, &table).await.unwrap_or_else(|| {
+            println!(
+                "{} Could not detect the source CRS, assuming SWEREF 99 TM",
+                style("[!]").bold().yellow()
+            );
+            FALLBACK_SOURCE_CRS.to_string()
+        }),
+    };
+    Ok((pool, table, source_crs))
+}
+
+/// The name of the only features table in the GeoPackage
+async fn detect_table(pool: &Pool<Sqlite>) -> anyhow::Result<String> {
+    let tables: Vec<(String,)> =
+        sqlx::query_as("SELECT table_name FROM gpkg_contents WHERE data_type = 'features'")
+            .fetch_all(pool)
+            .await
+            .context("Failed to read gpkg_contents")?;
+
+    match tables.as_slice() {
+        [(table,)] => Ok(table.clone()),
+        [] => anyhow::bail!("The GeoPackage has no features table"),
+        tables => anyhow::bail!(
+            "The GeoPackage has multiple features tables, pick one with --table: {}",
+            tables
+                .iter()
+                .map(|(table,)| table.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
     }
-    SqlitePoolOptions::new().connect(&path).await.unwrap()
 }
 
-pub async fn fetch_all_roads(pool: &Pool<Sqlite>, query: Option<String>) -> Vec<RoadData> {
-    let from_definition =
-        "+proj=utm +zone=33 +ellps=GRS80 +towgs84=0,0,0,0,0,0,0 +units=m +no_defs +type=crs";
-    let to_definition = "+proj=longlat +datum=WGS84 +no_defs +type=crs";
+/// Find the proj4 definition of the CRS the road geometries are stored in, from the GeoPackage
+/// metadata tables
+async fn detect_source_crs(pool: &Pool<Sqlite>, table: &str) -> Option<String> {
+    let (organization, code, definition): (String, i32, String) = sqlx::query_as(
+        "SELECT s.organization, s.organization_coordsys_id, s.definition \
+         FROM gpkg_geometry_columns g \
+         JOIN gpkg_spatial_ref_sys s ON g.srs_id = s.srs_id \
+         WHERE g.table_name = ?",
+    )
+    .bind(table)
+    .fetch_one(pool)
+    .await
+    .ok()?;
+
+    if definition.trim_start().starts_with("+proj") {
+        return Some(definition);
+    }
+    if !organization.eq_ignore_ascii_case("EPSG") {
+        return None;
+    }
+    epsg_to_proj_string(code)
+}
+
+/// Proj4 definitions of the EPSG codes road data is likely to be in, as proj4rs has no database
+fn epsg_to_proj_string(code: i32) -> Option<String> {
+    // Central meridians of the SWEREF 99 local zones, EPSG:3007 to EPSG:3018
+    const SWEREF_99_MERIDIANS: [f64; 12] = [
+        12.0, 13.5, 15.0, 16.5, 18.0, 14.25, 15.75, 17.25, 18.75, 20.25, 21.75, 23.25,
+    ];
+
+    let definition = match code {
+        4326 => "+proj=longlat +datum=WGS84 +no_defs +type=crs".to_string(),
+        3006 => FALLBACK_SOURCE_CRS.to_string(),
+        3007..=3018 => format!(
+            "+proj=tmerc +lat_0=0 +lon_0={} +k=1 +x_0=150000 +y_0=0 +ellps=GRS80 \
+             +towgs84=0,0,0,0,0,0,0 +units=m +no_defs +type=crs",
+            SWEREF_99_MERIDIANS[(code - 3007) as usize]
+        ),
+        32601..=32660 => format!(
+            "+proj=utm +zone={} +datum=WGS84 +units=m +no_defs +type=crs",
+            code - 32600
+        ),
+        32701..=32760 => format!(
+            "+proj=utm +zone={} +south +datum=WGS84 +units=m +no_defs +type=crs",
+            code - 32700
+        ),
+        25828..=25838 => format!(
+            "+proj=utm +zone={} +ellps=GRS80 +towgs84=0,0,0,0,0,0,0 +units=m +no_defs +type=crs",
+            code - 25800
+        ),
+        _ => return None,
+    };
+    Some(definition)
+}
 
-    let from = Proj::from_proj_string(&from_definition).unwrap();
-    let to = Proj::from_proj_string(&to_definition).unwrap();
+async fn create_connection_pool(path: &str) -> anyhow::Result<Pool<Sqlite>> {
+    let mut url = path.to_string();
+    if !url.starts_with("sqlite://") {
+        url = format!("sqlite://{}", url);
+    }
+    SqlitePoolOptions::new()
+        .connect(&url)
+        .await
+        .with_context(|| format!("Failed to open GeoPackage {}", path))
+}
+
+pub async fn fetch_all_roads(
+    pool: &Pool<Sqlite>,
+    query: Option<String>,
+    from_definition: &str,
+    table: &str,
+    columns: &ColumnMapping,
+) -> anyhow::Result<Vec<RoadData>> {
+    let (from, to) = projections(from_definition)?;
 
     println!(
         "{} Fetching roads from database...",
@@ -44,102 +193,313 @@ pub async fn fetch_all_roads(pool: &Pool<Sqlite>, query: Option<String>) -> Vec<
     );
 
     let filter = query.unwrap_or("".into());
-
-    let count_query = format!("SELECT COUNT(*) FROM SverigepaketTP {}", filter);
-    let road_count: (i32,) = sqlx::query_as(&count_query).fetch_one(pool).await.unwrap();
+    let road_count = count_roads(pool, table, &filter).await?;
 
     println!(
         "{} Parsing {} roads...",
         style("[2/2]").bold().dim(),
-        style(road_count.0).bold()
+        style(road_count).bold()
     );
     let start = Instant::now();
-    let pb = eta_bar(road_count.0 as usize);
+    let pb = eta_bar(road_count);
 
-    let query = format!("SELECT * FROM SverigepaketTP {}", filter);
-    let roads_stream = sqlx::query_as::<_, RawRoadRow>(&query)
+    let query = format!(
+        "SELECT {} FROM \"{}\" {}",
+        columns.select_list(),
+        table,
+        filter
+    );
+    let rows = sqlx::query_as::<_, RawRoadRow>(&query)
         .fetch_all(pool)
-        .await;
+        .await
+        .with_context(|| format!("Failed to read roads from {}", table))?;
 
-    let road_data = roads_stream
-        .unwrap()
-        .into_par_iter()
-        .filter_map(|road| {
-            if let Some(road_type) = road.road_type {
-                if road_type != "bilnät" {
-                    pb.inc(1);
-                    return None;
-                }
-            }
-
-            let mut coords = road
-                .geom
-                .geometry
-                .unwrap()
-                .coords_iter()
-                .map(|coord| (coord.x, coord.y))
-                .collect::<Vec<_>>();
-            proj4rs::transform::transform(&from, &to, coords.as_mut_slice()).unwrap();
-
-            let polyline = coords
-                .iter()
-                .map(|(x, y)| Point {
-                    latitude: y.to_degrees() as f64,
-                    longitude: x.to_degrees() as f64,
-                })
-                .collect::<Vec<_>>();
-
-            let speed_limit_f = road
-                .speed_limit_f
-                .map(|speed_limit| speed_limit.parse().unwrap_or_default());
-            let speed_limit_b = road
-                .speed_limit_b
-                .map(|speed_limit| speed_limit.parse().unwrap_or_default());
-
-            let speed_limit = match (speed_limit_f, speed_limit_b) {
-                (Some(f), Some(b)) => (f + b) / 2.0,
-                (Some(f), None) => f,
-                (None, Some(b)) => b,
-                (None, None) => 0.0,
-            };
+    let road_data = parse_roads(rows, &from, &to, &pb);
+    pb.finish_and_clear();
 
-            let fdf = if let Some(fdf) = road.forbidden_direction_f {
-                fdf.parse::<i32>().unwrap() == -1
-            } else {
-                false
-            };
-            let fdb = if let Some(fdb) = road.forbidden_direction_b {
-                fdb.parse::<i32>().unwrap() == -1
-            } else {
-                false
-            };
-            let direction = match (fdf, fdb) {
-                (true, true) => crate::parse::RoadDirection::None,
-                (true, false) => crate::parse::RoadDirection::Backward,
-                (false, true) => crate::parse::RoadDirection::Forward,
-                (false, false) => crate::parse::RoadDirection::Both,
-            };
+    println!(
+        "{:?} Parsed {} roads",
+        style(start.elapsed()).bold().dim().yellow(),
+        style(road_data.len()).bold(),
+    );
 
-            pb.inc(1);
-
-            Some(RoadData {
-                main_number: road.main_number,
-                sub_number: road.sub_number,
-                length: road.length,
-                unique_id: road.unique_id,
-                coordinates: polyline,
-                direction,
-                speed_limit,
-            })
-        })
-        .collect::<Vec<_>>();
+    Ok(road_data)
+}
+
+/// Read the roads from **table** one chunk of rows at a time, transforming and handing each chunk
+/// to **on_chunk** before reading the next
+pub async fn stream_roads(
+    pool: &Pool<Sqlite>,
+    query: Option<String>,
+    from_definition: &str,
+    table: &str,
+    columns: &ColumnMapping,
+    chunk_size: usize,
+    mut on_chunk: impl FnMut(Vec<RoadData>) -> anyhow::Result<()>,
+) -> anyhow::Result<usize> {
+    let (from, to) = projections(from_definition)?;
+
+    let filter = query.unwrap_or("".into());
+    let road_count = count_roads(pool, table, &filter).await?;
+
+    println!(
+        "{} Streaming {} roads in chunks of {}...",
+        style("[1/1]").bold().dim(),
+        style(road_count).bold(),
+        style(chunk_size).bold()
+    );
+    let start = Instant::now();
+    let pb = eta_bar(road_count);
+
+    let query = format!(
+        "SELECT {} FROM \"{}\" {}",
+        columns.select_list(),
+        table,
+        filter
+    );
+    let mut rows = sqlx::query_as::<_, RawRoadRow>(&query).fetch(pool);
+
+    let mut parsed = 0;
+    let mut chunk = Vec::with_capacity(chunk_size);
+    while let Some(row) = rows.try_next().await? {
+        chunk.push(row);
+        if chunk.len() >= chunk_size {
+            let roads = parse_roads(std::mem::take(&mut chunk), &from, &to, &pb);
+            parsed += roads.len();
+            on_chunk(roads)?;
+        }
+    }
+    if !chunk.is_empty() {
+        let roads = parse_roads(chunk, &from, &to, &pb);
+        parsed += roads.len();
+        on_chunk(roads)?;
+    }
     pb.finish_and_clear();
 
     println!(
         "{:?} Parsed {} roads",
         style(start.elapsed()).bold().dim().yellow(),
-        style(road_data.len()).bold(),
+        style(parsed).bold(),
     );
 
-    road_data
+    Ok(parsed)
+}
+
+/// The source projection from **from_definition**, and WGS84 to transform the roads into
+fn projections(from_definition: &str) -> anyhow::Result<(Proj, Proj)> {
+    let to_definition = "+proj=longlat +datum=WGS84 +no_defs +type=crs";
+
+    let from = Proj::from_proj_string(from_definition)
+        .with_context(|| format!("Invalid source CRS {}", from_definition))?;
+    let to = Proj::from_proj_string(to_definition).unwrap();
+    Ok((from, to))
+}
+
+async fn count_roads(pool: &Pool<Sqlite>, table: &str, filter: &str) -> anyhow::Result<usize> {
+    let count_query = format!("SELECT COUNT(*) FROM \"{}\" {}", table, filter);
+    let road_count: (i32,) = sqlx::query_as(&count_query)
+        .fetch_one(pool)
+        .await
+        .with_context(|| format!("Failed to count the roads in {}", table))?;
+    Ok(road_count.0 as usize)
+}
+
+/// Transform the rows into roads in parallel, in the order of the rows, skipping everything but
+/// the car network. Each worker transforms with its own copy of the projections.
+fn parse_roads(rows: Vec<RawRoadRow>, from: &Proj, to: &Proj, pb: &ProgressBar) -> Vec<RoadData> {
+    rows.into_par_iter()
+        .map_init(
+            || (from.clone(), to.clone()),
+            |(from, to), road| {
+                pb.inc(1);
+                parse_road(road, from, to)
+            },
+        )
+        .flatten()
+        .collect()
+}
+
+fn parse_road(road: RawRoadRow, from: &Proj, to: &Proj) -> Option<RoadData> {
+    if let Some(road_type) = road.road_type {
+        if road_type != "bilnät" {
+            return None;
+        }
+    }
+
+    let mut coords = road
+        .geom
+        .geometry
+        .unwrap()
+        .coords_iter()
+        .map(|coord| (coord.x, coord.y))
+        .collect::<Vec<_>>();
+    if from.is_latlong() {
+        // proj4rs works in radians for geographic coordinates
+        coords
+            .iter_mut()
+            .for_each(|(x, y)| (*x, *y) = (x.to_radians(), y.to_radians()));
+    }
+    proj4rs::transform::transform(from, to, coords.as_mut_slice()).unwrap();
+
+    let polyline = coords
+        .iter()
+        .map(|(x, y)| Point {
+            latitude: y.to_degrees() as f64,
+            longitude: x.to_degrees() as f64,
+        })
+        .collect::<Vec<_>>();
+
+    let speed_limit_f = road
+        .speed_limit_f
+        .map(|speed_limit| speed_limit.parse().unwrap_or_default());
+    let speed_limit_b = road
+        .speed_limit_b
+        .map(|speed_limit| speed_limit.parse().unwrap_or_default());
+
+    let speed_limit = match (speed_limit_f, speed_limit_b) {
+        (Some(f), Some(b)) => (f + b) / 2.0,
+        (Some(f), None) => f,
+        (None, Some(b)) => b,
+        (None, None) => 0.0,
+    };
+
+    let fdf = if let Some(fdf) = road.forbidden_direction_f {
+        fdf.parse::<i32>().unwrap() == -1
+    } else {
+        false
+    };
+    let fdb = if let Some(fdb) = road.forbidden_direction_b {
+        fdb.parse::<i32>().unwrap() == -1
+    } else {
+        false
+    };
+    let direction = match (fdf, fdb) {
+        (true, true) => crate::parse::RoadDirection::None,
+        (true, false) => crate::parse::RoadDirection::Backward,
+        (false, true) => crate::parse::RoadDirection::Forward,
+        (false, false) => crate::parse::RoadDirection::Both,
+    };
+
+    Some(RoadData {
+        main_number: road.main_number,
+        sub_number: road.sub_number,
+        length: road.length,
+        unique_id: road.unique_id,
+        coordinates: polyline,
+        direction,
+        speed_limit,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_graphs::{temp_path, TempPath};
+
+    /// A temporary copy of the GeoPackage fixture **name**, as opening a database with sqlx can
+    /// write to it
+    fn fixture(name: &str) -> TempPath {
+        let path = temp_path(name);
+        let fixture = format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name);
+        std::fs::copy(fixture, &path).unwrap();
+        path
+    }
+
+    #[test]
+    fn detected_crs_gives_wgs84_coordinates() {
+        let path = fixture("roads.gpkg");
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let detected = runtime.block_on(async {
+            let pool = create_connection_pool(&path).await.unwrap();
+            detect_source_crs(&pool, "vagar").await
+        });
+
+        let roads = read_database(&path, None, None, None, &ColumnMapping::default()).unwrap();
+
+        // The fixture is in SWEREF 99 TM around Stockholm
+        assert_eq!(detected.as_deref(), Some(FALLBACK_SOURCE_CRS));
+        assert!(!roads.is_empty());
+        for point in roads.iter().flat_map(|road| road.coordinates.iter()) {
+            assert!((59.0..59.6).contains(&point.latitude), "{:?}", point);
+            assert!((17.8..18.8).contains(&point.longitude), "{:?}", point);
+        }
+    }
+
+    #[test]
+    fn reads_the_named_table() {
+        let path = fixture("two_tables.gpkg");
+
+        let detected = read_database(&path, None, None, None, &ColumnMapping::default());
+        let named = read_database(
+            &path,
+            None,
+            None,
+            Some("vagar_2".into()),
+            &ColumnMapping::default(),
+        );
+
+        let error = detected.unwrap_err().to_string();
+        assert!(error.contains("multiple features tables"), "{}", error);
+        assert!(
+            error.contains("vagar") && error.contains("vagar_2"),
+            "{}",
+            error
+        );
+        // The 3 roads outside the car network are skipped
+        assert_eq!(named.unwrap().len(), 37);
+    }
+
+    #[test]
+    fn streamed_roads_match_the_roads_in_memory() {
+        let path = fixture("roads.gpkg");
+        let columns = ColumnMapping::default();
+
+        let in_memory = read_database(&path, None, None, None, &columns).unwrap();
+        let mut chunks = Vec::new();
+        let streamed = stream_database(&path, None, None, None, &columns, 7, |chunk| {
+            chunks.push(chunk);
+            Ok(())
+        })
+        .unwrap();
+
+        assert_eq!(streamed, in_memory.len());
+        assert!(chunks.iter().all(|chunk| chunk.len() <= 7));
+        assert_eq!(
+            serde_json::to_value(chunks.concat()).unwrap(),
+            serde_json::to_value(in_memory).unwrap()
+        );
+    }
+
+    #[test]
+    fn parallel_parsing_matches_sequential_parsing() {
+        let path = fixture("roads.gpkg");
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let (rows, same_rows) = runtime.block_on(async {
+            let pool = create_connection_pool(&path).await.unwrap();
+            let query = format!(
+                "SELECT {} FROM vagar",
+                ColumnMapping::default().select_list()
+            );
+            let fetch = || async {
+                sqlx::query_as::<_, RawRoadRow>(&query)
+                    .fetch_all(&pool)
+                    .await
+                    .unwrap()
+            };
+            (fetch().await, fetch().await)
+        });
+        let (from, to) = projections(FALLBACK_SOURCE_CRS).unwrap();
+
+        let parallel = parse_roads(rows, &from, &to, &ProgressBar::hidden());
+        let sequential = same_rows
+            .into_iter()
+            .filter_map(|row| parse_road(row, &from, &to))
+            .collect::<Vec<_>>();
+
+        assert_eq!(parallel.len(), 37);
+        assert_eq!(
+            serde_json::to_value(parallel).unwrap(),
+            serde_json::to_value(sequential).unwrap()
+        );
+    }
 }
@@ -1,26 +1,91 @@
 use ::sqlx::FromRow;
+use anyhow::Context;
 use geo::Geometry;
 use geozero::wkb;
+use serde::Deserialize;
+
+/// The columns of the road table to read each field of [`RawRoadRow`] from, the defaults match
+/// the Sverigepaket layer. Optional columns can be set to `null` if the table lacks them.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ColumnMapping {
+    pub geom: String,
+    pub main_number: String,
+    pub sub_number: String,
+    pub length: String,
+    pub unique_id: String,
+    pub speed_limit_f: Option<String>,
+    pub speed_limit_b: Option<String>,
+    pub road_type: Option<String>,
+    pub forbidden_direction_f: Option<String>,
+    pub forbidden_direction_b: Option<String>,
+}
+
+impl Default for ColumnMapping {
+    fn default() -> Self {
+        Self {
+            geom: "geom".into(),
+            main_number: "Vagnummer_Huvudnummer_Vard".into(),
+            sub_number: "Vagnummer_Undernummer".into(),
+            length: "_length".into(),
+            unique_id: "id".into(),
+            speed_limit_f: Some("Hastighetsgrans_HogstaTillatnaHastighet_F".into()),
+            speed_limit_b: Some("Hastighetsgrans_HogstaTillatnaHastighet_B".into()),
+            road_type: Some("Vagtrafiknat_Vagtrafiknattyp".into()),
+            forbidden_direction_f: Some("ForbjudenFardriktning_F".into()),
+            forbidden_direction_b: Some("ForbjudenFardriktning_B".into()),
+        }
+    }
+}
+
+impl ColumnMapping {
+    pub fn from_file(path: &str) -> anyhow::Result<Self> {
+        let raw = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read column mapping from {}", path))?;
+        serde_json::from_str(&raw)
+            .with_context(|| format!("Failed to parse column mapping from {}", path))
+    }
+
+    /// The select list aliasing every mapped column to its [`RawRoadRow`] field
+    pub fn select_list(&self) -> String {
+        let required = [
+            (&self.geom, "geom"),
+            (&self.main_number, "main_number"),
+            (&self.sub_number, "sub_number"),
+            (&self.length, "length"),
+            (&self.unique_id, "unique_id"),
+        ]
+        .map(|(column, field)| format!("\"{}\" AS {}", column, field));
+        let optional = [
+            (&self.speed_limit_f, "speed_limit_f"),
+            (&self.speed_limit_b, "speed_limit_b"),
+            (&self.road_type, "road_type"),
+            (&self.forbidden_direction_f, "forbidden_direction_f"),
+            (&self.forbidden_direction_b, "forbidden_direction_b"),
+        ]
+        .map(|(column, field)| match column {
+            Some(column) => format!("\"{}\" AS {}", column, field),
+            None => format!("NULL AS {}", field),
+        });
+
+        required
+            .into_iter()
+            .chain(optional)
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
 
 #[derive(Debug, FromRow)]
 pub(crate) struct RawRoadRow {
     pub geom: wkb::Decode<Geometry<f64>>,
-    #[sqlx(rename = "Vagnummer_Huvudnummer_Vard")]
     pub main_number: i32,
-    #[sqlx(rename = "Vagnummer_Undernummer")]
     pub sub_number: i32,
-    #[sqlx(rename = "_length")]
     pub length: f64,
-    #[sqlx(rename = "id")]
     pub unique_id: i32,
-    #[sqlx(rename = "Hastighetsgrans_HogstaTillatnaHastighet_F")]
     pub speed_limit_f: Option<String>,
-    #[sqlx(rename = "Hastighetsgrans_HogstaTillatnaHastighet_B")]
     pub speed_limit_b: Option<String>,
-    #[sqlx(rename = "Vagtrafiknat_Vagtrafiknattyp")]
     pub road_type: Option<String>,
-    #[sqlx(rename = "ForbjudenFardriktning_F")]
     pub forbidden_direction_f: Option<String>,
-    #[sqlx(rename = "ForbjudenFardriktning_B")]
     pub forbidden_direction_b: Option<String>,
 }
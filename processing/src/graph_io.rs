@@ -0,0 +1,271 @@
+use std::{collections::HashMap, path::Path};
+
+use anyhow::Context;
+use clap::{Args, ValueEnum};
+use console::style;
+use petgraph::{graph::NodeIndex, stable_graph::StableDiGraph};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    mongo::model::SensorMetadata,
+    processing::{
+        build_node_tree_from_points, node_points, EdgeData, NodeData, NodeTree, ProcessedGraph,
+        RoutingGraph,
+    },
+};
+
+/// Version of the serialized graph, bump whenever [`NodeData`], [`EdgeData`] or [`ProcessedGraph`]
+/// change in a way that breaks reading older files
+pub const GRAPH_VERSION: u32 = 1;
+
+/// What a JSON graph file holds, the graph tagged with the version it was written with
+#[derive(Serialize)]
+struct GraphFileRef<'a> {
+    version: u32,
+    graph: &'a ProcessedGraph,
+}
+
+#[derive(Deserialize)]
+struct GraphFile {
+    version: u32,
+    graph: ProcessedGraph,
+}
+
+/// Only the version of a JSON graph file, files from before the version was added have none
+#[derive(Deserialize)]
+struct GraphHeader {
+    #[serde(default)]
+    version: u32,
+}
+
+/// Whether to cache the node index of a graph next to the graph file
+#[derive(Debug, Clone, Copy, Args)]
+#[clap(about = None, long_about = None)]
+pub struct IndexOptions {
+    /// Load the node index cached next to the graph file instead of building it, writing the
+    /// cache if it is missing or out of date
+    #[clap(long, default_value = "false", default_missing_value = "true")]
+    pub reuse_index: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum GraphFormat {
+    Json,
+    Bitcode,
+}
+
+impl GraphFormat {
+    /// Detect the format from the file extension, `.bin` is bitcode and everything else is JSON
+    pub fn from_path(path: &str) -> Self {
+        if path.ends_with(".bin") {
+            GraphFormat::Bitcode
+        } else {
+            GraphFormat::Json
+        }
+    }
+}
+
+/// Load the graph at **path** for routing together with its sensors, with **reuse_index** the node
+/// tree is built from the index cached next to the graph instead of from scratch
+pub fn try_load_routing_graph(
+    path: &str,
+    reuse_index: bool,
+) -> anyhow::Result<(RoutingGraph, HashMap<NodeIndex, Vec<SensorMetadata>>)> {
+    let ProcessedGraph {
+        graph,
+        sensor_store,
+    } = try_load_graph(path)?;
+    let routing = if reuse_index {
+        let tree = load_index(path, &graph);
+        RoutingGraph::with_tree(graph, tree)
+    } else {
+        RoutingGraph::new(graph)
+    };
+    Ok((routing, sensor_store))
+}
+
+pub fn try_load_graph(path: &str) -> anyhow::Result<ProcessedGraph> {
+    match GraphFormat::from_path(path) {
+        GraphFormat::Json => {
+            let raw = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read graph from {}", path))?;
+            match serde_json::from_str::<GraphFile>(&raw) {
+                Ok(file) => {
+                    check_version(path, file.version)?;
+                    Ok(file.graph)
+                }
+                Err(e) => {
+                    // A graph of another version can fail to parse before its version is compared
+                    if let Ok(header) = serde_json::from_str::<GraphHeader>(&raw) {
+                        check_version(path, header.version)?;
+                    }
+                    Err(e).with_context(|| format!("Failed to parse {} as a JSON graph", path))
+                }
+            }
+        }
+        GraphFormat::Bitcode => {
+            let raw = std::fs::read(path)
+                .with_context(|| format!("Failed to read graph from {}", path))?;
+            // Bitcode is not self-describing, so the version is written as a prefix of its own
+            let (version, raw) = match raw.split_first_chunk::<4>() {
+                Some((version, raw)) => (u32::from_le_bytes(*version), raw),
+                None => (0, raw.as_slice()),
+            };
+            check_version(path, version)?;
+            bitcode::deserialize(raw)
+                .with_context(|| format!("Failed to parse {} as a bitcode graph", path))
+        }
+    }
+}
+
+fn check_version(path: &str, version: u32) -> anyhow::Result<()> {
+    if version != GRAPH_VERSION {
+        anyhow::bail!(
+            "Incompatible graph version {} in {}, expected version {}, re-run Process to rebuild it",
+            version,
+            path,
+            GRAPH_VERSION
+        );
+    }
+    Ok(())
+}
+
+pub fn save_graph(path: &str, graph: &ProcessedGraph) -> anyhow::Result<()> {
+    save_graph_as(path, graph, GraphFormat::from_path(path))
+}
+
+pub fn save_graph_as(
+    path: &str,
+    graph: &ProcessedGraph,
+    format: GraphFormat,
+) -> anyhow::Result<()> {
+    let data = match format {
+        GraphFormat::Json => serde_json::to_vec(&GraphFileRef {
+            version: GRAPH_VERSION,
+            graph,
+        })?,
+        GraphFormat::Bitcode => {
+            let mut data = GRAPH_VERSION.to_le_bytes().to_vec();
+            data.extend(bitcode::serialize(graph)?);
+            data
+        }
+    };
+    std::fs::write(path, data).with_context(|| format!("Failed to write graph to {}", path))
+}
+
+/// The path of the node index cached for the graph at **path**, `graph.json` has
+/// `graph.kdtree.bin`
+fn index_path(path: &str) -> String {
+    Path::new(path)
+        .with_extension("kdtree.bin")
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Build the node acceleration structure from the index cached next to the graph at **path**,
+/// rebuilding and rewriting the index if it is missing or any of its points is not where the node
+/// it belongs to is in **graph**
+fn load_index(path: &str, graph: &StableDiGraph<NodeData, EdgeData>) -> NodeTree {
+    let index_path = index_path(path);
+    let points = match read_index(&index_path) {
+        Ok(points) if index_matches(graph, &points) => points,
+        _ => {
+            let points = node_points(graph);
+            if let Err(e) = write_index(&index_path, &points) {
+                println!("{} {:#}", style("[!]").bold().yellow(), e);
+            }
+            points
+        }
+    };
+    build_node_tree_from_points(graph, &points)
+}
+
+/// Whether **points** has a point for every node of **graph**, at the position of the node
+fn index_matches(
+    graph: &StableDiGraph<NodeData, EdgeData>,
+    points: &[(NodeIndex, [f64; 2])],
+) -> bool {
+    points.len() == graph.node_count()
+        && points.iter().all(|(idx, point)| {
+            graph
+                .node_weight(*idx)
+                .is_some_and(|node| *point == [node.point.latitude, node.point.longitude])
+        })
+}
+
+fn read_index(path: &str) -> anyhow::Result<Vec<(NodeIndex, [f64; 2])>> {
+    let raw =
+        std::fs::read(path).with_context(|| format!("Failed to read node index from {}", path))?;
+    bitcode::deserialize(&raw).with_context(|| format!("Failed to parse {} as a node index", path))
+}
+
+fn write_index(path: &str, points: &[(NodeIndex, [f64; 2])]) -> anyhow::Result<()> {
+    let data = bitcode::serialize(points)?;
+    std::fs::write(path, data).with_context(|| format!("Failed to write node index to {}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_graphs::{diamond, grid, point, temp_path};
+
+    fn processed_diamond() -> ProcessedGraph {
+        ProcessedGraph {
+            graph: diamond().0,
+            sensor_store: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn graph_round_trips_with_its_version() {
+        for name in ["round-trip.json", "round-trip.bin"] {
+            let path = temp_path(name);
+            let graph = processed_diamond();
+            save_graph(&path, &graph).unwrap();
+
+            let loaded = try_load_graph(&path).unwrap();
+            assert_eq!(loaded.graph.node_count(), graph.graph.node_count());
+            assert!(loaded.graph.edge_weights().eq(graph.graph.edge_weights()));
+        }
+    }
+
+    #[test]
+    fn graph_of_another_version_is_rejected() {
+        let graph = processed_diamond();
+
+        let json_path = temp_path("old-version.json");
+        let file = serde_json::json!({ "version": GRAPH_VERSION + 1, "graph": graph });
+        std::fs::write(&json_path, file.to_string()).unwrap();
+        let bitcode_path = temp_path("old-version.bin");
+        let mut data = (GRAPH_VERSION + 1).to_le_bytes().to_vec();
+        data.extend(bitcode::serialize(&graph).unwrap());
+        std::fs::write(&bitcode_path, data).unwrap();
+
+        for path in [json_path, bitcode_path] {
+            let error = try_load_graph(&path).unwrap_err();
+            assert!(error.to_string().contains("Incompatible graph version"));
+        }
+    }
+
+    #[test]
+    fn cached_index_finds_the_same_nearest_nodes() {
+        let path = temp_path("cached-index.json");
+        let graph = ProcessedGraph {
+            graph: grid(5, 5).0,
+            sensor_store: HashMap::new(),
+        };
+        save_graph(&path, &graph).unwrap();
+        let fresh = RoutingGraph::new(graph.graph);
+
+        // The first load writes the index and the second one reads it
+        let (written, _) = try_load_routing_graph(&path, true).unwrap();
+        assert!(Path::new(&index_path(&path)).exists());
+        let (cached, _) = try_load_routing_graph(&path, true).unwrap();
+
+        for (latitude, longitude) in [(0.0, 0.0), (0.0012, 0.0031), (0.0049, 0.0026), (-1.0, 1.0)] {
+            let query = point(latitude, longitude);
+            assert_eq!(cached.nearest_node(query), fresh.nearest_node(query));
+            assert_eq!(written.nearest_node(query), fresh.nearest_node(query));
+        }
+    }
+}
@@ -1,33 +1,44 @@
 mod args;
 mod custom_bfs;
 mod gpkg;
+mod graph_io;
 mod math;
 mod modes;
 mod mongo;
+mod osm;
 mod output;
 mod parse;
 mod processing;
 mod progress;
+#[cfg(test)]
+mod test_graphs;
 mod travel_time;
+mod tsp;
 mod util;
 mod visitor;
 
+use std::io::Write;
+
+use anyhow::Context;
 use clap::{Parser, Subcommand};
 use console::style;
 use human_bytes::human_bytes;
-use math::geo_distance;
-use modes::{AggregateOptions, InspectOptions, TestPeriodDivisionOptions};
+use modes::{
+    AggregateOptions, InspectOptions, SimulationOptions, SimulationSetup, TestPeriodDivisionOptions,
+};
 use mongo::client::MongoOptions;
-use output::{calc_canvas_size_from_extents, Canvas, DrawOptions};
+use output::{calc_canvas_size_from_extents, Canvas, DrawOptions, Projection};
 use parse::{parse_road_data, parse_sensor_data, Point};
 use petgraph::visit::IntoEdgeReferences;
-use processing::build_node_acceleration_structure;
+use processing::{ProcessedGraph, RoutingGraph};
 use tokio::runtime::Runtime;
-use visitor::DistanceMetric;
+use visitor::{DistanceMetric, SearchStrategy};
 
 use crate::{
-    modes::test_period_division, mongo::client::async_client::AsyncMongoClient, parse::read_roads,
-    processing::ProcessedGraph, util::PointQuery,
+    modes::test_period_division,
+    mongo::client::async_client::AsyncMongoClient,
+    parse::read_roads,
+    util::{BoundingBox, CropOptions, PenaltyOptions, PointQuery, SnapOptions, SnapStrategy},
 };
 
 #[derive(Debug, Parser)]
@@ -40,6 +51,9 @@ use crate::{
 struct Cli {
     #[command(subcommand)]
     commands: Commands,
+    /// Number of threads used for parallel work, 0 uses every core
+    #[clap(long, global = true, default_value = "0")]
+    threads: usize,
 }
 
 #[derive(Debug, Subcommand)]
@@ -53,6 +67,11 @@ enum Commands {
         road_data: String,
         #[clap(short = 'S', long, default_value = "../sensorData.json")]
         sensor_data: String,
+        /// Format of the raw road data
+        #[clap(long, default_value = "json")]
+        format: parse::RawRoadFormat,
+        #[clap(flatten)]
+        geojson_keys: parse::GeoJsonRoadKeys,
     },
     DrawRoad {
         #[clap(long, default_value = "./out/./out/graph.bin")]
@@ -61,24 +80,56 @@ enum Commands {
         output: String,
         #[clap(short, long)]
         unique_ids: Vec<i32>,
+        #[clap(flatten)]
+        crop: CropOptions,
+        /// Query files of reference routes, draw the edges between two sensors along them solid
+        /// and the rest of the network faint instead of drawing roads by id
+        #[clap(long)]
+        coverage_routes: Vec<String>,
+        /// Metric the coverage routes are found by
+        #[clap(long, default_value = "space")]
+        metric: DistanceMetric,
+        #[clap(flatten)]
+        snap: SnapOptions,
+        #[clap(flatten)]
+        canvas: output::CanvasOptions,
     },
     ShortestPath {
+        #[clap(long, default_value = "./out/graph.json")]
+        input: String,
+        #[clap(long, default_value = "./out/graph.svg")]
+        output: String,
+        /// Repeat to route every query file separately and draw the routes in different colors
+        #[clap(short, long, default_value = "./queries/query1.json")]
+        query_file: Vec<String>,
+        #[clap(flatten)]
+        crop: CropOptions,
+        #[clap(flatten)]
+        options: modes::ShortestPathOptions,
+    },
+    CompareRoutes {
         #[clap(long, default_value = "./out/graph.json")]
         input: String,
         #[clap(long, default_value = "./out/graph.svg")]
         output: String,
         #[clap(short, long, default_value = "./queries/query1.json")]
         query_file: String,
-        #[clap(short, long, default_value = "nan")]
-        cull_to_path_distance: f64,
-        #[clap(short, long, default_value = "space")]
-        metric: DistanceMetric,
+        #[clap(flatten)]
+        snap: SnapOptions,
+        #[clap(flatten)]
+        penalty: PenaltyOptions,
+        #[clap(flatten)]
+        index: graph_io::IndexOptions,
+        #[clap(flatten)]
+        canvas: output::CanvasOptions,
     },
     DrawDisjoint {
         #[clap(long, default_value = "./out/graph.json")]
         input: String,
         #[clap(long, default_value = "./out/graph.svg")]
         output: String,
+        #[clap(flatten)]
+        canvas: output::CanvasOptions,
     },
     DrawReachable {
         #[clap(long, default_value = "./out/graph.bin")]
@@ -93,32 +144,34 @@ enum Commands {
         range: f64,
         #[clap(short, long, default_value = "false", default_missing_value = "true")]
         inverse: bool,
+        #[clap(flatten)]
+        canvas: output::CanvasOptions,
     },
     DrawDistance {
         #[clap(long, default_value = "./out/graph.bin")]
         input: String,
         #[clap(long, default_value = "./out/graph.svg")]
         output: String,
-        #[clap(short = 'a', long = "lat")]
-        latitude: f64,
-        #[clap(short = 'o', long = "lon")]
-        longitude: f64,
-        #[clap(short = 'c', long, default_value = "nan")]
-        max_distance: f64,
-        #[clap(short, long, default_value = "space")]
-        metric: DistanceMetric,
-        #[clap(short, long, default_value = "false", default_missing_value = "true")]
-        forward_only: bool,
+        #[clap(flatten)]
+        crop: CropOptions,
+        #[clap(flatten)]
+        options: modes::DrawDistanceOptions,
     },
     Process {
         #[clap(short, long, default_value = "./out/gpkgData.json")]
         road_data: String,
         #[clap(short, long, default_value = "./out/graph.json")]
         output: String,
+        /// Graph output format, detected from the output file extension if not set
+        #[clap(long)]
+        format: Option<graph_io::GraphFormat>,
         #[clap(flatten)]
         mongo_options: MongoOptions,
         #[clap(flatten)]
         processing_options: processing::GraphProcessingOptions,
+        /// Only estimate the size of the graph from the road and sensor data, without building it
+        #[clap(long, default_value = "false", default_missing_value = "true")]
+        dry_run: bool,
     },
     ExtractGpkgData {
         #[clap(short, long, default_value = "SverigepaketTP.gpkg")]
@@ -127,6 +180,43 @@ enum Commands {
         output: String,
         #[clap(short, long)]
         query: Option<String>,
+        /// Proj4 definition of the CRS the roads are stored in, detected from the GeoPackage if not set
+        #[clap(long)]
+        source_crs: Option<String>,
+        /// Table to read the roads from, the only features table in the GeoPackage if not set
+        #[clap(long)]
+        table: Option<String>,
+        /// JSON file mapping road fields to column names, for layers not following Sverigepaket
+        #[clap(long)]
+        columns: Option<String>,
+        /// Read and write this many roads at a time instead of holding them all in memory, writing
+        /// the roads as newline delimited JSON
+        #[clap(long)]
+        chunk_size: Option<usize>,
+    },
+    /// Extract highway ways from an OSM PBF extract into road data usable by `process`
+    ImportOsm {
+        #[clap(long)]
+        pbf: String,
+        #[clap(short, long, default_value = "./out/osmData.json")]
+        output: String,
+    },
+    /// Export the graph as GeoJSON, for use in GIS tools
+    #[clap(name = "export-geojson")]
+    ExportGeoJson {
+        #[clap(long, default_value = "./out/graph.json")]
+        input: String,
+        #[clap(long, default_value = "./out/graph.geojson")]
+        output: String,
+    },
+    /// Check the graph for isolated nodes, broken edges and self-loops, failing on the ones that
+    /// break routing or drawing
+    Validate {
+        #[clap(long, default_value = "./out/graph.json")]
+        input: String,
+        /// How many issues of each kind to list
+        #[clap(long, default_value = "5")]
+        examples: usize,
     },
     Inspect {
         #[clap(long, default_value = "./out/graph.json")]
@@ -134,9 +224,10 @@ enum Commands {
         #[clap(long, default_value = "./out/graph.svg")]
         output: String,
         #[clap(flatten)]
+        crop: CropOptions,
+        #[clap(flatten)]
         options: InspectOptions,
     },
-    /*
     Simulate {
         #[clap(long, default_value = "./out/graph.bin")]
         input: String,
@@ -146,7 +237,7 @@ enum Commands {
         setup: String,
         #[clap(flatten)]
         options: SimulationOptions,
-    }, */
+    },
     AggregateSensorData {
         #[clap(flatten)]
         options: AggregateOptions,
@@ -159,19 +250,76 @@ enum Commands {
         #[clap(flatten)]
         options: modes::LiveRouteOptions,
     },
+    /// Check that the graph, query files and database are set up correctly
+    Doctor {
+        #[clap(flatten)]
+        options: modes::DoctorOptions,
+    },
+    /// Average the historical speed of every sensor per hour and day of the week
+    BuildProfiles {
+        #[clap(flatten)]
+        options: modes::BuildProfilesOptions,
+    },
+    /// Draw a circle per sensor node sized and colored by its live flow rate
+    DrawHeatmap {
+        #[clap(flatten)]
+        options: modes::DrawHeatmapOptions,
+    },
+    /// Color a grid over the graph by the average live speed of the sensors in each cell
+    SpeedGrid {
+        #[clap(flatten)]
+        options: modes::SpeedGridOptions,
+    },
     FindGaps {
         #[clap(flatten)]
         options: modes::FindGapsOptions,
     },
+    /// Write the shortest distance between every pair of sensors as a CSV matrix
+    DistanceMatrix {
+        #[clap(flatten)]
+        options: modes::DistanceMatrixOptions,
+    },
+    /// Write the node every sensor is assigned to as CSV, to audit the assignment
+    ExportAssignments {
+        #[clap(flatten)]
+        options: modes::ExportAssignmentsOptions,
+    },
+    /// Find a small set of sensors such that every route passes at least one of them
+    SensorCover {
+        #[clap(flatten)]
+        options: modes::SensorCoverOptions,
+    },
+    /// Write polygons of the area reachable from a point within each travel time as GeoJSON
+    Isochrone {
+        #[clap(flatten)]
+        options: modes::IsochroneOptions,
+    },
+    /// Contract the graph so only junctions and sensor nodes remain, keeping shortest path distances
+    Skeletonize {
+        #[clap(long, default_value = "./out/graph.json")]
+        input: String,
+        #[clap(long, default_value = "./out/skeleton.json")]
+        output: String,
+        /// Simplify contracted edge polylines with more points than this
+        #[clap(long)]
+        max_polyline_points: Option<usize>,
+    },
     Custom {},
-    Custom2 {},
+    Custom2 {
+        #[clap(flatten)]
+        crop: CropOptions,
+    },
     Custom3 {},
 }
 
-fn main() {
+fn main() -> anyhow::Result<()> {
     let start = std::time::Instant::now();
 
     let args: Cli = Cli::parse();
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(args.threads)
+        .build_global()
+        .context("Failed to build the thread pool")?;
 
     match args.commands {
         Commands::ParseRawData {
@@ -179,20 +327,36 @@ fn main() {
             raw_sensor_data,
             road_data,
             sensor_data,
+            format,
+            geojson_keys,
         } => {
             if let Some(raw_road_data) = raw_road_data {
                 println!("{} Parsing raw road data", style("[1/3]").bold().dim());
-                let raw = std::fs::read_to_string(&raw_road_data).unwrap();
-                let bytes = raw.len();
+                let bytes = std::fs::metadata(&raw_road_data)
+                    .with_context(|| format!("Failed to read road data from {}", raw_road_data))?
+                    .len();
                 println!(
                     "{} Raw road data size: {}",
                     style("[2/3]").bold().dim(),
                     style(human_bytes(bytes as f64)).red()
                 );
-                let raw_road_data: Vec<parse::RawRoadData> = serde_json::from_str(&raw).unwrap();
-                let data = parse_road_data(raw_road_data);
-                std::fs::write(&road_data, serde_json::to_string(&data).unwrap()).unwrap();
-                let bytes = std::fs::metadata(&road_data).unwrap().len();
+                let data = match format {
+                    parse::RawRoadFormat::Json => {
+                        let raw = std::fs::read_to_string(&raw_road_data).with_context(|| {
+                            format!("Failed to read road data from {}", raw_road_data)
+                        })?;
+                        let raw_road_data: Vec<parse::RawRoadData> = serde_json::from_str(&raw)
+                            .with_context(|| {
+                                format!("Failed to parse road data from {}", raw_road_data)
+                            })?;
+                        parse_road_data(raw_road_data)
+                    }
+                    parse::RawRoadFormat::Geojson => {
+                        parse::parse_geojson_roads(&raw_road_data, &geojson_keys)?
+                    }
+                };
+                write_json(&road_data, &data)?;
+                let bytes = std::fs::metadata(&road_data)?.len();
                 println!(
                     "{} Parsed road data size: {}",
                     style("[3/3]").bold().dim(),
@@ -202,18 +366,22 @@ fn main() {
 
             if let Some(raw_sensor_data) = raw_sensor_data {
                 println!("{} Parsing raw sensor data", style("[1/3]").bold().dim());
-                let raw = std::fs::read_to_string(&raw_sensor_data).unwrap();
+                let raw = std::fs::read_to_string(&raw_sensor_data).with_context(|| {
+                    format!("Failed to read sensor data from {}", raw_sensor_data)
+                })?;
                 let bytes = raw.len();
                 println!(
                     "{} Raw sensor data size: {}",
                     style("[2/3]").bold().dim(),
                     style(human_bytes(bytes as f64)).red()
                 );
-                let raw_sensor_data: Vec<parse::RawSensorData> =
-                    serde_json::from_str(&raw).unwrap();
+                let raw_sensor_data: Vec<parse::RawSensorData> = serde_json::from_str(&raw)
+                    .with_context(|| {
+                        format!("Failed to parse sensor data from {}", raw_sensor_data)
+                    })?;
                 let data = parse_sensor_data(raw_sensor_data);
-                std::fs::write(&sensor_data, serde_json::to_string(&data).unwrap()).unwrap();
-                let bytes = std::fs::metadata(&sensor_data).unwrap().len();
+                write_json(&sensor_data, &data)?;
+                let bytes = std::fs::metadata(&sensor_data)?.len();
                 println!(
                     "{} Parsed sensor data size: {}",
                     style("[3/3]").bold().dim(),
@@ -225,32 +393,82 @@ fn main() {
             input,
             output,
             unique_ids,
+            coverage_routes,
+            metric,
+            snap,
+            crop,
+            canvas: canvas_options,
         } => {
-            let graph = bitcode::deserialize(&std::fs::read(&input).unwrap()).unwrap();
-            let canvas = modes::draw_roads(graph, unique_ids);
-            canvas.save(&output);
+            let mut processed_graph = graph_io::try_load_graph(&input)?;
+            crop.crop(&mut processed_graph.graph);
+            canvas_options.check_drawable(&processed_graph.graph)?;
+            let canvas = if coverage_routes.is_empty() {
+                modes::draw_roads(processed_graph.graph, unique_ids, &canvas_options)
+            } else {
+                modes::draw_coverage(
+                    processed_graph,
+                    &coverage_routes,
+                    metric,
+                    snap.strategy(),
+                    &canvas_options,
+                )?
+            };
+            canvas_options.save(&canvas, &output);
         }
         Commands::ShortestPath {
             input,
             output,
             query_file,
-            cull_to_path_distance,
-            metric,
+            crop,
+            options,
         } => {
-            let desired_path =
-                serde_json::from_str(&std::fs::read_to_string(&query_file).unwrap()).unwrap();
-            let processed_graph: ProcessedGraph =
-                serde_json::from_str(&std::fs::read_to_string(&input).unwrap()).unwrap();
-            let canvas =
-                modes::shortest_path(processed_graph, desired_path, cull_to_path_distance, metric);
-            canvas.save(&output);
+            let mut queries = query_file
+                .into_iter()
+                .map(|file| read_query(&file).map(|query| (file, query)))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            let mut processed_graph = graph_io::try_load_graph(&input)?;
+            crop.crop(&mut processed_graph.graph);
+            let canvas_options = options.canvas;
+            canvas_options.check_drawable(&processed_graph.graph)?;
+            let canvas = if queries.len() == 1 {
+                let (_, desired_path) = queries.remove(0);
+                modes::shortest_path(processed_graph, desired_path, options)?
+            } else {
+                modes::shortest_paths(processed_graph, queries, options)?
+            };
+            canvas_options.save(&canvas, &output);
         }
-        Commands::DrawDisjoint { input, output } => {
+        Commands::CompareRoutes {
+            input,
+            output,
+            query_file,
+            snap,
+            penalty,
+            index,
+            canvas: canvas_options,
+        } => {
+            let desired_path = read_query(&query_file)?;
+            let (routing, _) = graph_io::try_load_routing_graph(&input, index.reuse_index)?;
+            canvas_options.check_drawable(routing.graph())?;
+            let canvas = modes::compare_routes(
+                routing,
+                desired_path,
+                snap.strategy(),
+                penalty.connector_penalty,
+                &canvas_options,
+            )?;
+            canvas_options.save(&canvas, &output);
+        }
+        Commands::DrawDisjoint {
+            input,
+            output,
+            canvas: canvas_options,
+        } => {
             println!("Reading graph from {}", input);
-            let processed_graph: ProcessedGraph =
-                serde_json::from_str(&std::fs::read_to_string(&input).unwrap()).unwrap();
-            let canvas = modes::draw_disjoint(processed_graph.graph);
-            canvas.save(&output);
+            let processed_graph = graph_io::try_load_graph(&input)?;
+            canvas_options.check_drawable(&processed_graph.graph)?;
+            let canvas = modes::draw_disjoint(processed_graph.graph, &canvas_options);
+            canvas_options.save(&canvas, &output);
         }
         Commands::DrawReachable {
             input,
@@ -259,88 +477,176 @@ fn main() {
             longitude,
             range,
             inverse,
+            canvas: canvas_options,
         } => {
             let point = parse::Point {
                 latitude,
                 longitude,
             };
-            let graph = bitcode::deserialize(&std::fs::read(&input).unwrap()).unwrap();
-            let canvas = modes::draw_reachable(graph, point, range, inverse);
-            canvas.save(&output);
+            let graph = graph_io::try_load_graph(&input)?.graph;
+            canvas_options.check_drawable(&graph)?;
+            let canvas = modes::draw_reachable(graph, point, range, inverse, &canvas_options);
+            canvas_options.save(&canvas, &output);
         }
         Commands::DrawDistance {
             input,
             output,
-            latitude,
-            longitude,
-            max_distance,
-            metric,
-            forward_only,
+            crop,
+            options,
         } => {
-            let graph = bitcode::deserialize(&std::fs::read(&input).unwrap()).unwrap();
-            let query = PointQuery::new(latitude, longitude, max_distance, -180.0..180.0);
-            let canvas = modes::draw_distance(graph, query, max_distance, metric, forward_only);
-            canvas.save(&output);
+            let mut graph = graph_io::try_load_graph(&input)?.graph;
+            crop.crop(&mut graph);
+            let canvas_options = options.canvas;
+            canvas_options.check_drawable(&graph)?;
+            let canvas = modes::draw_distance(graph, &options)?;
+            canvas_options.save(&canvas, &output);
+        }
+        Commands::ExportGeoJson { input, output } => {
+            let processed_graph = graph_io::try_load_graph(&input)?;
+            output::export_geojson(&processed_graph.graph, &output)?;
+            let bytes = std::fs::metadata(&output)?.len();
+            println!(
+                "Wrote {} to {}",
+                style(human_bytes(bytes as f64)).green(),
+                output
+            );
+        }
+        Commands::Validate { input, examples } => {
+            let graph = graph_io::try_load_graph(&input)?.graph;
+            let issues = processing::validate_graph(&graph);
+            processing::print_report(&issues, examples);
+
+            let fatal = issues.iter().filter(|issue| issue.is_fatal()).count();
+            if fatal > 0 {
+                anyhow::bail!("Found {} issues that break routing or drawing", fatal);
+            }
+            println!(
+                "Validated {} nodes and {} edges with {} warnings",
+                graph.node_count(),
+                graph.edge_count(),
+                issues.len()
+            );
         }
         Commands::Inspect {
             input,
             output,
+            crop,
             options,
         } => {
-            let processed_graph: ProcessedGraph =
-                serde_json::from_str(&std::fs::read_to_string(&input).unwrap()).unwrap();
-            let canvas = modes::inspect(processed_graph.graph, options);
-            canvas.save(&output);
+            let ProcessedGraph {
+                mut graph,
+                sensor_store,
+            } = graph_io::try_load_graph(&input)?;
+            crop.crop(&mut graph);
+            let canvas_options = options.canvas;
+            canvas_options.check_drawable(&graph)?;
+            let canvas = modes::inspect(graph, sensor_store, options);
+            canvas_options.save(&canvas, &output);
         }
-        /*
         Commands::Simulate {
             input,
             output,
             setup,
             options,
         } => {
-            let mut graph = bitcode::deserialize(&std::fs::read(&input).unwrap()).unwrap();
-            let setup: SimulationSetup =
-                serde_json::from_str(&std::fs::read_to_string(&setup).unwrap()).unwrap();
-            modes::simulate(&mut graph, setup, options, &output);
-        } */
+            let graph = graph_io::try_load_graph(&input)?;
+            let setup: SimulationSetup = serde_json::from_str(
+                &std::fs::read_to_string(&setup)
+                    .with_context(|| format!("Failed to read {}", setup))?,
+            )
+            .with_context(|| format!("Failed to parse {}", setup))?;
+            modes::simulate(graph, setup, options, &output)?;
+        }
         Commands::Process {
             road_data,
             output,
+            format,
             mongo_options,
             processing_options,
+            dry_run,
         } => {
-            let runtime = Runtime::new().unwrap();
+            let runtime = Runtime::new()?;
 
             runtime.block_on(async {
-                let road_data = read_roads(&road_data);
+                let road_data = read_roads(&road_data)?;
 
-                let client = AsyncMongoClient::new(mongo_options).await.unwrap();
+                let client = AsyncMongoClient::new(mongo_options)
+                    .await
+                    .context("Failed to connect to MongoDB")?;
 
                 let sensor_data = client
                     .get_all_sensors()
                     .await
-                    .expect("Failed to get sensor data");
+                    .context("Failed to get sensor data")?;
+
+                if dry_run {
+                    processing::estimate_graph(&processing_options, &road_data, &sensor_data);
+                    return anyhow::Ok(());
+                }
 
                 let graph = processing::process_graph(processing_options, road_data, sensor_data);
-                let data = serde_json::to_string(&graph).unwrap();
-                std::fs::write(output.clone(), data).unwrap();
-                let size = std::fs::metadata(output.clone()).unwrap().len();
+                match format {
+                    Some(format) => graph_io::save_graph_as(&output, &graph, format)?,
+                    None => graph_io::save_graph(&output, &graph)?,
+                }
+                let size = std::fs::metadata(&output)?.len();
                 println!("Graph size: {} bytes", human_bytes(size as f64));
                 println!("Wrote graph to {}", output);
-
-                //let data = bitcode::serialize(&graph.graph).unwrap();
-                //let _: ProcessedGraph = bitcode::deserialize(&data).unwrap();
-            });
+                anyhow::Ok(())
+            })?;
         }
         Commands::ExtractGpkgData {
             sqlite_file,
             output,
             query,
+            source_crs,
+            table,
+            columns,
+            chunk_size,
         } => {
-            let road_data = gpkg::read_database(&sqlite_file, query);
-            std::fs::write(&output, serde_json::to_string(&road_data).unwrap()).unwrap();
-            let bytes = std::fs::metadata(&output).unwrap().len();
+            let columns = columns
+                .map(|path| gpkg::ColumnMapping::from_file(&path))
+                .transpose()?
+                .unwrap_or_default();
+            match chunk_size {
+                Some(chunk_size) => {
+                    let file = std::fs::File::create(&output)
+                        .with_context(|| format!("Failed to write {}", output))?;
+                    let mut writer = std::io::BufWriter::new(file);
+                    gpkg::stream_database(
+                        &sqlite_file,
+                        query,
+                        source_crs,
+                        table,
+                        &columns,
+                        chunk_size,
+                        |roads| {
+                            for road in roads {
+                                serde_json::to_writer(&mut writer, &road)?;
+                                writer.write_all(b"\n")?;
+                            }
+                            Ok(())
+                        },
+                    )?;
+                    writer.flush()?;
+                }
+                None => {
+                    let road_data =
+                        gpkg::read_database(&sqlite_file, query, source_crs, table, &columns)?;
+                    write_json(&output, &road_data)?;
+                }
+            }
+            let bytes = std::fs::metadata(&output)?.len();
+            println!(
+                "Wrote {} to {}",
+                style(human_bytes(bytes as f64)).green(),
+                output
+            );
+        }
+        Commands::ImportOsm { pbf, output } => {
+            let road_data = osm::read_pbf(&pbf)?;
+            write_json(&output, &road_data)?;
+            let bytes = std::fs::metadata(&output)?.len();
             println!(
                 "Wrote {} to {}",
                 style(human_bytes(bytes as f64)).green(),
@@ -348,79 +654,111 @@ fn main() {
             );
         }
         Commands::AggregateSensorData { options } => {
-            let runtime = Runtime::new().unwrap();
+            let runtime = Runtime::new()?;
             runtime.block_on(async {
                 modes::aggregate(options).await;
             });
         }
         Commands::TestPeriodDivision { options } => {
-            let runtime = Runtime::new().unwrap();
+            let runtime = Runtime::new()?;
             runtime.block_on(async {
                 test_period_division(options).await;
             });
         }
         Commands::LiveRoute { options } => {
-            let runtime = Runtime::new().unwrap();
-            runtime.block_on(async {
-                modes::live_route(options).await;
-            });
+            let runtime = Runtime::new()?;
+            runtime.block_on(async { modes::live_route(options).await })?;
+        }
+        Commands::Doctor { options } => {
+            let runtime = Runtime::new()?;
+            let healthy = runtime.block_on(async { modes::doctor(options).await });
+            if !healthy {
+                std::process::exit(1);
+            }
+        }
+        Commands::BuildProfiles { options } => {
+            let runtime = Runtime::new()?;
+            runtime.block_on(modes::build_profiles(options))?;
+        }
+        Commands::DrawHeatmap { options } => {
+            let runtime = Runtime::new()?;
+            runtime.block_on(modes::draw_heatmap(options))?;
+        }
+        Commands::SpeedGrid { options } => {
+            let runtime = Runtime::new()?;
+            runtime.block_on(modes::speed_grid(options))?;
         }
         Commands::FindGaps { options } => {
-            let runtime = Runtime::new().unwrap();
+            let runtime = Runtime::new()?;
             runtime.block_on(async {
                 modes::find_gaps(options).await;
             });
         }
+        Commands::DistanceMatrix { options } => {
+            modes::distance_matrix(options)?;
+        }
+        Commands::ExportAssignments { options } => {
+            modes::export_assignments(options)?;
+        }
+        Commands::SensorCover { options } => {
+            modes::sensor_cover(options)?;
+        }
+        Commands::Isochrone { options } => {
+            modes::isochrone(options)?;
+        }
+        Commands::Skeletonize {
+            input,
+            output,
+            max_polyline_points,
+        } => {
+            modes::skeletonize(&input, &output, max_polyline_points)?;
+        }
         Commands::Custom {} => {
-            let processed_graph: ProcessedGraph =
-                serde_json::from_str(&std::fs::read_to_string("./out/graph.json").unwrap())
-                    .unwrap();
+            let processed_graph = graph_io::try_load_graph("./out/graph.json")?;
+            let routing = RoutingGraph::new(processed_graph.graph);
 
             const COLORS: [&str; 4] = ["#FFF275", "#07BEB8", "#FF3C38", "#A4A8D1"];
 
-            fn get_polyline_from_query(graph: &ProcessedGraph, path: &str) -> Vec<Point> {
+            fn get_polyline_from_query(
+                routing: &RoutingGraph,
+                path: &str,
+            ) -> anyhow::Result<Vec<Point>> {
                 let query: Vec<PointQuery> =
                     serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
 
-                let graph = &graph.graph;
-
-                let tree = build_node_acceleration_structure(graph);
-                let points = query
-                    .iter()
-                    .map(|query| {
-                        let p = [query.point.latitude, query.point.longitude];
-                        let mut iter = tree.iter_nearest(&p, &geo_distance).unwrap();
-                        while let Some((dist, (idx, data))) = iter.next() {
-                            if query.heading.contains(&data.heading) && dist <= query.radius {
-                                return *idx;
-                            }
-                        }
-
-                        panic!("No node found for query {:?}", query);
-                    })
-                    .collect::<Vec<_>>();
+                let points = routing.find_points(&query, SnapStrategy::ProximityFirst)?;
+                let graph = routing.graph();
 
                 println!("Finding shortest path for points {:?}", points);
-                let path = visitor::shortest_path(&graph, points, DistanceMetric::Space)
-                    .expect("No path found");
+                let path = visitor::shortest_path(
+                    graph,
+                    points,
+                    DistanceMetric::Space,
+                    1.0,
+                    SearchStrategy::Dijkstra,
+                )
+                .expect("No path found");
 
                 println!("Path complete: {:?}", path.complete);
 
-                path.nodes
+                Ok(path
+                    .nodes
                     .windows(2)
                     .flat_map(|edge| {
                         let edge = graph.edges_connecting(edge[0], edge[1]).next().unwrap();
                         edge.weight().polyline.clone()
                     })
-                    .collect::<Vec<_>>()
+                    .collect::<Vec<_>>())
             }
 
             let extent = [59.293914, 59.370097, 17.974399, 18.138043];
 
-            let mut canvas =
-                Canvas::new_with_background(calc_canvas_size_from_extents(4000, extent), "#100e16");
+            let mut canvas = Canvas::new_with_background(
+                calc_canvas_size_from_extents(4000, extent, Projection::default()),
+                "#100e16",
+            );
 
-            for edge in processed_graph.graph.edge_references() {
+            for edge in routing.graph().edge_references() {
                 let data = edge.weight();
                 canvas.draw_polyline(
                     data.polyline.clone(),
@@ -432,14 +770,10 @@ fn main() {
                 );
             }
 
-            let polyline_query_1 =
-                get_polyline_from_query(&processed_graph, "./queries/query1km.json");
-            let polyline_query_2 =
-                get_polyline_from_query(&processed_graph, "./queries/query2km.json");
-            let polyline_query_3 =
-                get_polyline_from_query(&processed_graph, "./queries/query4km.json");
-            let polyline_query_4 =
-                get_polyline_from_query(&processed_graph, "./queries/query8km.json");
+            let polyline_query_1 = get_polyline_from_query(&routing, "./queries/query1km.json")?;
+            let polyline_query_2 = get_polyline_from_query(&routing, "./queries/query2km.json")?;
+            let polyline_query_3 = get_polyline_from_query(&routing, "./queries/query4km.json")?;
+            let polyline_query_4 = get_polyline_from_query(&routing, "./queries/query8km.json")?;
 
             let polylines = vec![
                 polyline_query_1,
@@ -463,7 +797,7 @@ fn main() {
 
             canvas.save("./out/graphpathsegmented.svg");
         }
-        Commands::Custom2 {} => {
+        Commands::Custom2 { crop } => {
             let runtime = Runtime::new().unwrap();
             runtime.block_on(async {
                 let mongo = AsyncMongoClient::new(MongoOptions {
@@ -472,6 +806,9 @@ fn main() {
                     raw_sensor_data_collection: "trafikverketflowentries_v2".into(),
                     sensors_collection: "sensors".into(),
                     data_points_collection: "sensordata".into(),
+                    speed_profiles_collection: "speed_profiles".into(),
+                    timeout_ms: 5000,
+                    max_retries: 3,
                 })
                 .await
                 .unwrap();
@@ -488,11 +825,10 @@ fn main() {
                     extent[3] = extent[3].max(sensor.location.coordinates[0]);
                 }
 
-                let processed_graph: ProcessedGraph =
-                    serde_json::from_str(&std::fs::read_to_string("./out/graph.json").unwrap())
-                        .unwrap();
+                let processed_graph = graph_io::try_load_graph("./out/graph.json")?;
 
-                let canvas_size = calc_canvas_size_from_extents(4000, extent);
+                let canvas_size =
+                    calc_canvas_size_from_extents(4000, extent, Projection::default());
                 let mut canvas_with_path = Canvas::new(canvas_size);
                 let mut canvas_just_points = Canvas::new(canvas_size);
 
@@ -519,10 +855,7 @@ fn main() {
                 }
 
                 for sensor in metadata.iter() {
-                    let point = Point {
-                        latitude: sensor.location.coordinates[1],
-                        longitude: sensor.location.coordinates[0],
-                    };
+                    let point = sensor.location.point();
                     canvas_with_path.draw_circle(point, "#ff0000", 5.0);
                     canvas_just_points.draw_circle(point, "#ff0000", 5.0);
                 }
@@ -530,57 +863,31 @@ fn main() {
                 let start = PointQuery::new(59.305007, 18.017391, 25.0, -90.0..90.0);
                 let end = PointQuery::new(59.356922, 18.032265, 25.0, -45.0..45.0);
 
-                let top_left = Point {
-                    latitude: 59.370097,
-                    longitude: 17.974399,
+                // Central Stockholm unless another box is given
+                let crop = CropOptions {
+                    bbox: crop.bbox.or(Some(BoundingBox {
+                        min_lat: 59.293914,
+                        max_lat: 59.370097,
+                        min_lon: 17.974399,
+                        max_lon: 18.138043,
+                    })),
                 };
-                let bottom_right = Point {
-                    latitude: 59.293914,
-                    longitude: 18.138043,
-                };
-
-                fn in_box(point: &Point, top_left: &Point, bottom_right: &Point) -> bool {
-                    point.latitude < top_left.latitude
-                        && point.latitude > bottom_right.latitude
-                        && point.longitude > top_left.longitude
-                        && point.longitude < bottom_right.longitude
-                }
+                let mut routing = RoutingGraph::new(processed_graph.graph);
+                crop.crop(routing.graph_mut());
 
-                let mut graph = processed_graph.graph;
-                let mut to_remove = Vec::new();
-                for node in graph.node_indices() {
-                    let data = graph.node_weight(node).unwrap();
-                    if !in_box(&data.point, &top_left, &bottom_right) {
-                        to_remove.push(node);
-                    }
-                }
-                for node in to_remove {
-                    graph.remove_node(node);
-                }
-
-                let tree = processing::build_node_acceleration_structure(&graph);
-                let (_, (start_idx, _)) = tree
-                    .iter_nearest(
-                        &[start.point.latitude, start.point.longitude],
-                        &math::geo_distance,
-                    )
-                    .unwrap()
-                    .skip_while(|(_, (_, data))| !start.heading.contains(&data.heading))
-                    .next()
+                let start_idx = routing
+                    .nearest_node_with_heading(start.point, &start.heading, f64::INFINITY)
                     .unwrap();
-                let (_, (end_idx, _)) = tree
-                    .iter_nearest(
-                        &[end.point.latitude, end.point.longitude],
-                        &math::geo_distance,
-                    )
-                    .unwrap()
-                    .skip_while(|(_, (_, data))| !end.heading.contains(&data.heading))
-                    .next()
+                let end_idx = routing
+                    .nearest_node_with_heading(end.point, &end.heading, f64::INFINITY)
                     .unwrap();
+                let graph = routing.graph();
                 let path = visitor::shortest_path(
-                    &graph,
-                    vec![*start_idx, *end_idx],
+                    graph,
+                    vec![start_idx, end_idx],
                     DistanceMetric::Space,
+                    1.0,
+                    SearchStrategy::Dijkstra,
                 )
                 .unwrap();
 
@@ -612,7 +919,8 @@ fn main() {
 
                 canvas_with_path.save("./out/allsensorswithpath.svg");
                 canvas_just_points.save("./out/allsensors.svg");
-            });
+                anyhow::Ok(())
+            })?;
         }
         Commands::Custom3 {} => {
             let runtime = Runtime::new().unwrap();
@@ -623,6 +931,9 @@ fn main() {
                     raw_sensor_data_collection: "trafikverketflowentries_v2".into(),
                     sensors_collection: "sensors".into(),
                     data_points_collection: "sensordata".into(),
+                    speed_profiles_collection: "speed_profiles".into(),
+                    timeout_ms: 5000,
+                    max_retries: 3,
                 })
                 .await
                 .unwrap();
@@ -641,12 +952,12 @@ fn main() {
 
                 let small_extent = [59.319467, 59.329296, 18.058204, 18.080229];
 
-                let processed_graph: ProcessedGraph =
-                    serde_json::from_str(&std::fs::read_to_string("./out/graph.json").unwrap())
-                        .unwrap();
+                let processed_graph = graph_io::try_load_graph("./out/graph.json")?;
 
-                let canvas_size_large = calc_canvas_size_from_extents(4000, large_extent);
-                let canvas_size_small = calc_canvas_size_from_extents(4000, small_extent);
+                let canvas_size_large =
+                    calc_canvas_size_from_extents(4000, large_extent, Projection::default());
+                let canvas_size_small =
+                    calc_canvas_size_from_extents(4000, small_extent, Projection::default());
 
                 let mut canvas_large = Canvas::new_with_background(canvas_size_large, "#100e16");
                 let mut canvas_small = Canvas::new_with_background(canvas_size_small, "#100e16");
@@ -684,9 +995,23 @@ fn main() {
 
                 canvas_large.save("./out/disjoint_large.svg");
                 canvas_small.save("./out/disjoint_small.svg");
-            });
+                anyhow::Ok(())
+            })?;
         }
     }
 
     println!("Runtime: {:?}", style(start.elapsed()).yellow().bold());
+
+    Ok(())
+}
+
+fn read_query(path: &str) -> anyhow::Result<Vec<PointQuery>> {
+    let raw =
+        std::fs::read_to_string(path).with_context(|| format!("Failed to read query {}", path))?;
+    serde_json::from_str(&raw).with_context(|| format!("Failed to parse query {}", path))
+}
+
+fn write_json<T: serde::Serialize>(path: &str, data: &T) -> anyhow::Result<()> {
+    let raw = serde_json::to_string(data)?;
+    std::fs::write(path, raw).with_context(|| format!("Failed to write {}", path))
 }
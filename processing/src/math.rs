@@ -1,12 +1,103 @@
+use clap::ValueEnum;
 use longitude::Location;
 
 use crate::parse::Point;
 
+/// The model of the earth distances are measured on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum DistanceModel {
+    /// Great circle distance on a sphere
+    #[default]
+    Haversine,
+    /// Geodesic distance on the WGS84 ellipsoid, slower but more accurate for long edges
+    Vincenty,
+}
+
+/// The smallest fraction of the great circle distance between two points that their distance on any
+/// [`DistanceModel`] can be, as the models differ by well under a percent
+pub const MIN_MODEL_RATIO: f64 = 0.99;
+
+/// Great circle distance in meters, use [`dist_with`] to measure on another model
 pub fn dist(a: Point, b: Point) -> f64 {
-    let a: Location = a.into();
-    let b: Location = b.into();
+    dist_with(a, b, DistanceModel::Haversine)
+}
+
+pub fn dist_with(a: Point, b: Point, model: DistanceModel) -> f64 {
+    match model {
+        DistanceModel::Haversine => {
+            let a: Location = a.into();
+            let b: Location = b.into();
+
+            a.distance(&b).meters()
+        }
+        DistanceModel::Vincenty => {
+            vincenty_distance(a, b).unwrap_or_else(|| dist_with(a, b, DistanceModel::Haversine))
+        }
+    }
+}
+
+/// Vincenty's inverse formula on the WGS84 ellipsoid, `None` if it does not converge, which
+/// happens for nearly antipodal points
+fn vincenty_distance(a: Point, b: Point) -> Option<f64> {
+    const A: f64 = 6_378_137.0;
+    const F: f64 = 1.0 / 298.257_223_563;
+    const B: f64 = A * (1.0 - F);
+
+    let l = (b.longitude - a.longitude).to_radians();
+    let u1 = ((1.0 - F) * a.latitude.to_radians().tan()).atan();
+    let u2 = ((1.0 - F) * b.latitude.to_radians().tan()).atan();
+    let (sin_u1, cos_u1) = u1.sin_cos();
+    let (sin_u2, cos_u2) = u2.sin_cos();
+
+    let mut lambda = l;
+    for _ in 0..200 {
+        let (sin_lambda, cos_lambda) = lambda.sin_cos();
+        let sin_sigma = ((cos_u2 * sin_lambda).powi(2)
+            + (cos_u1 * sin_u2 - sin_u1 * cos_u2 * cos_lambda).powi(2))
+        .sqrt();
+        if sin_sigma == 0.0 {
+            // Coincident points
+            return Some(0.0);
+        }
+        let cos_sigma = sin_u1 * sin_u2 + cos_u1 * cos_u2 * cos_lambda;
+        let sigma = sin_sigma.atan2(cos_sigma);
+        let sin_alpha = cos_u1 * cos_u2 * sin_lambda / sin_sigma;
+        let cos_sq_alpha = 1.0 - sin_alpha * sin_alpha;
+        // Both points on the equator
+        let cos_2_sigma_m = if cos_sq_alpha == 0.0 {
+            0.0
+        } else {
+            cos_sigma - 2.0 * sin_u1 * sin_u2 / cos_sq_alpha
+        };
+        let c = F / 16.0 * cos_sq_alpha * (4.0 + F * (4.0 - 3.0 * cos_sq_alpha));
+        let previous_lambda = lambda;
+        lambda = l
+            + (1.0 - c)
+                * F
+                * sin_alpha
+                * (sigma
+                    + c * sin_sigma
+                        * (cos_2_sigma_m + c * cos_sigma * (-1.0 + 2.0 * cos_2_sigma_m.powi(2))));
+
+        if (lambda - previous_lambda).abs() < 1e-12 {
+            let u_sq = cos_sq_alpha * (A * A - B * B) / (B * B);
+            let big_a =
+                1.0 + u_sq / 16384.0 * (4096.0 + u_sq * (-768.0 + u_sq * (320.0 - 175.0 * u_sq)));
+            let big_b = u_sq / 1024.0 * (256.0 + u_sq * (-128.0 + u_sq * (74.0 - 47.0 * u_sq)));
+            let delta_sigma = big_b
+                * sin_sigma
+                * (cos_2_sigma_m
+                    + big_b / 4.0
+                        * (cos_sigma * (-1.0 + 2.0 * cos_2_sigma_m.powi(2))
+                            - big_b / 6.0
+                                * cos_2_sigma_m
+                                * (-3.0 + 4.0 * sin_sigma.powi(2))
+                                * (-3.0 + 4.0 * cos_2_sigma_m.powi(2))));
+            return Some(B * big_a * (sigma - delta_sigma));
+        }
+    }
 
-    a.distance(&b).meters()
+    None
 }
 
 pub fn midpoint(a: Point, b: Point) -> Point {
@@ -16,43 +107,38 @@ pub fn midpoint(a: Point, b: Point) -> Point {
     }
 }
 
+/// Distance in meters from **point** to the closest point on the great circle segment between
+/// **line_start** and **line_end**. The point is projected onto the great circle through the
+/// segment, and the distance to the closest endpoint is used instead if the projection falls
+/// outside the segment.
 pub fn point_line_dist(point: Point, line_start: Point, line_end: Point) -> f64 {
-    let lat1 = line_start.latitude.to_radians();
-    let lon1 = line_start.longitude.to_radians();
-    let lat2 = line_end.latitude.to_radians();
-    let lon2 = line_end.longitude.to_radians();
-    let lat3 = point.latitude.to_radians();
-    let lon3 = point.longitude.to_radians();
-
-    let y = (lon3 - lon1).sin() * lat3.cos();
-    let x = lat1.cos() * lat3.sin() - lat1.sin() * lat3.cos() * (lon3 - lon1).cos();
-    let bearing1 = y.atan2(x);
+    const EARTH_RADIUS: f64 = 6_371_000.0;
 
-    let y2 = (lon2 - lon1).sin() * lat2.cos();
-    let x2 = lat1.cos() * lat2.sin() - lat1.sin() * lat2.cos() * (lon2 - lon1).cos();
-    let bearing2 = y2.atan2(x2);
-
-    let d_lon = lon3 - lon1;
-
-    let distance_ac =
-        ((lat1.sin() * lat3.sin()) + (lat1.cos() * lat3.cos() * (d_lon).cos())).acos() * 6371000.0;
-    let min_distance =
-        ((distance_ac / 6371000.0).sin() * (bearing1 - bearing2).sin()).asin() * 6371000.0;
-
-    min_distance
-}
-
-pub fn point_line_dist_approx(point: Point, line_start: Point, line_end: Point) -> f64 {
-    let a = dist(point, line_start);
-    let b = dist(point, line_end);
-
-    let midpoint = Point {
-        latitude: (line_start.latitude + line_end.latitude) / 2.0,
-        longitude: (line_start.longitude + line_end.longitude) / 2.0,
-    };
-    let c = dist(point, midpoint);
+    let to_start = dist(point, line_start);
+    let to_end = dist(point, line_end);
+    let length = dist(line_start, line_end);
+    if length == 0.0 {
+        return to_start;
+    }
 
-    a.min(b).min(c)
+    // Angular distance from the start to the point, with haversine so short distances stay exact
+    let lat1 = line_start.latitude.to_radians();
+    let lat3 = point.latitude.to_radians();
+    let d_lat = lat3 - lat1;
+    let d_lon = (point.longitude - line_start.longitude).to_radians();
+    let h = (d_lat / 2.0).sin().powi(2) + lat1.cos() * lat3.cos() * (d_lon / 2.0).sin().powi(2);
+    let angle_to_point = 2.0 * h.sqrt().min(1.0).asin();
+
+    let bearing_diff =
+        (line_heading(line_start, point) - line_heading(line_start, line_end)).to_radians();
+    let cross_track = (angle_to_point.sin() * bearing_diff.sin()).asin();
+    let along_track = (angle_to_point.sin() * bearing_diff.cos()).atan2(angle_to_point.cos());
+
+    if along_track <= 0.0 || along_track * EARTH_RADIUS >= length {
+        to_start.min(to_end)
+    } else {
+        (cross_track * EARTH_RADIUS).abs()
+    }
 }
 
 pub fn line_heading(start: Point, end: Point) -> f64 {
@@ -96,6 +182,9 @@ pub fn angle_diff(a: f64, b: f64) -> f64 {
     }
 }
 
+/// Great circle distance in meters between two latitude and longitude pairs, as the metric of
+/// kd-tree lookups. Lookups always use the great circle distance whatever model the edges were
+/// measured on, as nearby points are ordered the same on every model.
 pub fn geo_distance(a: &[f64], b: &[f64]) -> f64 {
     if a.len() != 2 || b.len() != 2 {
         panic!("Invalid input");
@@ -110,3 +199,57 @@ pub fn geo_distance(a: &[f64], b: &[f64]) -> f64 {
     };
     dist(a, b)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_graphs::point;
+
+    #[test]
+    fn haversine_and_vincenty_agree_over_50km() {
+        let start = point(59.3293, 18.0686);
+        // Roughly 50km north east
+        let end = point(59.6497, 18.7683);
+
+        let haversine = dist_with(start, end, DistanceModel::Haversine);
+        let vincenty = dist_with(start, end, DistanceModel::Vincenty);
+        assert!((45_000.0..55_000.0).contains(&vincenty));
+        assert!((haversine - vincenty).abs() / vincenty < 0.005);
+    }
+
+    #[test]
+    fn every_model_is_within_the_min_ratio_of_the_great_circle() {
+        for latitude in [0.0, 30.0, 59.3, 80.0] {
+            let start = point(latitude, 18.0);
+            for bearing in (0..360).step_by(15) {
+                let (sin, cos) = (bearing as f64).to_radians().sin_cos();
+                let end = point(latitude + 0.5 * cos, 18.0 + 0.5 * sin);
+
+                let haversine = dist_with(start, end, DistanceModel::Haversine);
+                for model in [DistanceModel::Haversine, DistanceModel::Vincenty] {
+                    assert!(dist_with(start, end, model) >= MIN_MODEL_RATIO * haversine);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn point_line_dist_is_clamped_to_the_segment() {
+        let start = point(0.0, 0.0);
+        let end = point(0.0, 0.001);
+
+        // Beside the middle of the segment, the distance is to the line, up to the cross track
+        // distance using a slightly different earth radius than [`dist`]
+        let beside = point(0.0005, 0.0005);
+        let to_line = dist(beside, point(0.0, 0.0005));
+        assert!((point_line_dist(beside, start, end) - to_line).abs() / to_line < 0.005);
+
+        // Past either end, the distance is to the closest endpoint
+        let past_end = point(0.0005, 0.002);
+        assert!((point_line_dist(past_end, start, end) - dist(past_end, end)).abs() < 1e-9);
+        let before_start = point(-0.0005, -0.001);
+        assert!(
+            (point_line_dist(before_start, start, end) - dist(before_start, start)).abs() < 1e-9
+        );
+    }
+}
@@ -1,13 +1,18 @@
 use std::{
     collections::HashMap,
     sync::{Arc, RwLock},
+    time::{Duration, Instant},
 };
 
 use clap::Args;
+use console::style;
 use indicatif::ProgressBar;
 use mongodb::{
-    bson::{doc, oid::ObjectId},
-    options::{CreateCollectionOptions, FindOptions, IndexOptions, TimeseriesOptions},
+    bson::{doc, oid::ObjectId, to_document},
+    options::{
+        CreateCollectionOptions, FindOneAndUpdateOptions, FindOptions, IndexOptions,
+        ReturnDocument, TimeseriesOptions,
+    },
     Client, IndexModel,
 };
 use tokio::sync::mpsc;
@@ -19,16 +24,35 @@ use crate::{
 
 use crate::mongo::model::{DataPoint, MeasurementSide, RawSensorData, SensorMetadata};
 
+/// How often the number of data points waiting to be inserted is logged
+const BACKLOG_INTERVAL: Duration = Duration::from_secs(10);
+
 #[derive(Debug, Args)]
 pub struct AggregateOptions {
     #[clap(flatten)]
     mongo_options: MongoOptions,
+    /// Report how many data points were aggregated per sensor once done
+    #[clap(long, default_value = "false", default_missing_value = "true")]
+    report_counts: bool,
+    /// How many sensors with the most and fewest data points to list in the report
+    #[clap(long, default_value = "10")]
+    report_limit: usize,
+    /// How many documents are processed at the same time
+    #[clap(long, default_value = "100")]
+    concurrency: usize,
+    /// How many data points are inserted at a time
+    #[clap(long, default_value = "2000")]
+    batch_size: usize,
 }
 
 pub async fn aggregate(options: AggregateOptions) {
     let mut progress = Progress::new();
 
     let mongo_options = options.mongo_options;
+    let report_counts = options.report_counts;
+    let report_limit = options.report_limit;
+    let batch_size = options.batch_size;
+    let concurrency = options.concurrency;
 
     progress.step_unsized("Connecting to MongoDB");
     let client = Client::with_uri_str(mongo_options.uri).await;
@@ -116,8 +140,6 @@ pub async fn aggregate(options: AggregateOptions) {
     let options = FindOptions::builder().batch_size(10000).build();
     let mut cursor = input_collection.find(None, options).await.unwrap();
 
-    let runtime = tokio::runtime::Runtime::new().unwrap();
-
     async fn process(
         data: RawSensorData,
         progress: ProgressBar,
@@ -126,11 +148,20 @@ pub async fn aggregate(options: AggregateOptions) {
         channel: mpsc::Sender<DataPoint>,
         permit: tokio::sync::OwnedSemaphorePermit,
     ) {
+        let sensor = match SensorMetadata::try_from(data.clone()) {
+            Ok(sensor) => sensor,
+            Err(e) => {
+                progress.println(format!("Skipping document {:?}: {}", data.mongo_id, e));
+                progress.inc(1);
+                drop(permit);
+                return;
+            }
+        };
         let key = (
-            data.site_id,
-            data.get_measurement_side(),
-            data.get_lane_i32(),
-            data.vehicle_type,
+            sensor.site_id,
+            sensor.measurement_side.clone(),
+            sensor.specific_lane,
+            sensor.vehicle_type,
         );
 
         let existing_sensor_id = {
@@ -141,41 +172,22 @@ pub async fn aggregate(options: AggregateOptions) {
         let sensor_id = match existing_sensor_id {
             Some(sensor_id) => sensor_id,
             None => {
-                let find_one = sensor_collection.find_one(data.filter(), None);
-                let existing = find_one.await.unwrap();
-
-                match existing {
-                    Some(existing) => existing.mongo_id.unwrap(),
-                    None => {
-                        // Acquite write lock before inserting new sensor to prevent duplicates
-
-                        let insert = sensor_collection
-                            .insert_one(&data.clone().into(), None)
-                            .await;
-
-                        match insert {
-                            Ok(inserted) => {
-                                {
-                                    let mut write_cache = sensor_id_cache.write().unwrap();
-                                    write_cache
-                                        .insert(key, inserted.inserted_id.as_object_id().unwrap());
-                                }
-
-                                inserted.inserted_id.as_object_id().unwrap()
-                            }
-                            Err(_) => {
-                                let find_one = sensor_collection.find_one(data.filter(), None);
-                                let existing = find_one.await.unwrap();
-                                existing.unwrap().mongo_id.unwrap()
-                            }
-                        }
-                    }
-                }
+                let sensor_id = upsert_sensor(&sensor_collection, &sensor).await;
+                let mut write_cache = sensor_id_cache.write().unwrap();
+                write_cache.insert(key, sensor_id);
+                sensor_id
             }
         };
 
-        let mut data_point: DataPoint = data.into();
-        data_point.sensor_id = sensor_id;
+        let data_point = match DataPoint::new(data, sensor_id) {
+            Ok(data_point) => data_point,
+            Err(e) => {
+                progress.println(format!("Skipping document: {}", e));
+                progress.inc(1);
+                drop(permit);
+                return;
+            }
+        };
 
         channel.send(data_point).await.unwrap();
 
@@ -185,15 +197,19 @@ pub async fn aggregate(options: AggregateOptions) {
 
     let pb = progress.get_pb().clone();
 
-    let (tx, mut rx) = mpsc::channel(2000);
+    let (tx, mut rx) = mpsc::channel(batch_size);
 
-    let semaphore = Arc::new(tokio::sync::Semaphore::new(100));
+    let semaphore = Arc::new(tokio::sync::Semaphore::new(concurrency));
 
-    tokio::spawn(async move {
-        let mut buf = Vec::with_capacity(2000);
+    let insert_task = tokio::spawn(async move {
+        let mut buf: Vec<DataPoint> = Vec::with_capacity(batch_size);
+        let mut counts = HashMap::<ObjectId, usize>::new();
 
-        while rx.recv_many(&mut buf, 2000).await > 0 {
-            if buf.len() >= 2000 {
+        while rx.recv_many(&mut buf, batch_size).await > 0 {
+            if buf.len() >= batch_size {
+                if report_counts {
+                    tally(&mut counts, &buf);
+                }
                 let insert_many_res = data_collection.insert_many(&buf, None).await;
                 if let Err(e) = insert_many_res {
                     eprintln!("Error inserting data: {:?}", e);
@@ -204,16 +220,35 @@ pub async fn aggregate(options: AggregateOptions) {
         }
 
         if !buf.is_empty() {
+            if report_counts {
+                tally(&mut counts, &buf);
+            }
             let insert_many_res = data_collection.insert_many(buf, None).await;
             if let Err(e) = insert_many_res {
                 eprintln!("Error inserting data: {:?}", e);
             }
         }
+
+        counts
     });
 
-    while cursor.advance().await.is_ok() {
+    let mut last_backlog_report = Instant::now();
+    while cursor
+        .advance()
+        .await
+        .expect("Failed to read raw sensor data")
+    {
         let data = cursor.deserialize_current().unwrap();
 
+        if last_backlog_report.elapsed() >= BACKLOG_INTERVAL {
+            pb.println(format!(
+                "{} documents being processed, {} data points waiting to be inserted",
+                concurrency - semaphore.available_permits(),
+                tx.max_capacity() - tx.capacity()
+            ));
+            last_backlog_report = Instant::now();
+        }
+
         let permit = semaphore.clone().acquire_owned().await.unwrap();
         let future = process(
             data,
@@ -224,9 +259,242 @@ pub async fn aggregate(options: AggregateOptions) {
             permit,
         );
 
-        runtime.spawn(future);
+        tokio::spawn(future);
     }
     drop(tx);
 
     progress.finish("Documents processed");
+
+    // The last batch is only inserted once every document has been processed
+    progress.step_unsized("Waiting for data points to be inserted");
+    let counts = insert_task.await.unwrap();
+    progress.finish("Data points inserted");
+
+    if report_counts {
+        println!("Inserted data points for {} sensors", counts.len());
+        report_data_point_counts(counts, report_limit);
+    }
+}
+
+/// Insert the sensor unless one with the same unique key exists, and return the id of the stored
+/// sensor. Concurrent upserts of the same sensor all return the id of the single stored document.
+async fn upsert_sensor(
+    sensor_collection: &mongodb::Collection<SensorMetadata>,
+    sensor: &SensorMetadata,
+) -> ObjectId {
+    let filter = sensor.filter();
+    let mut insert = to_document(sensor).unwrap();
+    // The filter fields are set on insert from the filter itself
+    for key in filter.keys() {
+        insert.remove(key);
+    }
+    let options = FindOneAndUpdateOptions::builder()
+        .upsert(true)
+        .return_document(ReturnDocument::After)
+        .build();
+    sensor_collection
+        .find_one_and_update(filter, doc! { "$setOnInsert": insert }, options)
+        .await
+        .unwrap()
+        .and_then(|sensor| sensor.mongo_id)
+        .expect("Upserted sensor has no id")
+}
+
+fn tally(counts: &mut HashMap<ObjectId, usize>, data_points: &[DataPoint]) {
+    for data_point in data_points {
+        *counts.entry(data_point.sensor_id).or_insert(0) += 1;
+    }
+}
+
+/// Print the distribution of data points per sensor, along with the sensors with the most and fewest
+fn report_data_point_counts(counts: HashMap<ObjectId, usize>, limit: usize) {
+    if counts.is_empty() {
+        println!("No data points were aggregated");
+        return;
+    }
+
+    let mut counts = counts.into_iter().collect::<Vec<_>>();
+    // Sort by count, then by id so the report is stable between runs
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+    let total = counts.iter().map(|(_, count)| count).sum::<usize>();
+    let mean = total as f64 / counts.len() as f64;
+    let median = counts[counts.len() / 2].1;
+    let max = counts.first().unwrap().1;
+    let min = counts.last().unwrap().1;
+
+    println!(
+        "Data points per sensor: min {}, median {}, mean {:.1}, max {}",
+        style(min).bold(),
+        style(median).bold(),
+        style(mean).bold(),
+        style(max).bold()
+    );
+
+    println!("Sensors with the most data points:");
+    for (sensor_id, count) in counts.iter().take(limit) {
+        println!("  {} {}", sensor_id, style(count).bold());
+    }
+
+    println!("Sensors with the fewest data points:");
+    for (sensor_id, count) in counts.iter().rev().take(limit) {
+        println!("  {} {}", sensor_id, style(count).bold());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data_point(sensor_id: ObjectId) -> DataPoint {
+        DataPoint {
+            mongo_id: None,
+            original_id: ObjectId::new(),
+            sensor_id,
+            time: mongodb::bson::DateTime::from_millis(0),
+            flow_rate: 600.0,
+            average_speed: 80.0,
+        }
+    }
+
+    #[test]
+    fn tally_counts_the_data_points_of_each_sensor_over_batches() {
+        let sensors = [ObjectId::new(), ObjectId::new(), ObjectId::new()];
+        let expected = [5, 1, 12];
+        let data_points = sensors
+            .iter()
+            .zip(expected)
+            .flat_map(|(sensor, count)| (0..count).map(|_| data_point(*sensor)))
+            .collect::<Vec<_>>();
+
+        let mut counts = HashMap::new();
+        for batch in data_points.chunks(4) {
+            tally(&mut counts, batch);
+        }
+
+        assert_eq!(counts.len(), 3);
+        for (sensor, count) in sensors.iter().zip(expected) {
+            assert_eq!(counts[sensor], count);
+        }
+    }
+
+    /// Runs against the MongoDB server at `PROCESSING_TEST_MONGO_URI` if set, in a database of
+    /// its own that is dropped afterwards
+    #[test]
+    fn concurrent_upserts_of_a_sensor_store_one_document() {
+        let Ok(uri) = std::env::var("PROCESSING_TEST_MONGO_URI") else {
+            return;
+        };
+        let db = format!("processing-test-{}-upsert", std::process::id());
+        let sensor = crate::test_graphs::sensor(1, 59.3, 18.0);
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+
+        let (first, second, stored) = runtime.block_on(async {
+            let client = Client::with_uri_str(uri).await.unwrap();
+            let db = client.database(&db);
+            let sensors = db.collection::<SensorMetadata>("sensors");
+            let unique = IndexModel::builder()
+                .options(IndexOptions::builder().unique(true).build())
+                .keys(doc! {
+                    "SiteId": 1,
+                    "VehicleType": 1,
+                    "SpecificLane": 1,
+                    "MeasurementSide": 1,
+                })
+                .build();
+            sensors.create_index(unique, None).await.unwrap();
+
+            let (first, second) = tokio::join!(
+                upsert_sensor(&sensors, &sensor),
+                upsert_sensor(&sensors, &sensor)
+            );
+            let stored = sensors
+                .count_documents(sensor.filter(), None)
+                .await
+                .unwrap();
+            db.drop(None).await.unwrap();
+            (first, second, stored)
+        });
+
+        assert_eq!(stored, 1);
+        assert_eq!(first, second);
+    }
+
+    /// Runs against the MongoDB server at `PROCESSING_TEST_MONGO_URI` if set, in databases of
+    /// their own that are dropped afterwards
+    #[test]
+    fn every_document_is_inserted_at_any_concurrency() {
+        let Ok(uri) = std::env::var("PROCESSING_TEST_MONGO_URI") else {
+            return;
+        };
+        // Three sensors, with a number of documents no batch size below divides evenly
+        let raw = (0..47)
+            .map(|idx| RawSensorData {
+                mongo_id: None,
+                site_id: idx % 3,
+                measurement_time: mongodb::bson::DateTime::from_millis(idx as i64 * 60_000),
+                period: 60,
+                vehicle_type: VehicleType::Car,
+                flow_rate: 600.0,
+                average_speed: 80.0,
+                modified_time: mongodb::bson::DateTime::from_millis(0),
+                specific_lane: "lane1".into(),
+                measurement_side: "northBound".into(),
+                location: crate::mongo::model::Location {
+                    _type: "Point".into(),
+                    coordinates: [18.0, 59.3],
+                },
+            })
+            .collect::<Vec<_>>();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+
+        for (concurrency, batch_size) in [(1, 10), (4, 5), (64, 2000)] {
+            let db = format!(
+                "processing-test-{}-aggregate-{}",
+                std::process::id(),
+                concurrency
+            );
+            let options = AggregateOptions {
+                mongo_options: MongoOptions {
+                    uri: uri.clone(),
+                    db: db.clone(),
+                    raw_sensor_data_collection: "raw".into(),
+                    sensors_collection: "sensors".into(),
+                    data_points_collection: "sensordata".into(),
+                    speed_profiles_collection: "speed_profiles".into(),
+                    timeout_ms: 5000,
+                    max_retries: 0,
+                },
+                report_counts: false,
+                report_limit: 10,
+                concurrency,
+                batch_size,
+            };
+
+            let (data_points, sensors) = runtime.block_on(async {
+                let client = Client::with_uri_str(&uri).await.unwrap();
+                let db = client.database(&db);
+                db.collection::<RawSensorData>("raw")
+                    .insert_many(&raw, None)
+                    .await
+                    .unwrap();
+                aggregate(options).await;
+                let data_points = db
+                    .collection::<DataPoint>("sensordata")
+                    .count_documents(None, None)
+                    .await
+                    .unwrap();
+                let sensors = db
+                    .collection::<SensorMetadata>("sensors")
+                    .count_documents(None, None)
+                    .await
+                    .unwrap();
+                db.drop(None).await.unwrap();
+                (data_points, sensors)
+            });
+
+            assert_eq!(data_points, 47, "concurrency {}", concurrency);
+            assert_eq!(sensors, 3, "concurrency {}", concurrency);
+        }
+    }
 }
@@ -0,0 +1,57 @@
+use anyhow::Context;
+use clap::Args;
+
+use crate::{
+    mongo::client::{async_client::AsyncMongoClient, MongoOptions},
+    progress::Progress,
+};
+
+#[derive(Debug, Args)]
+pub struct BuildProfilesOptions {
+    #[clap(flatten)]
+    pub mongo_options: MongoOptions,
+    /// Also write the profiles to this JSON file
+    #[clap(short, long)]
+    pub output: Option<String>,
+    /// Do not replace the speed profiles collection, only write the JSON file
+    #[clap(long, default_value = "false", default_missing_value = "true")]
+    pub skip_collection: bool,
+}
+
+/// Average the speed and flow rate of every sensor per hour and day of the week
+pub async fn build_profiles(options: BuildProfilesOptions) -> anyhow::Result<()> {
+    if options.skip_collection && options.output.is_none() {
+        anyhow::bail!("Nothing to write, pass --output or drop --skip-collection");
+    }
+
+    let mut progress = Progress::new();
+
+    progress.step_unsized("Connecting to MongoDB");
+    let client = AsyncMongoClient::new(options.mongo_options.clone())
+        .await
+        .context("Failed to connect to MongoDB")?;
+    progress.finish("");
+
+    progress.step_unsized("Averaging data points");
+    let profiles = client
+        .build_speed_profiles(!options.skip_collection)
+        .await
+        .context("Failed to build speed profiles")?;
+    progress.finish(format!("Built {} profile buckets", profiles.len()));
+    if !options.skip_collection {
+        println!(
+            "Wrote profiles to the {} collection",
+            options.mongo_options.speed_profiles_collection
+        );
+    }
+
+    if let Some(output) = options.output {
+        let file = std::fs::File::create(&output)
+            .with_context(|| format!("Failed to create {}", output))?;
+        serde_json::to_writer(file, &profiles)
+            .with_context(|| format!("Failed to write profiles to {}", output))?;
+        println!("Wrote profiles to {}", output);
+    }
+
+    Ok(())
+}
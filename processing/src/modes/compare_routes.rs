@@ -0,0 +1,197 @@
+use std::collections::HashSet;
+
+use console::style;
+use petgraph::{graph::NodeIndex, stable_graph::StableDiGraph};
+
+use crate::{
+    modes::shortest_path::calculate_travel_time,
+    output::{Canvas, CanvasOptions, DrawOptions},
+    processing::{EdgeData, NodeData, RoutingGraph},
+    util::{PointQuery, SnapStrategy},
+    visitor::{self, DistanceMetric, SearchStrategy},
+};
+
+const SHORTEST_COLOR: &str = "deepskyblue";
+const FASTEST_COLOR: &str = "orange";
+const SHARED_COLOR: &str = "white";
+
+/// Draw the shortest and the fastest route through the same waypoints on top of each other
+pub fn compare_routes(
+    routing: RoutingGraph,
+    desired_path: Vec<PointQuery>,
+    snap: SnapStrategy,
+    connector_penalty: f64,
+    canvas_options: &CanvasOptions,
+) -> anyhow::Result<Canvas> {
+    let points = routing.find_points(&desired_path, snap)?;
+    let graph = routing.into_graph();
+
+    let shortest = visitor::shortest_path(
+        &graph,
+        points.clone(),
+        DistanceMetric::Space,
+        connector_penalty,
+        SearchStrategy::Dijkstra,
+    )
+    .ok_or_else(|| anyhow::anyhow!("No shortest path found"))?;
+    let fastest = visitor::shortest_path(
+        &graph,
+        points,
+        DistanceMetric::Time,
+        connector_penalty,
+        SearchStrategy::Dijkstra,
+    )
+    .ok_or_else(|| anyhow::anyhow!("No fastest path found"))?;
+
+    for (name, path) in [("Shortest", &shortest), ("Fastest", &fastest)] {
+        let distance = path.nodes.windows(2).fold(0.0, |acc, nodes| {
+            let edge = graph.edges_connecting(nodes[0], nodes[1]).next().unwrap();
+            acc + edge.weight().distance
+        });
+        let time = calculate_travel_time(&graph, path);
+        let speed_limit = visitor::average_speed_limit(path.edges(&graph));
+        println!(
+            "{} route: {}m, {}s, average speed limit {}km/h",
+            name,
+            style(format!("{:.0}", distance)).bold(),
+            style(format!("{:.0}", time)).bold(),
+            style(format!("{:.1}", speed_limit)).bold()
+        );
+    }
+
+    let shortest_edges = path_edges(&shortest.nodes);
+    let fastest_edges = path_edges(&fastest.nodes);
+    let shared = shortest_edges.intersection(&fastest_edges).count();
+    println!(
+        "Routes share {} of {} and {} edges",
+        style(shared).bold(),
+        shortest_edges.len(),
+        fastest_edges.len()
+    );
+
+    let mut canvas = canvas_options.canvas(4000, &graph);
+
+    for edge in graph.edge_weights() {
+        canvas.draw_polyline(
+            edge.polyline.clone(),
+            DrawOptions {
+                color: "gray".into(),
+                stroke: 1.0,
+                ..Default::default()
+            },
+        );
+    }
+
+    draw_edges(
+        &mut canvas,
+        &graph,
+        shortest_edges.difference(&fastest_edges),
+        SHORTEST_COLOR,
+    );
+    draw_edges(
+        &mut canvas,
+        &graph,
+        fastest_edges.difference(&shortest_edges),
+        FASTEST_COLOR,
+    );
+    draw_edges(
+        &mut canvas,
+        &graph,
+        shortest_edges.intersection(&fastest_edges),
+        SHARED_COLOR,
+    );
+
+    for query in desired_path {
+        canvas.draw_circle(query.point, "magenta", 10.0);
+    }
+
+    canvas.draw_legend(
+        [
+            ("Shortest", SHORTEST_COLOR),
+            ("Fastest", FASTEST_COLOR),
+            ("Shared", SHARED_COLOR),
+        ]
+        .into_iter()
+        .map(|(label, color)| (label.to_string(), color.to_string()))
+        .collect(),
+    );
+
+    Ok(canvas)
+}
+
+fn path_edges(nodes: &[NodeIndex]) -> HashSet<(NodeIndex, NodeIndex)> {
+    nodes.windows(2).map(|pair| (pair[0], pair[1])).collect()
+}
+
+fn draw_edges<'a, I: Iterator<Item = &'a (NodeIndex, NodeIndex)>>(
+    canvas: &mut Canvas,
+    graph: &StableDiGraph<NodeData, EdgeData>,
+    edges: I,
+    color: &str,
+) {
+    for (from, to) in edges {
+        let edge = graph.edges_connecting(*from, *to).next().unwrap();
+        let data = edge.weight();
+        let opts = DrawOptions {
+            color: color.into(),
+            stroke: 3.0,
+            ..Default::default()
+        };
+        if data.is_connector {
+            let start = graph.node_weight(*from).unwrap();
+            let end = graph.node_weight(*to).unwrap();
+            canvas.draw_line(start.point, end.point, opts);
+        } else {
+            canvas.draw_polyline(data.polyline.clone(), opts);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_graphs::diamond;
+
+    fn routes(
+        graph: &StableDiGraph<NodeData, EdgeData>,
+        from: NodeIndex,
+        to: NodeIndex,
+    ) -> (Vec<NodeIndex>, Vec<NodeIndex>) {
+        let [shortest, fastest] = [DistanceMetric::Space, DistanceMetric::Time].map(|metric| {
+            visitor::shortest_path(graph, vec![from, to], metric, 1.0, SearchStrategy::Dijkstra)
+                .unwrap()
+                .nodes
+        });
+        (shortest, fastest)
+    }
+
+    #[test]
+    fn uniform_speeds_give_the_same_route() {
+        let (graph, [a, b, _, d]) = diamond();
+
+        let (shortest, fastest) = routes(&graph, a, d);
+
+        assert_eq!(shortest, vec![a, b, d]);
+        assert_eq!(path_edges(&shortest), path_edges(&fastest));
+    }
+
+    #[test]
+    fn fast_detour_diverges_from_the_shortest_route() {
+        let (mut graph, [a, b, c, d]) = diamond();
+        for (from, to) in [(a, c), (c, d)] {
+            let edge = graph.find_edge(from, to).unwrap();
+            graph[edge].speed_limit = Some(200.0);
+        }
+
+        let (shortest, fastest) = routes(&graph, a, d);
+
+        assert_eq!(shortest, vec![a, b, d]);
+        assert_eq!(fastest, vec![a, c, d]);
+        assert_eq!(
+            path_edges(&shortest)
+                .intersection(&path_edges(&fastest))
+                .count(),
+            0
+        );
+    }
+}
@@ -0,0 +1,70 @@
+use clap::Args;
+
+use crate::{
+    graph_io,
+    processing::ProcessedGraph,
+    progress::Progress,
+    visitor::{self, DistanceMetric},
+};
+
+#[derive(Debug, Args)]
+pub struct DistanceMatrixOptions {
+    #[clap(short, long, default_value = "./out/graph.json")]
+    pub graph_path: String,
+    #[clap(short, long, default_value = "./out/distance_matrix.csv")]
+    pub output: String,
+    #[clap(short, long, default_value = "space")]
+    pub metric: DistanceMetric,
+}
+
+pub fn distance_matrix(options: DistanceMatrixOptions) -> anyhow::Result<()> {
+    let mut progress = Progress::new();
+
+    progress.step_unsized("Reading graph");
+    let ProcessedGraph {
+        graph,
+        sensor_store,
+    } = graph_io::try_load_graph(&options.graph_path)?;
+    progress.finish(format!(
+        "Loaded graph with {} nodes and {} sensor nodes",
+        graph.node_count(),
+        sensor_store.len()
+    ));
+
+    progress.step_unsized("Calculating distances");
+    let distances = visitor::sensor_distance_matrix(&graph, &sensor_store, options.metric);
+    progress.finish(format!("Found {} reachable pairs", distances.len()));
+
+    // Several sensors can share a node, so each site gets its own row and column
+    let mut sites = sensor_store
+        .iter()
+        .flat_map(|(node, sensors)| sensors.iter().map(move |s| (s.site_id, *node)))
+        .collect::<Vec<_>>();
+    sites.sort_by_key(|(site_id, _)| *site_id);
+    sites.dedup_by_key(|(site_id, _)| *site_id);
+
+    progress.step_unsized("Writing matrix");
+    let mut writer = csv::Writer::from_path(&options.output)?;
+    writer.write_record(
+        std::iter::once("site_id".to_string())
+            .chain(sites.iter().map(|(site_id, _)| site_id.to_string())),
+    )?;
+    for (site_id, from) in sites.iter() {
+        let row = sites.iter().map(|(_, to)| {
+            distances
+                .get(&(*from, *to))
+                .map(|distance| distance.to_string())
+                .unwrap_or_default()
+        });
+        writer.write_record(std::iter::once(site_id.to_string()).chain(row))?;
+    }
+    writer.flush()?;
+    progress.finish(format!(
+        "Wrote {}x{} matrix to {}",
+        sites.len(),
+        sites.len(),
+        options.output
+    ));
+
+    Ok(())
+}
@@ -0,0 +1,207 @@
+use clap::Args;
+use console::style;
+use mongodb::bson::DateTime;
+
+use crate::{
+    graph_io,
+    mongo::client::{async_client::AsyncMongoClient, MongoOptions},
+    processing::{nearest_nodes_batch, RoutingGraph},
+    util::{PointQuery, SnapStrategy},
+    visitor,
+};
+
+use super::live_route::ParseableDuration;
+
+#[derive(Debug, Args)]
+pub struct DoctorOptions {
+    #[clap(flatten)]
+    pub mongo_options: MongoOptions,
+    #[clap(short, long, default_value = "./out/graph.json")]
+    pub graph_path: String,
+    /// Query files that should parse and snap to the graph
+    #[clap(short, long)]
+    pub query: Vec<String>,
+    /// The newest data point must be younger than this
+    #[clap(short, long, default_value = "1d")]
+    pub max_data_age: ParseableDuration,
+}
+
+struct Check {
+    name: String,
+    result: Result<String, String>,
+    hint: &'static str,
+}
+
+impl Check {
+    fn new(name: impl Into<String>, result: Result<String, String>, hint: &'static str) -> Self {
+        Check {
+            name: name.into(),
+            result,
+            hint,
+        }
+    }
+
+    fn print(&self) {
+        match &self.result {
+            Ok(message) => println!(
+                "{} {}: {}",
+                style("[pass]").green().bold(),
+                style(&self.name).bold(),
+                message
+            ),
+            Err(message) => {
+                println!(
+                    "{} {}: {}",
+                    style("[fail]").red().bold(),
+                    style(&self.name).bold(),
+                    message
+                );
+                println!("       {}", style(self.hint).dim());
+            }
+        }
+    }
+}
+
+/// Check that the graph, queries and database are usable, returns whether all checks passed
+pub async fn doctor(options: DoctorOptions) -> bool {
+    let mut checks = Vec::new();
+
+    let (check, routing) = check_graph(&options.graph_path);
+    checks.push(check);
+    checks.last().unwrap().print();
+
+    for query in options.query.iter() {
+        let result = check_query(query, routing.as_ref());
+        checks.push(Check::new(
+            format!("Query {}", query),
+            result,
+            "Every query needs a node within its radius and heading range",
+        ));
+        checks.last().unwrap().print();
+    }
+
+    let client = AsyncMongoClient::new(options.mongo_options.clone())
+        .await
+        .map_err(|e| e.to_string());
+    let client = match client {
+        Ok(client) => match client.ping().await {
+            Ok(()) => Ok(client),
+            Err(e) => Err(e.to_string()),
+        },
+        Err(e) => Err(e),
+    };
+    checks.push(Check::new(
+        "MongoDB",
+        client
+            .as_ref()
+            .map(|_| format!("reachable at {}", options.mongo_options.uri))
+            .map_err(|e| e.clone()),
+        "Start MongoDB or pass the right --uri",
+    ));
+    checks.last().unwrap().print();
+
+    if let Ok(client) = client {
+        let sensors = match client.count_sensors().await {
+            Ok(0) => Err("the sensors collection is empty".to_string()),
+            Ok(count) => Ok(format!("{} sensors", count)),
+            Err(e) => Err(e.to_string()),
+        };
+        checks.push(Check::new(
+            "Sensors",
+            sensors,
+            "Run `aggregate-sensor-data` to fill the sensors collection",
+        ));
+        checks.last().unwrap().print();
+
+        let data = match client.get_latest_data_point_time().await {
+            Ok(Some(time)) => {
+                let age = DateTime::now().timestamp_millis() - time.timestamp_millis();
+                if age <= *options.max_data_age {
+                    Ok(format!("latest data point at {}", time))
+                } else {
+                    Err(format!("latest data point at {} is too old", time))
+                }
+            }
+            Ok(None) => Err("the data points collection is empty".to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        checks.push(Check::new(
+            "Sensor data",
+            data,
+            "Run `aggregate-sensor-data` to import recent data points",
+        ));
+        checks.last().unwrap().print();
+    }
+
+    let failed = checks.iter().filter(|check| check.result.is_err()).count();
+    if failed == 0 {
+        println!("{}", style("All checks passed").green().bold());
+    } else {
+        println!(
+            "{}",
+            style(format!("{} of {} checks failed", failed, checks.len()))
+                .red()
+                .bold()
+        );
+    }
+
+    failed == 0
+}
+
+/// Check that the graph at **path** loads, returning it ready for routing if it does
+fn check_graph(path: &str) -> (Check, Option<RoutingGraph>) {
+    let graph = graph_io::try_load_graph(path);
+    let check = Check::new(
+        "Graph",
+        graph
+            .as_ref()
+            .map(|graph| {
+                format!(
+                    "{} nodes, {} edges, {} nodes with sensors, average speed limit {:.1}km/h",
+                    graph.graph.node_count(),
+                    graph.graph.edge_count(),
+                    graph.sensor_store.len(),
+                    visitor::average_speed_limit(graph.graph.edge_weights())
+                )
+            })
+            .map_err(|e| format!("{:#}", e)),
+        "Run `process` to build the graph, or point --graph-path at it",
+    );
+    (
+        check,
+        graph.ok().map(|graph| RoutingGraph::new(graph.graph)),
+    )
+}
+
+fn check_query(path: &str, routing: Option<&RoutingGraph>) -> Result<String, String> {
+    let raw = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let queries: Vec<PointQuery> = serde_json::from_str(&raw).map_err(|e| e.to_string())?;
+    let routing = routing.ok_or("the graph did not load")?;
+
+    let missing = nearest_nodes_batch(routing.tree(), &queries, SnapStrategy::ProximityFirst)
+        .iter()
+        .filter(|node| node.is_none())
+        .count();
+    if missing > 0 {
+        return Err(format!(
+            "{} of {} points did not snap to a node",
+            missing,
+            queries.len()
+        ));
+    }
+
+    Ok(format!("{} points snapped", queries.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_graph_fails_its_check() {
+        let (check, routing) = check_graph("./does/not/exist/graph.json");
+
+        assert!(check.result.is_err());
+        assert!(routing.is_none());
+    }
+}
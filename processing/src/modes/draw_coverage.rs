@@ -0,0 +1,154 @@
+use std::collections::HashSet;
+
+use console::style;
+use petgraph::visit::EdgeRef;
+
+use crate::{
+    output::{Canvas, CanvasOptions, DrawOptions},
+    processing::{ProcessedGraph, RoutingGraph},
+    util::SnapStrategy,
+    visitor::DistanceMetric,
+};
+
+use super::sensor_cover::{covered_span, resolve_route};
+
+const COVERED_COLOR: &str = "limegreen";
+const UNCOVERED_COLOR: &str = "#404040";
+
+/// Draw the whole network faint, and the edges that lie between two sensors along any of the
+/// routes in the **route_queries** files solid. The routes are found by **metric**, with their
+/// queries snapped by **snap**
+pub fn draw_coverage(
+    processed_graph: ProcessedGraph,
+    route_queries: &[String],
+    metric: DistanceMetric,
+    snap: SnapStrategy,
+    canvas_options: &CanvasOptions,
+) -> anyhow::Result<Canvas> {
+    let ProcessedGraph {
+        graph,
+        sensor_store,
+    } = processed_graph;
+    let routing = RoutingGraph::new(graph);
+
+    let mut covered = HashSet::new();
+    for path in route_queries {
+        let route = resolve_route(&routing, path, metric, snap)?;
+        let span = covered_span(&route.nodes, &sensor_store);
+        for pair in span.windows(2) {
+            covered.extend(
+                routing
+                    .graph()
+                    .edges_connecting(pair[0], pair[1])
+                    .map(|e| e.id()),
+            );
+        }
+    }
+
+    let graph = routing.into_graph();
+    let total = graph.edge_weights().map(|edge| edge.distance).sum::<f64>();
+    let observed = covered
+        .iter()
+        .map(|edge| graph[*edge].distance)
+        .sum::<f64>();
+    println!(
+        "{} of {} edges covered, {:.1}% of the network length",
+        style(covered.len()).bold(),
+        graph.edge_count(),
+        style(observed / total.max(f64::EPSILON) * 100.0).bold()
+    );
+
+    let mut canvas = canvas_options.canvas(4000, &graph);
+    for edge in graph.edge_indices() {
+        let options = if covered.contains(&edge) {
+            DrawOptions {
+                color: COVERED_COLOR.into(),
+                stroke: 3.0,
+                ..Default::default()
+            }
+        } else {
+            DrawOptions {
+                color: UNCOVERED_COLOR.into(),
+                stroke: 1.0,
+                ..Default::default()
+            }
+        };
+        canvas.draw_polyline(graph[edge].polyline.clone(), options);
+    }
+
+    canvas.draw_legend(
+        [
+            ("Between sensors", COVERED_COLOR),
+            ("Unmonitored", UNCOVERED_COLOR),
+        ]
+        .into_iter()
+        .map(|(label, color)| (label.to_string(), color.to_string()))
+        .collect(),
+    );
+
+    Ok(canvas)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        output::Projection,
+        test_graphs::{grid, sensor, temp_path},
+        util::PointQuery,
+    };
+
+    #[test]
+    fn edges_between_sensors_along_a_route_are_solid() {
+        let (graph, nodes) = grid(1, 5);
+        let row = &nodes[0];
+        let sensor_store = [(row[1], 1), (row[3], 2)]
+            .into_iter()
+            .map(|(node, site_id)| {
+                let point = graph[node].point;
+                (node, vec![sensor(site_id, point.latitude, point.longitude)])
+            })
+            .collect();
+        let query = [row[0], row[4]]
+            .map(|node| PointQuery {
+                point: graph[node].point,
+                radius: 20.0,
+                heading: -180.0..180.0,
+                snap: None,
+            })
+            .to_vec();
+        let query_path = temp_path("coverage.json");
+        std::fs::write(&query_path, serde_json::to_string(&query).unwrap()).unwrap();
+        let svg_path = temp_path("coverage.svg");
+        let canvas_options = CanvasOptions {
+            projection: Projection::default(),
+            max_nodes: 1000,
+            tiles: None,
+        };
+
+        let canvas = draw_coverage(
+            ProcessedGraph {
+                graph,
+                sensor_store,
+            },
+            &[query_path.to_string()],
+            DistanceMetric::Time,
+            SnapStrategy::ProximityFirst,
+            &canvas_options,
+        )
+        .unwrap();
+        canvas.save(&svg_path);
+        let svg = std::fs::read_to_string(&svg_path).unwrap();
+
+        // The two edges east between the sensors are solid, the six others faint
+        let edges = |color: &str, stroke: &str| {
+            svg.lines()
+                .filter(|element| element.starts_with("<path"))
+                .filter(|element| element.contains(&format!("stroke=\"{}\" ", color)))
+                .filter(|element| element.contains(&format!("stroke-width=\"{}\"", stroke)))
+                .count()
+        };
+        assert_eq!(edges(COVERED_COLOR, "3"), 2);
+        assert_eq!(edges(UNCOVERED_COLOR, "1"), 6);
+    }
+}
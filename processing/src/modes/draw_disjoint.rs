@@ -1,13 +1,14 @@
+use std::collections::HashMap;
+
 use console::style;
-use fixedbitset::FixedBitSet;
 use petgraph::{
-    graph::NodeIndex,
     stable_graph::StableDiGraph,
-    visit::{VisitMap, Visitable},
+    unionfind::UnionFind,
+    visit::{EdgeRef, IntoEdgeReferences, NodeIndexable},
 };
 
 use crate::{
-    output::{Canvas, DrawOptions},
+    output::{Canvas, CanvasOptions, DrawOptions},
     processing::{EdgeData, NodeData},
 };
 
@@ -18,33 +19,24 @@ pub const COLORS: [&str; 25] = [
     "#ee82ee",
 ];
 
-pub fn draw_disjoint(graph: StableDiGraph<NodeData, EdgeData>) -> Canvas {
+pub fn draw_disjoint(
+    graph: StableDiGraph<NodeData, EdgeData>,
+    canvas_options: &CanvasOptions,
+) -> Canvas {
     let start_draw = std::time::Instant::now();
 
-    let mut canvas = Canvas::from_graph(4000, &graph);
+    let mut canvas = canvas_options.canvas(4000, &graph);
 
     println!("{} Find disjoint sets", style("[1/3]").bold().dim());
     let start = std::time::Instant::now();
     let pb = indicatif::ProgressBar::new(graph.node_count() as u64);
 
-    let mut sets: Vec<FixedBitSet> = Vec::new();
-    'outer: for node in graph.node_indices() {
-        for set in sets.iter() {
-            if set.is_visited(&node) {
-                pb.inc(1);
-                continue 'outer;
-            }
-        }
-        let mut visited = graph.visit_map();
-        visit(&graph, node, &mut visited);
-        sets.push(visited);
-        pb.inc(1);
-    }
+    let (components, component_count) = weak_components(&graph);
     pb.finish();
     println!(
         "{:?} Found {} disjoint sets",
         style(start.elapsed()).bold().dim().yellow(),
-        sets.len()
+        component_count
     );
 
     println!("{} Drawing disjoint edges", style("[2/3]").bold().dim());
@@ -53,14 +45,7 @@ pub fn draw_disjoint(graph: StableDiGraph<NodeData, EdgeData>) -> Canvas {
     for edge in graph.edge_indices() {
         let start = graph.edge_endpoints(edge).unwrap().0;
         let end = graph.edge_endpoints(edge).unwrap().1;
-        let mut color = None;
-        for (idx, set) in sets.iter().enumerate() {
-            if set.is_visited(&start) && set.is_visited(&end) {
-                color = Some(COLORS[idx % COLORS.len()]);
-                break;
-            }
-        }
-        let color = color.unwrap();
+        let color = COLORS[components[start.index()] % COLORS.len()];
         canvas.draw_line(
             graph.node_weight(start).unwrap().point,
             graph.node_weight(end).unwrap().point,
@@ -84,14 +69,7 @@ pub fn draw_disjoint(graph: StableDiGraph<NodeData, EdgeData>) -> Canvas {
     let pb = indicatif::ProgressBar::new(graph.node_count() as u64);
     for node in graph.node_indices() {
         let data = graph.node_weight(node).unwrap();
-        let mut color = None;
-        for (idx, set) in sets.iter().enumerate() {
-            if set.is_visited(&node) {
-                color = Some(COLORS[idx % COLORS.len()]);
-                break;
-            }
-        }
-        let color = color.unwrap();
+        let color = COLORS[components[node.index()] % COLORS.len()];
         canvas.draw_triangle(data.point, color, 1.0, data.heading);
         pb.inc(1);
     }
@@ -102,6 +80,21 @@ pub fn draw_disjoint(graph: StableDiGraph<NodeData, EdgeData>) -> Canvas {
         graph.node_count()
     );
 
+    // Components share a color once there are more components than colors
+    let legend = COLORS
+        .iter()
+        .take(component_count)
+        .enumerate()
+        .map(|(idx, color)| {
+            let components = (idx..component_count)
+                .step_by(COLORS.len())
+                .map(|component| (component + 1).to_string())
+                .collect::<Vec<_>>();
+            (format!("Set {}", components.join(", ")), color.to_string())
+        })
+        .collect();
+    canvas.draw_legend(legend);
+
     println!(
         "{:?} Finished drawing disjoint sets",
         style(start_draw.elapsed()).bold().dim().yellow()
@@ -110,12 +103,56 @@ pub fn draw_disjoint(graph: StableDiGraph<NodeData, EdgeData>) -> Canvas {
     return canvas;
 }
 
-fn visit(graph: &StableDiGraph<NodeData, EdgeData>, node: NodeIndex, visited: &mut FixedBitSet) {
-    let mut to_visit = vec![node];
-    while let Some(node) = to_visit.pop() {
-        if visited.visit(node) {
-            let neighbours = graph.neighbors_undirected(node);
-            to_visit.extend(neighbours);
+/// Label every node with the weakly connected component it belongs to, indexed by node index.
+/// Components are numbered in the order of their lowest node index, so colors are stable between
+/// runs on the same graph.
+fn weak_components(graph: &StableDiGraph<NodeData, EdgeData>) -> (Vec<usize>, usize) {
+    let mut union_find = UnionFind::new(graph.node_bound());
+    for edge in graph.edge_references() {
+        union_find.union(edge.source().index(), edge.target().index());
+    }
+
+    let mut labels = HashMap::new();
+    let mut components = vec![0; graph.node_bound()];
+    for node in graph.node_indices() {
+        let next = labels.len();
+        components[node.index()] = *labels
+            .entry(union_find.find_mut(node.index()))
+            .or_insert(next);
+    }
+    (components, labels.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_graphs::{add_road, node, TestGraph};
+    use petgraph::graph::NodeIndex;
+
+    /// Add a one-way triangle with its first corner at **latitude**, **longitude**
+    fn add_triangle(graph: &mut TestGraph, latitude: f64, longitude: f64) -> [NodeIndex; 3] {
+        let a = graph.add_node(node(latitude, longitude));
+        let b = graph.add_node(node(latitude + 0.001, longitude));
+        let c = graph.add_node(node(latitude, longitude + 0.001));
+        add_road(graph, a, b, 1, 50.0);
+        add_road(graph, b, c, 1, 50.0);
+        add_road(graph, c, a, 1, 50.0);
+        [a, b, c]
+    }
+
+    #[test]
+    fn disjoint_triangles_are_separate_components() {
+        let mut graph = TestGraph::default();
+        let first = add_triangle(&mut graph, 0.0, 0.0);
+        let second = add_triangle(&mut graph, 1.0, 1.0);
+
+        let (components, component_count) = weak_components(&graph);
+
+        assert_eq!(component_count, 2);
+        for (triangle, component) in [(first, 0), (second, 1)] {
+            assert!(triangle
+                .iter()
+                .all(|node| components[node.index()] == component));
         }
     }
 }
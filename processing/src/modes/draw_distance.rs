@@ -1,28 +1,55 @@
 use std::{mem::swap, time::Instant};
 
+use clap::Args;
 use console::style;
 use petgraph::{
     stable_graph::StableDiGraph,
-    visit::{EdgeRef, IntoEdgeReferences, VisitMap},
+    visit::{EdgeRef, IntoEdgeReferences},
 };
 
 use crate::{
-    custom_bfs::CustomBfs,
     math::{geo_distance, lerp},
-    output::{Canvas, DrawOptions},
-    processing::{build_node_acceleration_structure, EdgeData, NodeData},
+    output::{
+        build_gradient, gradient_color, Canvas, CanvasOptions, DrawOptions, Framing, GradientSpec,
+    },
+    processing::{EdgeData, NodeData, RoutingGraph},
     progress::eta_bar,
+    util::SnapStrategy,
     visitor::{self},
     PointQuery,
 };
 
-pub fn draw_distance(
-    mut graph: StableDiGraph<NodeData, EdgeData>,
-    query: PointQuery,
+#[derive(Debug, Args)]
+pub struct DrawDistanceOptions {
+    #[clap(short = 'a', long = "lat")]
+    latitude: f64,
+    #[clap(short = 'o', long = "lon")]
+    longitude: f64,
+    #[clap(short = 'c', long, default_value = "nan")]
     max_distance: f64,
-    distance_metric: visitor::DistanceMetric,
+    #[clap(short, long, default_value = "space")]
+    metric: visitor::DistanceMetric,
+    #[clap(short, long, default_value = "false", default_missing_value = "true")]
     forward_only: bool,
-) -> Canvas {
+    #[clap(flatten)]
+    gradient: GradientSpec,
+    #[clap(flatten)]
+    framing: Framing,
+    #[clap(flatten)]
+    pub canvas: CanvasOptions,
+}
+
+pub fn draw_distance(
+    graph: StableDiGraph<NodeData, EdgeData>,
+    options: &DrawDistanceOptions,
+) -> anyhow::Result<Canvas> {
+    let max_distance = options.max_distance;
+    let query = PointQuery::new(
+        options.latitude,
+        options.longitude,
+        max_distance,
+        -180.0..180.0,
+    );
     let mut step = 1;
     let steps = 3;
 
@@ -30,14 +57,9 @@ pub fn draw_distance(
         style(format!("[{}/{}]", step, steps)).bold().dim()
     }
 
-    let tree = build_node_acceleration_structure(&graph);
-    let p = [query.point.latitude, query.point.longitude];
-    let (_, (node, _)) = tree
-        .iter_nearest(&p, &geo_distance)
-        .unwrap()
-        .filter(|(dist, (_, data))| query.heading.contains(&data.heading) && *dist <= query.radius)
-        .next()
-        .expect("No node found for query");
+    let routing = RoutingGraph::new(graph);
+    let node = routing.resolve_point(&query, SnapStrategy::ProximityFirst)?;
+    let mut graph = routing.into_graph();
 
     println!(
         "{} Filtering {} nodes at a distance of {}",
@@ -46,25 +68,17 @@ pub fn draw_distance(
         style(max_distance).bold().dim()
     );
     let start = Instant::now();
-    let pb = eta_bar(graph.node_count() as usize);
-    let mut bfs = CustomBfs::new(&graph, *node, distance_metric.to_function());
-    let next_func = if forward_only {
-        CustomBfs::next
-    } else {
-        CustomBfs::next_undirected
-    };
-    while let Some((idx, dist, _)) = next_func(&mut bfs, &graph) {
-        if dist > max_distance {
-            bfs.discovered.set(idx.index(), false);
-            break;
-        }
-        pb.inc(1);
-    }
-    pb.finish_and_clear();
+    let distances = visitor::distance_field(
+        &graph,
+        node,
+        options.metric,
+        options.forward_only,
+        max_distance,
+    );
     println!(
         "{:?} Found {} nodes in range",
         style(start.elapsed()).bold().dim().yellow(),
-        style(bfs.distances.len()).bold().dim()
+        style(distances.len()).bold().dim()
     );
     step += 1;
 
@@ -74,7 +88,7 @@ pub fn draw_distance(
 
     let mut to_remove = Vec::new();
     for node in graph.node_indices() {
-        if bfs.discovered.is_visited(&node) {
+        if distances.contains_key(&node) {
             pb.inc(1);
             continue;
         }
@@ -94,14 +108,12 @@ pub fn draw_distance(
     );
     step += 1;
 
-    let mut canvas = Canvas::from_graph(4000, &graph);
+    let mut canvas = options
+        .framing
+        .canvas(4000, &graph, options.canvas.projection);
     canvas.draw_circle(query.point, "red", 10.0);
 
-    let grad = colorgrad::CustomGradient::new()
-        .html_colors(&["gold", "hotpink", "darkturquoise"])
-        .domain(&[0.0, max_distance])
-        .build()
-        .unwrap();
+    let grad = build_gradient(&options.gradient, 0.0, max_distance);
 
     println!("{} Drawing graph", fsteps(step, steps));
     let start = Instant::now();
@@ -112,8 +124,8 @@ pub fn draw_distance(
         let mut source = graph.node_weight(edge.source()).unwrap();
         let mut target = graph.node_weight(edge.target()).unwrap();
 
-        let mut source_distance = bfs.distances.get(&edge.source()).unwrap();
-        let mut target_distance = bfs.distances.get(&edge.target()).unwrap();
+        let mut source_distance = distances.get(&edge.source()).unwrap();
+        let mut target_distance = distances.get(&edge.target()).unwrap();
 
         let mut polyline = data.polyline.clone();
         if source_distance > target_distance {
@@ -143,14 +155,7 @@ pub fn draw_distance(
             let diff = target_distance - source_distance;
             let dist = lerp(0.0, diff, traversed_perc) + source_distance;
 
-            let color = grad.at(dist);
-
-            let color = format!(
-                "rgb({}, {}, {})",
-                color.r * 255.0,
-                color.g * 255.0,
-                color.b * 255.0
-            );
+            let color = gradient_color(&grad, dist);
             canvas.draw_line(
                 source.point,
                 target.point,
@@ -171,5 +176,5 @@ pub fn draw_distance(
         style(graph.edge_count()).bold().dim()
     );
 
-    canvas
+    Ok(canvas)
 }
@@ -0,0 +1,192 @@
+use std::collections::HashMap;
+
+use anyhow::Context;
+use clap::Args;
+
+use crate::{
+    graph_io,
+    mongo::{
+        client::{
+            async_client::{AsyncMongoClient, DataAggregation},
+            MongoOptions,
+        },
+        model::VehicleType,
+    },
+    output::{build_gradient, gradient_color, CanvasOptions, DrawOptions, GradientSpec},
+    processing::ProcessedGraph,
+    progress::Progress,
+};
+
+use super::live_route::{ParseableDate, ParseableDuration};
+
+/// The color of sensor nodes without data at the timestamp
+const NO_DATA_COLOR: &str = "#5a5a8c";
+/// The radius of the circles of sensor nodes without data, and of the lowest flow
+const MIN_RADIUS: f32 = 4.0;
+const MAX_RADIUS: f32 = 20.0;
+
+#[derive(Debug, Args)]
+pub struct DrawHeatmapOptions {
+    #[clap(flatten)]
+    pub mongo_options: MongoOptions,
+    #[clap(short, long, default_value = "./out/graph.json")]
+    pub graph_path: String,
+    #[clap(short, long, default_value = "./out/heatmap.svg")]
+    pub output: String,
+    #[clap(short, long, default_value = "now")]
+    pub timestamp: ParseableDate,
+    #[clap(short, long, default_value = "15m")]
+    pub max_sensor_data_age: ParseableDuration,
+    #[clap(short, long, default_value = "any-vehicle")]
+    pub vehicle_type: VehicleType,
+    /// How the data points of each sensor are turned into a flow rate
+    #[clap(long, default_value = "latest")]
+    pub aggregation: DataAggregation,
+    #[clap(flatten)]
+    pub gradient: GradientSpec,
+    #[clap(flatten)]
+    pub canvas: CanvasOptions,
+}
+
+/// Draw a circle at every sensor node over the road network, sized and colored by the summed flow
+/// rate of its sensors
+pub async fn draw_heatmap(options: DrawHeatmapOptions) -> anyhow::Result<()> {
+    let mut progress = Progress::new();
+
+    progress.step_unsized("Connecting to MongoDB");
+    let client = AsyncMongoClient::new(options.mongo_options.clone())
+        .await
+        .context("Failed to connect to MongoDB")?;
+    progress.finish("");
+
+    progress.step_unsized("Reading graph");
+    let ProcessedGraph {
+        graph,
+        sensor_store,
+    } = graph_io::try_load_graph(&options.graph_path)?;
+    progress.finish(format!(
+        "Loaded graph with {} nodes and {} edges",
+        graph.node_count(),
+        graph.edge_count()
+    ));
+
+    let sensors = sensor_store
+        .values()
+        .flatten()
+        .filter(|sensor| sensor.vehicle_type == options.vehicle_type)
+        .collect::<Vec<_>>();
+
+    progress.step_sized(sensors.len(), "Fetching sensor data");
+    let data = client
+        .get_sensor_data_at(
+            sensors.iter().copied(),
+            *options.timestamp,
+            *options.max_sensor_data_age,
+            options.aggregation,
+        )
+        .await
+        .context("Failed to get sensor data")?;
+    progress.finish(format!(
+        "Found data for {} of {} sensors",
+        data.len(),
+        sensors.len()
+    ));
+
+    // The summed flow of every sensor node, or None if none of its sensors have data
+    let flows = sensor_store
+        .iter()
+        .map(|(node, sensors)| {
+            let flows = sensors
+                .iter()
+                .filter(|sensor| sensor.vehicle_type == options.vehicle_type)
+                .filter_map(|sensor| data.get(&sensor.site_id))
+                .map(|data_point| data_point.flow_rate)
+                .collect::<Vec<_>>();
+            let flow = (!flows.is_empty()).then(|| flows.iter().sum::<f64>());
+            (*node, flow)
+        })
+        .collect::<HashMap<_, _>>();
+    let max_flow = flows.values().flatten().copied().fold(0.0, f64::max);
+    let grad = build_gradient(&options.gradient, 0.0, max_flow.max(1.0));
+
+    let mut canvas = options.canvas.canvas(4000, &graph);
+    for edge in graph.edge_weights() {
+        canvas.draw_polyline(
+            edge.polyline.clone(),
+            DrawOptions {
+                color: "gray".into(),
+                stroke: 1.0,
+                ..Default::default()
+            },
+        );
+    }
+
+    // Draw the largest flows first so smaller circles stay visible on top
+    let mut markers = flows
+        .iter()
+        .map(|(node, flow)| {
+            let (radius, color) = flow_marker(*flow, max_flow, &grad);
+            (graph[*node].point, radius, color)
+        })
+        .collect::<Vec<_>>();
+    markers.sort_by(|a, b| b.1.total_cmp(&a.1));
+    for (point, radius, color) in markers {
+        canvas.draw_circle(point, &color, radius);
+    }
+    canvas.draw_legend(vec![("No data".to_string(), NO_DATA_COLOR.to_string())]);
+
+    options.canvas.save(&canvas, &options.output);
+    println!("Wrote heatmap to {}", options.output);
+
+    Ok(())
+}
+
+/// The radius and color of the circle of a sensor node with **flow**, out of the highest flow of
+/// all nodes, or the no data marker if the node has no flow
+fn flow_marker(flow: Option<f64>, max_flow: f64, grad: &colorgrad::Gradient) -> (f32, String) {
+    match flow {
+        Some(flow) => {
+            let scale = if max_flow > 0.0 {
+                (flow / max_flow).clamp(0.0, 1.0)
+            } else {
+                0.0
+            };
+            // Scale the area rather than the radius with the flow
+            let radius = MIN_RADIUS + (MAX_RADIUS - MIN_RADIUS) * scale.sqrt() as f32;
+            (radius, gradient_color(grad, flow))
+        }
+        None => (MIN_RADIUS, NO_DATA_COLOR.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flow_scales_the_marker_area_and_color() {
+        let grad = colorgrad::CustomGradient::new()
+            .html_colors(&["black", "white"])
+            .domain(&[0.0, 400.0])
+            .build()
+            .unwrap();
+
+        let (none_radius, none_color) = flow_marker(None, 400.0, &grad);
+        let (low_radius, low_color) = flow_marker(Some(0.0), 400.0, &grad);
+        let (quarter_radius, _) = flow_marker(Some(100.0), 400.0, &grad);
+        let (max_radius, max_color) = flow_marker(Some(400.0), 400.0, &grad);
+
+        assert_eq!(
+            (none_radius, none_color.as_str()),
+            (MIN_RADIUS, NO_DATA_COLOR)
+        );
+        assert_eq!(low_radius, MIN_RADIUS);
+        // The area grows with the flow, so a quarter of the flow is half way up the radius range
+        assert_eq!(quarter_radius, (MIN_RADIUS + MAX_RADIUS) / 2.0);
+        assert_eq!(max_radius, MAX_RADIUS);
+        assert_eq!(low_color, gradient_color(&grad, 0.0));
+        assert_eq!(max_color, gradient_color(&grad, 400.0));
+        assert_ne!(low_color, max_color);
+        assert_ne!(low_color, NO_DATA_COLOR);
+    }
+}
@@ -7,7 +7,7 @@ use petgraph::{
 
 use crate::{
     math::geo_distance,
-    output::{Canvas, DrawOptions},
+    output::{Canvas, CanvasOptions, DrawOptions},
     parse::Point,
     processing::{build_node_acceleration_structure, EdgeData, NodeData},
 };
@@ -17,8 +17,9 @@ pub fn draw_reachable(
     point: Point,
     range: f64,
     inverse: bool,
+    canvas_options: &CanvasOptions,
 ) -> Canvas {
-    let mut canvas = Canvas::from_graph(4000, &graph);
+    let mut canvas = canvas_options.canvas(4000, &graph);
 
     let node_tree = build_node_acceleration_structure(&graph);
     let borrow = [point.latitude, point.longitude];
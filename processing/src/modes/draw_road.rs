@@ -1,7 +1,7 @@
 use petgraph::stable_graph::StableDiGraph;
 
 use crate::{
-    output::{Canvas, DrawOptions},
+    output::{Canvas, CanvasOptions, DrawOptions},
     processing::{EdgeData, NodeData},
 };
 
@@ -12,14 +12,18 @@ const COLORS: [&str; 25] = [
     "#ee82ee",
 ];
 
-pub fn draw_roads(graph: StableDiGraph<NodeData, EdgeData>, unique_ids: Vec<i32>) -> Canvas {
+pub fn draw_roads(
+    graph: StableDiGraph<NodeData, EdgeData>,
+    unique_ids: Vec<i32>,
+    canvas_options: &CanvasOptions,
+) -> Canvas {
     let draw_all = unique_ids.is_empty();
 
     if unique_ids.len() > COLORS.len() {
         panic!("Too many unique ids provided");
     }
 
-    let mut canvas = Canvas::from_graph(4000, &graph);
+    let mut canvas = canvas_options.canvas(4000, &graph);
 
     for edge in graph.edge_indices() {
         let data = graph.edge_weight(edge).unwrap();
@@ -0,0 +1,148 @@
+use clap::Args;
+
+use crate::{
+    graph_io,
+    math::{angle_diff, dist},
+    processing::ProcessedGraph,
+    progress::Progress,
+};
+
+#[derive(Debug, Args)]
+pub struct ExportAssignmentsOptions {
+    #[clap(short, long, default_value = "./out/graph.json")]
+    pub graph_path: String,
+    #[clap(short, long, default_value = "./out/assignments.csv")]
+    pub output: String,
+}
+
+/// Write one CSV row per sensor in the sensor store with the node it is assigned to, how far the
+/// sensor is from the node, and how far the measurement side of the sensor is from the heading of
+/// the node in degrees, empty if the sensor has no known measurement side
+pub fn export_assignments(options: ExportAssignmentsOptions) -> anyhow::Result<()> {
+    let mut progress = Progress::new();
+
+    progress.step_unsized("Reading graph");
+    let ProcessedGraph {
+        graph,
+        sensor_store,
+    } = graph_io::try_load_graph(&options.graph_path)?;
+    progress.finish(format!(
+        "Loaded graph with {} nodes and {} sensor nodes",
+        graph.node_count(),
+        sensor_store.len()
+    ));
+
+    let mut assignments = sensor_store
+        .iter()
+        .flat_map(|(node, sensors)| sensors.iter().map(move |sensor| (*node, sensor)))
+        .collect::<Vec<_>>();
+    assignments.sort_by_key(|(node, sensor)| (sensor.site_id, node.index()));
+
+    progress.step_sized(assignments.len(), "Writing assignments");
+    let mut writer = csv::Writer::from_path(&options.output)?;
+    writer.write_record([
+        "site_id",
+        "node_index",
+        "node_lat",
+        "node_lon",
+        "sensor_lat",
+        "sensor_lon",
+        "assign_distance",
+        "heading_diff",
+    ])?;
+    for (node, sensor) in assignments.iter() {
+        let data = graph.node_weight(*node).ok_or_else(|| {
+            anyhow::anyhow!(
+                "Sensor {} is on missing node {}",
+                sensor.site_id,
+                node.index()
+            )
+        })?;
+        let point = sensor.location.point();
+        let heading_diff = sensor
+            .measurement_side
+            .to_bearing()
+            .map(|bearing| angle_diff(bearing, data.heading).abs().to_string())
+            .unwrap_or_default();
+        writer.write_record([
+            sensor.site_id.to_string(),
+            node.index().to_string(),
+            data.point.latitude.to_string(),
+            data.point.longitude.to_string(),
+            point.latitude.to_string(),
+            point.longitude.to_string(),
+            dist(data.point, point).to_string(),
+            heading_diff,
+        ])?;
+        progress.tick();
+    }
+    writer.flush()?;
+    progress.finish(format!(
+        "Wrote {} assignments to {}",
+        assignments.len(),
+        options.output
+    ));
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        graph_io::save_graph,
+        mongo::model::{MeasurementSide, SensorMetadata},
+        processing::NodeData,
+        test_graphs::{add_road, node, point, sensor, temp_path, TestGraph},
+    };
+
+    #[test]
+    fn one_row_per_assigned_sensor_with_its_distance() {
+        let (graph_path, output) = (temp_path("assignments.json"), temp_path("assignments.csv"));
+
+        let mut graph = TestGraph::default();
+        let east = graph.add_node(NodeData {
+            heading: 90.0,
+            ..node(0.0, 0.0)
+        });
+        let end = graph.add_node(node(0.0, 0.001));
+        add_road(&mut graph, east, end, 1, 50.0);
+        // A northbound sensor beside the eastbound node, and two sensors without a side
+        let beside = SensorMetadata {
+            measurement_side: MeasurementSide::NorthBound,
+            ..sensor(1, 0.0001, 0.0)
+        };
+        let sensor_store = [
+            (east, vec![beside, sensor(3, 0.0, 0.0)]),
+            (end, vec![sensor(2, 0.0, 0.001)]),
+        ]
+        .into();
+        save_graph(
+            &graph_path,
+            &ProcessedGraph {
+                graph,
+                sensor_store,
+            },
+        )
+        .unwrap();
+
+        export_assignments(ExportAssignmentsOptions {
+            graph_path: graph_path.to_string(),
+            output: output.to_string(),
+        })
+        .unwrap();
+
+        let mut reader = csv::Reader::from_path(&output).unwrap();
+        let rows = reader.records().map(|row| row.unwrap()).collect::<Vec<_>>();
+
+        let site_ids = rows.iter().map(|row| &row[0]).collect::<Vec<_>>();
+        assert_eq!(site_ids, ["1", "2", "3"]);
+        let distance = |row: &csv::StringRecord| row[6].parse::<f64>().unwrap();
+        let expected = dist(point(0.0, 0.0), point(0.0001, 0.0));
+        assert!((distance(&rows[0]) - expected).abs() < 1e-9);
+        assert_eq!(&rows[0][7], "90");
+        assert_eq!(distance(&rows[1]), 0.0);
+        assert_eq!(&rows[1][1], end.index().to_string());
+        assert_eq!(&rows[2][7], "");
+    }
+}
@@ -1,5 +1,12 @@
+use std::collections::BTreeMap;
+
 use clap::Args;
-use mongodb::{bson::doc, options::FindOptions, Client};
+use console::style;
+use mongodb::{
+    bson::{doc, oid::ObjectId, DateTime},
+    options::FindOptions,
+    Client,
+};
 
 use crate::{mongo::client::MongoOptions, progress::Progress};
 
@@ -7,8 +14,13 @@ use crate::mongo::model::DataPoint;
 
 #[derive(Debug, Args)]
 pub struct FindGapsOptions {
+    /// Longest time in seconds between two data points that is not a gap
     #[clap(short, long)]
     max_time_between: i32,
+    /// Find the gaps in the data of each sensor, rather than in the data of all sensors together,
+    /// and write them as CSV with their duration in seconds
+    #[clap(long, default_value = "false", default_missing_value = "true")]
+    per_sensor: bool,
     #[clap(flatten)]
     mongo_options: MongoOptions,
 }
@@ -41,26 +53,178 @@ pub async fn find_gaps(options: FindGapsOptions) {
         .await
         .unwrap();
 
-    let mut times = Vec::with_capacity(length);
+    let mut times = SensorTimes::new(options.per_sensor);
 
     let pb = progress.get_pb();
     let mut i = 0;
     while cursor.advance().await.unwrap() {
-        let point = cursor.deserialize_current().unwrap();
-        let time = point.time.timestamp_millis() / 1000;
-        times.push(time as u32);
+        times.push(&cursor.deserialize_current().unwrap());
         i += 1;
         if i % 1000 == 0 {
             pb.inc(1000);
         }
     }
     progress.finish("Read data points");
+    if let Some(warning) = times.warning() {
+        println!("{} {}", style("[!]").bold().yellow(), warning);
+    }
+
+    let max_time_between = options.max_time_between as i64 * 1000;
+
+    if !options.per_sensor {
+        progress.step_unsized("Finding gaps");
+        for times in times.times.values() {
+            for (start, end) in gaps(times, max_time_between) {
+                println!(
+                    "Gap between {} and {}",
+                    format_time(start),
+                    format_time(end)
+                );
+            }
+        }
+        return;
+    }
+
+    progress.step_sized(times.times.len(), "Finding gaps per sensor");
+    let mut gap_count = 0;
+    let mut writer = csv::Writer::from_writer(std::io::stdout());
+    writer
+        .write_record(["sensor_id", "gap_start", "gap_end", "duration"])
+        .unwrap();
+    for (sensor, times) in times.times.iter() {
+        let sensor = sensor.unwrap().to_hex();
+        for (start, end) in gaps(times, max_time_between) {
+            writer
+                .write_record([
+                    sensor.clone(),
+                    format_time(start),
+                    format_time(end),
+                    ((end - start) / 1000).to_string(),
+                ])
+                .unwrap();
+            gap_count += 1;
+        }
+        progress.tick();
+    }
+    writer.flush().unwrap();
+    progress.finish(format!(
+        "Found {} gaps in the data of {} sensors",
+        gap_count,
+        times.times.len()
+    ));
+}
+
+/// The times in milliseconds of the data points of each sensor, or of all sensors together under
+/// `None`. Data points are read in time order, so the times of each sensor are sorted as well.
+struct SensorTimes {
+    per_sensor: bool,
+    times: BTreeMap<Option<ObjectId>, Vec<i64>>,
+    /// The number of data points skipped for being older than the one before them
+    out_of_order: usize,
+}
+
+impl SensorTimes {
+    fn new(per_sensor: bool) -> Self {
+        Self {
+            per_sensor,
+            times: BTreeMap::new(),
+            out_of_order: 0,
+        }
+    }
+
+    fn push(&mut self, point: &DataPoint) {
+        let time = point.time.timestamp_millis();
+        let sensor = self.per_sensor.then_some(point.sensor_id);
+        let sensor_times = self.times.entry(sensor).or_default();
+        if sensor_times.last().is_some_and(|last| time < *last) {
+            self.out_of_order += 1;
+        } else {
+            sensor_times.push(time);
+        }
+    }
 
-    progress.step_unsized("Finding gaps");
-    for pair in times.windows(2) {
-        let diff = pair[1] - pair[0];
-        if diff > options.max_time_between as u32 {
-            println!("Gap between {} and {}", pair[0], pair[1]);
+    /// A warning about the data points skipped for being out of time order, if any were
+    fn warning(&self) -> Option<String> {
+        (self.out_of_order > 0).then(|| {
+            format!(
+                "Skipped {} data points read out of time order",
+                self.out_of_order
+            )
+        })
+    }
+}
+
+/// The start and end of every gap longer than **max_time_between** in the sorted **times**, all
+/// in milliseconds
+fn gaps(times: &[i64], max_time_between: i64) -> impl Iterator<Item = (i64, i64)> + '_ {
+    times
+        .windows(2)
+        .filter(move |pair| pair[1] - pair[0] > max_time_between)
+        .map(|pair| (pair[0], pair[1]))
+}
+
+fn format_time(millis: i64) -> String {
+    DateTime::from_millis(millis)
+        .try_to_rfc3339_string()
+        .unwrap_or_else(|_| millis.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data_point(sensor_id: ObjectId, seconds: i64) -> DataPoint {
+        DataPoint {
+            mongo_id: None,
+            original_id: ObjectId::new(),
+            sensor_id,
+            time: DateTime::from_millis(seconds * 1000),
+            flow_rate: 600.0,
+            average_speed: 80.0,
         }
     }
+
+    #[test]
+    fn gaps_are_found_per_sensor() {
+        let (steady, silent) = (ObjectId::new(), ObjectId::new());
+        let mut times = SensorTimes::new(true);
+        for seconds in [0, 60, 120, 180, 240, 300] {
+            times.push(&data_point(steady, seconds));
+        }
+        for seconds in [0, 60, 240, 300] {
+            times.push(&data_point(silent, seconds));
+        }
+
+        let gaps_of = |sensor| gaps(&times.times[&Some(sensor)], 90 * 1000).collect::<Vec<_>>();
+
+        assert_eq!(gaps_of(steady), vec![]);
+        assert_eq!(gaps_of(silent), vec![(60 * 1000, 240 * 1000)]);
+        // Together the sensors never go silent
+        let mut together = SensorTimes::new(false);
+        for seconds in [0, 60, 120, 180, 240, 300] {
+            together.push(&data_point(steady, seconds));
+            together.push(&data_point(silent, seconds));
+        }
+        assert_eq!(gaps(&together.times[&None], 90 * 1000).count(), 0);
+    }
+
+    #[test]
+    fn out_of_order_times_are_skipped_with_a_warning() {
+        let sensor = ObjectId::new();
+        let mut times = SensorTimes::new(false);
+        for seconds in [0, 300, 60, 400] {
+            times.push(&data_point(sensor, seconds));
+        }
+
+        assert_eq!(times.times[&None], vec![0, 300 * 1000, 400 * 1000]);
+        assert_eq!(
+            gaps(&times.times[&None], 90 * 1000).collect::<Vec<_>>(),
+            vec![(0, 300 * 1000), (300 * 1000, 400 * 1000)]
+        );
+        assert_eq!(
+            times.warning().as_deref(),
+            Some("Skipped 1 data points read out of time order")
+        );
+        assert_eq!(SensorTimes::new(false).warning(), None);
+    }
 }
@@ -10,16 +10,15 @@ use petgraph::{
 };
 
 use crate::{
-    custom_bfs::CustomBfs,
     math::{geo_distance, lerp},
     modes::{
         draw_disjoint::COLORS,
         inspect::{find_closest_node_to, InspectOptions},
     },
-    output::Canvas,
+    output::{build_gradient, gradient_color, Canvas},
     processing::{EdgeData, NodeData},
     progress::Progress,
-    visitor::DistanceMetric,
+    visitor::{distance_field, DistanceMetric},
 };
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
@@ -29,18 +28,29 @@ pub enum EdgeColor {
     Distance,
     DistanceDirected,
     ForbiddenDirection,
+    SpeedLimit,
 }
 
+/// Label and color of each legend entry
+type Legend = Vec<(String, String)>;
+
+/// Speed limit in km/h at the end of the gradient when coloring by speed limit
+const MAX_SPEED_LIMIT: f64 = 120.0;
+/// Number of steps the legend splits a gradient into
+const LEGEND_STOPS: usize = 4;
+
 impl EdgeColor {
     pub fn coloring_function(
         &self,
-    ) -> fn(&mut Progress, &mut Canvas, &StableDiGraph<NodeData, EdgeData>, &InspectOptions) {
+    ) -> fn(&mut Progress, &mut Canvas, &StableDiGraph<NodeData, EdgeData>, &InspectOptions) -> Legend
+    {
         match self {
             EdgeColor::None => noop,
             EdgeColor::Disjoint => disjoint,
             EdgeColor::Distance => distance,
             EdgeColor::DistanceDirected => distance,
             EdgeColor::ForbiddenDirection => forbidden_direction,
+            EdgeColor::SpeedLimit => speed_limit,
         }
     }
 }
@@ -50,7 +60,8 @@ fn noop(
     _canvas: &mut Canvas,
     _graph: &StableDiGraph<NodeData, EdgeData>,
     _options: &InspectOptions,
-) {
+) -> Legend {
+    Vec::new()
 }
 
 fn disjoint(
@@ -58,7 +69,7 @@ fn disjoint(
     canvas: &mut Canvas,
     graph: &StableDiGraph<NodeData, EdgeData>,
     opts: &InspectOptions,
-) {
+) -> Legend {
     progress.step_sized(
         graph.edge_count(),
         format!("Drawing {} edges", style(graph.edge_count()).bold()),
@@ -137,6 +148,20 @@ fn disjoint(
         style(graph.edge_count()).bold(),
         style(sets.len()).bold(),
     ));
+
+    // Sets share a color once there are more sets than colors
+    COLORS
+        .iter()
+        .take(sets.len())
+        .enumerate()
+        .map(|(idx, color)| {
+            let sets = (idx..sets.len())
+                .step_by(COLORS.len())
+                .map(|set| (set + 1).to_string())
+                .collect::<Vec<_>>();
+            (format!("Set {}", sets.join(", ")), color.to_string())
+        })
+        .collect()
 }
 
 fn distance(
@@ -144,25 +169,13 @@ fn distance(
     canvas: &mut Canvas,
     graph: &StableDiGraph<NodeData, EdgeData>,
     opts: &InspectOptions,
-) {
-    progress.step_sized(graph.node_count(), "Calculating distances");
+) -> Legend {
+    progress.step_unsized("Calculating distances");
     let center_node = find_closest_node_to(&graph, opts.point);
-    let mut bfs = CustomBfs::new(&graph, center_node, opts.metric.to_function());
-    let next_func = if opts.directed {
-        CustomBfs::next
-    } else {
-        CustomBfs::next_undirected
-    };
-    while let Some((idx, dist, _)) = next_func(&mut bfs, &graph) {
-        if dist > opts.range {
-            bfs.discovered.set(idx.index(), false);
-            break;
-        }
-        progress.tick();
-    }
+    let distances = distance_field(graph, center_node, opts.metric, opts.directed, opts.range);
     progress.finish(format!(
         "Found {} nodes in range",
-        style(bfs.distances.len()).bold()
+        style(distances.len()).bold()
     ));
 
     let direction = if graph.is_directed() {
@@ -184,11 +197,7 @@ fn distance(
         ),
     );
 
-    let grad = colorgrad::CustomGradient::new()
-        .html_colors(&["gold", "hotpink", "darkturquoise"])
-        .domain(&[0.0, opts.range])
-        .build()
-        .unwrap();
+    let grad = build_gradient(&opts.gradient, 0.0, opts.range);
 
     for edge in graph.edge_references() {
         let data = edge.weight();
@@ -196,8 +205,8 @@ fn distance(
         let mut source = graph.node_weight(edge.source()).unwrap();
         let mut target = graph.node_weight(edge.target()).unwrap();
 
-        let source_distance = bfs.distances.get(&edge.source());
-        let target_distance = bfs.distances.get(&edge.target());
+        let source_distance = distances.get(&edge.source());
+        let target_distance = distances.get(&edge.target());
 
         if source_distance.is_some() && target_distance.is_some() {
             let mut source_distance = source_distance.unwrap();
@@ -231,14 +240,7 @@ fn distance(
                 let diff = target_distance - source_distance;
                 let dist = lerp(0.0, diff, traversed_perc) + source_distance;
 
-                let color = grad.at(dist);
-
-                let color = format!(
-                    "rgb({}, {}, {})",
-                    color.r * 255.0,
-                    color.g * 255.0,
-                    color.b * 255.0
-                );
+                let color = gradient_color(&grad, dist);
                 let opts = opts.line_style.to_draw(color.as_str());
                 canvas.draw_line(source.point, target.point, opts);
             }
@@ -250,6 +252,22 @@ fn distance(
         progress.tick();
     }
     progress.finish(format!("Drew {} edges", style(graph.edge_count()).bold()));
+
+    let unit = match opts.metric {
+        DistanceMetric::Space => "m",
+        DistanceMetric::Time => "s",
+    };
+    let mut legend = (0..=LEGEND_STOPS)
+        .map(|stop| {
+            let value = opts.range * stop as f64 / LEGEND_STOPS as f64;
+            (
+                format!("{:.0} {}", value, unit),
+                gradient_color(&grad, value),
+            )
+        })
+        .collect::<Vec<_>>();
+    legend.push(("Out of range".to_string(), "gray".to_string()));
+    legend
 }
 
 fn forbidden_direction(
@@ -257,7 +275,7 @@ fn forbidden_direction(
     canvas: &mut Canvas,
     graph: &StableDiGraph<NodeData, EdgeData>,
     opts: &InspectOptions,
-) {
+) -> Legend {
     progress.step_sized(graph.node_count(), "Drawing edges with forbidden direction");
 
     for edge in graph.edge_references() {
@@ -275,4 +293,96 @@ fn forbidden_direction(
         progress.tick();
     }
     progress.finish(format!("Drew {} edges", style(graph.edge_count()).bold()));
+
+    [
+        ("Forward", "blue"),
+        ("Backward", "red"),
+        ("Both", "green"),
+        ("None", "magenta"),
+    ]
+    .into_iter()
+    .map(|(label, color)| (label.to_string(), color.to_string()))
+    .collect()
+}
+
+fn speed_limit(
+    progress: &mut Progress,
+    canvas: &mut Canvas,
+    graph: &StableDiGraph<NodeData, EdgeData>,
+    opts: &InspectOptions,
+) -> Legend {
+    progress.step_sized(graph.edge_count(), "Drawing edges by speed limit");
+
+    let grad = build_gradient(&opts.gradient, 0.0, MAX_SPEED_LIMIT);
+    let mut missing = 0;
+    for edge in graph.edge_weights() {
+        if edge.speed_limit.is_none() {
+            missing += 1;
+        }
+        let opts = opts
+            .line_style
+            .to_draw(speed_limit_color(&grad, edge.speed_limit));
+        canvas.draw_polyline(edge.polyline.clone(), opts);
+
+        progress.tick();
+    }
+    progress.finish(format!(
+        "Drew {} edges, {} without a speed limit",
+        style(graph.edge_count()).bold(),
+        style(missing).bold()
+    ));
+
+    let mut legend = (0..=LEGEND_STOPS)
+        .map(|stop| {
+            let value = MAX_SPEED_LIMIT * stop as f64 / LEGEND_STOPS as f64;
+            (format!("{:.0} km/h", value), gradient_color(&grad, value))
+        })
+        .collect::<Vec<_>>();
+    legend.push(("No speed limit".to_string(), "gray".to_string()));
+    legend
+}
+
+/// The color of an edge with **speed_limit** on **grad**, or gray if it has none
+fn speed_limit_color(grad: &colorgrad::Gradient, speed_limit: Option<f64>) -> String {
+    match speed_limit {
+        Some(speed_limit) => gradient_color(grad, speed_limit),
+        None => "gray".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::{GradientPreset, GradientSpec};
+
+    /// The red, green and blue of an `rgb()` color
+    fn channels(color: &str) -> [f64; 3] {
+        let values = color
+            .trim_start_matches("rgb(")
+            .trim_end_matches(')')
+            .split(", ")
+            .map(|value| value.parse().unwrap())
+            .collect::<Vec<_>>();
+        [values[0], values[1], values[2]]
+    }
+
+    #[test]
+    fn speed_limits_get_distinct_colors_along_the_gradient() {
+        let spec = GradientSpec {
+            gradient: GradientPreset::Default,
+            gradient_colors: vec![],
+        };
+        let grad = build_gradient(&spec, 0.0, MAX_SPEED_LIMIT);
+
+        let [slow, medium, fast] =
+            [30.0, 50.0, 90.0].map(|speed| speed_limit_color(&grad, Some(speed)));
+
+        assert_eq!(speed_limit_color(&grad, None), "gray");
+        assert!(slow != medium && medium != fast && slow != fast);
+        // From gold at 0 over hot pink at 60 to dark turquoise at 120 km/h
+        let [slow, medium, fast] = [slow, medium, fast].map(|color| channels(&color));
+        assert!(slow[0] > 250.0 && medium[0] > 250.0 && fast[0] < 200.0);
+        assert!(slow[2] < medium[2] && medium[2] < fast[2]);
+        assert!(slow[1] > medium[1]);
+    }
 }
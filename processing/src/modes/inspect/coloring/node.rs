@@ -1,9 +1,13 @@
+use std::collections::HashMap;
+
 use clap::ValueEnum;
 use console::style;
-use petgraph::stable_graph::StableDiGraph;
+use petgraph::{graph::NodeIndex, stable_graph::StableDiGraph};
 
 use crate::{
+    math::angle_diff,
     modes::inspect::InspectOptions,
+    mongo::model::SensorMetadata,
     output::Canvas,
     processing::{EdgeData, NodeData},
     progress::Progress,
@@ -14,16 +18,36 @@ pub enum NodeColor {
     None,
     Simple,
     Junctions,
+    /// Nodes with sensors, sized by how many sensors are assigned to them
+    Sensors,
+    /// Sensors as arrows in the direction they measure, green if it is close to the heading of
+    /// their node and red if not
+    SensorDirections,
 }
 
+/// Degrees a sensor direction can differ from the heading of its node and still match
+const MAX_DIRECTION_MISMATCH: f64 = 45.0;
+/// Length in pixels of the arrow drawn for each sensor
+const SENSOR_ARROW_LENGTH: f64 = 15.0;
+
+type SensorStore = HashMap<NodeIndex, Vec<SensorMetadata>>;
+
 impl NodeColor {
     pub fn coloring_function(
         &self,
-    ) -> fn(&mut Progress, &mut Canvas, &StableDiGraph<NodeData, EdgeData>, &InspectOptions) {
+    ) -> fn(
+        &mut Progress,
+        &mut Canvas,
+        &StableDiGraph<NodeData, EdgeData>,
+        &SensorStore,
+        &InspectOptions,
+    ) {
         match self {
             NodeColor::None => noop,
             NodeColor::Simple => simple_coloring,
             NodeColor::Junctions => coloring_junctions,
+            NodeColor::Sensors => coloring_sensors,
+            NodeColor::SensorDirections => coloring_sensor_directions,
         }
     }
 }
@@ -32,6 +56,7 @@ fn noop(
     _progress: &mut Progress,
     _canvas: &mut Canvas,
     _graph: &StableDiGraph<NodeData, EdgeData>,
+    _sensor_store: &SensorStore,
     _options: &InspectOptions,
 ) {
 }
@@ -40,6 +65,7 @@ fn simple_coloring(
     progress: &mut Progress,
     canvas: &mut Canvas,
     graph: &StableDiGraph<NodeData, EdgeData>,
+    _sensor_store: &SensorStore,
     _options: &InspectOptions,
 ) {
     progress.step_sized(
@@ -60,6 +86,7 @@ fn coloring_junctions(
     progress: &mut Progress,
     canvas: &mut Canvas,
     graph: &StableDiGraph<NodeData, EdgeData>,
+    _sensor_store: &SensorStore,
     _options: &InspectOptions,
 ) {
     progress.step_sized(
@@ -86,3 +113,118 @@ fn coloring_junctions(
         progress.tick();
     }
 }
+
+fn coloring_sensors(
+    progress: &mut Progress,
+    canvas: &mut Canvas,
+    graph: &StableDiGraph<NodeData, EdgeData>,
+    sensor_store: &SensorStore,
+    _options: &InspectOptions,
+) {
+    progress.step_sized(
+        graph.node_count(),
+        format!("Drawing {} nodes", style(graph.node_count()).bold()),
+    );
+
+    let mut sensor_nodes = 0;
+    for node in graph.node_indices() {
+        let data = graph.node_weight(node).unwrap();
+
+        let sensors = sensor_store.get(&node).map_or(0, |sensors| sensors.len());
+        if data.has_sensor {
+            sensor_nodes += 1;
+        }
+        let (color, size) = sensor_marker(data, sensors);
+        canvas.draw_triangle(data.point, color, size, data.heading);
+
+        progress.tick();
+    }
+    progress.finish(format!(
+        "Drew {} nodes with sensors",
+        style(sensor_nodes).bold()
+    ));
+}
+
+/// The color and size of the triangle of a node with **sensors** assigned to it, with the area
+/// growing with the number of sensors
+fn sensor_marker(data: &NodeData, sensors: usize) -> (&'static str, f64) {
+    if data.has_sensor {
+        ("red", 1.5 * (sensors.max(1) as f64).sqrt())
+    } else {
+        ("gray", 1.0)
+    }
+}
+
+fn coloring_sensor_directions(
+    progress: &mut Progress,
+    canvas: &mut Canvas,
+    graph: &StableDiGraph<NodeData, EdgeData>,
+    sensor_store: &SensorStore,
+    _options: &InspectOptions,
+) {
+    progress.step_sized(
+        sensor_store.len(),
+        format!(
+            "Drawing sensors on {} nodes",
+            style(sensor_store.len()).bold()
+        ),
+    );
+
+    let mut mismatched = 0;
+    let mut unknown = 0;
+    for (node, sensors) in sensor_store {
+        let Some(data) = graph.node_weight(*node) else {
+            progress.tick();
+            continue;
+        };
+
+        for sensor in sensors {
+            match sensor.measurement_side.to_bearing() {
+                Some(bearing) => {
+                    let color = if angle_diff(bearing, data.heading).abs() <= MAX_DIRECTION_MISMATCH
+                    {
+                        "green"
+                    } else {
+                        mismatched += 1;
+                        "red"
+                    };
+                    canvas.draw_arrow(data.point, bearing, color, SENSOR_ARROW_LENGTH);
+                }
+                None => {
+                    unknown += 1;
+                    canvas.draw_circle(data.point, "gray", (SENSOR_ARROW_LENGTH / 4.0) as f32);
+                }
+            }
+        }
+
+        progress.tick();
+    }
+    progress.finish(format!(
+        "Drew sensors, {} facing away from their node and {} without a known direction",
+        style(mismatched).bold(),
+        style(unknown).bold()
+    ));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_graphs::node;
+
+    #[test]
+    fn sensor_nodes_stand_out_and_grow_with_their_sensors() {
+        let sensor_node = NodeData {
+            has_sensor: true,
+            ..node(0.0, 0.0)
+        };
+
+        let (plain_color, plain_size) = sensor_marker(&node(0.0, 0.0), 0);
+        let (single_color, single_size) = sensor_marker(&sensor_node, 1);
+        let (multi_color, multi_size) = sensor_marker(&sensor_node, 4);
+
+        assert_ne!(plain_color, multi_color);
+        assert_eq!(single_color, multi_color);
+        assert!(plain_size < single_size && single_size < multi_size);
+        assert_eq!(multi_size, 2.0 * single_size);
+    }
+}
@@ -1,5 +1,7 @@
 mod coloring;
 
+use std::collections::HashMap;
+
 use clap::{Args, ValueEnum};
 use console::style;
 use petgraph::{graph::NodeIndex, stable_graph::StableDiGraph, visit::VisitMap};
@@ -7,10 +9,12 @@ use petgraph::{graph::NodeIndex, stable_graph::StableDiGraph, visit::VisitMap};
 use crate::{
     custom_bfs::CustomBfs,
     math::geo_distance,
-    output::Canvas,
+    mongo::model::SensorMetadata,
+    output::{Canvas, CanvasOptions, Framing, GradientSpec},
     parse::Point,
-    processing::{build_node_acceleration_structure, EdgeData, NodeData},
+    processing::{build_node_acceleration_structure, EdgeData, NodeData, RoutingGraph},
     progress::Progress,
+    util::GraticuleStep,
     visitor::DistanceMetric,
 };
 
@@ -44,6 +48,20 @@ pub struct InspectOptions {
     filter: FilterMode,
     #[clap(flatten)]
     line_style: LineStyle,
+    /// Draw a scale bar of this many meters
+    #[clap(long)]
+    scale_bar: Option<f64>,
+    #[clap(long, default_value = "false", default_missing_value = "true")]
+    north_arrow: bool,
+    /// Draw lines every lat_step,lon_step degrees labelled with their coordinates
+    #[clap(long)]
+    graticule: Option<GraticuleStep>,
+    #[clap(flatten)]
+    gradient: GradientSpec,
+    #[clap(flatten)]
+    framing: Framing,
+    #[clap(flatten)]
+    pub canvas: CanvasOptions,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
@@ -54,21 +72,43 @@ pub enum FilterMode {
     AirDistance,
 }
 
-pub fn inspect(mut graph: StableDiGraph<NodeData, EdgeData>, options: InspectOptions) -> Canvas {
+pub fn inspect(
+    graph: StableDiGraph<NodeData, EdgeData>,
+    sensor_store: HashMap<NodeIndex, Vec<SensorMetadata>>,
+    options: InspectOptions,
+) -> Canvas {
     let mut progress = Progress::new();
 
     // Remove nodes outside of range
-    filter_distance(&mut progress, &mut graph, &options);
-
-    let mut canvas = Canvas::from_graph(4000, &graph);
+    let mut routing = RoutingGraph::new(graph);
+    filter_distance(&mut progress, &mut routing, &options);
+    let graph = routing.into_graph();
+
+    let mut canvas = options
+        .framing
+        .canvas(4000, &graph, options.canvas.projection);
+    if let Some(step) = options.graticule {
+        canvas.draw_graticule(step.lat_step, step.lon_step);
+    }
 
     canvas.draw_cross(options.point, "red", 5.0);
 
     let color_func = options.edge_color.coloring_function();
-    color_func(&mut progress, &mut canvas, &graph, &options);
+    let legend = color_func(&mut progress, &mut canvas, &graph, &options);
 
     let color_func = options.node_color.coloring_function();
-    color_func(&mut progress, &mut canvas, &graph, &options);
+    color_func(&mut progress, &mut canvas, &graph, &sensor_store, &options);
+
+    if !legend.is_empty() {
+        canvas.draw_legend(legend);
+    }
+
+    if let Some(meters) = options.scale_bar {
+        canvas.draw_scale_bar(meters);
+    }
+    if options.north_arrow {
+        canvas.draw_north_arrow();
+    }
 
     canvas
 }
@@ -85,43 +125,44 @@ fn find_closest_node_to(graph: &StableDiGraph<NodeData, EdgeData>, point: Point)
     *center_node
 }
 
-fn filter_distance(
-    progress: &mut Progress,
-    graph: &mut StableDiGraph<NodeData, EdgeData>,
-    opts: &InspectOptions,
-) {
+fn filter_distance(progress: &mut Progress, routing: &mut RoutingGraph, opts: &InspectOptions) {
     if opts.filter == FilterMode::AirDistance && opts.metric == DistanceMetric::Time {
         panic!("Cannot filter by air distance using time metric");
     }
 
     if opts.filter == FilterMode::AirDistance {
-        progress.step_sized(graph.node_count(), "Filtering nodes by air distance");
-        let tree = build_node_acceleration_structure(&graph);
+        progress.step_sized(
+            routing.graph().node_count(),
+            "Filtering nodes by air distance",
+        );
         let p = [opts.point.latitude, opts.point.longitude];
-        let to_remove = tree
+        let to_remove = routing
+            .tree()
             .iter_nearest(&p, &geo_distance)
             .unwrap()
             .filter(|(dist, _)| {
                 progress.tick();
                 *dist > opts.range
             })
-            .map(|(_, (node, _))| node)
+            .map(|(_, (node, _))| *node)
             .collect::<Vec<_>>();
         let len = to_remove.len();
+        let graph = routing.graph_mut();
         for node in to_remove {
-            graph.remove_node(*node);
+            graph.remove_node(node);
         }
         progress.finish(format!("Removed {} nodes", style(len).bold()));
     } else {
         progress.step_sized(
-            graph.node_count(),
+            routing.graph().node_count(),
             format!(
                 "Filtering nodes at a distance of {}{}",
                 style(opts.range).bold(),
                 style(opts.metric.unit()).bold()
             ),
         );
-        let center_node = find_closest_node_to(&graph, opts.point);
+        let (_, center_node) = routing.nearest_node(opts.point);
+        let graph = routing.graph_mut();
         let mut bfs = CustomBfs::new(&graph, center_node, opts.metric.to_function());
 
         let next_func = if opts.directed {
@@ -0,0 +1,98 @@
+use clap::Args;
+use console::style;
+use geo::CoordsIter;
+
+use crate::{
+    custom_bfs::CustomBfs,
+    graph_io,
+    output::isochrone_polygons,
+    parse::Point,
+    processing::{build_node_acceleration_structure, find_closest_node},
+    progress::Progress,
+    visitor::DistanceMetric,
+};
+
+#[derive(Debug, Args)]
+pub struct IsochroneOptions {
+    #[clap(short, long, default_value = "./out/graph.json")]
+    pub graph_path: String,
+    #[clap(long, default_value = "./out/isochrone.geojson")]
+    pub output: String,
+    #[clap(flatten)]
+    pub point: Point,
+    /// Travel times in seconds to draw a polygon for, separated by commas
+    #[clap(short, long, value_delimiter = ',', required = true)]
+    pub thresholds: Vec<f64>,
+}
+
+pub fn isochrone(options: IsochroneOptions) -> anyhow::Result<()> {
+    let mut progress = Progress::new();
+
+    progress.step_unsized("Reading graph");
+    let graph = graph_io::try_load_graph(&options.graph_path)?.graph;
+    progress.finish(format!(
+        "Loaded graph with {} nodes and {} edges",
+        graph.node_count(),
+        graph.edge_count()
+    ));
+
+    let mut thresholds = options.thresholds.clone();
+    thresholds.sort_by(f64::total_cmp);
+    let max_threshold = thresholds.last().copied().unwrap_or_default();
+
+    progress.step_unsized("Finding reachable nodes");
+    let node_tree = build_node_acceleration_structure(&graph);
+    let (_, start) = find_closest_node(&node_tree, options.point);
+    let mut bfs = CustomBfs::new(&graph, start, DistanceMetric::Time.to_function());
+    let mut reachable = Vec::new();
+    while let Some((node, time, _)) = bfs.next(&graph) {
+        if time > max_threshold {
+            break;
+        }
+        reachable.push((graph[node].point, time));
+    }
+    progress.finish(format!(
+        "Found {} nodes within {}s",
+        style(reachable.len()).bold(),
+        max_threshold
+    ));
+
+    progress.step_unsized("Building polygons");
+    let polygons = isochrone_polygons(&reachable, &thresholds);
+    // Largest first, so the smaller polygons are drawn on top when rendered in order
+    let features = thresholds
+        .iter()
+        .zip(polygons.iter())
+        .rev()
+        .map(|(threshold, polygon)| {
+            let ring = polygon
+                .exterior()
+                .coords_iter()
+                .map(|coord| [coord.x, coord.y])
+                .collect::<Vec<_>>();
+            let coordinates = if ring.is_empty() { vec![] } else { vec![ring] };
+            serde_json::json!({
+                "type": "Feature",
+                "geometry": {
+                    "type": "Polygon",
+                    "coordinates": coordinates,
+                },
+                "properties": {
+                    "threshold": threshold,
+                },
+            })
+        })
+        .collect::<Vec<_>>();
+    let collection = serde_json::json!({
+        "type": "FeatureCollection",
+        "features": features,
+    });
+    std::fs::write(&options.output, serde_json::to_string(&collection)?)?;
+    progress.finish(format!(
+        "Wrote {} polygons to {}",
+        polygons.len(),
+        options.output
+    ));
+
+    Ok(())
+}
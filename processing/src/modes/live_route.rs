@@ -1,23 +1,32 @@
 use std::{
+    collections::HashMap,
     fs,
     ops::Deref,
     time::{Instant, SystemTime},
 };
 
-use clap::Args;
+use anyhow::Context;
+use clap::{Args, ValueEnum};
+use console::style;
 use mongodb::bson::DateTime;
+use petgraph::stable_graph::StableDiGraph;
 
 use crate::{
-    math::geo_distance,
+    graph_io::{self, IndexOptions},
     mongo::{
-        client::{async_client::AsyncMongoClient, MongoOptions},
+        client::{
+            async_client::{AsyncMongoClient, DataAggregation},
+            MongoOptions,
+        },
         model::VehicleType,
     },
-    processing::{build_node_acceleration_structure, ProcessedGraph},
+    processing::{EdgeData, NodeData, ProcessedGraph},
     progress::Progress,
-    travel_time::{self, DataPointFilter},
-    util::PointQuery,
-    visitor::{self, convert_ms_to_kmh},
+    travel_time::{
+        self, DataPointFilter, LiveRouteResults, SpeedInterpolation, TimeDependentMetric,
+    },
+    util::{suffixed_path, PenaltyOptions, PointQuery, SnapOptions},
+    visitor::{self, convert_ms_to_kmh, Path},
 };
 
 #[derive(Debug, Clone)]
@@ -87,6 +96,52 @@ impl Deref for ParseableDuration {
     }
 }
 
+const MILLIS_PER_DAY: i64 = 24 * 3600 * 1000;
+
+/// Period to split the output into one file per
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SplitPeriod {
+    Day,
+    /// Weeks starting on Monday, named by their first day
+    Week,
+}
+
+impl SplitPeriod {
+    /// The date the period containing **timestamp** starts on, as `YYYY-MM-DD`
+    fn period_start(self, timestamp: i64) -> String {
+        let mut day = timestamp.div_euclid(MILLIS_PER_DAY);
+        if self == SplitPeriod::Week {
+            // The epoch was a Thursday
+            day -= (day + 3).rem_euclid(7);
+        }
+        let date = DateTime::from_millis(day * MILLIS_PER_DAY)
+            .try_to_rfc3339_string()
+            .unwrap();
+        date[..10].to_string()
+    }
+}
+
+/// A speed in km/h to use for a sensor instead of its measured speed, parsed from `SITE_ID=SPEED`
+#[derive(Debug, Clone, Copy)]
+pub struct SpeedOverride {
+    pub site_id: i32,
+    pub speed: f64,
+}
+
+impl std::str::FromStr for SpeedOverride {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (site_id, speed) = s
+            .split_once('=')
+            .ok_or_else(|| format!("Expected SITE_ID=SPEED, got {}", s))?;
+        Ok(SpeedOverride {
+            site_id: site_id.trim().parse().map_err(|e| format!("{}", e))?,
+            speed: speed.trim().parse().map_err(|e| format!("{}", e))?,
+        })
+    }
+}
+
 #[derive(Debug, Args)]
 pub struct LiveRouteOptions {
     #[clap(flatten)]
@@ -109,118 +164,391 @@ pub struct LiveRouteOptions {
     pub output: String,
     #[clap(short, long, default_value = "anyVehicle")]
     pub vehicle_type: VehicleType,
+    #[clap(flatten)]
+    pub snap: SnapOptions,
+    /// How the speed changes between two sensors along the route
+    #[clap(long, default_value = "harmonic")]
+    pub interpolation: SpeedInterpolation,
+    /// How the data points of each sensor are turned into a speed
+    #[clap(long, default_value = "latest")]
+    pub aggregation: DataAggregation,
+    /// Add travel time standard deviation and 90% interval columns, using the speed variance of
+    /// each sensor over this window before every step
+    #[clap(long)]
+    pub variance_window: Option<ParseableDuration>,
+    /// Use this speed for a sensor instead of its measured speed, as SITE_ID=SPEED in km/h
+    #[clap(long)]
+    pub speed_override: Vec<SpeedOverride>,
+    /// Write one file per period instead of a single file, with the start date of the period
+    /// appended to the file name
+    #[clap(long)]
+    pub split_by: Option<SplitPeriod>,
+    /// Add a travel time column using the historical average speed of each sensor at the hour of
+    /// every step
+    #[clap(long, default_value = "false", default_missing_value = "true")]
+    pub historical: bool,
+    /// Also write the routed corridor as a GeoJSON LineString to this file
+    #[clap(long)]
+    pub route_geojson: Option<String>,
+    /// Choose the route by the sensor speeds at the start date instead of the speed limits
+    #[clap(long, default_value = "false", default_missing_value = "true")]
+    pub live_routing: bool,
+    #[clap(flatten)]
+    pub penalty: PenaltyOptions,
+    #[clap(flatten)]
+    pub index: IndexOptions,
 }
 
-pub async fn live_route(options: LiveRouteOptions) {
+pub async fn live_route(options: LiveRouteOptions) -> anyhow::Result<()> {
     let mut progress = Progress::new();
 
     progress.step_unsized("Connecting to MongoDB");
     let client = AsyncMongoClient::new(options.mongo_options.clone())
         .await
-        .expect("Failed to connect to MongoDB");
+        .context("Failed to connect to MongoDB")?;
     progress.finish("");
 
     progress.step_unsized("Reading graph");
-    let ProcessedGraph {
-        graph,
-        sensor_store,
-    } = serde_json::from_str(fs::read_to_string(&options.graph_path).unwrap().as_str()).unwrap();
+    let (routing, sensor_store) =
+        graph_io::try_load_routing_graph(&options.graph_path, options.index.reuse_index)?;
     progress.finish(format!(
         "Loaded graph with {} nodes and {} edges",
-        graph.node_count(),
-        graph.edge_count()
+        routing.graph().node_count(),
+        routing.graph().edge_count()
     ));
 
     progress.step_unsized("Reading query");
-    let query: Vec<PointQuery> =
-        serde_json::from_str(fs::read_to_string(&options.query).unwrap().as_str()).unwrap();
+    let query: Vec<PointQuery> = serde_json::from_str(
+        &fs::read_to_string(&options.query)
+            .with_context(|| format!("Failed to read query {}", options.query))?,
+    )
+    .with_context(|| format!("Failed to parse query {}", options.query))?;
     progress.finish(format!("Loaded query: {:?}", query));
 
-    progress.step_sized(query.len(), "Finding shortest path");
-    let tree = build_node_acceleration_structure(&graph);
-    let points = query
-        .iter()
-        .map(|query| {
-            let p = [query.point.latitude, query.point.longitude];
-            let mut iter = tree.iter_nearest(&p, &geo_distance).unwrap();
-            while let Some((dist, (idx, data))) = iter.next() {
-                if query.heading.contains(&data.heading) && dist <= query.radius {
-                    return *idx;
-                }
-            }
-
-            progress.tick();
-            panic!("No node found for query {:?}", query);
-        })
-        .collect::<Vec<_>>();
+    progress.step_unsized("Finding query nodes");
+    let points = routing.find_points(&query, options.snap.strategy())?;
+    let processed_graph = ProcessedGraph {
+        graph: routing.into_graph(),
+        sensor_store,
+    };
+    let graph = &processed_graph.graph;
     progress.finish("Found nodes");
 
-    progress.step_unsized("Finding shortest path");
-    let path = visitor::shortest_path(&graph, points, visitor::DistanceMetric::Time)
-        .expect("No path found");
+    let path = if options.live_routing {
+        progress.step_unsized("Fetching sensor speeds to route by");
+        let sensors = processed_graph
+            .sensor_store
+            .values()
+            .flatten()
+            .filter(|sensor| sensor.vehicle_type == options.vehicle_type);
+        let data = client
+            .get_sensor_data_at(
+                sensors,
+                *options.start_date,
+                *options.max_sensor_data_age,
+                options.aggregation,
+            )
+            .await
+            .context("Failed to get sensor data")?;
+        let speeds = travel_time::live_edge_speeds(&processed_graph, &data, options.vehicle_type);
+        progress.finish(format!(
+            "Found data for {} sensors, covering {} edges",
+            data.len(),
+            speeds.len()
+        ));
+
+        progress.step_unsized("Finding fastest path at live speeds");
+        let distance_fn = visitor::live_distance_fn(speeds, options.penalty.connector_penalty);
+        visitor::shortest_path_with(graph, points, distance_fn)
+    } else {
+        progress.step_unsized("Finding shortest path");
+        visitor::shortest_path(
+            graph,
+            points,
+            visitor::DistanceMetric::Time,
+            options.penalty.connector_penalty,
+            visitor::SearchStrategy::Dijkstra,
+        )
+    }
+    .context("No path found")?;
     let distance = path.nodes.windows(2).fold(0.0, |acc, nodes| {
         let edge = graph.edges_connecting(nodes[0], nodes[1]).next().unwrap();
         acc + edge.weight().distance
     });
     let average_speed = distance / path.length;
     progress.finish(format!(
-        "Shortest path time: {}s, distance: {}, average speed: {:.1}km/h",
+        "Shortest path time: {}s, distance: {}, average speed: {:.1}km/h, average speed limit: {:.1}km/h",
         path.length,
         distance,
-        convert_ms_to_kmh(average_speed)
+        convert_ms_to_kmh(average_speed),
+        visitor::average_speed_limit(path.edges(graph))
     ));
 
-    let processed_graph = ProcessedGraph {
-        graph,
-        sensor_store,
+    if let Some(route_geojson) = &options.route_geojson {
+        progress.step_unsized("Writing route");
+        save_route_geojson(graph, &path, route_geojson)?;
+        progress.finish(format!("Route written to {}", route_geojson));
+    }
+
+    let speed_overrides = options
+        .speed_override
+        .iter()
+        .map(|speed_override| (speed_override.site_id, speed_override.speed))
+        .collect::<HashMap<_, _>>();
+
+    let site_profiles = if options.historical {
+        progress.step_unsized("Fetching historical speed profiles");
+        let sensors = path
+            .nodes
+            .iter()
+            .filter_map(|node| processed_graph.sensor_store.get(node))
+            .flatten()
+            .filter(|sensor| sensor.vehicle_type == options.vehicle_type);
+        let site_profiles = client
+            .get_hourly_speed_profiles(sensors)
+            .await
+            .context("Failed to get speed profiles")?;
+        progress.finish(format!(
+            "Found profiles for {} sensors",
+            site_profiles.len()
+        ));
+        Some(site_profiles)
+    } else {
+        None
     };
+    let historical = site_profiles
+        .as_ref()
+        .map(|site_profiles| TimeDependentMetric::new(&processed_graph, site_profiles));
 
     progress.step_sized(options.max_steps as usize, "Simulating route");
     let mut data = Vec::new();
     for i in 0..options.max_steps {
         let current_time = *options.start_date + i * *options.step_size;
 
-        let live_travel_time = travel_time::calculate_live_travel_time(
+        let mut live_travel_time = travel_time::calculate_live_travel_time(
             &processed_graph,
             &path,
             &client,
             DataPointFilter {
                 timestamp: Some(current_time),
                 max_age: Some(*options.max_sensor_data_age),
+                variance_window: options.variance_window.as_deref().copied(),
+                aggregation: options.aggregation,
             },
             Some(options.vehicle_type),
+            options.interpolation,
+            &speed_overrides,
         )
-        .await;
+        .await
+        .unwrap_or_else(|e| {
+            progress
+                .get_pb()
+                .println(format!("{} {}", style("[!]").bold().yellow(), e));
+            LiveRouteResults::missing()
+        });
 
-        let date = DateTime::from_millis(current_time + *options.date_offset);
+        let hour = (current_time / 3_600_000).rem_euclid(24) as usize;
+        live_travel_time.historical_travel_time = historical
+            .as_ref()
+            .map(|historical| historical.route_time(&path, hour));
+
+        let timestamp = current_time + *options.date_offset;
+        let date = DateTime::from_millis(timestamp);
         let date = date.try_to_rfc3339_string().unwrap();
         let date = date.replace("T", " ").replace("Z", "");
-        data.push((date, live_travel_time));
+        data.push((timestamp, date, live_travel_time));
 
         progress.tick();
     }
     progress.finish("Simulation finished");
 
     progress.step_unsized("Writing output");
-    let mut writer = csv::Writer::from_path(&options.output).unwrap();
-    writer
-        .write_record(&[
-            "time",
-            "travelTimeSensors",
-            "totalFlow",
-            "averageFlow",
-            "sensorCount",
-        ])
-        .unwrap();
-    for (time, results) in data {
-        let _ = writer.write_record(&[
+    let files = split_results(&options.output, options.split_by, data);
+    for (path, rows) in files.iter() {
+        write_results(
+            path,
+            rows,
+            options.variance_window.is_some(),
+            options.historical,
+        )?;
+    }
+    progress.finish(format!("Output written to {} files", files.len()));
+
+    Ok(())
+}
+
+/// Group the rows of every step into the file of its period, named after **output**, in the order
+/// of the steps. Without a **split_by** period every row goes to **output** itself.
+fn split_results(
+    output: &str,
+    split_by: Option<SplitPeriod>,
+    data: Vec<(i64, String, LiveRouteResults)>,
+) -> Vec<(String, Vec<(String, LiveRouteResults)>)> {
+    let mut files: Vec<(String, Vec<(String, LiveRouteResults)>)> = Vec::new();
+    for (timestamp, time, results) in data {
+        let path = match split_by {
+            Some(period) => suffixed_path(output, &period.period_start(timestamp)),
+            None => output.to_string(),
+        };
+        match files.iter_mut().find(|(file, _)| *file == path) {
+            Some((_, rows)) => rows.push((time, results)),
+            None => files.push((path, vec![(time, results)])),
+        }
+    }
+    files
+}
+
+fn write_results(
+    path: &str,
+    rows: &[(String, LiveRouteResults)],
+    with_variance: bool,
+    with_historical: bool,
+) -> anyhow::Result<()> {
+    let mut writer =
+        csv::Writer::from_path(path).with_context(|| format!("Failed to write {}", path))?;
+    let mut header = vec![
+        "time",
+        "travelTimeSensors",
+        "totalFlow",
+        "averageFlow",
+        "sensorCount",
+    ];
+    if with_variance {
+        header.extend(["travelTimeStdDev", "travelTimeLow90", "travelTimeHigh90"]);
+    }
+    if with_historical {
+        header.push("travelTimeHistorical");
+    }
+    writer.write_record(&header)?;
+    for (time, results) in rows {
+        let mut record = vec![
             time.to_string(),
             results.travel_time.to_string(),
             results.total_flow_rate.to_string(),
             results.average_flow_rate.to_string(),
             results.sensor_count.to_string(),
-        ]);
+        ];
+        if let (Some(std_dev), Some((low, high))) =
+            (results.travel_time_std_dev, results.interval_90())
+        {
+            record.extend([std_dev.to_string(), low.to_string(), high.to_string()]);
+        }
+        if let Some(historical) = results.historical_travel_time {
+            record.push(historical.to_string());
+        }
+        writer.write_record(&record)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Write the path as a GeoJSON feature with a LineString geometry in longitude, latitude order
+fn save_route_geojson(
+    graph: &StableDiGraph<NodeData, EdgeData>,
+    path: &Path,
+    file_path: &str,
+) -> anyhow::Result<()> {
+    let distance = path.nodes.windows(2).fold(0.0, |acc, nodes| {
+        let edge = graph.edges_connecting(nodes[0], nodes[1]).next().unwrap();
+        acc + edge.weight().distance
+    });
+    let coordinates = path
+        .to_polyline(graph)
+        .iter()
+        .map(|point| [point.longitude, point.latitude])
+        .collect::<Vec<_>>();
+
+    let feature = serde_json::json!({
+        "type": "Feature",
+        "geometry": {
+            "type": "LineString",
+            "coordinates": coordinates,
+        },
+        "properties": {
+            "travelTime": path.length,
+            "distance": distance,
+            "complete": path.complete,
+        },
+    });
+    fs::write(file_path, serde_json::to_string_pretty(&feature)?)
+        .with_context(|| format!("Failed to write {}", file_path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_graphs::{diamond, temp_path};
+
+    #[test]
+    fn route_geojson_follows_the_path_nodes() {
+        let (graph, [a, b, _, d]) = diamond();
+        let path = Path {
+            nodes: vec![a, b, d],
+            length: 42.0,
+            complete: true,
+            missed: vec![],
+        };
+        let file_path = temp_path("route.geojson");
+
+        save_route_geojson(&graph, &path, &file_path).unwrap();
+        let raw = fs::read_to_string(&file_path).unwrap();
+        let feature: serde_json::Value = serde_json::from_str(&raw).unwrap();
+
+        assert_eq!(feature["geometry"]["type"], "LineString");
+        let expected = path
+            .nodes
+            .iter()
+            .map(|node| [graph[*node].point.longitude, graph[*node].point.latitude])
+            .collect::<Vec<_>>();
+        assert_eq!(
+            feature["geometry"]["coordinates"],
+            serde_json::json!(expected)
+        );
+        assert_eq!(feature["properties"]["travelTime"], 42.0);
+        assert_eq!(feature["properties"]["complete"], true);
+    }
+
+    #[test]
+    fn steps_over_two_days_are_split_into_a_file_per_day() {
+        let output = temp_path("live_route.csv");
+        // Every 6 hours from midnight on 2024-01-01, with the step as its travel time
+        let start = 1_704_067_200_000;
+        let data = (0..8)
+            .map(|step| {
+                let timestamp = start + step * 6 * 3_600_000;
+                let results = LiveRouteResults {
+                    travel_time: step as f64,
+                    ..LiveRouteResults::missing()
+                };
+                (timestamp, format!("step {}", step), results)
+            })
+            .collect();
+
+        let files = split_results(&output, Some(SplitPeriod::Day), data);
+        let mut written = Vec::new();
+        for (path, rows) in files.iter() {
+            write_results(path, rows, false, false).unwrap();
+            let raw = fs::read_to_string(path).unwrap();
+            let travel_times = raw
+                .lines()
+                .skip(1)
+                .map(|line| line.split(',').nth(1).unwrap().to_string())
+                .collect::<Vec<_>>();
+            written.push((path.clone(), travel_times));
+        }
+
+        assert_eq!(
+            written,
+            vec![
+                (
+                    suffixed_path(&output, "2024-01-01"),
+                    vec!["0".into(), "1".into(), "2".into(), "3".into()]
+                ),
+                (
+                    suffixed_path(&output, "2024-01-02"),
+                    vec!["4".into(), "5".into(), "6".into(), "7".into()]
+                ),
+            ]
+        );
     }
-    writer.flush().unwrap();
-    progress.finish("Output written");
 }
@@ -1,30 +1,63 @@
 mod aggregate;
+mod build_profiles;
+mod compare_routes;
+mod distance_matrix;
+mod doctor;
+mod draw_coverage;
 mod draw_disjoint;
 mod draw_distance;
+mod draw_heatmap;
 mod draw_reachable;
 mod draw_road;
+mod export_assignments;
+mod find_gaps;
 mod inspect;
+mod isochrone;
 mod live_route;
+mod sensor_cover;
 mod shortest_path;
-//mod simulate;
-mod find_gaps;
+mod simulate;
+mod skeletonize;
+mod speed_grid;
 mod test_period_division;
 
 pub use aggregate::aggregate;
 pub use aggregate::AggregateOptions;
+pub use build_profiles::build_profiles;
+pub use build_profiles::BuildProfilesOptions;
+pub use compare_routes::compare_routes;
+pub use distance_matrix::distance_matrix;
+pub use distance_matrix::DistanceMatrixOptions;
+pub use doctor::doctor;
+pub use doctor::DoctorOptions;
+pub use draw_coverage::draw_coverage;
 pub use draw_disjoint::draw_disjoint;
 pub use draw_distance::draw_distance;
+pub use draw_distance::DrawDistanceOptions;
+pub use draw_heatmap::draw_heatmap;
+pub use draw_heatmap::DrawHeatmapOptions;
 pub use draw_reachable::draw_reachable;
 pub use draw_road::draw_roads;
+pub use export_assignments::export_assignments;
+pub use export_assignments::ExportAssignmentsOptions;
+pub use find_gaps::find_gaps;
+pub use find_gaps::FindGapsOptions;
 pub use inspect::inspect;
 pub use inspect::InspectOptions;
+pub use isochrone::isochrone;
+pub use isochrone::IsochroneOptions;
 pub use live_route::live_route;
 pub use live_route::LiveRouteOptions;
+pub use sensor_cover::sensor_cover;
+pub use sensor_cover::SensorCoverOptions;
 pub use shortest_path::shortest_path;
-//pub use simulate::simulate;
-//pub use simulate::SimulationOptions;
-//pub use simulate::SimulationSetup;
-pub use find_gaps::find_gaps;
-pub use find_gaps::FindGapsOptions;
+pub use shortest_path::shortest_paths;
+pub use shortest_path::ShortestPathOptions;
+pub use simulate::simulate;
+pub use simulate::SimulationOptions;
+pub use simulate::SimulationSetup;
+pub use skeletonize::skeletonize;
+pub use speed_grid::speed_grid;
+pub use speed_grid::SpeedGridOptions;
 pub use test_period_division::test_period_division;
 pub use test_period_division::TestPeriodDivisionOptions;
@@ -0,0 +1,185 @@
+use std::collections::{BTreeSet, HashMap};
+
+use anyhow::Context;
+use clap::Args;
+use console::style;
+use petgraph::stable_graph::NodeIndex;
+
+use crate::{
+    graph_io::{self, IndexOptions},
+    mongo::model::SensorMetadata,
+    processing::RoutingGraph,
+    progress::Progress,
+    util::{PointQuery, SnapOptions, SnapStrategy},
+    visitor::{self, DistanceMetric, Path, SearchStrategy},
+};
+
+#[derive(Debug, Args)]
+pub struct SensorCoverOptions {
+    #[clap(short, long, alias = "graph", default_value = "./out/graph.json")]
+    pub graph_path: String,
+    /// Query files of the routes that should each pass at least one chosen sensor
+    #[clap(short, long, num_args = 1.., required = true)]
+    pub queries: Vec<String>,
+    #[clap(short, long, default_value = "time")]
+    pub metric: DistanceMetric,
+    #[clap(flatten)]
+    pub snap: SnapOptions,
+    #[clap(flatten)]
+    pub index: IndexOptions,
+}
+
+pub fn sensor_cover(options: SensorCoverOptions) -> anyhow::Result<()> {
+    let mut progress = Progress::new();
+
+    progress.step_unsized("Reading graph");
+    let (routing, sensor_store) =
+        graph_io::try_load_routing_graph(&options.graph_path, options.index.reuse_index)?;
+    progress.finish(format!(
+        "Loaded graph with {} nodes and {} sensor nodes",
+        routing.graph().node_count(),
+        sensor_store.len()
+    ));
+
+    progress.step_sized(options.queries.len(), "Resolving routes");
+    let mut routes = Vec::new();
+    for path in options.queries.iter() {
+        let route = resolve_route(&routing, path, options.metric, options.snap.strategy())?;
+        routes.push(route_sensors(&route.nodes, &sensor_store));
+        progress.tick();
+    }
+    progress.finish(format!("Resolved {} routes", routes.len()));
+
+    let uncoverable = routes.iter().filter(|sensors| sensors.is_empty()).count();
+    let chosen = greedy_set_cover(&routes);
+
+    println!(
+        "Chose {} sensors covering {} of {} routes",
+        style(chosen.len()).bold(),
+        style(routes.len() - uncoverable).bold(),
+        routes.len()
+    );
+    for (site_id, covered) in chosen.iter() {
+        println!("  site {} covers {} new routes", site_id, covered);
+    }
+    for (path, sensors) in options.queries.iter().zip(routes.iter()) {
+        if sensors.is_empty() {
+            println!(
+                "{} Route {} passes no sensors",
+                style("[!]").bold().yellow(),
+                path
+            );
+        }
+    }
+
+    let passed = routes.iter().flatten().collect::<BTreeSet<_>>();
+    println!(
+        "{} of the {} sensors along the routes are redundant",
+        style(passed.len() - chosen.len()).bold(),
+        passed.len()
+    );
+
+    Ok(())
+}
+
+/// Read the query file at **path** and find the route through its points
+pub fn resolve_route(
+    routing: &RoutingGraph,
+    path: &str,
+    metric: DistanceMetric,
+    snap: SnapStrategy,
+) -> anyhow::Result<Path> {
+    let raw =
+        std::fs::read_to_string(path).with_context(|| format!("Failed to read query {}", path))?;
+    let query: Vec<PointQuery> =
+        serde_json::from_str(&raw).with_context(|| format!("Failed to parse query {}", path))?;
+    let points = routing.find_points(&query, snap)?;
+    visitor::shortest_path(
+        routing.graph(),
+        points,
+        metric,
+        1.0,
+        SearchStrategy::Dijkstra,
+    )
+    .with_context(|| format!("No route found for query {}", path))
+}
+
+/// The part of a route between its first and last sensor node, which live data can observe
+pub fn covered_span<'a>(
+    nodes: &'a [NodeIndex],
+    sensor_store: &HashMap<NodeIndex, Vec<SensorMetadata>>,
+) -> &'a [NodeIndex] {
+    let first = nodes
+        .iter()
+        .position(|node| sensor_store.contains_key(node));
+    let last = nodes
+        .iter()
+        .rposition(|node| sensor_store.contains_key(node));
+    match (first, last) {
+        (Some(first), Some(last)) => &nodes[first..=last],
+        _ => &[],
+    }
+}
+
+/// The site ids of all sensors on the nodes of a route
+pub fn route_sensors(
+    nodes: &[NodeIndex],
+    sensor_store: &HashMap<NodeIndex, Vec<SensorMetadata>>,
+) -> BTreeSet<i32> {
+    nodes
+        .iter()
+        .filter_map(|node| sensor_store.get(node))
+        .flatten()
+        .map(|sensor| sensor.site_id)
+        .collect()
+}
+
+/// Greedily pick the sensor passed by the most routes not yet covered until every route passing
+/// any sensor is covered, returning each pick with the number of routes it newly covered
+fn greedy_set_cover(routes: &[BTreeSet<i32>]) -> Vec<(i32, usize)> {
+    let mut uncovered = routes
+        .iter()
+        .filter(|sensors| !sensors.is_empty())
+        .collect::<Vec<_>>();
+    let mut chosen = Vec::new();
+
+    while !uncovered.is_empty() {
+        let mut counts: HashMap<i32, usize> = HashMap::new();
+        for site_id in uncovered.iter().copied().flatten() {
+            *counts.entry(*site_id).or_default() += 1;
+        }
+        // Ties go to the lowest site id so the result is stable between runs
+        let (site_id, covered) = counts
+            .into_iter()
+            .max_by(|(a_id, a), (b_id, b)| a.cmp(b).then(b_id.cmp(a_id)))
+            .unwrap();
+        uncovered.retain(|sensors| !sensors.contains(&site_id));
+        chosen.push((site_id, covered));
+    }
+
+    chosen
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn most_shared_sensor_is_picked_first_and_every_route_is_covered() {
+        let routes = [
+            BTreeSet::from([1, 2]),
+            BTreeSet::from([2, 3]),
+            BTreeSet::from([2]),
+            BTreeSet::from([4]),
+            BTreeSet::from([3, 4]),
+            BTreeSet::new(),
+        ];
+
+        let chosen = greedy_set_cover(&routes);
+
+        assert_eq!(chosen, vec![(2, 3), (4, 2)]);
+        for sensors in routes.iter().filter(|sensors| !sensors.is_empty()) {
+            assert!(chosen.iter().any(|(site_id, _)| sensors.contains(site_id)));
+        }
+    }
+}
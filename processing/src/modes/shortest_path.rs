@@ -1,44 +1,112 @@
-use petgraph::{stable_graph::StableDiGraph, visit::IntoNodeReferences};
+use clap::Args;
+use console::style;
+use petgraph::{
+    graph::{EdgeIndex, NodeIndex},
+    stable_graph::StableDiGraph,
+    visit::{EdgeRef, IntoNodeReferences},
+};
 use rayon::iter::{ParallelBridge, ParallelIterator};
 
 use crate::{
     custom_bfs::Positionable,
     math::geo_distance,
-    output::{Canvas, DrawOptions},
-    processing::{build_node_acceleration_structure, EdgeData, NodeData, ProcessedGraph},
+    modes::draw_disjoint::COLORS,
+    output::{Canvas, CanvasOptions, DrawOptions},
+    processing::{EdgeData, NodeData, ProcessedGraph, RoutingGraph},
+    progress::Progress,
+    util::{PenaltyOptions, SnapOptions},
     visitor::{self, convert_kmh_to_ms},
     PointQuery,
 };
 
+#[derive(Debug, Args)]
+pub struct ShortestPathOptions {
+    #[clap(short, long, default_value = "nan")]
+    cull_to_path_distance: f64,
+    #[clap(short, long, default_value = "space")]
+    metric: visitor::DistanceMetric,
+    #[clap(flatten)]
+    snap: SnapOptions,
+    #[clap(long, default_value = "dijkstra")]
+    search: visitor::SearchStrategy,
+    /// Write the route legs per numbered road to this file, as JSON if it ends in `.json` and CSV otherwise
+    #[clap(long)]
+    legs: Option<String>,
+    /// Also draw this many alternative routes between the first and last waypoint
+    #[clap(long)]
+    alternatives: Option<usize>,
+    /// Visit the waypoints in the order with the shortest total length, starting at the first one
+    #[clap(long, default_value = "false", default_missing_value = "true")]
+    optimize_order: bool,
+    /// Write the travel time of every edge along the route to this CSV file
+    #[clap(long)]
+    breakdown: Option<String>,
+    #[clap(flatten)]
+    penalty: PenaltyOptions,
+    #[clap(flatten)]
+    pub canvas: CanvasOptions,
+}
+
 pub fn shortest_path(
     progessed_graph: ProcessedGraph,
     desired_path: Vec<PointQuery>,
-    cull_to_path_distance: f64,
-    distance_metric: visitor::DistanceMetric,
-) -> Canvas {
+    options: ShortestPathOptions,
+) -> anyhow::Result<Canvas> {
+    let ShortestPathOptions {
+        cull_to_path_distance,
+        metric: distance_metric,
+        snap,
+        search,
+        legs: legs_output,
+        alternatives,
+        optimize_order,
+        breakdown: breakdown_output,
+        penalty: PenaltyOptions { connector_penalty },
+        canvas: canvas_options,
+    } = options;
+
     let ProcessedGraph {
-        mut graph,
+        graph,
         sensor_store,
     } = progessed_graph;
 
-    let tree = build_node_acceleration_structure(&graph);
-    let points = desired_path
-        .iter()
-        .map(|query| {
-            let p = [query.point.latitude, query.point.longitude];
-            let mut iter = tree.iter_nearest(&p, &geo_distance).unwrap();
-            while let Some((dist, (idx, data))) = iter.next() {
-                if query.heading.contains(&data.heading) && dist <= query.radius {
-                    return *idx;
-                }
-            }
+    let routing = RoutingGraph::new(graph);
+    let mut points = routing.find_points(&desired_path, snap.strategy())?;
+    let mut graph = routing.into_graph();
+
+    if optimize_order {
+        points = visitor::optimize_visit_order(&graph, &points, distance_metric);
+        println!("Optimized waypoint order: {:?}", points);
+    }
 
-            panic!("No node found for query {:?}", query);
+    let alternatives = alternatives
+        .map(|k| {
+            println!("Finding {} alternative paths", k);
+            let start = *points.first().unwrap();
+            let end = *points.last().unwrap();
+            let paths = visitor::k_shortest_paths(
+                &graph,
+                start,
+                end,
+                distance_metric,
+                connector_penalty,
+                k,
+            );
+            for (idx, path) in paths.iter().enumerate() {
+                println!(
+                    "Alternative {}: {}{}",
+                    idx + 1,
+                    path.length,
+                    distance_metric.unit()
+                );
+            }
+            paths
         })
-        .collect::<Vec<_>>();
+        .unwrap_or_default();
 
     println!("Finding shortest path");
-    let path = visitor::shortest_path(&graph, points, distance_metric).expect("No path found");
+    let path = visitor::shortest_path(&graph, points, distance_metric, connector_penalty, search)
+        .ok_or_else(|| anyhow::anyhow!("No path found"))?;
     let distance = match distance_metric {
         visitor::DistanceMetric::Space => {
             println!("Shortest path distance: {}m", path.length);
@@ -60,26 +128,18 @@ pub fn shortest_path(
     };
 
     println!("Shortest path length: {}", path.length);
+    println!(
+        "Average speed limit: {:.1}km/h",
+        visitor::average_speed_limit(path.edges(&graph))
+    );
+
+    if let Some(legs_output) = legs_output {
+        let legs = visitor::route_legs(&graph, &path);
+        save_legs(&legs, &legs_output);
+        println!("Wrote {} legs to {}", legs.len(), legs_output);
+    }
 
-    let start = path.nodes[0];
-    let start = graph.node_weight(start).unwrap().point;
-    let points = vec![start]
-        .into_iter()
-        .chain(
-            path.nodes
-                .windows(2)
-                .flat_map(|pair| {
-                    let from = pair[0];
-                    let to = pair[1];
-
-                    let edge = graph.edges_connecting(from, to).next().unwrap();
-
-                    edge.weight().polyline.iter().skip(1).cloned()
-                })
-                .collect::<Vec<_>>()
-                .into_iter(),
-        )
-        .collect::<Vec<_>>();
+    let points = path.to_polyline(&graph);
 
     if !cull_to_path_distance.is_nan() {
         let mut path_tree = kdtree::KdTree::new(2);
@@ -94,26 +154,33 @@ pub fn shortest_path(
                 .add([data.point.latitude, data.point.longitude], ())
                 .unwrap();
         }
+        for node in alternatives.iter().flat_map(|p| p.nodes.iter()) {
+            let data = graph.node_weight(*node).unwrap();
+            path_tree
+                .add([data.point.latitude, data.point.longitude], ())
+                .unwrap();
+        }
 
-        let par_iter = graph.node_indices().par_bridge();
-        let to_remove = par_iter
-            .filter(|node| {
-                let data = graph.node_weight(*node).unwrap();
-                let point = data.point;
-                let (dist, _) = path_tree
-                    .nearest(&[point.latitude, point.longitude], 1, &geo_distance)
-                    .unwrap()[0];
-
-                dist > cull_to_path_distance
-            })
-            .collect::<Vec<_>>();
-
+        let mut progress = Progress::new();
+        progress.step_sized(
+            graph.node_count(),
+            format!(
+                "Culling nodes farther than {}m from the path",
+                style(cull_to_path_distance).bold()
+            ),
+        );
+        let pb = progress.get_pb();
+        let to_remove =
+            nodes_far_from_path(&graph, &path_tree, cull_to_path_distance, || pb.inc(1));
+
+        let len = to_remove.len();
         for node in to_remove {
             graph.remove_node(node);
         }
+        progress.finish(format!("Removed {} nodes", style(len).bold()));
     }
 
-    let mut canvas = Canvas::from_graph(4000, &graph);
+    let mut canvas = canvas_options.canvas(4000, &graph);
 
     let grad = colorgrad::CustomGradient::new()
         .html_colors(&["gold", "hotpink", "darkturquoise"])
@@ -136,6 +203,16 @@ pub fn shortest_path(
         )
     }
 
+    // Draw the longest alternative first so shorter ones end up on top
+    for (idx, alternative) in alternatives.iter().enumerate().rev() {
+        draw_route(
+            &mut canvas,
+            &graph,
+            &alternative.nodes,
+            COLORS[idx % COLORS.len()],
+        );
+    }
+
     let mut line_distance = 0.0;
     for pair in path.nodes.windows(2) {
         let edge = graph.edges_connecting(pair[0], pair[1]).next().unwrap();
@@ -227,27 +304,393 @@ pub fn shortest_path(
 
     println!("Travel time: {}s", travel_time);
 
-    canvas
+    if let Some(breakdown_output) = breakdown_output {
+        let breakdown = travel_time_breakdown(&graph, &path);
+        save_breakdown(&breakdown, &breakdown_output);
+        println!(
+            "Wrote travel times of {} edges to {}",
+            breakdown.len(),
+            breakdown_output
+        );
+    }
+
+    Ok(canvas)
 }
 
-fn calculate_travel_time(graph: &StableDiGraph<NodeData, EdgeData>, path: &visitor::Path) -> f64 {
-    let mut travel_time = 0.0;
-    let mut previous_speed_limit = convert_kmh_to_ms(50.0);
+/// Route the waypoints of every query file independently, and draw each route in its own color
+/// with a legend naming the query file
+pub fn shortest_paths(
+    processed_graph: ProcessedGraph,
+    queries: Vec<(String, Vec<PointQuery>)>,
+    options: ShortestPathOptions,
+) -> anyhow::Result<Canvas> {
+    if !options.cull_to_path_distance.is_nan()
+        || options.legs.is_some()
+        || options.alternatives.is_some()
+        || options.breakdown.is_some()
+    {
+        anyhow::bail!(
+            "Culling, legs, alternatives and breakdowns need a single query file, got {}",
+            queries.len()
+        );
+    }
+
+    let routing = RoutingGraph::new(processed_graph.graph);
+    let routes = route_queries(&routing, &queries, &options)?;
+    let graph = routing.into_graph();
+
+    let mut canvas = options.canvas.canvas(4000, &graph);
+    for edge in graph.edge_weights() {
+        canvas.draw_polyline(
+            edge.polyline.clone(),
+            DrawOptions {
+                color: "gray".into(),
+                stroke: 1.0,
+                ..Default::default()
+            },
+        )
+    }
+
+    for (idx, path) in routes.iter().enumerate() {
+        draw_route(&mut canvas, &graph, &path.nodes, COLORS[idx % COLORS.len()]);
+    }
+    for (_, desired_path) in queries.iter() {
+        for query in desired_path {
+            canvas.draw_circle(query.point, "magenta", 10.0);
+        }
+    }
+
+    let legend = queries
+        .iter()
+        .enumerate()
+        .map(|(idx, (file, _))| (file.clone(), COLORS[idx % COLORS.len()].to_string()))
+        .collect();
+    canvas.draw_legend(legend);
+
+    Ok(canvas)
+}
+
+/// The route through the waypoints of every query file, in the order of the query files, printing
+/// the length of each
+fn route_queries(
+    routing: &RoutingGraph,
+    queries: &[(String, Vec<PointQuery>)],
+    options: &ShortestPathOptions,
+) -> anyhow::Result<Vec<visitor::Path>> {
+    let mut routes = Vec::new();
+    for (file, desired_path) in queries.iter() {
+        let mut points = routing
+            .find_points(desired_path, options.snap.strategy())
+            .map_err(|e| anyhow::anyhow!("{}: {}", file, e))?;
+        if options.optimize_order {
+            points = visitor::optimize_visit_order(routing.graph(), &points, options.metric);
+        }
+        let path = visitor::shortest_path(
+            routing.graph(),
+            points,
+            options.metric,
+            options.penalty.connector_penalty,
+            options.search,
+        )
+        .ok_or_else(|| anyhow::anyhow!("No path found for {}", file))?;
+        println!(
+            "{}: {}{}",
+            file,
+            style(format!("{:.1}", path.length)).bold(),
+            options.metric.unit()
+        );
+        routes.push(path);
+    }
+    Ok(routes)
+}
+
+fn draw_route(
+    canvas: &mut Canvas,
+    graph: &StableDiGraph<NodeData, EdgeData>,
+    nodes: &[NodeIndex],
+    color: &str,
+) {
+    let opts = DrawOptions {
+        color: color.into(),
+        stroke: 3.0,
+        ..Default::default()
+    };
+    for pair in nodes.windows(2) {
+        let edge = graph.edges_connecting(pair[0], pair[1]).next().unwrap();
+        let data = edge.weight();
+        if data.is_connector {
+            let start = graph.node_weight(pair[0]).unwrap();
+            let end = graph.node_weight(pair[1]).unwrap();
+            canvas.draw_line(start.point, end.point, opts.clone());
+        } else {
+            canvas.draw_polyline(data.polyline.clone(), opts.clone());
+        }
+    }
+}
+
+/// The travel time over one edge of a route
+pub struct EdgeTime {
+    pub edge: EdgeIndex,
+    pub main_number: i32,
+    pub sub_number: i32,
+    pub distance: f64,
+    /// In km/h
+    pub speed_limit: f64,
+    /// The edge has no speed limit of its own, so the one of the edge before it is used
+    pub inherited_speed: bool,
+    pub time: f64,
+    pub cumulative_time: f64,
+}
+
+/// The travel time of every edge along **path** at the speed limit
+pub fn travel_time_breakdown(
+    graph: &StableDiGraph<NodeData, EdgeData>,
+    path: &visitor::Path,
+) -> Vec<EdgeTime> {
+    let mut breakdown = Vec::new();
+    let mut cumulative_time = 0.0;
+    let mut previous_speed_limit = visitor::DEFAULT_SPEED_LIMIT;
 
     for nodes in path.nodes.windows(2) {
         let edge = graph.edges_connecting(nodes[0], nodes[1]).next().unwrap();
         let data = edge.weight();
-        let speed_limit = if let Some(speed_limit) = data.speed_limit {
-            convert_kmh_to_ms(speed_limit)
-        } else {
-            previous_speed_limit
-        };
+        let speed_limit = data.speed_limit.unwrap_or(previous_speed_limit);
         let distance = data.distance;
 
-        let time = distance / speed_limit;
-        travel_time += time;
+        let time = distance / convert_kmh_to_ms(speed_limit);
+        cumulative_time += time;
         previous_speed_limit = speed_limit;
+
+        breakdown.push(EdgeTime {
+            edge: edge.id(),
+            main_number: data.main_number,
+            sub_number: data.sub_number,
+            distance,
+            speed_limit,
+            inherited_speed: data.speed_limit.is_none(),
+            time,
+            cumulative_time,
+        });
+    }
+
+    breakdown
+}
+
+pub fn calculate_travel_time(
+    graph: &StableDiGraph<NodeData, EdgeData>,
+    path: &visitor::Path,
+) -> f64 {
+    travel_time_breakdown(graph, path)
+        .last()
+        .map(|edge| edge.cumulative_time)
+        .unwrap_or(0.0)
+}
+
+fn save_breakdown(breakdown: &[EdgeTime], file_path: &str) {
+    let mut writer = csv::Writer::from_path(file_path).expect("Failed to open file");
+    writer
+        .write_record([
+            "edge_index",
+            "main_number",
+            "sub_number",
+            "distance",
+            "speed_limit",
+            "inherited_speed",
+            "segment_time",
+            "cumulative_time",
+        ])
+        .expect("Failed to write header");
+    for edge in breakdown {
+        writer
+            .write_record(&[
+                edge.edge.index().to_string(),
+                edge.main_number.to_string(),
+                edge.sub_number.to_string(),
+                edge.distance.to_string(),
+                edge.speed_limit.to_string(),
+                edge.inherited_speed.to_string(),
+                edge.time.to_string(),
+                edge.cumulative_time.to_string(),
+            ])
+            .expect("Failed to write record");
+    }
+    writer.flush().expect("Failed to flush writer");
+}
+
+/// Save route legs as JSON if the path ends in `.json`, otherwise as CSV
+fn save_legs(legs: &Vec<visitor::Leg>, file_path: &str) {
+    if file_path.ends_with(".json") {
+        std::fs::write(file_path, serde_json::to_string_pretty(legs).unwrap()).unwrap();
+        return;
     }
 
-    travel_time
+    let mut writer = csv::Writer::from_path(file_path).expect("Failed to open file");
+    writer
+        .write_record([
+            "main_number",
+            "sub_number",
+            "start_distance",
+            "end_distance",
+            "length",
+            "speed_limit",
+        ])
+        .expect("Failed to write header");
+    for leg in legs {
+        writer
+            .write_record(&[
+                leg.main_number.to_string(),
+                leg.sub_number.to_string(),
+                leg.start_distance.to_string(),
+                leg.end_distance.to_string(),
+                leg.length.to_string(),
+                leg.speed_limit.map(|s| s.to_string()).unwrap_or_default(),
+            ])
+            .expect("Failed to write record");
+    }
+    writer.flush().expect("Failed to flush writer");
+}
+
+/// The nodes farther than **max_distance** meters from every point in **path_tree**, calling
+/// **tick** once per node checked
+fn nodes_far_from_path(
+    graph: &StableDiGraph<NodeData, EdgeData>,
+    path_tree: &kdtree::KdTree<f64, (), [f64; 2]>,
+    max_distance: f64,
+    tick: impl Fn() + Sync,
+) -> Vec<NodeIndex> {
+    graph
+        .node_indices()
+        .par_bridge()
+        .filter(|node| {
+            tick();
+            let point = graph[*node].point;
+            let (dist, _) = path_tree
+                .nearest(&[point.latitude, point.longitude], 1, &geo_distance)
+                .unwrap()[0];
+
+            dist > max_distance
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_graphs::{add_road, grid, node, temp_path, TestGraph};
+
+    #[derive(clap::Parser)]
+    struct ShortestPathArgs {
+        #[clap(flatten)]
+        options: ShortestPathOptions,
+    }
+
+    #[test]
+    fn breakdown_adds_up_to_the_travel_time() {
+        let mut graph = TestGraph::default();
+        let nodes = (0..4)
+            .map(|idx| graph.add_node(node(0.0, idx as f64 * 0.001)))
+            .collect::<Vec<_>>();
+        add_road(&mut graph, nodes[0], nodes[1], 1, 50.0);
+        let connector = add_road(&mut graph, nodes[1], nodes[2], 1, 50.0);
+        graph[connector].is_connector = true;
+        graph[connector].speed_limit = None;
+        add_road(&mut graph, nodes[2], nodes[3], 2, 90.0);
+        let path = visitor::Path {
+            nodes,
+            length: 0.0,
+            complete: true,
+            missed: vec![],
+        };
+        let file_path = temp_path("breakdown.csv");
+
+        let breakdown = travel_time_breakdown(&graph, &path);
+        save_breakdown(&breakdown, &file_path);
+        let mut reader = csv::Reader::from_path(&file_path).unwrap();
+        let rows = reader
+            .records()
+            .map(|record| record.unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(rows.len(), 3);
+        // The connector is traveled at the speed limit of the edge before it
+        assert_eq!((&rows[1][4], &rows[1][5]), ("50", "true"));
+        let cumulative_time = rows[2][7].parse::<f64>().unwrap();
+        assert!((cumulative_time - calculate_travel_time(&graph, &path)).abs() < 1e-9);
+        let segment_times = rows
+            .iter()
+            .map(|row| row[6].parse::<f64>().unwrap())
+            .sum::<f64>();
+        assert!((segment_times - cumulative_time).abs() < 1e-9);
+    }
+
+    #[test]
+    fn culled_nodes_do_not_depend_on_progress_reporting() {
+        let (graph, nodes) = grid(6, 6);
+        // A path along the southern row
+        let mut path_tree = kdtree::KdTree::new(2);
+        for node in nodes[0].iter() {
+            let point = graph[*node].point;
+            path_tree
+                .add([point.latitude, point.longitude], ())
+                .unwrap();
+        }
+        // Keeps the two southern rows, roughly 110m apart
+        let max_distance = 150.0;
+
+        let mut quiet = nodes_far_from_path(&graph, &path_tree, max_distance, || {});
+        let pb = indicatif::ProgressBar::hidden();
+        let mut reported = nodes_far_from_path(&graph, &path_tree, max_distance, || pb.inc(1));
+        quiet.sort();
+        reported.sort();
+
+        assert_eq!(pb.position(), graph.node_count() as u64);
+        assert_eq!(quiet, reported);
+        let mut expected = nodes[2..].concat();
+        expected.sort();
+        assert_eq!(quiet, expected);
+    }
+
+    #[test]
+    fn each_query_file_is_routed_and_labeled() {
+        let (graph, nodes) = grid(4, 4);
+        let query = |node: NodeIndex| PointQuery {
+            point: graph[node].point,
+            radius: 20.0,
+            heading: -180.0..180.0,
+            snap: None,
+        };
+        // One route east along the southern row and one north along the western column
+        let queries = vec![
+            (
+                "east.json".to_string(),
+                vec![query(nodes[0][0]), query(nodes[0][3])],
+            ),
+            (
+                "north.json".to_string(),
+                vec![query(nodes[0][0]), query(nodes[3][0])],
+            ),
+        ];
+        let options = || <ShortestPathArgs as clap::Parser>::parse_from(["shortest-path"]).options;
+
+        let routing = RoutingGraph::new(graph.clone());
+        let routes = route_queries(&routing, &queries, &options()).unwrap();
+        assert_eq!(routes.len(), 2);
+        assert_eq!(routes[0].nodes, nodes[0]);
+        assert_eq!(
+            routes[1].nodes,
+            nodes.iter().map(|row| row[0]).collect::<Vec<_>>()
+        );
+
+        let processed_graph = ProcessedGraph {
+            graph,
+            sensor_store: Default::default(),
+        };
+        let canvas = shortest_paths(processed_graph, queries, options()).unwrap();
+        let path = temp_path("routes.svg");
+        canvas.save(&path);
+        let svg = std::fs::read_to_string(&path).unwrap();
+        assert!(svg.contains("east.json"));
+        assert!(svg.contains("north.json"));
+        assert!(svg.contains(COLORS[0]) && svg.contains(COLORS[1]));
+    }
 }
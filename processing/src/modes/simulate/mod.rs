@@ -1,5 +1,9 @@
-use std::ops::RangeInclusive;
+use std::{
+    collections::{HashMap, HashSet},
+    ops::RangeInclusive,
+};
 
+use anyhow::Context;
 use clap::Args;
 use console::style;
 use petgraph::{
@@ -11,14 +15,15 @@ use serde::{Deserialize, Serialize};
 
 use crate::{
     math::geo_distance,
-    output::{Canvas, DrawOptions},
-    parse::SensorData,
-    processing::{build_node_acceleration_structure, EdgeData, NodeData},
-    progress::Progress,
-    util::{find_point, PointQuery},
-    visitor::{
-        self, calculate_travel_distance, calculate_travel_time_sensors, DistanceMetric, TravelTime,
+    mongo::model::SensorMetadata,
+    output::{CanvasOptions, DrawOptions},
+    processing::{
+        build_node_acceleration_structure, EdgeData, NodeData, ProcessedGraph, RoutingGraph,
     },
+    progress::Progress,
+    travel_time::{route_travel_time, SpeedInterpolation, TravelTimeCalculator},
+    util::{PointQuery, SnapStrategy},
+    visitor::{self, DistanceMetric, SearchStrategy, TravelTime},
 };
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -70,18 +75,11 @@ pub enum SensorModification {
 }
 
 impl SensorModification {
-    pub fn apply(&self, sensor: SensorData) -> SensorData {
+    /// The speed in km/h a sensor measuring **speed** reports after the modification
+    pub fn apply(&self, speed: f64) -> f64 {
         match self {
-            SensorModification::Deviation { modifier } => {
-                let mut sensor = sensor.clone();
-                sensor.average_speed *= modifier;
-                sensor
-            }
-            SensorModification::SetSpeed { speed } => {
-                let mut sensor = sensor.clone();
-                sensor.average_speed = *speed;
-                sensor
-            }
+            SensorModification::Deviation { modifier } => speed * modifier,
+            SensorModification::SetSpeed { speed } => *speed,
         }
     }
 }
@@ -98,7 +96,6 @@ pub struct SimulationSetup {
     pub metric: DistanceMetric,
     pub sensors: Vec<SensorSetup>,
     pub sensor_mode: SensorMode,
-    pub fake_data_speed: f64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -112,6 +109,11 @@ pub struct SimulationOptions {
     pub ignore_missing_sensors: bool,
     #[clap(short, long, default_value = "999999999")]
     pub cull_distance: f64,
+    /// How the speed changes between two sensors along the paths
+    #[clap(long, default_value = "harmonic")]
+    pub interpolation: SpeedInterpolation,
+    #[clap(flatten)]
+    pub canvas: CanvasOptions,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -134,80 +136,34 @@ pub struct ExtendedPath {
     pub length: f64,
 }
 
+/// Route every path of **setup**, and write the travel time along it with the speeds at the chosen
+/// sensors modified by each step of the sensor mode to **output_path** as CSV.
+///
+/// The graph holds no measured speeds, so every sensor on a path starts out at the speed limit of
+/// the road it is on and the modifications are applied to that.
 pub fn simulate(
-    graph: &mut StableDiGraph<NodeData, EdgeData>,
+    graph: ProcessedGraph,
     setup: SimulationSetup,
-    mut options: SimulationOptions,
+    options: SimulationOptions,
     output_path: &str,
-) {
+) -> anyhow::Result<()> {
     let mut progress = Progress::new();
+    let ProcessedGraph {
+        graph,
+        sensor_store,
+    } = graph;
+    let routing = RoutingGraph::new(graph);
 
-    progress.step_sized(setup.paths.len(), "Finding node waypoints");
-    let mut paths = Vec::new();
-    for path in &setup.paths {
-        let path = match path {
-            SimulationPathQuery::Raw(path) => path.clone(),
-            SimulationPathQuery::File(path) => {
-                serde_json::from_str(&std::fs::read_to_string(path).expect("Failed to read file"))
-                    .expect("Failed to parse file")
-            }
-        };
-
-        let path = path
-            .iter()
-            .map(|query| find_point(graph, query.clone()).expect("Failed to find point"))
-            .collect::<Vec<_>>();
-        paths.push(path);
-        progress.tick();
-    }
-    progress.finish("Waypoints found");
+    let (result, paths) = simulate_paths(&mut progress, &routing, &sensor_store, setup, &options)?;
 
-    progress.step_sized(paths.len(), "Simulating paths");
-    let paths = paths
-        .into_iter()
-        .map(|path| {
-            let path =
-                visitor::shortest_path(graph, path, setup.metric).expect("Failed to find path");
-            let length = calculate_travel_distance(graph, &path.nodes);
-            let res = ExtendedPath { path, length };
-            progress.tick();
-            res
-        })
-        .collect::<Vec<_>>();
-    progress.finish("Paths simulated");
-
-    // Ensure all sensors exist in the paths
-    progress.step_unsized("Ensuring all sensors exist on paths");
-    let existing_sensors = paths
-        .iter()
-        .map(|ext| ext.path.nodes.clone())
-        .flat_map(|nodes| {
-            nodes
-                .clone()
-                .into_iter()
-                .filter_map(|node| graph[node].sensor)
-        })
-        .map(|sensor| sensor.site_id)
-        .collect::<Vec<_>>();
-    for sensor in &setup.sensors {
-        if !existing_sensors.contains(&sensor.site_id) {
-            if options.ignore_missing_sensors {
-                println!(
-                    "Sensor {} not found on any path",
-                    style(sensor.site_id).bold()
-                );
-            } else {
-                panic!(
-                    "Sensor {} not found on any path, existing sensors are: {:?}",
-                    sensor.site_id, existing_sensors
-                );
-            }
-        }
-    }
-    progress.finish("Found all sensors");
+    save_as_csv(result, output_path)?;
+    progress.step_single(format!(
+        "Simulation results saved to {}",
+        style(output_path).bold()
+    ));
 
     progress.step_unsized("Trimming graph to path");
-    let mut stripped_graph = graph.clone();
+    let mut stripped_graph = routing.graph().clone();
     let mut keep = stripped_graph.visit_map();
     for path in &paths {
         for node in path.path.nodes.iter() {
@@ -221,50 +177,7 @@ pub fn simulate(
         style(stripped_graph.node_count()).bold()
     ));
 
-    progress.step_sized(
-        setup.sensor_mode.count() * paths.len(),
-        "Simulating sensors",
-    );
-    let mut results = Vec::new();
-    let sensors = setup
-        .sensors
-        .iter()
-        .map(|sensor| sensor.site_id)
-        .collect::<Vec<_>>();
-    for (index, ExtendedPath { path, length }) in paths.iter().enumerate() {
-        for modification in setup.sensor_mode.modifications() {
-            let modifications = vec![modification.clone(); setup.sensors.len()];
-            let input = sensors
-                .iter()
-                .cloned()
-                .zip(modifications)
-                .collect::<Vec<_>>();
-            let travel_time =
-                simulate_modifications(&stripped_graph, &setup.clone(), &path, &input);
-            let result = SimulatedPath {
-                nodes: path.nodes.clone(),
-                length: *length,
-                travel_time,
-                path_index: index,
-                modification,
-            };
-            results.push(result);
-            progress.tick();
-        }
-    }
-    progress.finish("Sensors simulated");
-
-    let result = SimulationResult {
-        paths: results,
-        sensor_mode: setup.sensor_mode,
-    };
-
-    save_as_csv(result.clone(), output_path);
-    progress.step_single(format!(
-        "Simulation results saved to {}",
-        style(output_path).bold()
-    ));
-
+    let mut graph = routing.into_graph();
     progress.step_sized(
         graph.node_count(),
         format!("Culling graph to {}m", options.cull_distance),
@@ -282,7 +195,7 @@ pub fn simulate(
         style(graph.node_count()).bold()
     ));
 
-    let mut canvas = Canvas::from_graph(4000, &graph);
+    let mut canvas = options.canvas.canvas(4000, &graph);
 
     progress.step_sized(graph.edge_count(), "Drawing edges");
     for edge in graph.edge_references() {
@@ -329,139 +242,299 @@ pub fn simulate(
     }
     progress.finish(format!("Drew {} paths", style(paths.len()).bold()));
 
-    path_canvas.save("./out/graph.svg");
+    options.canvas.save(&path_canvas, "./out/graph.svg");
 
-    /*
-    progress.step_sized(result.paths.len(), "Drawing individual sims");
-    for path in result.paths.iter() {
-        let mut canvas = canvas.clone();
+    Ok(())
+}
 
-        let mut prev_speed;
+/// Route every path of **setup** and compute the travel time along it for each step of the sensor
+/// mode, returning the results together with the routed paths
+fn simulate_paths(
+    progress: &mut Progress,
+    routing: &RoutingGraph,
+    sensor_store: &HashMap<NodeIndex, Vec<SensorMetadata>>,
+    setup: SimulationSetup,
+    options: &SimulationOptions,
+) -> anyhow::Result<(SimulationResult, Vec<ExtendedPath>)> {
+    progress.step_sized(setup.paths.len(), "Finding node waypoints");
+    let mut paths = Vec::new();
+    for path in &setup.paths {
+        let path: Vec<PointQuery> = match path {
+            SimulationPathQuery::Raw(path) => path.clone(),
+            SimulationPathQuery::File(path) => serde_json::from_str(
+                &std::fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read {}", path))?,
+            )
+            .with_context(|| format!("Failed to parse {}", path))?,
+        };
+
+        paths.push(routing.find_points(&path, SnapStrategy::default())?);
+        progress.tick();
+    }
+    progress.finish("Waypoints found");
 
-        let first_node = path.nodes.first().unwrap();
-        let data = graph.node_weight(*first_node).unwrap();
+    let graph = routing.graph();
+    progress.step_sized(paths.len(), "Simulating paths");
+    let paths = paths
+        .into_iter()
+        .map(|path| {
+            let path =
+                visitor::shortest_path(graph, path, setup.metric, 1.0, SearchStrategy::Dijkstra)
+                    .context("Failed to find path")?;
+            let length = path
+                .nodes
+                .windows(2)
+                .map(|pair| edge_between(graph, pair[0], pair[1]).distance)
+                .sum();
+            progress.tick();
+            Ok(ExtendedPath { path, length })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    progress.finish("Paths simulated");
 
-        if let Some(sensor) = data.sensor {
-            prev_speed = (first_node, sensor.average_speed);
-        } else {
-            let second_node = path.nodes.get(1).unwrap();
-            let edge = graph.find_edge(*first_node, *second_node).unwrap();
-            let data = graph.edge_weight(edge).unwrap();
-            prev_speed = (first_node, data.speed_limit.unwrap_or(50.0));
+    // Ensure all sensors exist in the paths
+    progress.step_unsized("Ensuring all sensors exist on paths");
+    let existing_sensors = paths
+        .iter()
+        .flat_map(|ext| ext.path.nodes.iter())
+        .filter_map(|node| sensor_store.get(node))
+        .flatten()
+        .map(|sensor| sensor.site_id)
+        .collect::<Vec<_>>();
+    for sensor in &setup.sensors {
+        if !existing_sensors.contains(&sensor.site_id) {
+            if options.ignore_missing_sensors {
+                println!(
+                    "Sensor {} not found on any path",
+                    style(sensor.site_id).bold()
+                );
+            } else {
+                anyhow::bail!(
+                    "Sensor {} not found on any path, existing sensors are: {:?}",
+                    sensor.site_id,
+                    existing_sensors
+                );
+            }
         }
+    }
+    progress.finish("Found all sensors");
 
-        let mut speed_iter = path.nodes.iter().skip(1).filter_map(|node| {
-            let data = graph.node_weight(*node).unwrap();
-            if let Some(sensor) = data.sensor {
-                return Some((node, sensor.average_speed));
-            }
+    progress.step_sized(
+        setup.sensor_mode.count() * paths.len(),
+        "Simulating sensors",
+    );
+    let mut results = Vec::new();
+    let sensors = setup
+        .sensors
+        .iter()
+        .map(|sensor| sensor.site_id)
+        .collect::<HashSet<_>>();
+    for (index, ExtendedPath { path, length }) in paths.iter().enumerate() {
+        let base_speeds = sensor_speed_limits(graph, sensor_store, path);
+        for modification in setup.sensor_mode.modifications() {
+            let travel_time = simulate_modification(
+                graph,
+                sensor_store,
+                path,
+                &base_speeds,
+                &sensors,
+                &modification,
+                options.interpolation,
+            );
+            let result = SimulatedPath {
+                nodes: path.nodes.clone(),
+                length: *length,
+                travel_time,
+                path_index: index,
+                modification,
+            };
+            results.push(result);
+            progress.tick();
+        }
+    }
+    progress.finish("Sensors simulated");
 
-            None
-        });
+    Ok((
+        SimulationResult {
+            paths: results,
+            sensor_mode: setup.sensor_mode,
+        },
+        paths,
+    ))
+}
 
-        let next_speed = speed_iter.next().unwrap_or(prev_speed);
+fn edge_between(
+    graph: &StableDiGraph<NodeData, EdgeData>,
+    from: NodeIndex,
+    to: NodeIndex,
+) -> &EdgeData {
+    graph.edges_connecting(from, to).next().unwrap().weight()
+}
 
-        let mut sub_path = Vec::new();
-        for pair in path.nodes.windows(2) {
-            let (start, end) = (pair[0], pair[1]);
-            let edge = graph.find_edge(start, end).unwrap();
-            let data = graph.edge_weight(edge).unwrap();
-            sub_path.push(edge.polyline.clone());
+/// Speed in km/h a sensor starts out at when the road it is on has no speed limit, the most common
+/// speed limit in Swedish towns
+const FALLBACK_SENSOR_SPEED: f64 = 50.0;
 
-            prev_speed = next_speed;
-            next_speed = speed_iter.next().unwrap_or((end, next_speed.1));
+/// The speed limit in km/h of the road at every sensor node on **path**, standing in for the speed
+/// the sensors measure, or [`FALLBACK_SENSOR_SPEED`] if the road has no speed limit
+fn sensor_speed_limits(
+    graph: &StableDiGraph<NodeData, EdgeData>,
+    sensor_store: &HashMap<NodeIndex, Vec<SensorMetadata>>,
+    path: &visitor::Path,
+) -> HashMap<NodeIndex, f64> {
+    let mut speeds = HashMap::new();
+    for (idx, node) in path.nodes.iter().enumerate() {
+        if !sensor_store.contains_key(node) {
+            continue;
         }
-
-        let opts = DrawOptions {
-            color: "purple".into(),
-            stroke: 1.0,
-            ..Default::default()
+        // The edge leaving the node, or the one arriving at the end of the path
+        let edge = match path.nodes.get(idx + 1) {
+            Some(next) => edge_between(graph, *node, *next),
+            None if idx > 0 => edge_between(graph, path.nodes[idx - 1], *node),
+            None => continue,
         };
-
-        augmented_path.windows(2).for_each(|nodes| {
-            let edge = graph.find_edge(nodes[0], nodes[1]).unwrap();
-            let data = &graph[edge];
-            canvas.draw_polyline(data.polyline.clone(), opts.clone());
-        });
-
-        let start = augmented_path.first().unwrap();
-        let end = augmented_path.last().unwrap();
-        let start_data = graph.node_weight(*start).unwrap();
-        let end_data = graph.node_weight(*end).unwrap();
-
-        canvas.draw_circle(start_data.point, "green", 5.0);
-        canvas.draw_circle(end_data.point, "red", 5.0);
-
-        progress.tick();
+        let speed = edge
+            .speed_limit
+            .filter(|speed| *speed > 0.0)
+            .unwrap_or(FALLBACK_SENSOR_SPEED);
+        speeds.insert(*node, speed);
     }
-     */
+    speeds
 }
 
-fn simulate_modifications(
+/// The travel time along **path** with **modification** applied to the speed at the nodes of the
+/// **sensors**, given by site id
+fn simulate_modification(
     graph: &StableDiGraph<NodeData, EdgeData>,
-    setup: &SimulationSetup,
+    sensor_store: &HashMap<NodeIndex, Vec<SensorMetadata>>,
     path: &visitor::Path,
-    sensors: &Vec<(i32, SensorModification)>,
+    base_speeds: &HashMap<NodeIndex, f64>,
+    sensors: &HashSet<i32>,
+    modification: &SensorModification,
+    interpolation: SpeedInterpolation,
 ) -> TravelTime {
-    let mut graph = graph.clone();
-    for node in &path.nodes {
-        if let Some(sensor) = graph[*node].sensor {
-            for (site_id, modification) in sensors {
-                if *site_id == sensor.site_id {
-                    let real_speed = sensor.average_speed;
-                    let sensor = modification.apply(sensor);
-                    let fake_speed = sensor.average_speed;
-
-                    let real_vehicle_count = sensor.flow_rate;
-
-                    let fake_vehicle_count = real_vehicle_count * (fake_speed - real_speed)
-                        / (setup.fake_data_speed - fake_speed);
-
-                    println!(
-                        "{} Count: {:.2} -> {:.2} \t Speed: {:.2} -> {:.2}",
-                        sensor.site_id,
-                        real_vehicle_count,
-                        fake_vehicle_count,
-                        real_speed,
-                        fake_speed
-                    );
-
-                    graph[*node].sensor = Some(sensor);
-                }
-            }
+    let mut calculator = TravelTimeCalculator::new(graph);
+    for (node, speed) in base_speeds {
+        let modified = sensor_store[node]
+            .iter()
+            .any(|sensor| sensors.contains(&sensor.site_id));
+        if modified {
+            calculator.set_override(*node, modification.apply(*speed));
         }
     }
 
-    calculate_travel_time_sensors(&graph, path)
+    let (measurements, distance) = calculator.measurements(path, base_speeds);
+    if measurements.is_empty() {
+        return TravelTime {
+            time: f64::NAN,
+            speeds: Vec::new(),
+        };
+    }
+    let time = route_travel_time(
+        &measurements
+            .iter()
+            .map(|(_, distance, speed)| (*distance, *speed))
+            .collect::<Vec<_>>(),
+        distance,
+        interpolation,
+    );
+
+    TravelTime {
+        time,
+        speeds: measurements
+            .into_iter()
+            .map(|(node, _, speed)| (node, speed))
+            .collect(),
+    }
 }
 
-pub fn save_as_csv(result: SimulationResult, file_path: &str) {
-    let mut writer = csv::Writer::from_path(file_path).expect("Failed to open file");
-    writer
-        .write_record(&[
-            "path_index",
-            "length",
-            "travel_time",
-            "modification_value",
-            "modification_mode",
-        ])
-        .expect("Failed to write header");
+pub fn save_as_csv(result: SimulationResult, file_path: &str) -> anyhow::Result<()> {
+    let mut writer = csv::Writer::from_path(file_path)
+        .with_context(|| format!("Failed to write {}", file_path))?;
+    writer.write_record([
+        "path_index",
+        "length",
+        "travel_time",
+        "modification_value",
+        "modification_mode",
+    ])?;
     for path in result.paths {
-        writer
-            .write_record(&[
-                path.path_index.to_string(),
-                path.length.to_string(),
-                path.travel_time.time.to_string(),
-                match path.modification {
-                    SensorModification::Deviation { modifier } => modifier.to_string(),
-                    SensorModification::SetSpeed { speed } => speed.to_string(),
-                },
-                match path.modification {
-                    SensorModification::Deviation { .. } => "deviation".to_string(),
-                    SensorModification::SetSpeed { .. } => "set_speed".to_string(),
-                },
-            ])
-            .expect("Failed to write record");
+        writer.write_record(&[
+            path.path_index.to_string(),
+            path.length.to_string(),
+            path.travel_time.time.to_string(),
+            match path.modification {
+                SensorModification::Deviation { modifier } => modifier.to_string(),
+                SensorModification::SetSpeed { speed } => speed.to_string(),
+            },
+            match path.modification {
+                SensorModification::Deviation { .. } => "deviation".to_string(),
+                SensorModification::SetSpeed { .. } => "set_speed".to_string(),
+            },
+        ])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_graphs::{add_chain, sensor, temp_path, TestGraph};
+
+    #[derive(clap::Parser)]
+    struct SimulateArgs {
+        #[clap(flatten)]
+        options: SimulationOptions,
+    }
+
+    #[test]
+    fn set_speed_writes_a_row_per_speed() {
+        let mut graph = TestGraph::default();
+        let points = [(0.0, 0.0), (0.0, 0.001), (0.0, 0.002), (0.0, 0.003)];
+        let nodes = add_chain(&mut graph, &points, 1, 50.0);
+        let mut sensor_store = HashMap::new();
+        for (site_id, node) in [(1, nodes[1]), (2, nodes[2])] {
+            graph[node].has_sensor = true;
+            let point = graph[node].point;
+            sensor_store.insert(node, vec![sensor(site_id, point.latitude, point.longitude)]);
+        }
+        let routing = RoutingGraph::new(graph);
+        let query =
+            |(latitude, longitude)| PointQuery::new(latitude, longitude, 10.0, -180.0..180.0);
+        let setup = SimulationSetup {
+            paths: vec![SimulationPathQuery::Raw(vec![
+                query(points[0]),
+                query(points[3]),
+            ])],
+            metric: DistanceMetric::Space,
+            sensors: vec![SensorSetup { site_id: 1 }],
+            sensor_mode: SensorMode::SetSpeed {
+                speeds: vec![25.0, 50.0, 100.0],
+            },
+        };
+        let options = <SimulateArgs as clap::Parser>::parse_from(["simulate"]).options;
+
+        let (result, _) = simulate_paths(
+            &mut Progress::new(),
+            &routing,
+            &sensor_store,
+            setup,
+            &options,
+        )
+        .unwrap();
+        let times = result
+            .paths
+            .iter()
+            .map(|path| path.travel_time.time)
+            .collect::<Vec<_>>();
+        let path = temp_path("simulation.csv");
+        save_as_csv(result, &path).unwrap();
+
+        let mut reader = csv::Reader::from_path(&path).unwrap();
+        let rows = reader.records().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(rows.len(), 3);
+        assert!(rows.iter().all(|row| &row[4] == "set_speed"));
+        assert!(times[0] > times[1] && times[1] > times[2]);
     }
-    writer.flush().expect("Failed to flush writer");
 }
@@ -0,0 +1,54 @@
+use console::style;
+
+use crate::{
+    graph_io,
+    processing::{collapse, compact, remap_sensor_store, ProcessedGraph},
+    progress::Progress,
+};
+
+/// Contract the graph at **input** down to its junctions and sensor nodes and save it to
+/// **output**, simplifying contracted polylines to at most **max_polyline_points** points
+pub fn skeletonize(
+    input: &str,
+    output: &str,
+    max_polyline_points: Option<usize>,
+) -> anyhow::Result<()> {
+    let mut progress = Progress::new();
+
+    progress.step_unsized("Reading graph");
+    let ProcessedGraph {
+        mut graph,
+        sensor_store,
+    } = graph_io::try_load_graph(input)?;
+    progress.finish(format!(
+        "Loaded graph with {} nodes and {} edges",
+        graph.node_count(),
+        graph.edge_count()
+    ));
+
+    progress.step_unsized("Contracting chains");
+    let nodes = graph.node_count();
+    let edges = graph.edge_count();
+    collapse::skeleton(&mut graph, max_polyline_points);
+    let (graph, remap) = compact(&graph);
+    let sensor_store = remap_sensor_store(sensor_store, &remap);
+    progress.finish(format!(
+        "Kept {} of {} nodes and {} of {} edges",
+        style(graph.node_count()).bold(),
+        nodes,
+        style(graph.edge_count()).bold(),
+        edges
+    ));
+
+    progress.step_unsized("Saving graph");
+    graph_io::save_graph(
+        output,
+        &ProcessedGraph {
+            graph,
+            sensor_store,
+        },
+    )?;
+    progress.finish(format!("Saved graph to {}", output));
+
+    Ok(())
+}
@@ -0,0 +1,369 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::Context;
+use clap::Args;
+use petgraph::stable_graph::StableDiGraph;
+
+use crate::{
+    custom_bfs::Positionable,
+    graph_io,
+    mongo::{
+        client::{
+            async_client::{AsyncMongoClient, DataAggregation},
+            MongoOptions,
+        },
+        model::{DataPoint, SensorMetadata, VehicleType},
+    },
+    output::{CanvasOptions, CanvasSize, DrawOptions},
+    parse::Point,
+    processing::{EdgeData, NodeData, ProcessedGraph},
+    progress::Progress,
+    util::suffixed_path,
+};
+
+use super::live_route::{ParseableDate, ParseableDuration};
+
+const METERS_PER_DEGREE: f64 = 111_320.0;
+const COMPASS_POINTS: [&str; 8] = ["N", "NE", "E", "SE", "S", "SW", "W", "NW"];
+
+#[derive(Debug, Args)]
+pub struct SpeedGridOptions {
+    #[clap(flatten)]
+    pub mongo_options: MongoOptions,
+    #[clap(short, long, default_value = "./out/graph.json")]
+    pub graph_path: String,
+    #[clap(short, long, default_value = "./out/speed_grid.svg")]
+    pub output: String,
+    /// Side length of each grid cell in meters
+    #[clap(short, long, default_value = "500")]
+    pub cell_size: f64,
+    #[clap(short, long, default_value = "now")]
+    pub timestamp: ParseableDate,
+    #[clap(short, long, default_value = "15m")]
+    pub max_sensor_data_age: ParseableDuration,
+    #[clap(short, long, default_value = "anyVehicle")]
+    pub vehicle_type: VehicleType,
+    /// How the data points of each sensor are turned into a speed
+    #[clap(long, default_value = "latest")]
+    pub aggregation: DataAggregation,
+    /// Split the sensors into this many direction buckets by the bearing of their measurement
+    /// side, and write one grid per bucket with the bucket name appended to the file name
+    #[clap(long)]
+    pub direction_buckets: Option<usize>,
+    /// Bearing in degrees the first direction bucket is centered on, 90 splits into east and west
+    #[clap(long, default_value = "0")]
+    pub bucket_offset: f64,
+    #[clap(flatten)]
+    pub canvas: CanvasOptions,
+}
+
+pub async fn speed_grid(options: SpeedGridOptions) -> anyhow::Result<()> {
+    let mut progress = Progress::new();
+
+    progress.step_unsized("Connecting to MongoDB");
+    let client = AsyncMongoClient::new(options.mongo_options.clone())
+        .await
+        .context("Failed to connect to MongoDB")?;
+    progress.finish("");
+
+    progress.step_unsized("Reading graph");
+    let ProcessedGraph {
+        graph,
+        sensor_store,
+    } = graph_io::try_load_graph(&options.graph_path)?;
+    progress.finish(format!(
+        "Loaded graph with {} nodes and {} edges",
+        graph.node_count(),
+        graph.edge_count()
+    ));
+
+    let sensors = sensor_store
+        .values()
+        .flatten()
+        .filter(|s| s.vehicle_type == options.vehicle_type)
+        .collect::<Vec<_>>();
+
+    progress.step_sized(sensors.len(), "Fetching sensor data");
+    let data = client
+        .get_sensor_data_at(
+            sensors.iter().copied(),
+            *options.timestamp,
+            *options.max_sensor_data_age,
+            options.aggregation,
+        )
+        .await
+        .context("Failed to get sensor data")?;
+    progress.finish(format!(
+        "Found data for {} of {} sensors",
+        data.len(),
+        sensors.len()
+    ));
+
+    let groups = match options.direction_buckets {
+        Some(buckets) => {
+            let mut groups = (0..buckets)
+                .map(|bucket| {
+                    let label = bucket_label(bucket, buckets, options.bucket_offset);
+                    (suffixed_path(&options.output, &label), Vec::new())
+                })
+                .collect::<Vec<_>>();
+            let mut unknown = 0;
+            for sensor in sensors.iter() {
+                match sensor.measurement_side.to_bearing() {
+                    Some(bearing) => {
+                        let bucket = direction_bucket(bearing, buckets, options.bucket_offset);
+                        groups[bucket].1.push(*sensor);
+                    }
+                    None => unknown += 1,
+                }
+            }
+            if unknown > 0 {
+                println!(
+                    "Skipping {} sensors without a known measurement side",
+                    unknown
+                );
+            }
+            groups
+        }
+        None => vec![(options.output.clone(), sensors)],
+    };
+
+    for (output, sensors) in groups {
+        draw_speed_grid(
+            &mut progress,
+            &graph,
+            &sensors,
+            &data,
+            options.cell_size,
+            &options.canvas,
+            &output,
+        );
+    }
+
+    Ok(())
+}
+
+/// Draw the average speed of the **sensors** in each grid cell under the graph edges
+fn draw_speed_grid(
+    progress: &mut Progress,
+    graph: &StableDiGraph<NodeData, EdgeData>,
+    sensors: &[&SensorMetadata],
+    data: &HashMap<i32, DataPoint>,
+    cell_size: f64,
+    canvas_options: &CanvasOptions,
+    output: &str,
+) {
+    let mut canvas = canvas_options.canvas(4000, graph);
+    let size = canvas.size;
+    let mid_latitude = (size.min_lat + size.max_lat) / 2.0;
+    let lat_step = cell_size / METERS_PER_DEGREE;
+    let lon_step = cell_size / (METERS_PER_DEGREE * mid_latitude.to_radians().cos());
+    let rows = ((size.max_lat - size.min_lat) / lat_step).ceil() as usize;
+    let cols = ((size.max_lon - size.min_lon) / lon_step).ceil() as usize;
+
+    progress.step_unsized("Averaging cell speeds");
+    let cells = cell_speeds(sensors, data, size, lat_step, lon_step);
+    progress.finish(format!(
+        "Found speeds for {} of {} cells",
+        cells.len(),
+        rows * cols
+    ));
+
+    let max_speed = cells.iter().map(|(_, _, s)| *s).fold(0.0, f64::max);
+    let grad = colorgrad::CustomGradient::new()
+        .html_colors(&["red", "yellow", "green"])
+        .domain(&[0.0, max_speed.max(1.0)])
+        .build()
+        .unwrap();
+
+    for (min, max, speed) in cells {
+        canvas.draw_rect(min, max, &grad.at(speed).to_hex_string(), 0.6);
+    }
+
+    for edge in graph.edge_weights() {
+        canvas.draw_polyline(
+            edge.polyline.clone(),
+            DrawOptions {
+                color: "gray".into(),
+                stroke: 1.0,
+                ..Default::default()
+            },
+        );
+    }
+
+    canvas_options.save(&canvas, output);
+    println!("Wrote speed grid to {}", output);
+}
+
+/// The index of the bucket **bearing** falls in, with **buckets** equally wide buckets and the first
+/// one centered on **offset**
+fn direction_bucket(bearing: f64, buckets: usize, offset: f64) -> usize {
+    let width = 360.0 / buckets as f64;
+    ((bearing - offset + width / 2.0).rem_euclid(360.0) / width) as usize % buckets
+}
+
+/// The compass point of the center of a bucket, or its bearing if there are more buckets than points
+fn bucket_label(bucket: usize, buckets: usize, offset: f64) -> String {
+    let center = (offset + bucket as f64 * 360.0 / buckets as f64).rem_euclid(360.0);
+    if buckets <= COMPASS_POINTS.len() && center % 45.0 == 0.0 {
+        COMPASS_POINTS[(center / 45.0) as usize].to_string()
+    } else {
+        format!("{:03.0}", center)
+    }
+}
+
+/// The corners and average speed of every cell with data in a grid of **lat_step** by **lon_step**
+/// degrees over **size**. Each sensor is put in the one cell its position falls in, and sensors
+/// outside the grid are left out
+fn cell_speeds(
+    sensors: &[&SensorMetadata],
+    data: &HashMap<i32, DataPoint>,
+    size: CanvasSize,
+    lat_step: f64,
+    lon_step: f64,
+) -> Vec<(Point, Point, f64)> {
+    let rows = ((size.max_lat - size.min_lat) / lat_step).ceil() as usize;
+    let cols = ((size.max_lon - size.min_lon) / lon_step).ceil() as usize;
+
+    let mut buckets: BTreeMap<(usize, usize), Vec<f64>> = BTreeMap::new();
+    for sensor in sensors {
+        let Some(data_point) = data.get(&sensor.site_id) else {
+            continue;
+        };
+        let point = sensor.point();
+        if !point.within(&size) {
+            continue;
+        }
+        // Sensors on the far edge of the grid belong to its last cell
+        let row = (((point.latitude - size.min_lat) / lat_step).floor() as usize)
+            .min(rows.saturating_sub(1));
+        let col = (((point.longitude - size.min_lon) / lon_step).floor() as usize)
+            .min(cols.saturating_sub(1));
+        buckets
+            .entry((row, col))
+            .or_default()
+            .push(data_point.average_speed);
+    }
+
+    buckets
+        .into_iter()
+        .filter_map(|((row, col), speeds)| {
+            let min = Point {
+                latitude: size.min_lat + row as f64 * lat_step,
+                longitude: size.min_lon + col as f64 * lon_step,
+            };
+            let max = Point {
+                latitude: min.latitude + lat_step,
+                longitude: min.longitude + lon_step,
+            };
+            average_speed(&speeds).map(|speed| (min, max, speed))
+        })
+        .collect()
+}
+
+fn average_speed(speeds: &[f64]) -> Option<f64> {
+    if speeds.is_empty() {
+        None
+    } else {
+        Some(speeds.iter().sum::<f64>() / speeds.len() as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::{oid::ObjectId, DateTime};
+
+    use super::*;
+    use crate::{
+        output::{calc_canvas_size_from_extents, Projection},
+        test_graphs::sensor,
+    };
+
+    fn data_point(average_speed: f64) -> DataPoint {
+        DataPoint {
+            mongo_id: None,
+            original_id: ObjectId::new(),
+            sensor_id: ObjectId::new(),
+            time: DateTime::now(),
+            flow_rate: 0.0,
+            average_speed,
+        }
+    }
+
+    #[test]
+    fn cell_speed_is_the_average_of_its_sensors() {
+        let size =
+            calc_canvas_size_from_extents(1000, [0.0, 0.002, 0.0, 0.002], Projection::default());
+        let sensors = [
+            sensor(1, 0.0002, 0.0002),
+            sensor(2, 0.0008, 0.0007),
+            sensor(3, 0.0015, 0.0015),
+            // Outside the grid
+            sensor(4, 0.01, 0.01),
+        ];
+        let data = [(1, 40.0), (2, 60.0), (3, 80.0), (4, 100.0)]
+            .into_iter()
+            .map(|(site_id, speed)| (site_id, data_point(speed)))
+            .collect::<HashMap<_, _>>();
+
+        let cells = cell_speeds(
+            &sensors.iter().collect::<Vec<_>>(),
+            &data,
+            size,
+            0.001,
+            0.001,
+        );
+
+        let speeds = cells
+            .iter()
+            .map(|(min, _, speed)| {
+                (
+                    (min.latitude * 1000.0).round(),
+                    (min.longitude * 1000.0).round(),
+                    *speed,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(speeds, vec![(0.0, 0.0, 50.0), (1.0, 1.0, 80.0)]);
+    }
+
+    #[test]
+    fn two_buckets_offset_to_the_east_split_east_from_west() {
+        let bucket = |bearing| direction_bucket(bearing, 2, 90.0);
+
+        assert_eq!(bucket(90.0), 0);
+        assert_eq!(bucket(45.0), 0);
+        assert_eq!(bucket(270.0), 1);
+        assert_eq!(bucket(200.0), 1);
+        assert_eq!(bucket_label(0, 2, 90.0), "E");
+        assert_eq!(bucket_label(1, 2, 90.0), "W");
+    }
+
+    #[test]
+    fn four_buckets_are_centered_on_the_cardinal_points() {
+        let bucket = |bearing| direction_bucket(bearing, 4, 0.0);
+
+        assert_eq!(
+            [10.0, 100.0, 200.0, 260.0, 350.0].map(bucket),
+            [0, 1, 2, 3, 0]
+        );
+        assert_eq!(
+            (0..4)
+                .map(|idx| bucket_label(idx, 4, 0.0))
+                .collect::<Vec<_>>(),
+            ["N", "E", "S", "W"]
+        );
+    }
+
+    #[test]
+    fn bearings_on_a_boundary_go_to_the_next_bucket_clockwise() {
+        assert_eq!(
+            [45.0, 135.0, 225.0, 315.0, 360.0].map(|bearing| direction_bucket(bearing, 4, 0.0)),
+            [1, 2, 3, 0, 0]
+        );
+        assert_eq!(direction_bucket(0.0, 2, 90.0), 0);
+        assert_eq!(direction_bucket(180.0, 2, 90.0), 1);
+        // Bucket centers off the compass points are labeled by their bearing
+        assert_eq!(bucket_label(1, 5, 0.0), "072");
+    }
+}
@@ -1,78 +1,690 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, future::Future, time::Duration};
 
+use clap::ValueEnum;
 use mongodb::{
-    bson::{doc, DateTime},
-    options::FindOneOptions,
+    bson::{doc, oid::ObjectId, DateTime, Document},
+    error::ErrorKind,
+    options::{ClientOptions, FindOneOptions, FindOptions},
+    Database,
 };
 
-use crate::mongo::model::{DataPoint, SensorMetadata};
+use crate::{
+    mongo::model::{DataPoint, SensorMetadata, SpeedProfile},
+    visitor::HourlySpeeds,
+};
 
 use super::{Collections, MongoOptions};
 
+/// How the data points of a sensor before a timestamp are turned into a single data point
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum DataAggregation {
+    /// The most recent data point within the max age
+    #[default]
+    Latest,
+    /// The average of all data points within one measurement period of the sensor. The window is
+    /// always the period, so the max age is not used
+    PeriodAverage,
+}
+
+/// The delay before the first retry of a failed query, doubled for every following retry
+const RETRY_BACKOFF_MS: u64 = 250;
+
 pub struct AsyncMongoClient {
+    db: Database,
     collections: Collections,
+    max_retries: u32,
 }
 
 impl AsyncMongoClient {
     pub async fn new(options: MongoOptions) -> Result<Self, mongodb::error::Error> {
-        let client = mongodb::Client::with_uri_str(&options.uri).await?;
+        let timeout = Duration::from_millis(options.timeout_ms);
+        let mut client_options = ClientOptions::parse(&options.uri).await?;
+        client_options.connect_timeout = Some(timeout);
+        client_options.server_selection_timeout = Some(timeout);
+        let client = mongodb::Client::with_options(client_options)?;
         let db = client.database(&options.db);
 
         let raw_sensor_data = db.collection(&options.raw_sensor_data_collection);
         let sensors = db.collection(&options.sensors_collection);
         let data_points = db.collection(&options.data_points_collection);
+        let speed_profiles = db.collection(&options.speed_profiles_collection);
 
         Ok(Self {
+            db,
             collections: Collections {
                 raw_sensor_data,
                 sensors,
                 data_points,
+                speed_profiles,
             },
+            max_retries: options.max_retries,
         })
     }
 
+    /// Run **operation** until it succeeds, retrying with exponential backoff up to the max
+    /// retries if it fails with an error that may go away, like a network error
+    async fn with_retries<T, F, Fut>(&self, mut operation: F) -> mongodb::error::Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = mongodb::error::Result<T>>,
+    {
+        let mut attempt = 0;
+        loop {
+            match operation().await {
+                Err(error) if attempt < self.max_retries && is_transient(&error) => {
+                    tokio::time::sleep(Duration::from_millis(RETRY_BACKOFF_MS << attempt)).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    pub async fn ping(&self) -> mongodb::error::Result<()> {
+        self.db.run_command(doc! { "ping": 1 }, None).await?;
+        Ok(())
+    }
+
+    pub async fn count_sensors(&self) -> mongodb::error::Result<u64> {
+        self.collections
+            .sensors
+            .estimated_document_count(None)
+            .await
+    }
+
+    /// The time of the most recent data point, if there are any
+    pub async fn get_latest_data_point_time(&self) -> mongodb::error::Result<Option<DateTime>> {
+        let data_point = self
+            .collections
+            .data_points
+            .find_one(
+                None,
+                FindOneOptions::builder().sort(doc! { "Time": -1 }).build(),
+            )
+            .await?;
+
+        Ok(data_point.map(|data_point| data_point.time))
+    }
+
     pub async fn get_all_sensors(&self) -> Result<Vec<SensorMetadata>, mongodb::error::Error> {
-        let collection = self.collections.sensors.clone();
-        let mut cursor = collection.find(None, None).await?;
-        let mut acc = Vec::new();
+        let collection = &self.collections.sensors;
+        self.with_retries(|| async move {
+            let mut cursor = collection.find(None, None).await?;
+            let mut acc = Vec::new();
 
-        while cursor.advance().await? {
-            acc.push(cursor.deserialize_current().unwrap());
-        }
+            while cursor.advance().await? {
+                acc.push(cursor.deserialize_current()?);
+            }
 
-        Ok(acc)
+            Ok(acc)
+        })
+        .await
     }
 
+    /// The data point of each sensor at **timestamp**, keyed by site id, found with a single query
+    /// for the data points of all sensors within the window and then picked out per sensor.
+    /// The window is **max_age** for the latest data point and the measurement period of each
+    /// sensor for the period average, which does not use **max_age**. Sensors without data are
+    /// left out.
     pub async fn get_sensor_data_at<'a, I: Iterator<Item = &'a SensorMetadata>>(
         &self,
         sensors: I,
         timestamp: i64,
         max_age: i64,
+        aggregation: DataAggregation,
     ) -> mongodb::error::Result<HashMap<i32, DataPoint>> {
-        let mut data = HashMap::new();
-        let diff = timestamp - max_age;
+        let sensors = sensors
+            .filter_map(|sensor| sensor.mongo_id.map(|id| (id, sensor)))
+            .collect::<HashMap<_, _>>();
+        let sensor_ids = sensors.keys().copied().collect::<Vec<_>>();
+
+        let window = |sensor: &SensorMetadata| data_window(sensor, max_age, aggregation);
+        let max_window = sensors.values().map(|sensor| window(sensor)).max();
+        let Some(max_window) = max_window else {
+            return Ok(HashMap::new());
+        };
+
+        let collection = &self.collections.data_points;
+        let filter = doc! {
+            "SensorId": { "$in": sensor_ids },
+            "Time": {
+                "$lte": DateTime::from_millis(timestamp),
+                "$gte": DateTime::from_millis(timestamp - max_window),
+            },
+        };
+        let options = FindOptions::builder().sort(doc! { "Time": -1 }).build();
+        let data_points = self
+            .with_retries(|| {
+                let (filter, options) = (filter.clone(), options.clone());
+                async move {
+                    let mut cursor = collection.find(filter, options).await?;
+                    let mut data_points = Vec::new();
+                    while cursor.advance().await? {
+                        data_points.push(cursor.deserialize_current()?);
+                    }
+                    Ok(data_points)
+                }
+            })
+            .await?;
+
+        Ok(pick_sensor_data(
+            &sensors,
+            data_points,
+            timestamp,
+            window,
+            aggregation,
+        ))
+    }
 
-        let max_timestamp = DateTime::from_millis(timestamp);
-        let min_timestamp = DateTime::from_millis(diff);
+    /// The average speed of each sensor for every hour of the day in UTC, over all its data,
+    /// keyed by site id. Sensors without data are left out.
+    pub async fn get_hourly_speed_profiles<'a, I: Iterator<Item = &'a SensorMetadata>>(
+        &self,
+        sensors: I,
+    ) -> mongodb::error::Result<HashMap<i32, HourlySpeeds>> {
+        let site_ids = sensors
+            .filter_map(|sensor| sensor.mongo_id.map(|id| (id, sensor.site_id)))
+            .collect::<HashMap<_, _>>();
+        let sensor_ids = site_ids.keys().copied().collect::<Vec<_>>();
+
+        let pipeline = vec![
+            doc! {
+                "$match": { "SensorId": { "$in": sensor_ids } },
+            },
+            doc! {
+                "$group": {
+                    "_id": { "sensor": "$SensorId", "hour": { "$hour": "$Time" } },
+                    "speed": { "$avg": "$AverageSpeed" },
+                },
+            },
+        ];
+
+        let (collection, site_ids) = (&self.collections.data_points, &site_ids);
+        self.with_retries(|| {
+            let pipeline = pipeline.clone();
+            async move {
+                let mut cursor = collection.aggregate(pipeline, None).await?;
+                let mut profiles: HashMap<i32, HourlySpeeds> = HashMap::new();
+                while cursor.advance().await? {
+                    let document = cursor.deserialize_current()?;
+                    let (Ok(id), Ok(speed)) =
+                        (document.get_document("_id"), document.get_f64("speed"))
+                    else {
+                        continue;
+                    };
+                    let (Ok(sensor_id), Ok(hour)) =
+                        (id.get_object_id("sensor"), id.get_i32("hour"))
+                    else {
+                        continue;
+                    };
+                    if let Some(site_id) = site_ids.get(&sensor_id) {
+                        profiles.entry(*site_id).or_insert([None; 24])[hour as usize % 24] =
+                            Some(speed);
+                    }
+                }
+                Ok(profiles)
+            }
+        })
+        .await
+    }
 
-        for sensor in sensors {
-            let data_point = self
+    /// Average the data points of every sensor per hour and day of the week. If **store** is set
+    /// the profiles replace the contents of the speed profiles collection.
+    pub async fn build_speed_profiles(
+        &self,
+        store: bool,
+    ) -> mongodb::error::Result<Vec<SpeedProfile>> {
+        let output = store.then(|| self.collections.speed_profiles.name());
+        let pipeline = speed_profile_pipeline(output);
+
+        let mut cursor = self
+            .collections
+            .data_points
+            .aggregate(pipeline, None)
+            .await?;
+        if store {
+            // $out returns nothing, read the profiles back from the collection instead
+            cursor = self
                 .collections
-                .data_points
-                .find_one(
-                    doc! {
-                        "SensorId": sensor.mongo_id.unwrap(),
-                        "Time": { "$lte": max_timestamp, "$gte": min_timestamp},
-                    },
-                    FindOneOptions::builder().sort(doc! { "Time": -1 }).build(),
+                .speed_profiles
+                .clone_with_type()
+                .find(
+                    None,
+                    FindOptions::builder()
+                        .sort(doc! { "SensorId": 1, "DayOfWeek": 1, "Hour": 1 })
+                        .build(),
                 )
                 .await?;
+        }
+
+        let mut profiles = Vec::new();
+        while cursor.advance().await? {
+            profiles.push(mongodb::bson::from_document(cursor.deserialize_current()?)?);
+        }
+
+        Ok(profiles)
+    }
+
+    /// The standard deviation of the average speed of each sensor over the **window**
+    /// milliseconds before **timestamp**, keyed by site id. Sensors without data are left out.
+    pub async fn get_sensor_speed_std_dev<'a, I: Iterator<Item = &'a SensorMetadata>>(
+        &self,
+        sensors: I,
+        timestamp: i64,
+        window: i64,
+    ) -> mongodb::error::Result<HashMap<i32, f64>> {
+        let site_ids = sensors
+            .filter_map(|sensor| sensor.mongo_id.map(|id| (id, sensor.site_id)))
+            .collect::<HashMap<_, _>>();
+        let sensor_ids = site_ids.keys().copied().collect::<Vec<_>>();
 
-            if let Some(data_point) = data_point {
-                data.insert(sensor.site_id, data_point);
+        let pipeline = vec![
+            doc! {
+                "$match": {
+                    "SensorId": { "$in": sensor_ids },
+                    "Time": {
+                        "$lte": DateTime::from_millis(timestamp),
+                        "$gte": DateTime::from_millis(timestamp - window),
+                    },
+                },
+            },
+            doc! {
+                "$group": {
+                    "_id": "$SensorId",
+                    "stdDev": { "$stdDevPop": "$AverageSpeed" },
+                },
+            },
+        ];
+
+        let (collection, site_ids) = (&self.collections.data_points, &site_ids);
+        self.with_retries(|| {
+            let pipeline = pipeline.clone();
+            async move {
+                let mut cursor = collection.aggregate(pipeline, None).await?;
+                let mut std_devs = HashMap::new();
+                while cursor.advance().await? {
+                    let document = cursor.deserialize_current()?;
+                    let (Ok(sensor_id), Ok(std_dev)) =
+                        (document.get_object_id("_id"), document.get_f64("stdDev"))
+                    else {
+                        continue;
+                    };
+                    if let Some(site_id) = site_ids.get(&sensor_id) {
+                        std_devs.insert(*site_id, std_dev);
+                    }
+                }
+                Ok(std_devs)
             }
+        })
+        .await
+    }
+}
+
+/// The stages grouping data points into a speed profile per sensor, day of the week and hour,
+/// writing the profiles to the **output** collection if set
+fn speed_profile_pipeline(output: Option<&str>) -> Vec<Document> {
+    let mut pipeline = vec![
+        doc! {
+            "$group": {
+                "_id": {
+                    "sensor": "$SensorId",
+                    "day": { "$dayOfWeek": "$Time" },
+                    "hour": { "$hour": "$Time" },
+                },
+                "AverageSpeed": { "$avg": "$AverageSpeed" },
+                "FlowRate": { "$avg": "$FlowRate" },
+                "Samples": { "$sum": 1 },
+            },
+        },
+        doc! {
+            "$project": {
+                "_id": 0,
+                "SensorId": "$_id.sensor",
+                "DayOfWeek": "$_id.day",
+                "Hour": "$_id.hour",
+                "AverageSpeed": 1,
+                "FlowRate": 1,
+                "Samples": 1,
+            },
+        },
+        doc! {
+            "$sort": { "SensorId": 1, "DayOfWeek": 1, "Hour": 1 },
+        },
+    ];
+    if let Some(output) = output {
+        pipeline.push(doc! { "$out": output });
+    }
+    pipeline
+}
+
+/// Whether **error** may not happen again if the query is retried
+fn is_transient(error: &mongodb::error::Error) -> bool {
+    matches!(
+        *error.kind,
+        ErrorKind::Io(_)
+            | ErrorKind::ServerSelection { .. }
+            | ErrorKind::ConnectionPoolCleared { .. }
+    )
+}
+
+/// The age of the oldest data point of **sensor** the **aggregation** uses, in milliseconds
+fn data_window(sensor: &SensorMetadata, max_age: i64, aggregation: DataAggregation) -> i64 {
+    match aggregation {
+        DataAggregation::Latest => max_age,
+        // The period is in seconds
+        DataAggregation::PeriodAverage => sensor.period as i64 * 1000,
+    }
+}
+
+/// The data point of each of **sensors** at **timestamp**, keyed by site id, out of
+/// **data_points** sorted most recent first. Only data points within the **window** of their
+/// sensor before the timestamp are used.
+fn pick_sensor_data(
+    sensors: &HashMap<ObjectId, &SensorMetadata>,
+    data_points: Vec<DataPoint>,
+    timestamp: i64,
+    window: impl Fn(&SensorMetadata) -> i64,
+    aggregation: DataAggregation,
+) -> HashMap<i32, DataPoint> {
+    // Most recent first, so the first sample of each sensor is its latest
+    let mut samples: HashMap<i32, Vec<DataPoint>> = HashMap::new();
+    for data_point in data_points {
+        let Some(sensor) = sensors.get(&data_point.sensor_id) else {
+            continue;
+        };
+        if data_point.time.timestamp_millis() >= timestamp - window(sensor) {
+            samples.entry(sensor.site_id).or_default().push(data_point);
         }
+    }
+
+    samples
+        .into_iter()
+        .filter_map(|(site_id, samples)| {
+            let data_point = match aggregation {
+                DataAggregation::Latest => samples.into_iter().next(),
+                DataAggregation::PeriodAverage => average_data_points(samples),
+            };
+            data_point.map(|data_point| (site_id, data_point))
+        })
+        .collect()
+}
+
+/// A data point with the mean speed and flow rate of **samples**, keeping the identity and time of
+/// the first sample
+fn average_data_points(samples: Vec<DataPoint>) -> Option<DataPoint> {
+    let count = samples.len() as f64;
+    let average_speed = samples.iter().map(|s| s.average_speed).sum::<f64>() / count;
+    let flow_rate = samples.iter().map(|s| s.flow_rate).sum::<f64>() / count;
+    samples.into_iter().next().map(|first| DataPoint {
+        average_speed,
+        flow_rate,
+        ..first
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::test_graphs::sensor;
+
+    #[test]
+    fn unreachable_server_fails_within_the_timeout() {
+        let options = MongoOptions {
+            // Nothing listens on the discard port
+            uri: "mongodb://127.0.0.1:9".into(),
+            db: "exjobb".into(),
+            raw_sensor_data_collection: "raw".into(),
+            sensors_collection: "sensors".into(),
+            data_points_collection: "sensordata".into(),
+            speed_profiles_collection: "speed_profiles".into(),
+            timeout_ms: 200,
+            max_retries: 1,
+        };
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+
+        let start = Instant::now();
+        let result = runtime.block_on(async {
+            let client = AsyncMongoClient::new(options).await?;
+            client.get_all_sensors().await
+        });
+
+        let error = result.unwrap_err();
+        assert!(is_transient(&error), "{}", error);
+        // Two attempts of 200ms and one backoff of 250ms, with plenty of slack
+        assert!(
+            start.elapsed() < Duration::from_secs(5),
+            "{:?}",
+            start.elapsed()
+        );
+    }
+
+    #[test]
+    fn speed_aggregations_are_retried() {
+        let options = MongoOptions {
+            // Nothing listens on the discard port
+            uri: "mongodb://127.0.0.1:9".into(),
+            db: "exjobb".into(),
+            raw_sensor_data_collection: "raw".into(),
+            sensors_collection: "sensors".into(),
+            data_points_collection: "sensordata".into(),
+            speed_profiles_collection: "speed_profiles".into(),
+            timeout_ms: 100,
+            max_retries: 1,
+        };
+        let sensors = [SensorMetadata {
+            mongo_id: Some(ObjectId::new()),
+            ..sensor(1, 59.3, 18.0)
+        }];
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let client = runtime.block_on(AsyncMongoClient::new(options)).unwrap();
+
+        // A retry waits out the backoff before failing again
+        let backoff = Duration::from_millis(RETRY_BACKOFF_MS);
+        let start = Instant::now();
+        let error = runtime
+            .block_on(client.get_hourly_speed_profiles(sensors.iter()))
+            .unwrap_err();
+        assert!(is_transient(&error), "{}", error);
+        assert!(start.elapsed() >= backoff, "{:?}", start.elapsed());
+
+        let start = Instant::now();
+        let error = runtime
+            .block_on(client.get_sensor_speed_std_dev(sensors.iter(), 1_000_000, 60_000))
+            .unwrap_err();
+        assert!(is_transient(&error), "{}", error);
+        assert!(start.elapsed() >= backoff, "{:?}", start.elapsed());
+    }
+
+    #[test]
+    fn batched_sensor_data_matches_one_query_per_sensor() {
+        let (timestamp, max_age) = (1_000_000, 60_000);
+        let sensors = (0..3)
+            .map(|site_id| SensorMetadata {
+                mongo_id: Some(ObjectId::new()),
+                ..sensor(site_id, 59.3, 18.0)
+            })
+            .collect::<Vec<_>>();
+        // Every sensor reports every 20 seconds, the last one stopped before the window
+        let mut data_points = sensors
+            .iter()
+            .enumerate()
+            .flat_map(|(idx, sensor)| {
+                let last = if idx == 2 {
+                    timestamp - 2 * max_age
+                } else {
+                    timestamp
+                };
+                (0..10).map(move |step| DataPoint {
+                    mongo_id: None,
+                    original_id: ObjectId::new(),
+                    sensor_id: sensor.mongo_id.unwrap(),
+                    time: DateTime::from_millis(last - step * 20_000 - idx as i64 * 1000),
+                    flow_rate: 600.0,
+                    average_speed: 50.0 + step as f64,
+                })
+            })
+            .filter(|data_point| data_point.time.timestamp_millis() <= timestamp)
+            .collect::<Vec<_>>();
+        data_points.sort_by_key(|data_point| std::cmp::Reverse(data_point.time));
+
+        let by_id = sensors
+            .iter()
+            .map(|sensor| (sensor.mongo_id.unwrap(), sensor))
+            .collect::<HashMap<_, _>>();
+        let batched = pick_sensor_data(
+            &by_id,
+            data_points.clone(),
+            timestamp,
+            |_| max_age,
+            DataAggregation::Latest,
+        );
+
+        // What a find_one for the latest data point in the window of each sensor returns
+        let per_sensor = sensors
+            .iter()
+            .filter_map(|sensor| {
+                data_points
+                    .iter()
+                    .filter(|data_point| {
+                        data_point.sensor_id == sensor.mongo_id.unwrap()
+                            && data_point.time.timestamp_millis() >= timestamp - max_age
+                    })
+                    .max_by_key(|data_point| data_point.time)
+                    .map(|data_point| (sensor.site_id, data_point.original_id))
+            })
+            .collect::<HashMap<_, _>>();
+        assert_eq!(per_sensor.len(), 2);
+        assert_eq!(
+            batched
+                .iter()
+                .map(|(site_id, data_point)| (*site_id, data_point.original_id))
+                .collect::<HashMap<_, _>>(),
+            per_sensor
+        );
+    }
+
+    #[test]
+    fn latest_and_period_average_pick_from_their_windows() {
+        let (timestamp, max_age) = (1_000_000, 120_000);
+        let sensor = SensorMetadata {
+            mongo_id: Some(ObjectId::new()),
+            ..sensor(1, 59.3, 18.0)
+        };
+        // Most recent first, the last sample is within the max age but not the 60s period
+        let data_points = [(0, 60.0), (20_000, 50.0), (40_000, 40.0), (80_000, 10.0)]
+            .into_iter()
+            .map(|(age, average_speed)| DataPoint {
+                mongo_id: None,
+                original_id: ObjectId::new(),
+                sensor_id: sensor.mongo_id.unwrap(),
+                time: DateTime::from_millis(timestamp - age),
+                flow_rate: average_speed * 10.0,
+                average_speed,
+            })
+            .collect::<Vec<_>>();
+        let by_id = HashMap::from([(sensor.mongo_id.unwrap(), &sensor)]);
+        let pick = |aggregation| {
+            let picked = pick_sensor_data(
+                &by_id,
+                data_points.clone(),
+                timestamp,
+                |sensor| data_window(sensor, max_age, aggregation),
+                aggregation,
+            );
+            picked[&1].clone()
+        };
+
+        let latest = pick(DataAggregation::Latest);
+        assert_eq!(latest.original_id, data_points[0].original_id);
+        assert_eq!((latest.average_speed, latest.flow_rate), (60.0, 600.0));
+        let average = pick(DataAggregation::PeriodAverage);
+        assert_eq!(average.original_id, data_points[0].original_id);
+        assert_eq!((average.average_speed, average.flow_rate), (50.0, 500.0));
+    }
+
+    #[test]
+    fn speed_profile_stages_group_then_write_last() {
+        let stages = |pipeline: Vec<Document>| {
+            pipeline
+                .iter()
+                .map(|stage| stage.keys().next().unwrap().clone())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            stages(speed_profile_pipeline(None)),
+            ["$group", "$project", "$sort"]
+        );
+        let pipeline = speed_profile_pipeline(Some("speed_profiles"));
+        assert_eq!(
+            pipeline.last().unwrap().get_str("$out"),
+            Ok("speed_profiles")
+        );
+        let group = pipeline[0].get_document("$group").unwrap();
+        let id = group.get_document("_id").unwrap();
+        assert_eq!(id.keys().collect::<Vec<_>>(), ["sensor", "day", "hour"]);
+    }
+
+    /// Runs against the MongoDB server at `PROCESSING_TEST_MONGO_URI` if set, in a database of
+    /// its own that is dropped afterwards
+    #[test]
+    fn speed_profiles_have_a_bucket_per_sensor_day_and_hour() {
+        let Ok(uri) = std::env::var("PROCESSING_TEST_MONGO_URI") else {
+            return;
+        };
+        let db = format!("processing-test-{}", std::process::id());
+        let options = MongoOptions {
+            uri,
+            db: db.clone(),
+            raw_sensor_data_collection: "raw".into(),
+            sensors_collection: "sensors".into(),
+            data_points_collection: "sensordata".into(),
+            speed_profiles_collection: "speed_profiles".into(),
+            timeout_ms: 5000,
+            max_retries: 0,
+        };
+        // Two sensors, the first with data at two hours of a Monday and the second at one
+        const HOUR: i64 = 3_600_000;
+        let monday = 1_704_067_200_000;
+        let (first, second) = (ObjectId::new(), ObjectId::new());
+        let seeded = [
+            (first, monday),
+            (first, monday + 60_000),
+            (first, monday + HOUR),
+            (second, monday + 5 * HOUR),
+        ]
+        .map(|(sensor_id, time)| DataPoint {
+            mongo_id: None,
+            original_id: ObjectId::new(),
+            sensor_id,
+            time: DateTime::from_millis(time),
+            flow_rate: 600.0,
+            average_speed: 80.0,
+        });
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+
+        let profiles = runtime.block_on(async {
+            let client = AsyncMongoClient::new(options).await.unwrap();
+            client
+                .collections
+                .data_points
+                .insert_many(seeded, None)
+                .await
+                .unwrap();
+            let profiles = client.build_speed_profiles(true).await;
+            client.db.drop(None).await.unwrap();
+            profiles.unwrap()
+        });
 
-        Ok(data)
+        assert_eq!(profiles.len(), 3);
+        assert_eq!(
+            profiles.iter().map(|profile| profile.samples).sum::<i32>(),
+            4
+        );
+        assert!(profiles
+            .iter()
+            .all(|profile| profile.day_of_week == 2 && profile.average_speed == 80.0));
     }
 }
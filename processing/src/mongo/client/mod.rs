@@ -3,12 +3,13 @@ pub mod async_client;
 use clap::Args;
 use mongodb::Collection;
 
-use super::model::{DataPoint, RawSensorData, SensorMetadata};
+use super::model::{DataPoint, RawSensorData, SensorMetadata, SpeedProfile};
 
 pub struct Collections {
     pub raw_sensor_data: Collection<RawSensorData>,
     pub sensors: Collection<SensorMetadata>,
     pub data_points: Collection<DataPoint>,
+    pub speed_profiles: Collection<SpeedProfile>,
 }
 
 #[derive(Debug, Args, Clone)]
@@ -23,4 +24,12 @@ pub struct MongoOptions {
     pub sensors_collection: String,
     #[clap(long, default_value = "sensordata")]
     pub data_points_collection: String,
+    #[clap(long, default_value = "speed_profiles")]
+    pub speed_profiles_collection: String,
+    /// Milliseconds to wait for connecting to and selecting a server before failing
+    #[clap(long, default_value = "5000")]
+    pub timeout_ms: u64,
+    /// Times to retry a query failing with a network or server selection error
+    #[clap(long, default_value = "3")]
+    pub max_retries: u32,
 }
@@ -17,6 +17,23 @@ pub enum MeasurementSide {
     SouthEastBound,
 }
 
+impl MeasurementSide {
+    /// The compass bearing of the measured direction of travel in degrees, `None` if unknown
+    pub fn to_bearing(&self) -> Option<f64> {
+        match self {
+            MeasurementSide::Unknown => None,
+            MeasurementSide::NorthBound => Some(0.0),
+            MeasurementSide::NorthEastBound => Some(45.0),
+            MeasurementSide::EastBound => Some(90.0),
+            MeasurementSide::SouthEastBound => Some(135.0),
+            MeasurementSide::SouthBound => Some(180.0),
+            MeasurementSide::SouthWestBound => Some(225.0),
+            MeasurementSide::WestBound => Some(270.0),
+            MeasurementSide::NorthWestBound => Some(315.0),
+        }
+    }
+}
+
 impl Into<Bson> for MeasurementSide {
     fn into(self) -> Bson {
         match self {
@@ -140,25 +157,17 @@ pub struct RawSensorData {
 
 impl Positionable for RawSensorData {
     fn point(&self) -> crate::parse::Point {
-        crate::parse::Point {
-            latitude: self.location.coordinates[0],
-            longitude: self.location.coordinates[1],
-        }
+        self.location.point()
     }
 }
 
 impl RawSensorData {
-    pub fn filter(&self) -> Document {
-        doc! {
-            "SiteId": self.site_id,
-            "VehicleType": self.vehicle_type,
-            "SpecificLane": self.get_lane_i32(),
-            "MeasurementSide": self.get_measurement_side(),
-        }
-    }
-
-    pub fn get_lane_i32(&self) -> i32 {
-        self.specific_lane[4..].chars().as_str().parse().unwrap()
+    /// The number of the lane, from a specific lane like `lane1`
+    pub fn get_lane_i32(&self) -> Result<i32, String> {
+        self.specific_lane
+            .get(4..)
+            .and_then(|lane| lane.parse().ok())
+            .ok_or_else(|| format!("Malformed specific lane {:?}", self.specific_lane))
     }
 
     pub fn get_measurement_side(&self) -> MeasurementSide {
@@ -184,6 +193,16 @@ pub struct Location {
     pub coordinates: [f64; 2],
 }
 
+impl Location {
+    /// The point of a GeoJSON position, which is stored as `[longitude, latitude]`
+    pub fn point(&self) -> crate::parse::Point {
+        crate::parse::Point {
+            latitude: self.coordinates[1],
+            longitude: self.coordinates[0],
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
 #[serde(rename_all = "PascalCase")]
 pub struct SensorMetadata {
@@ -197,11 +216,13 @@ pub struct SensorMetadata {
     pub period: i32,
 }
 
-impl From<RawSensorData> for SensorMetadata {
-    fn from(data: RawSensorData) -> Self {
+impl TryFrom<RawSensorData> for SensorMetadata {
+    type Error = String;
+
+    fn try_from(data: RawSensorData) -> Result<Self, Self::Error> {
         let measurement_side = data.get_measurement_side();
-        let lane = data.get_lane_i32();
-        Self {
+        let lane = data.get_lane_i32()?;
+        Ok(Self {
             mongo_id: None,
             site_id: data.site_id,
             location: data.location,
@@ -209,20 +230,29 @@ impl From<RawSensorData> for SensorMetadata {
             vehicle_type: data.vehicle_type,
             specific_lane: lane,
             period: data.period,
+        })
+    }
+}
+
+impl SensorMetadata {
+    /// Filter matching the stored document of this sensor
+    pub fn filter(&self) -> Document {
+        doc! {
+            "SiteId": self.site_id,
+            "VehicleType": self.vehicle_type,
+            "SpecificLane": self.specific_lane,
+            "MeasurementSide": self.measurement_side.clone(),
         }
     }
 }
 
 impl Positionable for SensorMetadata {
     fn point(&self) -> crate::parse::Point {
-        crate::parse::Point {
-            latitude: self.location.coordinates[1],
-            longitude: self.location.coordinates[0],
-        }
+        self.location.point()
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "PascalCase")]
 pub struct DataPoint {
     #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
@@ -234,15 +264,127 @@ pub struct DataPoint {
     pub average_speed: f64,
 }
 
-impl From<RawSensorData> for DataPoint {
-    fn from(data: RawSensorData) -> Self {
-        Self {
+/// The average speed and flow rate of a sensor in one hour of one day of the week, over all its data
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct SpeedProfile {
+    pub sensor_id: ObjectId,
+    /// 1 for Sunday through 7 for Saturday, in UTC
+    pub day_of_week: i32,
+    /// 0 through 23, in UTC
+    pub hour: i32,
+    pub average_speed: f64,
+    pub flow_rate: f64,
+    /// The number of data points averaged
+    pub samples: i32,
+}
+
+impl DataPoint {
+    /// The data point of a raw sensor document, measured by the sensor stored as **sensor_id**.
+    /// Fails if the document has no id to refer back to.
+    pub fn new(data: RawSensorData, sensor_id: ObjectId) -> Result<Self, String> {
+        let original_id = data
+            .mongo_id
+            .ok_or_else(|| "Sensor document has no id".to_string())?;
+        Ok(Self {
             mongo_id: None,
-            original_id: data.mongo_id.unwrap(),
-            sensor_id: data.mongo_id.unwrap(),
+            original_id,
+            sensor_id,
             time: data.measurement_time,
             flow_rate: data.flow_rate,
             average_speed: data.average_speed,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw_sensor_data(specific_lane: &str) -> RawSensorData {
+        RawSensorData {
+            mongo_id: Some(ObjectId::new()),
+            site_id: 1,
+            measurement_time: DateTime::from_millis(0),
+            period: 60,
+            vehicle_type: VehicleType::Car,
+            flow_rate: 600.0,
+            average_speed: 80.0,
+            modified_time: DateTime::from_millis(0),
+            specific_lane: specific_lane.into(),
+            measurement_side: "northBound".into(),
+            location: Location {
+                _type: "Point".into(),
+                coordinates: [18.0, 59.3],
+            },
+        }
+    }
+
+    #[test]
+    fn lane_numbers_are_parsed() {
+        assert_eq!(raw_sensor_data("lane1").get_lane_i32(), Ok(1));
+        assert_eq!(raw_sensor_data("lane12").get_lane_i32(), Ok(12));
+    }
+
+    #[test]
+    fn malformed_lanes_are_errors() {
+        assert!(raw_sensor_data("").get_lane_i32().is_err());
+        assert!(raw_sensor_data("laneX").get_lane_i32().is_err());
+        assert!(SensorMetadata::try_from(raw_sensor_data("laneX")).is_err());
+    }
+
+    #[test]
+    fn data_points_keep_the_source_id_apart_from_the_sensor_id() {
+        let data = raw_sensor_data("lane1");
+        let source_id = data.mongo_id.unwrap();
+        let sensor_id = ObjectId::new();
+
+        let point = DataPoint::new(data, sensor_id).unwrap();
+
+        assert_eq!(point.original_id, source_id);
+        assert_eq!(point.sensor_id, sensor_id);
+        assert_ne!(point.sensor_id, source_id);
+    }
+
+    #[test]
+    fn data_points_need_a_source_id() {
+        let data = RawSensorData {
+            mongo_id: None,
+            ..raw_sensor_data("lane1")
+        };
+
+        assert!(DataPoint::new(data, ObjectId::new()).is_err());
+    }
+
+    #[test]
+    fn geojson_positions_are_longitude_first() {
+        let data = raw_sensor_data("lane1");
+        let sensor = SensorMetadata::try_from(data.clone()).unwrap();
+        let expected = crate::parse::Point {
+            latitude: 59.3,
+            longitude: 18.0,
+        };
+
+        assert_eq!(data.point(), expected);
+        assert_eq!(sensor.point(), expected);
+    }
+
+    #[test]
+    fn every_measurement_side_has_its_compass_bearing() {
+        let bearings = [
+            (MeasurementSide::Unknown, None),
+            (MeasurementSide::NorthBound, Some(0.0)),
+            (MeasurementSide::NorthEastBound, Some(45.0)),
+            (MeasurementSide::EastBound, Some(90.0)),
+            (MeasurementSide::SouthEastBound, Some(135.0)),
+            (MeasurementSide::SouthBound, Some(180.0)),
+            (MeasurementSide::SouthWestBound, Some(225.0)),
+            (MeasurementSide::WestBound, Some(270.0)),
+            (MeasurementSide::NorthWestBound, Some(315.0)),
+        ];
+
+        for (side, bearing) in bearings {
+            assert_eq!(side.to_bearing(), bearing, "{:?}", side);
         }
     }
 }
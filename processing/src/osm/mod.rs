@@ -0,0 +1,189 @@
+use std::{
+    collections::{HashMap, HashSet},
+    time::Instant,
+};
+
+use anyhow::Context;
+use console::style;
+use osmpbf::{Element, ElementReader};
+
+use crate::{
+    math::geo_distance,
+    parse::{Point, RoadData, RoadDirection},
+};
+
+const MPH_TO_KMH: f64 = 1.609344;
+
+struct RawWay {
+    refs: Vec<i64>,
+    direction: RoadDirection,
+    speed_limit: f64,
+    main_number: i32,
+}
+
+/// Read all `highway=*` ways from an OSM PBF extract.
+pub fn read_pbf(path: &str) -> anyhow::Result<Vec<RoadData>> {
+    println!("{} Reading ways...", style("[1/3]").bold().dim());
+    let start = Instant::now();
+
+    let mut ways = Vec::new();
+    ElementReader::from_path(path)
+        .with_context(|| format!("Failed to open {}", path))?
+        .for_each(|element| {
+            if let Element::Way(way) = element {
+                let tags = way.tags().collect::<HashMap<_, _>>();
+                if !tags.contains_key("highway") {
+                    return;
+                }
+
+                ways.push(RawWay {
+                    refs: way.refs().collect(),
+                    direction: parse_oneway(tags.get("oneway").copied()),
+                    speed_limit: tags
+                        .get("maxspeed")
+                        .and_then(|s| parse_maxspeed(s))
+                        .unwrap_or_default(),
+                    main_number: tags.get("ref").map(|s| parse_ref(s)).unwrap_or_default(),
+                });
+            }
+        })
+        .with_context(|| format!("Failed to read ways from {}", path))?;
+
+    println!(
+        "{} Reading node locations for {} ways...",
+        style("[2/3]").bold().dim(),
+        style(ways.len()).bold()
+    );
+
+    let wanted = ways
+        .iter()
+        .flat_map(|way| way.refs.iter().copied())
+        .collect::<HashSet<_>>();
+    let mut locations = HashMap::with_capacity(wanted.len());
+    ElementReader::from_path(path)
+        .with_context(|| format!("Failed to open {}", path))?
+        .for_each(|element| {
+            let (id, lat, lon) = match element {
+                Element::Node(node) => (node.id(), node.lat(), node.lon()),
+                Element::DenseNode(node) => (node.id(), node.lat(), node.lon()),
+                _ => return,
+            };
+            if wanted.contains(&id) {
+                locations.insert(
+                    id,
+                    Point {
+                        latitude: lat,
+                        longitude: lon,
+                    },
+                );
+            }
+        })
+        .with_context(|| format!("Failed to read nodes from {}", path))?;
+
+    println!("{} Building roads...", style("[3/3]").bold().dim());
+
+    let road_data = ways
+        .into_iter()
+        .filter_map(|way| {
+            let coordinates = way
+                .refs
+                .iter()
+                .filter_map(|id| locations.get(id).copied())
+                .collect::<Vec<_>>();
+            if coordinates.len() < 2 {
+                return None;
+            }
+
+            let length = coordinates.windows(2).fold(0.0, |acc, pair| {
+                acc + geo_distance(
+                    &[pair[0].latitude, pair[0].longitude],
+                    &[pair[1].latitude, pair[1].longitude],
+                )
+            });
+
+            Some((way, coordinates, length))
+        })
+        .enumerate()
+        .map(|(unique_id, (way, coordinates, length))| RoadData {
+            direction: way.direction,
+            main_number: way.main_number,
+            sub_number: 0,
+            coordinates,
+            length,
+            unique_id: unique_id as i32,
+            speed_limit: way.speed_limit,
+        })
+        .collect::<Vec<_>>();
+
+    println!(
+        "{:?} Parsed {} roads",
+        style(start.elapsed()).bold().dim().yellow(),
+        style(road_data.len()).bold(),
+    );
+
+    Ok(road_data)
+}
+
+fn parse_oneway(value: Option<&str>) -> RoadDirection {
+    match value {
+        Some("yes") | Some("1") | Some("true") => RoadDirection::Forward,
+        Some("-1") | Some("reverse") => RoadDirection::Backward,
+        _ => RoadDirection::Both,
+    }
+}
+
+/// Parse a `maxspeed` tag into km/h, returns `None` for non-numeric values such as `none` or `signals`
+fn parse_maxspeed(value: &str) -> Option<f64> {
+    let value = value.trim();
+    if let Some(mph) = value.strip_suffix("mph") {
+        return mph.trim().parse::<f64>().ok().map(|s| s * MPH_TO_KMH);
+    }
+
+    let kmh = value
+        .trim_end_matches("km/h")
+        .trim_end_matches("kmh")
+        .trim_end_matches("kph")
+        .trim();
+    kmh.parse().ok()
+}
+
+/// Take the leading number from a `ref` tag, e.g. `E 4` -> 4 or `222;225` -> 222
+fn parse_ref(value: &str) -> i32 {
+    value
+        .chars()
+        .skip_while(|c| !c.is_ascii_digit())
+        .take_while(|c| c.is_ascii_digit())
+        .collect::<String>()
+        .parse()
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn highway_ways_are_read_into_roads() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/roads.osm.pbf");
+
+        let roads = read_pbf(path).unwrap();
+
+        // The fixture has a one-way primary road, a residential road and a building
+        assert_eq!(roads.len(), 2);
+        let (primary, residential) = (&roads[0], &roads[1]);
+        assert_eq!(primary.direction, RoadDirection::Forward);
+        assert_eq!(primary.main_number, 4);
+        assert_eq!(primary.speed_limit, 70.0);
+        assert_eq!(primary.coordinates.len(), 3);
+        assert!((primary.coordinates[2].latitude - 59.001).abs() < 1e-7);
+        assert!((primary.coordinates[2].longitude - 18.001).abs() < 1e-7);
+        assert!(primary.length > 150.0 && primary.length < 180.0);
+        assert_eq!(residential.direction, RoadDirection::Both);
+        assert!((residential.speed_limit - 30.0 * MPH_TO_KMH).abs() < 1e-9);
+    }
+
+    #[test]
+    fn missing_file_is_an_error() {
+        assert!(read_pbf("./does-not-exist.osm.pbf").is_err());
+    }
+}
@@ -1,10 +1,15 @@
-use geo::Within;
-use petgraph::stable_graph::StableGraph;
+use anyhow::Context;
+use clap::{Args, ValueEnum};
+use geo::{ConcaveHull, LineString, MultiPoint, Polygon, Within};
+use petgraph::stable_graph::{StableDiGraph, StableGraph};
+use resvg::{tiny_skia, usvg};
 use svg::{node::element::path::Data, Document, Node};
 
 use crate::{
+    math::geo_distance,
     parse::Point,
     processing::{EdgeData, NodeData},
+    util::TileGrid,
 };
 
 #[derive(Debug, Clone, Copy, Default)]
@@ -23,30 +28,243 @@ const WITH_SIMPLE_PROJECTION: bool = true;
 
 const PROJECTION_LATITUDE: f64 = 59.323700;
 
-fn convert_point(point: Point, canvas_size: CanvasSize) -> (f64, f64) {
-    let lat_extent = canvas_size.max_lat - canvas_size.min_lat;
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Projection {
+    /// Latitude and longitude are mapped linearly to pixels
+    #[default]
+    Equirectangular,
+    WebMercator,
+}
+
+impl Projection {
+    /// Project a latitude to the vertical axis, in the same unit as the longitude
+    fn project_latitude(self, latitude: f64) -> f64 {
+        match self {
+            Projection::Equirectangular => latitude,
+            Projection::WebMercator => {
+                let phi = latitude.to_radians();
+                (std::f64::consts::FRAC_PI_4 + phi / 2.0)
+                    .tan()
+                    .ln()
+                    .to_degrees()
+            }
+        }
+    }
+}
+
+/// Color for values that are not finite, so data problems stand out instead of blending in
+pub const INVALID_COLOR: &str = "magenta";
+
+/// The multiples of **step** between **min** and **max**, or both bounds if there are none
+fn graticule_values(min: f64, max: f64, step: f64) -> Vec<f64> {
+    // Allow for rounding so lines on the bounds are not lost
+    let first = (min / step - 1e-9).ceil() as i64;
+    let last = (max / step + 1e-9).floor() as i64;
+    if first > last {
+        return vec![min, max];
+    }
+    (first..=last).map(|k| k as f64 * step).collect()
+}
+
+/// **value** in degrees with as many decimals as **step** needs
+fn format_degrees(value: f64, step: f64) -> String {
+    let decimals = (-step.log10().floor()).max(0.0) as usize;
+    format!("{:.*}°", decimals, value)
+}
+
+/// The color of **value** on **grad** as an `rgb()` string, clamped to the domain of the
+/// gradient, or [`INVALID_COLOR`] if the value is NaN or infinite
+pub fn gradient_color(grad: &colorgrad::Gradient, value: f64) -> String {
+    if !value.is_finite() {
+        return INVALID_COLOR.to_string();
+    }
+
+    let (min, max) = grad.domain();
+    let color = grad.at(value.clamp(min, max));
+    format!(
+        "rgb({}, {}, {})",
+        color.r * 255.0,
+        color.g * 255.0,
+        color.b * 255.0
+    )
+}
+
+/// Color gradient presets for maps colored by a continuous value
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum GradientPreset {
+    /// Gold over hot pink to dark turquoise
+    #[default]
+    Default,
+    Viridis,
+    Magma,
+    Turbo,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct GradientSpec {
+    #[clap(long, default_value = "default")]
+    pub gradient: GradientPreset,
+    /// Comma separated color stops, used instead of the preset when set
+    #[clap(long, value_delimiter = ',')]
+    pub gradient_colors: Vec<colorgrad::Color>,
+}
+
+/// Build the gradient described by **spec**, spanning **min** to **max**
+pub fn build_gradient(spec: &GradientSpec, min: f64, max: f64) -> colorgrad::Gradient {
+    let colors = if !spec.gradient_colors.is_empty() {
+        spec.gradient_colors.clone()
+    } else {
+        // Presets span 0 to 1, so sample them into stops that can be given a domain
+        let preset = match spec.gradient {
+            GradientPreset::Default => {
+                return colorgrad::CustomGradient::new()
+                    .html_colors(&["gold", "hotpink", "darkturquoise"])
+                    .domain(&[min, max])
+                    .build()
+                    .unwrap();
+            }
+            GradientPreset::Viridis => colorgrad::viridis(),
+            GradientPreset::Magma => colorgrad::magma(),
+            GradientPreset::Turbo => colorgrad::turbo(),
+        };
+        preset.colors(16)
+    };
+
+    colorgrad::CustomGradient::new()
+        .colors(&colors)
+        .domain(&[min, max])
+        .build()
+        .unwrap()
+}
+
+/// How a drawing command draws and saves its canvas
+#[derive(Debug, Clone, Copy, Args)]
+#[clap(about = None, long_about = None)]
+pub struct CanvasOptions {
+    /// Map projection used when drawing
+    #[clap(long, default_value = "equirectangular")]
+    pub projection: Projection,
+    /// Refuse to draw graphs with more nodes or edges than this
+    #[clap(long, default_value = "1000000")]
+    pub max_nodes: usize,
+    /// Save the drawing as a grid of cols,rows tiles instead of one file
+    #[clap(long)]
+    pub tiles: Option<TileGrid>,
+}
+
+impl CanvasOptions {
+    /// A canvas over the whole graph
+    pub fn canvas(&self, width: u32, graph: &StableGraph<NodeData, EdgeData>) -> Canvas {
+        Canvas::from_graph(width, graph, self.projection)
+    }
+
+    /// Fail with advice on shrinking the graph if it has too many nodes or edges to draw, as the
+    /// drawing would be too large to write or open
+    pub fn check_drawable(&self, graph: &StableDiGraph<NodeData, EdgeData>) -> anyhow::Result<()> {
+        if graph.node_count() > self.max_nodes || graph.edge_count() > self.max_nodes {
+            anyhow::bail!(
+                "Graph has {} nodes and {} edges, more than the limit of {} for drawing. Crop it \
+                 with --bbox, process it with --simplify, or raise --max-nodes",
+                graph.node_count(),
+                graph.edge_count(),
+                self.max_nodes
+            );
+        }
+        Ok(())
+    }
+
+    /// Save **canvas** to **path**, split into tiles if asked for
+    pub fn save(&self, canvas: &Canvas, path: &str) {
+        match self.tiles {
+            Some(tiles) => {
+                canvas.tile_save(path, tiles);
+            }
+            None => canvas.save(path),
+        }
+    }
+}
+
+/// The pixel area a drawn element covers
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct PixelBounds {
+    min_x: f64,
+    min_y: f64,
+    max_x: f64,
+    max_y: f64,
+}
+
+impl PixelBounds {
+    /// The smallest area holding every one of **points**, grown by **margin** on each side
+    fn around(points: &[(f64, f64)], margin: f64) -> Self {
+        let (min_x, min_y, max_x, max_y) = points.iter().fold(
+            (f64::MAX, f64::MAX, f64::MIN, f64::MIN),
+            |(min_x, min_y, max_x, max_y), (x, y)| {
+                (min_x.min(*x), min_y.min(*y), max_x.max(*x), max_y.max(*y))
+            },
+        );
+        PixelBounds {
+            min_x: min_x - margin,
+            min_y: min_y - margin,
+            max_x: max_x + margin,
+            max_y: max_y + margin,
+        }
+    }
+
+    /// Roughly the area of **label** written at **x**, **y**, as the text is measured by the
+    /// renderer and may be anchored at its start or middle
+    fn text(x: f64, y: f64, label: &str, font_size: f64) -> Self {
+        let width = label.chars().count() as f64 * font_size * 0.6;
+        PixelBounds {
+            min_x: x - width,
+            min_y: y - font_size,
+            max_x: x + width,
+            max_y: y + font_size,
+        }
+    }
+
+    fn meets(&self, other: &PixelBounds) -> bool {
+        self.min_x <= other.max_x
+            && self.max_x >= other.min_x
+            && self.min_y <= other.max_y
+            && self.max_y >= other.min_y
+    }
+}
+
+pub(crate) fn convert_point(point: Point, canvas_size: CanvasSize) -> (f64, f64) {
+    let projection = canvas_size.projection;
+    let min_y = projection.project_latitude(canvas_size.min_lat);
+    let y_extent = projection.project_latitude(canvas_size.max_lat) - min_y;
     let lon_extent = canvas_size.max_lon - canvas_size.min_lon;
 
     let x = ((point.longitude - canvas_size.min_lon) / lon_extent) * canvas_size.width as f64;
-    //let y = ((point.latitude - canvas_size.min_lat) / lat_extent) * canvas_size.height as f32;
 
     let y = canvas_size.height as f64
-        - ((point.latitude - canvas_size.min_lat) / lat_extent) * canvas_size.height as f64;
+        - ((projection.project_latitude(point.latitude) - min_y) / y_extent)
+            * canvas_size.height as f64;
 
     (x, y)
 }
 
-pub fn calc_canvas_size_from_extents(width: u32, extents: [f64; 4]) -> CanvasSize {
+pub fn calc_canvas_size_from_extents(
+    width: u32,
+    extents: [f64; 4],
+    projection: Projection,
+) -> CanvasSize {
     let min_lat = extents[0];
     let max_lat = extents[1];
     let min_lon = extents[2];
     let max_lon = extents[3];
 
-    let height = if WITH_SIMPLE_PROJECTION {
-        let ratio = 1.0 / PROJECTION_LATITUDE.to_radians().cos();
-        (width as f64 * (max_lat - min_lat) / (max_lon - min_lon) * ratio) as u32
-    } else {
-        (width as f64 * (max_lat - min_lat) / (max_lon - min_lon)) as u32
+    let height = match projection {
+        Projection::Equirectangular if WITH_SIMPLE_PROJECTION => {
+            let ratio = 1.0 / PROJECTION_LATITUDE.to_radians().cos();
+            (width as f64 * (max_lat - min_lat) / (max_lon - min_lon) * ratio) as u32
+        }
+        _ => {
+            let y_extent =
+                projection.project_latitude(max_lat) - projection.project_latitude(min_lat);
+            (width as f64 * y_extent / (max_lon - min_lon)) as u32
+        }
     };
 
     CanvasSize {
@@ -56,10 +274,84 @@ pub fn calc_canvas_size_from_extents(width: u32, extents: [f64; 4]) -> CanvasSiz
         max_lat,
         min_lon,
         max_lon,
+        projection,
+    }
+}
+
+/// A canvas size framing **radius** meters around **center** in every direction
+pub fn calc_canvas_size_around(
+    width: u32,
+    center: Point,
+    radius: f64,
+    projection: Projection,
+) -> CanvasSize {
+    let offset = |d_lat: f64, d_lon: f64| {
+        geo_distance(
+            &[center.latitude, center.longitude],
+            &[center.latitude + d_lat, center.longitude + d_lon],
+        )
+    };
+    let north = degrees_spanning(radius, |d| offset(d, 0.0));
+    let south = degrees_spanning(radius, |d| offset(-d, 0.0));
+    let east = degrees_spanning(radius, |d| offset(0.0, d));
+
+    calc_canvas_size_from_extents(
+        width,
+        [
+            center.latitude - south,
+            center.latitude + north,
+            center.longitude - east,
+            center.longitude + east,
+        ],
+        projection,
+    )
+}
+
+/// The offset in degrees at which **distance_at** reaches **meters**, found by rescaling the
+/// offset by how far off its distance is, as the distance per degree barely changes over a frame
+fn degrees_spanning(meters: f64, distance_at: impl Fn(f64) -> f64) -> f64 {
+    let mut degrees = 1e-3 * meters / distance_at(1e-3);
+    for _ in 0..FRAME_ITERATIONS {
+        degrees *= meters / distance_at(degrees);
+    }
+    degrees
+}
+
+/// Refinements of the offset of each side of a framed canvas, it converges within a few
+const FRAME_ITERATIONS: usize = 4;
+
+/// Frame a drawing around a point instead of the whole graph
+#[derive(Debug, Clone, Copy, Args)]
+#[clap(about = None, long_about = None)]
+pub struct Framing {
+    /// Center of the frame as lat,lon
+    #[clap(long, requires = "radius")]
+    pub center: Option<Point>,
+    /// Distance in meters from the center to the sides of the frame
+    #[clap(long, requires = "center")]
+    pub radius: Option<f64>,
+}
+
+impl Framing {
+    /// A canvas framing the center and radius if set, otherwise the whole graph
+    pub fn canvas(
+        &self,
+        width: u32,
+        graph: &StableGraph<NodeData, EdgeData>,
+        projection: Projection,
+    ) -> Canvas {
+        match (self.center, self.radius) {
+            (Some(center), Some(radius)) => Canvas::from_center(width, center, radius, projection),
+            _ => Canvas::from_graph(width, graph, projection),
+        }
     }
 }
 
-pub fn calc_canvas_size(width: u32, graph: &StableGraph<NodeData, EdgeData>) -> CanvasSize {
+pub fn calc_canvas_size(
+    width: u32,
+    graph: &StableGraph<NodeData, EdgeData>,
+    projection: Projection,
+) -> CanvasSize {
     let points = graph.node_weights().collect::<Vec<_>>();
 
     let min_lat = points
@@ -83,7 +375,95 @@ pub fn calc_canvas_size(width: u32, graph: &StableGraph<NodeData, EdgeData>) ->
         .max_by(|a, b| a.partial_cmp(b).unwrap())
         .unwrap();
 
-    calc_canvas_size_from_extents(width, [min_lat, max_lat, min_lon, max_lon])
+    calc_canvas_size_from_extents(width, [min_lat, max_lat, min_lon, max_lon], projection)
+}
+
+/// Write the graph as a GeoJSON FeatureCollection, with a LineString for every edge and a Point for
+/// every node with a sensor
+pub fn export_geojson(graph: &StableDiGraph<NodeData, EdgeData>, path: &str) -> anyhow::Result<()> {
+    let lon_lat = |point: &Point| [point.longitude, point.latitude];
+
+    let edges = graph.edge_indices().map(|edge| {
+        let data = graph.edge_weight(edge).unwrap();
+        let coordinates = if data.polyline.is_empty() {
+            // Connectors have no polyline, use a straight line between their endpoints
+            let (start, end) = graph.edge_endpoints(edge).unwrap();
+            vec![
+                lon_lat(&graph.node_weight(start).unwrap().point),
+                lon_lat(&graph.node_weight(end).unwrap().point),
+            ]
+        } else {
+            data.polyline.iter().map(lon_lat).collect()
+        };
+
+        serde_json::json!({
+            "type": "Feature",
+            "geometry": {
+                "type": "LineString",
+                "coordinates": coordinates,
+            },
+            "properties": {
+                "main_number": data.main_number,
+                "sub_number": data.sub_number,
+                "original_road_id": data.original_road_id,
+                "distance": data.distance,
+                "speed_limit": data.speed_limit,
+                "is_connector": data.is_connector,
+            },
+        })
+    });
+
+    let sensors = graph
+        .node_indices()
+        .filter(|node| graph.node_weight(*node).unwrap().has_sensor)
+        .map(|node| {
+            let data = graph.node_weight(node).unwrap();
+            serde_json::json!({
+                "type": "Feature",
+                "geometry": {
+                    "type": "Point",
+                    "coordinates": lon_lat(&data.point),
+                },
+                "properties": {
+                    "node": node.index(),
+                    "main_number": data.main_number,
+                    "sub_number": data.sub_number,
+                    "heading": data.heading,
+                },
+            })
+        });
+
+    let collection = serde_json::json!({
+        "type": "FeatureCollection",
+        "features": edges.chain(sensors).collect::<Vec<_>>(),
+    });
+    std::fs::write(path, serde_json::to_string(&collection)?)
+        .with_context(|| format!("Failed to write GeoJSON to {}", path))
+}
+
+/// How closely isochrone polygons follow the reachable points, lower is tighter and higher
+/// approaches the convex hull
+const ISOCHRONE_CONCAVITY: f64 = 2.0;
+
+/// One concave hull per threshold of the points reachable within it, with **reachable_points**
+/// being each point and the time it is reached in. A threshold with fewer than three reachable
+/// points gets an empty polygon. Each hull is built from a superset of the points of every
+/// smaller threshold, so sorted thresholds give nested polygons.
+pub fn isochrone_polygons(reachable_points: &[(Point, f64)], thresholds: &[f64]) -> Vec<Polygon> {
+    thresholds
+        .iter()
+        .map(|threshold| {
+            let points = reachable_points
+                .iter()
+                .filter(|(_, time)| time <= threshold)
+                .map(|(point, _)| (point.longitude, point.latitude))
+                .collect::<Vec<_>>();
+            if points.len() < 3 {
+                return Polygon::new(LineString::new(vec![]), vec![]);
+            }
+            MultiPoint::from(points).concave_hull(ISOCHRONE_CONCAVITY)
+        })
+        .collect()
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -94,12 +474,15 @@ pub struct CanvasSize {
     pub max_lat: f64,
     pub min_lon: f64,
     pub max_lon: f64,
+    pub projection: Projection,
 }
 
 #[derive(Debug, Clone)]
 pub struct Canvas {
     pub size: CanvasSize,
     pub document: Document,
+    /// The area covered by each child of the document, so tiles only hold what is on them
+    bounds: Vec<PixelBounds>,
 }
 
 #[derive(Debug, Clone)]
@@ -125,33 +508,48 @@ impl<'a> Default for DrawOptions {
 
 impl Canvas {
     pub fn new(size: CanvasSize) -> Self {
-        let document = Document::new()
-            .set("viewBox", (0, 0, size.width, size.height))
-            .add(
-                svg::node::element::Rectangle::new()
-                    .set("width", size.width)
-                    .set("height", size.height)
-                    .set("fill", "#1f1f1f"),
-            );
-
-        Canvas { size, document }
+        Canvas::new_with_background(size, "#1f1f1f")
     }
 
     pub fn new_with_background(size: CanvasSize, color: &str) -> Self {
-        let document = Document::new()
-            .set("viewBox", (0, 0, size.width, size.height))
-            .add(
-                svg::node::element::Rectangle::new()
-                    .set("width", size.width)
-                    .set("height", size.height)
-                    .set("fill", color),
-            );
+        let mut canvas = Canvas {
+            size,
+            document: Document::new().set("viewBox", (0, 0, size.width, size.height)),
+            bounds: Vec::new(),
+        };
+        canvas.add(
+            svg::node::element::Rectangle::new()
+                .set("width", size.width)
+                .set("height", size.height)
+                .set("fill", color),
+            PixelBounds::around(&[(0.0, 0.0), (size.width as f64, size.height as f64)], 0.0),
+        );
+        canvas
+    }
+
+    /// Append **node** to the document, covering **bounds**
+    fn add(&mut self, node: impl Into<Box<dyn Node>>, bounds: PixelBounds) {
+        self.document.append(node);
+        self.bounds.push(bounds);
+    }
 
-        Canvas { size, document }
+    pub fn from_graph(
+        width: u32,
+        graph: &StableGraph<NodeData, EdgeData>,
+        projection: Projection,
+    ) -> Self {
+        let size = calc_canvas_size(width, graph, projection);
+        Canvas::new(size)
     }
 
-    pub fn from_graph(width: u32, graph: &StableGraph<NodeData, EdgeData>) -> Self {
-        let size = calc_canvas_size(width, graph);
+    /// A canvas spanning **radius_meters** around **center**, anything outside is clipped
+    pub fn from_center(
+        width: u32,
+        center: Point,
+        radius_meters: f64,
+        projection: Projection,
+    ) -> Self {
+        let size = calc_canvas_size_around(width, center, radius_meters, projection);
         Canvas::new(size)
     }
 
@@ -160,12 +558,13 @@ impl Canvas {
         if !point.within(&self.size) {
             return;
         }
-        self.document.append(
+        self.add(
             svg::node::element::Circle::new()
                 .set("cx", x)
                 .set("cy", y)
                 .set("r", size)
                 .set("fill", color),
+            PixelBounds::around(&[(x, y)], size as f64),
         );
     }
 
@@ -177,24 +576,20 @@ impl Canvas {
         if points.len() < 2 {
             return;
         }
-        let mut path = Data::new();
-        let mut iter = points.iter();
-        let point = iter.next().unwrap();
-        path = path.move_to(convert_point(*point, self.size));
-        let mut any_inside = false;
-        for point in iter {
-            let (x, y) = convert_point(*point, self.size);
-            if !any_inside && self.contains_point(x, y) {
-                any_inside = true;
-            }
-            path = path.line_to(convert_point(*point, self.size));
-        }
-
-        if !any_inside {
+        if !self.overlaps(&points) {
             return;
         }
+        let pixels = points
+            .iter()
+            .map(|point| convert_point(*point, self.size))
+            .collect::<Vec<_>>();
+        let mut path = Data::new().move_to(pixels[0]);
+        for pixel in pixels.iter().skip(1) {
+            path = path.line_to(*pixel);
+        }
 
-        self.document.append(
+        let bounds = PixelBounds::around(&pixels, opts.stroke as f64 / 2.0);
+        self.add(
             svg::node::element::Path::new()
                 .set("fill", "none")
                 .set("stroke", opts.color)
@@ -203,6 +598,7 @@ impl Canvas {
                 .set("stroke-linejoin", opts.stroke_linejoin)
                 .set("stroke-dasharray", opts.stroke_dasharray)
                 .set("d", path),
+            bounds,
         );
     }
 
@@ -226,22 +622,297 @@ impl Canvas {
             .line_to((x2, y2))
             .line_to((x3, y3))
             .close();
-        self.document.append(
+        self.add(
             svg::node::element::Path::new()
                 .set("fill", color)
                 .set("d", path),
+            PixelBounds::around(&[(x, y), (x1, y1), (x2, y2), (x3, y3)], 0.0),
         );
     }
 
     pub fn text(&mut self, point: Point, text: &str) {
         let (x, y) = convert_point(point, self.size);
 
-        self.document.append(
+        self.add(
             svg::node::element::Text::new(text)
                 .set("x", x)
                 .set("y", y)
                 .set("font-size", 1)
                 .set("fill", "white"),
+            PixelBounds::text(x, y, text, 1.0),
+        );
+    }
+
+    /// The number of pixels per meter along the horizontal axis at the center latitude
+    pub fn pixels_per_meter(&self) -> f64 {
+        let latitude = (self.size.min_lat + self.size.max_lat) / 2.0;
+        let meters = geo_distance(
+            &[latitude, self.size.min_lon],
+            &[latitude, self.size.max_lon],
+        );
+        self.size.width as f64 / meters
+    }
+
+    /// Draw a bar **meters** long with its length as label in the bottom left corner
+    pub fn draw_scale_bar(&mut self, meters: f64) {
+        let font_size = self.size.width as f64 / 100.0;
+        let padding = font_size;
+        let width = meters * self.pixels_per_meter();
+        let y = self.size.height as f64 - padding;
+        let tick = font_size / 2.0;
+
+        let path = Data::new()
+            .move_to((padding, y - tick))
+            .line_to((padding, y))
+            .line_to((padding + width, y))
+            .line_to((padding + width, y - tick));
+        self.add(
+            svg::node::element::Path::new()
+                .set("fill", "none")
+                .set("stroke", "white")
+                .set("stroke-width", font_size / 4.0)
+                .set("d", path),
+            PixelBounds::around(
+                &[(padding, y - tick), (padding + width, y)],
+                font_size / 8.0,
+            ),
+        );
+
+        let label = if meters >= 1000.0 {
+            format!("{} km", meters / 1000.0)
+        } else {
+            format!("{} m", meters)
+        };
+        let bounds = PixelBounds::text(padding + width / 2.0, y - tick, &label, font_size);
+        self.add(
+            svg::node::element::Text::new(label)
+                .set("x", padding + width / 2.0)
+                .set("y", y - tick)
+                .set("text-anchor", "middle")
+                .set("font-size", font_size)
+                .set("fill", "white"),
+            bounds,
+        );
+    }
+
+    /// Draw an arrow **length** pixels long from **start** towards the compass **bearing** in degrees
+    pub fn draw_arrow(&mut self, start: Point, bearing: f64, color: &str, length: f64) {
+        let (x, y) = convert_point(start, self.size);
+        let bearing = bearing.to_radians();
+        // Bearings are clockwise from north, and the y axis points down
+        let direction = (bearing.sin(), -bearing.cos());
+        let tip = (x + direction.0 * length, y + direction.1 * length);
+        let head = length / 3.0;
+        let side = (-direction.1 * head / 2.0, direction.0 * head / 2.0);
+        let base = (tip.0 - direction.0 * head, tip.1 - direction.1 * head);
+
+        let path = Data::new()
+            .move_to((x, y))
+            .line_to(tip)
+            .move_to((base.0 + side.0, base.1 + side.1))
+            .line_to(tip)
+            .line_to((base.0 - side.0, base.1 - side.1));
+        self.add(
+            svg::node::element::Path::new()
+                .set("fill", "none")
+                .set("stroke", color)
+                .set("stroke-width", length / 8.0)
+                .set("d", path),
+            PixelBounds::around(&[(x, y), tip], head),
+        );
+    }
+
+    /// Draw an arrow pointing north in the top right corner
+    pub fn draw_north_arrow(&mut self) {
+        let font_size = self.size.width as f64 / 100.0;
+        let x = self.size.width as f64 - font_size * 2.0;
+        let top = font_size * 2.5;
+        let bottom = top + font_size * 3.0;
+
+        let path = Data::new()
+            .move_to((x, top))
+            .line_to((x + font_size, bottom))
+            .line_to((x, bottom - font_size))
+            .line_to((x - font_size, bottom))
+            .close();
+        self.add(
+            svg::node::element::Path::new()
+                .set("fill", "white")
+                .set("d", path),
+            PixelBounds::around(&[(x, top), (x, bottom)], font_size),
+        );
+        self.add(
+            svg::node::element::Text::new("N")
+                .set("x", x)
+                .set("y", top - font_size / 2.0)
+                .set("text-anchor", "middle")
+                .set("font-size", font_size)
+                .set("fill", "white"),
+            PixelBounds::text(x, top - font_size / 2.0, "N", font_size),
+        );
+    }
+
+    /// Draw faint lines every **lat_step** and **lon_step** degrees across the canvas, labelled with
+    /// their coordinate. If a step is larger than the extent the edges of the canvas are drawn.
+    pub fn draw_graticule(&mut self, lat_step: f64, lon_step: f64) {
+        let font_size = self.size.width as f64 / 150.0;
+        let padding = font_size / 2.0;
+        let width = self.size.width as f64;
+        let height = self.size.height as f64;
+
+        for latitude in graticule_values(self.size.min_lat, self.size.max_lat, lat_step) {
+            let (_, y) = convert_point(
+                Point {
+                    latitude,
+                    longitude: self.size.min_lon,
+                },
+                self.size,
+            );
+            self.draw_graticule_line((0.0, y), (width, y));
+            self.draw_graticule_label(
+                &format_degrees(latitude, lat_step),
+                padding,
+                (y - padding).clamp(font_size, height - padding),
+                font_size,
+            );
+        }
+
+        for longitude in graticule_values(self.size.min_lon, self.size.max_lon, lon_step) {
+            let (x, _) = convert_point(
+                Point {
+                    latitude: self.size.min_lat,
+                    longitude,
+                },
+                self.size,
+            );
+            self.draw_graticule_line((x, 0.0), (x, height));
+            let label = format_degrees(longitude, lon_step);
+            // Roughly the width of the label, so it is moved left of lines near the right edge
+            let label_width = label.len() as f64 * font_size * 0.6;
+            self.draw_graticule_label(
+                &label,
+                (x + padding).clamp(padding, width - label_width - padding),
+                height - padding,
+                font_size,
+            );
+        }
+    }
+
+    fn draw_graticule_line(&mut self, start: (f64, f64), end: (f64, f64)) {
+        let stroke = self.size.width as f64 / 2000.0;
+        self.add(
+            svg::node::element::Path::new()
+                .set("fill", "none")
+                .set("stroke", "white")
+                .set("stroke-opacity", 0.25)
+                .set("stroke-width", stroke)
+                .set("d", Data::new().move_to(start).line_to(end)),
+            PixelBounds::around(&[start, end], stroke),
+        );
+    }
+
+    fn draw_graticule_label(&mut self, label: &str, x: f64, y: f64, font_size: f64) {
+        self.add(
+            svg::node::element::Text::new(label)
+                .set("x", x)
+                .set("y", y)
+                .set("font-size", font_size)
+                .set("fill", "white")
+                .set("fill-opacity", 0.6),
+            PixelBounds::text(x, y, label, font_size),
+        );
+    }
+
+    /// Draw a legend in the top left corner from **entries** of a label and its color, with a line
+    /// of each color next to its label. Labels too long for the canvas are shortened, and entries
+    /// that do not fit below each other are summarized in a last line
+    pub fn draw_legend(&mut self, entries: Vec<(String, String)>) {
+        let font_size = self.size.width as f64 / 100.0;
+        let padding = font_size / 2.0;
+        let line_height = font_size * 1.5;
+        let swatch_width = font_size * 1.5;
+        // Approximate the label width, as the text is measured by the renderer
+        let char_width = font_size * 0.6;
+
+        let max_chars = ((self.size.width as f64 - padding * 5.0 - swatch_width) / char_width)
+            .max(1.0) as usize;
+        let max_lines = ((self.size.height as f64 - padding * 4.0) / line_height).max(1.0) as usize;
+
+        let mut lines = entries
+            .iter()
+            .map(|(label, color)| (Some(color.as_str()), legend_label(label, max_chars)))
+            .collect::<Vec<_>>();
+        if lines.len() > max_lines {
+            let hidden = lines.len() - max_lines + 1;
+            lines.truncate(max_lines - 1);
+            lines.push((
+                None,
+                legend_label(&format!("...and {} more", hidden), max_chars),
+            ));
+        }
+
+        let longest_label = lines
+            .iter()
+            .map(|(_, label)| label.chars().count())
+            .max()
+            .unwrap_or(0);
+        let width = padding * 3.0 + swatch_width + longest_label as f64 * char_width;
+        let height = padding * 2.0 + lines.len() as f64 * line_height;
+        // Every part of the legend is inside its background
+        let bounds = PixelBounds::around(
+            &[(padding, padding), (padding + width, padding + height)],
+            0.0,
+        );
+        self.add(
+            svg::node::element::Rectangle::new()
+                .set("x", padding)
+                .set("y", padding)
+                .set("width", width)
+                .set("height", height)
+                .set("fill", "black")
+                .set("fill-opacity", 0.6),
+            bounds,
+        );
+
+        for (idx, (color, label)) in lines.into_iter().enumerate() {
+            let y = padding * 2.0 + idx as f64 * line_height + line_height / 2.0;
+            if let Some(color) = color {
+                let path = Data::new()
+                    .move_to((padding * 2.0, y))
+                    .line_to((padding * 2.0 + swatch_width, y));
+                self.add(
+                    svg::node::element::Path::new()
+                        .set("fill", "none")
+                        .set("stroke", color)
+                        .set("stroke-width", font_size / 4.0)
+                        .set("d", path),
+                    bounds,
+                );
+            }
+            self.add(
+                svg::node::element::Text::new(label)
+                    .set("x", padding * 3.0 + swatch_width)
+                    .set("y", y + font_size / 3.0)
+                    .set("font-size", font_size)
+                    .set("fill", "white"),
+                bounds,
+            );
+        }
+    }
+
+    /// Fill the area between two opposite corners
+    pub fn draw_rect(&mut self, a: Point, b: Point, color: &str, opacity: f32) {
+        let (x1, y1) = convert_point(a, self.size);
+        let (x2, y2) = convert_point(b, self.size);
+        self.add(
+            svg::node::element::Rectangle::new()
+                .set("x", x1.min(x2))
+                .set("y", y1.min(y2))
+                .set("width", (x2 - x1).abs())
+                .set("height", (y2 - y1).abs())
+                .set("fill", color)
+                .set("fill-opacity", opacity),
+            PixelBounds::around(&[(x1, y1), (x2, y2)], 0.0),
         );
     }
 
@@ -252,21 +923,92 @@ impl Canvas {
             .line_to((x + size, y + size))
             .move_to((x - size, y + size))
             .line_to((x + size, y - size));
-        self.document.append(
+        self.add(
             svg::node::element::Path::new()
                 .set("fill", "none")
                 .set("stroke", color)
                 .set("stroke-width", size)
                 .set("d", path),
+            PixelBounds::around(&[(x, y)], size * 1.5),
         );
     }
 
+    /// Save the canvas as a PNG if the path ends in `.png`, otherwise as an SVG
     pub fn save(&self, path: &str) {
-        svg::save(path, &self.document).unwrap();
+        if path.ends_with(".png") {
+            self.save_png(path, 1.0);
+        } else {
+            svg::save(path, &self.document).unwrap();
+        }
     }
 
-    pub fn contains_point(&self, x: f64, y: f64) -> bool {
-        x >= 0.0 && x <= self.size.width as f64 && y >= 0.0 && y <= self.size.height as f64
+    /// Save the canvas as a grid of **tiles** named `<stem>_<row>_<col>` with the extension of
+    /// **path**, so as PNGs or SVGs like [`Canvas::save`]. Each tile has a view box over its part
+    /// of the canvas and only holds the elements on that part. Returns the paths of the tiles
+    pub fn tile_save(&self, path: &str, tiles: TileGrid) -> Vec<String> {
+        let path = std::path::Path::new(path);
+        let stem = path.with_extension("").to_string_lossy().into_owned();
+        let extension = path
+            .extension()
+            .map_or("svg".into(), |extension| extension.to_string_lossy());
+        let tile_width = self.size.width as f64 / tiles.cols as f64;
+        let tile_height = self.size.height as f64 / tiles.rows as f64;
+
+        let mut paths = Vec::new();
+        for row in 0..tiles.rows {
+            for col in 0..tiles.cols {
+                let (x, y) = (col as f64 * tile_width, row as f64 * tile_height);
+                let tile = PixelBounds::around(&[(x, y), (x + tile_width, y + tile_height)], 0.0);
+
+                let mut document = Document::new();
+                for (name, value) in self.document.get_attributes() {
+                    document.assign(name.clone(), value.clone());
+                }
+                document.assign("viewBox", (x, y, tile_width, tile_height));
+                for (child, bounds) in self.document.get_children().iter().zip(&self.bounds) {
+                    if bounds.meets(&tile) {
+                        document.append(child.clone());
+                    }
+                }
+
+                let path = format!("{}_{}_{}.{}", stem, row, col, extension);
+                if extension == "png" {
+                    render_png(&document, &path, 1.0);
+                } else {
+                    svg::save(&path, &document).unwrap();
+                }
+                paths.push(path);
+            }
+        }
+        paths
+    }
+
+    /// Rasterize the canvas to a PNG, scaling its size by **scale**
+    pub fn save_png(&self, path: &str, scale: f32) {
+        render_png(&self.document, path, scale);
+    }
+
+    /// Whether any part of the polyline through **points** can be on the canvas, checked on its
+    /// bounding box so segments crossing the canvas without a point inside are kept
+    fn overlaps(&self, points: &[Point]) -> bool {
+        if points.iter().any(|point| point.within(&self.size)) {
+            return true;
+        }
+        let (min_lat, max_lat, min_lon, max_lon) = points.iter().fold(
+            (f64::MAX, f64::MIN, f64::MAX, f64::MIN),
+            |(min_lat, max_lat, min_lon, max_lon), point| {
+                (
+                    min_lat.min(point.latitude),
+                    max_lat.max(point.latitude),
+                    min_lon.min(point.longitude),
+                    max_lon.max(point.longitude),
+                )
+            },
+        );
+        min_lat <= self.size.max_lat
+            && max_lat >= self.size.min_lat
+            && min_lon <= self.size.max_lon
+            && max_lon >= self.size.min_lon
     }
 
     pub fn get_node_count(&self) -> usize {
@@ -278,3 +1020,313 @@ impl Canvas {
             .assign("style", format!("background-color: {}", color));
     }
 }
+
+/// Rasterize **document** to a PNG, scaling its size by **scale**
+fn render_png(document: &Document, path: &str, scale: f32) {
+    let mut options = usvg::Options::default();
+    options.fontdb_mut().load_system_fonts();
+    let tree = usvg::Tree::from_str(&document.to_string(), &options).unwrap();
+
+    let size = tree
+        .size()
+        .to_int_size()
+        .scale_by(scale)
+        .expect("Invalid PNG scale");
+    let mut pixmap = tiny_skia::Pixmap::new(size.width(), size.height()).unwrap();
+    resvg::render(
+        &tree,
+        tiny_skia::Transform::from_scale(scale, scale),
+        &mut pixmap.as_mut(),
+    );
+    pixmap.save_png(path).unwrap();
+}
+
+/// Shorten **label** to at most **max_chars** characters, ending it with an ellipsis if cut
+fn legend_label(label: &str, max_chars: usize) -> String {
+    if label.chars().count() <= max_chars {
+        label.to_string()
+    } else {
+        let mut label = label.chars().take(max_chars - 1).collect::<String>();
+        label.push('…');
+        label
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_graphs::{add_road, grid, node, point, temp_path, TestGraph};
+
+    #[test]
+    fn gradient_color_clamps_to_the_domain_and_flags_invalid_values() {
+        let grad = colorgrad::CustomGradient::new()
+            .html_colors(&["black", "white"])
+            .domain(&[0.0, 100.0])
+            .build()
+            .unwrap();
+
+        for value in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            assert_eq!(gradient_color(&grad, value), INVALID_COLOR);
+        }
+        assert_eq!(gradient_color(&grad, -10.0), gradient_color(&grad, 0.0));
+        assert_eq!(gradient_color(&grad, 0.0), "rgb(0, 0, 0)");
+        assert_eq!(gradient_color(&grad, 1000.0), gradient_color(&grad, 100.0));
+        assert_eq!(gradient_color(&grad, 100.0), "rgb(255, 255, 255)");
+    }
+
+    #[test]
+    fn scale_bar_is_as_wide_as_its_length_in_pixels() {
+        // 10km across, so a 1km bar spans a tenth of the 1000px width
+        let mut canvas =
+            Canvas::from_center(1000, point(59.33, 18.06), 5000.0, Projection::default());
+        canvas.draw_scale_bar(1000.0);
+
+        let font_size = canvas.size.width as f64 / 100.0;
+        let bar = &canvas.bounds[canvas.bounds.len() - 2];
+        let width = bar.max_x - bar.min_x - font_size / 4.0;
+        assert!((width - 1000.0 * canvas.pixels_per_meter()).abs() < 1e-9);
+        assert!((width - 100.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn tiles_together_cover_the_canvas() {
+        let canvas = Canvas::from_graph(1000, &grid(3, 4).0, Projection::default());
+        let path = temp_path("tiles.svg");
+
+        let tiles = canvas.tile_save(&path, TileGrid { cols: 2, rows: 2 });
+
+        assert_eq!(tiles.len(), 4);
+        let (mut min_x, mut min_y, mut max_x, mut max_y) = (f64::MAX, f64::MAX, f64::MIN, f64::MIN);
+        let mut area = 0.0;
+        for tile in tiles {
+            let raw = std::fs::read_to_string(&tile).unwrap();
+            let view_box = raw.split("viewBox=\"").nth(1).unwrap();
+            let [x, y, width, height] = view_box[..view_box.find('"').unwrap()]
+                .split(' ')
+                .map(|value| value.parse::<f64>().unwrap())
+                .collect::<Vec<_>>()[..]
+            else {
+                panic!("Malformed view box in {}", tile);
+            };
+            (min_x, min_y) = (min_x.min(x), min_y.min(y));
+            (max_x, max_y) = (max_x.max(x + width), max_y.max(y + height));
+            area += width * height;
+        }
+
+        let (width, height) = (canvas.size.width as f64, canvas.size.height as f64);
+        assert_eq!((min_x, min_y, max_x, max_y), (0.0, 0.0, width, height));
+        assert!((area - width * height).abs() < 1e-6);
+    }
+
+    #[test]
+    fn web_mercator_maps_latitude_to_the_expected_pixel() {
+        let size =
+            calc_canvas_size_from_extents(1000, [0.0, 60.0, 0.0, 100.0], Projection::WebMercator);
+        // ln(tan(45° + φ/2)) is 0.5493061443 at 30° and 1.3169578969 at 60°, in radians
+        assert_eq!(
+            size.height,
+            (1000.0 * 1.3169578969_f64.to_degrees() / 100.0) as u32
+        );
+
+        let (x, y) = convert_point(point(30.0, 25.0), size);
+        let expected_y = size.height as f64 * (1.0 - 0.5493061443 / 1.3169578969);
+        assert!((x - 250.0).abs() < 1e-9);
+        assert!((y - expected_y).abs() < 1e-6);
+    }
+
+    #[test]
+    fn legend_adds_a_swatch_and_label_per_entry() {
+        let mut canvas = Canvas::from_graph(1000, &grid(3, 4).0, Projection::default());
+        let children = canvas.document.get_children().len();
+
+        canvas.draw_legend(
+            [("Road", "white"), ("Connector", "red"), ("Sensor", "lime")]
+                .into_iter()
+                .map(|(label, color)| (label.to_string(), color.to_string()))
+                .collect(),
+        );
+
+        // The background, then a swatch and a label for each entry
+        assert_eq!(canvas.document.get_children().len() - children, 1 + 3 * 2);
+        assert_eq!(canvas.bounds.len(), canvas.document.get_children().len());
+    }
+
+    #[test]
+    fn exported_geojson_round_trips() {
+        let (mut graph, nodes) = grid(2, 2);
+        graph[nodes[1][1]].has_sensor = true;
+        let path = temp_path("export.geojson");
+
+        export_geojson(&graph, &path).unwrap();
+        let raw = std::fs::read_to_string(&path).unwrap();
+        let collection: serde_json::Value = serde_json::from_str(&raw).unwrap();
+
+        let features = collection["features"].as_array().unwrap();
+        assert_eq!(collection["type"], "FeatureCollection");
+        assert_eq!(features.len(), graph.edge_count() + 1);
+        let edge = &features[0];
+        let data = graph.edge_weights().next().unwrap();
+        assert_eq!(edge["geometry"]["type"], "LineString");
+        assert_eq!(
+            edge["geometry"]["coordinates"][0],
+            serde_json::json!([data.polyline[0].longitude, data.polyline[0].latitude])
+        );
+        assert_eq!(edge["properties"]["speed_limit"], 50.0);
+        assert_eq!(edge["properties"]["is_connector"], false);
+        let sensor = features.last().unwrap();
+        assert_eq!(sensor["geometry"]["type"], "Point");
+        assert_eq!(sensor["properties"]["node"], nodes[1][1].index());
+    }
+
+    #[test]
+    fn smaller_isochrones_lie_within_larger_ones() {
+        use geo::{Area, CoordsIter, Intersects};
+
+        // A grid of points reached later the further they are from its center
+        let reachable_points = (-3..=3)
+            .flat_map(|row: i32| {
+                (-3..=3).map(move |col: i32| {
+                    let time = row.abs().max(col.abs()) as f64 * 60.0;
+                    (point(row as f64 * 0.001, col as f64 * 0.001), time)
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let polygons = isochrone_polygons(&reachable_points, &[60.0, 180.0]);
+
+        let [smaller, larger] = &polygons[..] else {
+            panic!("Expected one polygon per threshold");
+        };
+        assert!(smaller.unsigned_area() > 0.0);
+        assert!(smaller.unsigned_area() < larger.unsigned_area());
+        assert!(smaller
+            .coords_iter()
+            .all(|coord| larger.intersects(&geo::Point::from(coord))));
+    }
+
+    #[test]
+    fn graticule_has_a_line_per_step_within_the_extent() {
+        let size =
+            calc_canvas_size_from_extents(1000, [59.0, 59.5, 18.0, 19.0], Projection::default());
+        let mut canvas = Canvas::new(size);
+        let children = canvas.document.get_children().len();
+
+        canvas.draw_graticule(0.1, 0.25);
+
+        // 59.0° through 59.5° and 18.0° through 19.0°, both bounds included, each with a label
+        let lines = 6 + 5;
+        assert_eq!(canvas.document.get_children().len() - children, lines * 2);
+        let raw = canvas.document.to_string();
+        assert_eq!(raw.matches("stroke-opacity=\"0.25\"").count(), lines);
+        assert!(raw.contains("59.3°") && raw.contains("18.5°"));
+    }
+
+    #[test]
+    fn two_node_graph_is_saved_as_a_valid_png() {
+        let mut graph = TestGraph::default();
+        let a = graph.add_node(node(0.0, 0.0));
+        let b = graph.add_node(node(0.001, 0.001));
+        add_road(&mut graph, a, b, 1, 50.0);
+        let mut canvas = Canvas::from_graph(200, &graph, Projection::default());
+        canvas.draw_line(graph[a].point, graph[b].point, DrawOptions::default());
+        canvas.draw_circle(graph[a].point, "red", 3.0);
+        canvas.draw_triangle(graph[b].point, "lime", 3.0, 45.0);
+        canvas.draw_cross(graph[b].point, "white", 3.0);
+        canvas.draw_legend(vec![("Road".into(), "white".into())]);
+        let path = temp_path("canvas.png");
+
+        canvas.save(&path);
+        let raw = std::fs::read(&path).unwrap();
+
+        assert!(raw.starts_with(b"\x89PNG\r\n\x1a\n"));
+        let pixmap = tiny_skia::Pixmap::decode_png(&raw).unwrap();
+        assert_eq!(
+            (pixmap.width(), pixmap.height()),
+            (canvas.size.width, canvas.size.height)
+        );
+    }
+
+    #[derive(clap::Parser)]
+    struct GradientArgs {
+        #[clap(flatten)]
+        spec: GradientSpec,
+    }
+
+    #[test]
+    fn unknown_gradient_preset_is_an_error() {
+        let error =
+            <GradientArgs as clap::Parser>::try_parse_from(["draw", "--gradient", "rainbow"])
+                .err()
+                .unwrap()
+                .to_string();
+
+        assert!(error.contains("rainbow"), "{}", error);
+        assert!(error.contains("viridis"), "{}", error);
+    }
+
+    #[test]
+    fn custom_gradient_spans_the_requested_domain() {
+        let args = <GradientArgs as clap::Parser>::try_parse_from([
+            "draw",
+            "--gradient-colors",
+            "#000000,#ffffff",
+        ])
+        .unwrap();
+        let gradient = build_gradient(&args.spec, 10.0, 30.0);
+
+        assert_eq!(gradient.domain(), (10.0, 30.0));
+        assert_eq!(gradient.at(10.0).to_hex_string(), "#000000");
+        assert_eq!(gradient.at(30.0).to_hex_string(), "#ffffff");
+        assert_ne!(gradient.at(20.0).to_hex_string(), "#000000");
+    }
+
+    #[test]
+    fn polylines_off_the_canvas_are_not_drawn() {
+        let (graph, nodes) = grid(3, 4);
+        let mut canvas = Canvas::from_graph(1000, &graph, Projection::default());
+        let children = canvas.get_node_count();
+        let (start, end) = (graph[nodes[0][0]].point, graph[nodes[2][3]].point);
+
+        canvas.draw_polyline(vec![start, end], DrawOptions::default());
+        canvas.draw_polyline(
+            vec![point(1.0, 1.0), point(1.0, 1.1)],
+            DrawOptions::default(),
+        );
+        assert_eq!(canvas.get_node_count(), children + 1);
+
+        // Crossing the canvas without a point on it is still drawn
+        let middle = (start.latitude + end.latitude) / 2.0;
+        canvas.draw_line(
+            point(middle, -1.0),
+            point(middle, 1.0),
+            DrawOptions::default(),
+        );
+        assert_eq!(canvas.get_node_count(), children + 2);
+    }
+
+    #[test]
+    fn framed_canvas_spans_the_radius_around_its_center() {
+        let center = point(59.33, 18.06);
+        for radius in [50.0, 500.0, 20_000.0] {
+            let size = Canvas::from_center(1000, center, radius, Projection::default()).size;
+            let from_center = |latitude: f64, longitude: f64| {
+                geo_distance(&[center.latitude, center.longitude], &[latitude, longitude])
+            };
+
+            for (latitude, longitude) in [
+                (size.max_lat, center.longitude),
+                (size.min_lat, center.longitude),
+                (center.latitude, size.max_lon),
+                (center.latitude, size.min_lon),
+            ] {
+                let distance = from_center(latitude, longitude);
+                assert!(
+                    (distance - radius).abs() < radius * 1e-6,
+                    "{}m framed as {}m",
+                    radius,
+                    distance
+                );
+            }
+        }
+    }
+}
@@ -1,8 +1,9 @@
-use clap::Args;
+use anyhow::Context;
+use clap::{Args, ValueEnum};
 use longitude::Location;
 use serde::{Deserialize, Serialize};
 
-use crate::output::CanvasSize;
+use crate::{math::geo_distance, output::CanvasSize};
 
 #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Args)]
 #[group(required = true, multiple = true)]
@@ -25,6 +26,25 @@ impl Point {
     }
 }
 
+/// Parsed from `lat,lon`
+impl std::str::FromStr for Point {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values = s
+            .split(',')
+            .map(|v| v.trim().parse::<f64>().map_err(|e| format!("{}: {}", v, e)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let [latitude, longitude] = values[..] else {
+            return Err(format!("Expected lat,lon, got {}", s));
+        };
+        Ok(Point {
+            latitude,
+            longitude,
+        })
+    }
+}
+
 impl Into<Location> for Point {
     fn into(self) -> Location {
         Location::from(self.latitude, self.longitude)
@@ -183,7 +203,192 @@ pub fn parse_road_data(raw: Vec<RawRoadData>) -> Vec<RoadData> {
         .collect()
 }
 
-pub fn read_roads(path: &str) -> Vec<RoadData> {
-    let raw = std::fs::read_to_string(path).unwrap();
-    serde_json::from_str(&raw).unwrap()
+/// Read roads written as a JSON array, or as newline delimited JSON by a chunked extraction
+pub fn read_roads(path: &str) -> anyhow::Result<Vec<RoadData>> {
+    let raw = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read road data from {}", path))?;
+    let roads = if raw.trim_start().starts_with('[') {
+        serde_json::from_str(&raw)
+    } else {
+        serde_json::Deserializer::from_str(&raw)
+            .into_iter()
+            .collect::<Result<_, _>>()
+    };
+    roads.with_context(|| format!("Failed to parse road data from {}", path))
+}
+
+/// File formats raw road data can be parsed from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum RawRoadFormat {
+    /// Trafikverket's JSON dump
+    Json,
+    /// A FeatureCollection of LineStrings
+    Geojson,
+}
+
+/// Property keys to read road attributes from in a GeoJSON road file
+#[derive(Debug, Clone, Args)]
+#[clap(about = None, long_about = None)]
+pub struct GeoJsonRoadKeys {
+    #[clap(long, default_value = "speed_limit")]
+    pub speed_limit_key: String,
+    /// Accepts forward/backward/both, yes/-1/no and the Swedish Med/Mot
+    #[clap(long, default_value = "direction")]
+    pub direction_key: String,
+    #[clap(long, default_value = "main_number")]
+    pub main_number_key: String,
+    #[clap(long, default_value = "sub_number")]
+    pub sub_number_key: String,
+    /// Falls back to the index of the feature if missing
+    #[clap(long, default_value = "unique_id")]
+    pub unique_id_key: String,
+}
+
+/// Read every LineString feature in a GeoJSON FeatureCollection as a road, other geometries are skipped
+pub fn parse_geojson_roads(path: &str, keys: &GeoJsonRoadKeys) -> anyhow::Result<Vec<RoadData>> {
+    let raw = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read road data from {}", path))?;
+    let collection: serde_json::Value = serde_json::from_str(&raw)
+        .with_context(|| format!("Failed to parse road data from {}", path))?;
+    geojson_roads(&collection, keys).with_context(|| format!("Invalid GeoJSON in {}", path))
+}
+
+fn geojson_roads(
+    collection: &serde_json::Value,
+    keys: &GeoJsonRoadKeys,
+) -> anyhow::Result<Vec<RoadData>> {
+    let features = collection["features"]
+        .as_array()
+        .context("Expected a FeatureCollection")?;
+
+    features
+        .iter()
+        .enumerate()
+        .filter(|(_, feature)| feature["geometry"]["type"] == "LineString")
+        .map(|(idx, feature)| {
+            let coordinates = feature["geometry"]["coordinates"]
+                .as_array()
+                .with_context(|| format!("Feature {} has no coordinates", idx))?
+                .iter()
+                .map(
+                    |position| match (position[0].as_f64(), position[1].as_f64()) {
+                        (Some(longitude), Some(latitude)) => Ok(Point {
+                            latitude,
+                            longitude,
+                        }),
+                        _ => anyhow::bail!("Feature {} has an invalid position {}", idx, position),
+                    },
+                )
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            let length = coordinates.windows(2).fold(0.0, |acc, pair| {
+                acc + geo_distance(
+                    &[pair[0].latitude, pair[0].longitude],
+                    &[pair[1].latitude, pair[1].longitude],
+                )
+            });
+
+            let properties = &feature["properties"];
+            Ok(RoadData {
+                direction: parse_geojson_direction(&properties[&keys.direction_key]),
+                main_number: geojson_number(&properties[&keys.main_number_key]).unwrap_or(0.0)
+                    as i32,
+                sub_number: geojson_number(&properties[&keys.sub_number_key]).unwrap_or(0.0) as i32,
+                coordinates,
+                length,
+                unique_id: geojson_number(&properties[&keys.unique_id_key])
+                    .map_or(idx as i32, |id| id as i32),
+                speed_limit: geojson_number(&properties[&keys.speed_limit_key]).unwrap_or(0.0),
+            })
+        })
+        .collect()
+}
+
+/// A number property, which may also be given as a string such as `"70"`
+fn geojson_number(value: &serde_json::Value) -> Option<f64> {
+    match value {
+        serde_json::Value::Number(number) => number.as_f64(),
+        serde_json::Value::String(string) => string.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+fn parse_geojson_direction(value: &serde_json::Value) -> RoadDirection {
+    match value.as_str().map(str::to_lowercase).as_deref() {
+        Some("forward") | Some("yes") | Some("med") => RoadDirection::Forward,
+        Some("backward") | Some("-1") | Some("mot") => RoadDirection::Backward,
+        _ => match value.as_i64() {
+            Some(1) => RoadDirection::Forward,
+            Some(-1) => RoadDirection::Backward,
+            _ => RoadDirection::Both,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(clap::Parser)]
+    struct KeyArgs {
+        #[clap(flatten)]
+        keys: GeoJsonRoadKeys,
+    }
+
+    #[test]
+    fn geojson_features_are_read_as_lon_lat_roads() {
+        let keys = <KeyArgs as clap::Parser>::parse_from(["parse"]).keys;
+        let collection = serde_json::json!({
+            "type": "FeatureCollection",
+            "features": [
+                {
+                    "type": "Feature",
+                    "geometry": {
+                        "type": "LineString",
+                        "coordinates": [[18.0, 59.0], [18.001, 59.001]]
+                    },
+                    "properties": { "speed_limit": 70, "direction": "forward", "unique_id": 7 }
+                },
+                {
+                    "type": "Feature",
+                    "geometry": {
+                        "type": "LineString",
+                        "coordinates": [[18.001, 59.001], [18.002, 59.001]]
+                    },
+                    "properties": { "speed_limit": "50", "main_number": 222 }
+                }
+            ]
+        });
+
+        let roads = geojson_roads(&collection, &keys).unwrap();
+
+        assert_eq!(roads.len(), 2);
+        assert_eq!(
+            roads[0].coordinates[0],
+            Point {
+                latitude: 59.0,
+                longitude: 18.0
+            }
+        );
+        assert_eq!(roads[0].speed_limit, 70.0);
+        assert_eq!(roads[0].direction, RoadDirection::Forward);
+        assert_eq!(roads[0].unique_id, 7);
+        assert_eq!(roads[1].speed_limit, 50.0);
+        assert_eq!(roads[1].direction, RoadDirection::Both);
+        assert_eq!(roads[1].main_number, 222);
+        assert_eq!(roads[1].unique_id, 1);
+    }
+
+    #[test]
+    fn malformed_geojson_is_an_error() {
+        let keys = <KeyArgs as clap::Parser>::parse_from(["parse"]).keys;
+        let not_a_collection = serde_json::json!({ "type": "Feature" });
+        let bad_position = serde_json::json!({
+            "features": [
+                { "geometry": { "type": "LineString", "coordinates": [[18.0], [18.001, 59.0]] } }
+            ]
+        });
+
+        assert!(geojson_roads(&not_a_collection, &keys).is_err());
+        assert!(geojson_roads(&bad_position, &keys).is_err());
+    }
 }
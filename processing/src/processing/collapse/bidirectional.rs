@@ -0,0 +1,148 @@
+use petgraph::{
+    graph::{EdgeIndex, NodeIndex},
+    stable_graph::StableDiGraph,
+    visit::EdgeRef,
+    Direction::{Incoming, Outgoing},
+};
+
+use crate::processing::{merge_edge_data, EdgeData, NodeData};
+
+use super::{cap_polyline, take_roads, MergedRoads};
+
+/// Collapse every node in the middle of a road, merging the edges on both sides of it in each
+/// direction the road can be traveled, so two-way roads stay traversable both ways.
+/// Returns the input roads merged into each added edge. The polylines of added edges are
+/// simplified to at most **max_points** points.
+pub fn bidirectional(
+    graph: &mut StableDiGraph<NodeData, EdgeData>,
+    max_points: Option<usize>,
+) -> MergedRoads {
+    let mut merged = MergedRoads::new();
+    let nodes = graph.node_indices().collect::<Vec<_>>();
+    for node in nodes {
+        if let Some(pairs) = collapsible_pairs(graph, node) {
+            collapse_node(graph, node, pairs, &mut merged, max_points);
+        }
+    }
+    merged
+}
+
+fn collapse_node(
+    graph: &mut StableDiGraph<NodeData, EdgeData>,
+    node: NodeIndex,
+    pairs: Vec<(EdgeIndex, EdgeIndex)>,
+    merged: &mut MergedRoads,
+    max_points: Option<usize>,
+) {
+    for (in_edge, out_edge) in pairs {
+        let (start, _) = graph.edge_endpoints(in_edge).unwrap();
+        let (_, end) = graph.edge_endpoints(out_edge).unwrap();
+        let start_data = graph.node_weight(start).unwrap();
+        let end_data = graph.node_weight(end).unwrap();
+
+        let edges_data = vec![
+            graph.edge_weight(in_edge).unwrap().clone(),
+            graph.edge_weight(out_edge).unwrap().clone(),
+        ];
+        let mut roads = take_roads(merged, in_edge, &edges_data[0]);
+        roads.extend(take_roads(merged, out_edge, &edges_data[1]));
+
+        let mut edge_data = merge_edge_data(*start_data, *end_data, edges_data);
+        cap_polyline(&mut edge_data, max_points);
+        let edge = graph.add_edge(start, end, edge_data);
+        merged.insert(edge, roads);
+    }
+
+    graph.remove_node(node);
+}
+
+/// Pair every edge into **node** with the edge out of it continuing to the neighbour on the other
+/// side, or **None** if the node is not in the middle of a one-way or two-way road
+fn collapsible_pairs(
+    graph: &StableDiGraph<NodeData, EdgeData>,
+    node: NodeIndex,
+) -> Option<Vec<(EdgeIndex, EdgeIndex)>> {
+    let data = graph.node_weight(node).unwrap();
+    if data.has_sensor || data.original_road_id == -1 {
+        return None;
+    }
+
+    let in_edges = graph.edges_directed(node, Incoming).collect::<Vec<_>>();
+    let out_edges = graph.edges_directed(node, Outgoing).collect::<Vec<_>>();
+
+    // One edge through the node for one-way roads, and one in each direction for two-way roads
+    if in_edges.len() != out_edges.len() || !(1..=2).contains(&in_edges.len()) {
+        return None;
+    }
+
+    let first = in_edges[0].weight();
+    let consistent = in_edges.iter().chain(out_edges.iter()).all(|edge| {
+        let data = edge.weight();
+        edge.source() != edge.target()
+            && !data.is_connector
+            && data.main_number == first.main_number
+            && data.sub_number == first.sub_number
+    });
+    if !consistent {
+        return None;
+    }
+
+    let mut pairs = Vec::new();
+    for in_edge in in_edges.iter() {
+        let mut continuing = out_edges
+            .iter()
+            .filter(|out_edge| out_edge.target() != in_edge.source());
+        match (continuing.next(), continuing.next()) {
+            (Some(out_edge), None) => pairs.push((in_edge.id(), out_edge.id())),
+            _ => return None,
+        }
+    }
+
+    // Both directions of a two-way road must continue to different neighbours
+    if pairs.len() == 2 && pairs[0].1 == pairs[1].1 {
+        return None;
+    }
+
+    Some(pairs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_graphs::{add_chain, add_road, TestGraph},
+        visitor::{shortest_path, DistanceMetric, SearchStrategy},
+    };
+
+    #[test]
+    fn two_way_road_routes_both_ways_after_collapse() {
+        let mut graph = TestGraph::default();
+        let nodes = add_chain(
+            &mut graph,
+            &[(0.0, 0.0), (0.0, 0.001), (0.0, 0.002), (0.0, 0.003)],
+            1,
+            50.0,
+        );
+        for pair in nodes.windows(2) {
+            add_road(&mut graph, pair[1], pair[0], 1, 50.0);
+        }
+        let (start, end) = (nodes[0], nodes[3]);
+
+        bidirectional(&mut graph, None);
+
+        assert_eq!(graph.node_count(), 2);
+        assert_eq!(graph.edge_count(), 2);
+        let route = |from, to| {
+            shortest_path(
+                &graph,
+                vec![from, to],
+                DistanceMetric::Space,
+                1.0,
+                SearchStrategy::Dijkstra,
+            )
+            .unwrap()
+        };
+        assert_eq!(route(start, end).nodes, vec![start, end]);
+        assert_eq!(route(end, start).nodes, vec![end, start]);
+    }
+}
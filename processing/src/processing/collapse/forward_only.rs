@@ -7,7 +7,16 @@ use petgraph::{
 
 use crate::processing::{merge_edge_data, EdgeData, NodeData};
 
-pub fn forward_only(graph: &mut StableDiGraph<NodeData, EdgeData>) {
+use super::{cap_polyline, take_roads, MergedRoads};
+
+/// Collapse the chains of nodes following each nucleation point, returning the input roads
+/// merged into each added edge. The polylines of added edges are simplified to at most
+/// **max_points** points.
+pub fn forward_only(
+    graph: &mut StableDiGraph<NodeData, EdgeData>,
+    max_points: Option<usize>,
+) -> MergedRoads {
+    let mut merged = MergedRoads::new();
     let mut nucleation_points = Vec::new();
     for node in graph.node_indices() {
         if is_nucleation_point(graph, node) {
@@ -15,28 +24,40 @@ pub fn forward_only(graph: &mut StableDiGraph<NodeData, EdgeData>) {
         }
     }
     for node in nucleation_points {
-        start_nucleation(graph, node);
+        start_nucleation(graph, node, &mut merged, max_points);
     }
+    merged
 }
 
-fn start_nucleation(graph: &mut StableDiGraph<NodeData, EdgeData>, node: NodeIndex) {
+fn start_nucleation(
+    graph: &mut StableDiGraph<NodeData, EdgeData>,
+    node: NodeIndex,
+    merged: &mut MergedRoads,
+    max_points: Option<usize>,
+) {
     let paths = graph
         .edges_directed(node, Outgoing)
         .filter(|edge| !edge.weight().is_connector)
         .map(|edge| edge.id())
         .collect::<Vec<_>>();
     for edge in paths {
-        collapse_node(graph, edge);
+        collapse_node(graph, edge, merged, max_points);
     }
 }
 
-fn collapse_node(graph: &mut StableDiGraph<NodeData, EdgeData>, edge: EdgeIndex) {
+fn collapse_node(
+    graph: &mut StableDiGraph<NodeData, EdgeData>,
+    edge: EdgeIndex,
+    merged: &mut MergedRoads,
+    max_points: Option<usize>,
+) {
     let data = graph.edge_weight(edge).unwrap();
     let endpoints = graph.edge_endpoints(edge).unwrap();
     let start = endpoints.0;
     let end = endpoints.1;
 
     let mut edges_data = vec![data.clone()];
+    let mut edges = vec![edge];
     let mut nodes = Vec::new();
     let speed_limit = data
         .speed_limit
@@ -69,6 +90,7 @@ fn collapse_node(graph: &mut StableDiGraph<NodeData, EdgeData>, edge: EdgeIndex)
         nodes.push(head);
 
         edges_data.push(data.clone());
+        edges.push(edge.id());
         head = edge.target();
 
         let head_data = graph.node_weight(head).unwrap();
@@ -86,13 +108,20 @@ fn collapse_node(graph: &mut StableDiGraph<NodeData, EdgeData>, edge: EdgeIndex)
     let start_data = graph.node_weight(start).unwrap();
     let end_data = graph.node_weight(head).unwrap();
 
-    let edge_data = merge_edge_data(*start_data, *end_data, edges_data);
+    let roads = edges
+        .into_iter()
+        .zip(edges_data.iter())
+        .flat_map(|(edge, data)| take_roads(merged, edge, data))
+        .collect();
+    let mut edge_data = merge_edge_data(*start_data, *end_data, edges_data);
+    cap_polyline(&mut edge_data, max_points);
 
     for node in nodes {
         graph.remove_node(node);
     }
 
-    graph.add_edge(start, head, edge_data);
+    let edge = graph.add_edge(start, head, edge_data);
+    merged.insert(edge, roads);
 }
 
 fn is_nucleation_point(graph: &StableDiGraph<NodeData, EdgeData>, node: NodeIndex) -> bool {
@@ -1,5 +1,35 @@
+use std::collections::HashMap;
+
+use petgraph::graph::EdgeIndex;
+
+use super::{simplify::simplify_polyline, EdgeData};
+
+mod bidirectional;
 mod forward_only;
 mod naive;
+mod skeleton;
 
+pub use bidirectional::bidirectional;
 pub use forward_only::forward_only;
 pub use naive::naive;
+pub use skeleton::skeleton;
+
+/// The input roads merged into each edge added by a collapse
+pub type MergedRoads = HashMap<EdgeIndex, Vec<i32>>;
+
+/// Take the roads merged into **edge** so far, or just its own road if it has not been merged
+fn take_roads(merged: &mut MergedRoads, edge: EdgeIndex, data: &EdgeData) -> Vec<i32> {
+    merged
+        .remove(&edge)
+        .unwrap_or_else(|| vec![data.original_road_id])
+}
+
+/// Simplify the polyline of an edge added by a collapse to at most **max_points** points, so
+/// chains merged over and over do not build up polylines far past the cap before the final pass
+fn cap_polyline(data: &mut EdgeData, max_points: Option<usize>) {
+    if let Some(max_points) = max_points {
+        if data.polyline.len() > max_points {
+            data.polyline = simplify_polyline(&data.polyline, max_points);
+        }
+    }
+}
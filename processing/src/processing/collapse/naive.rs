@@ -10,13 +10,27 @@ use crate::{
     processing::{direction_from_data, EdgeData, NodeData},
 };
 
-pub fn naive(graph: &mut StableDiGraph<NodeData, EdgeData>) {
+use super::{cap_polyline, take_roads, MergedRoads};
+
+/// Collapse every node in the middle of a road, returning the input roads merged into each added
+/// edge. The polylines of added edges are simplified to at most **max_points** points.
+pub fn naive(
+    graph: &mut StableDiGraph<NodeData, EdgeData>,
+    max_points: Option<usize>,
+) -> MergedRoads {
+    let mut merged = MergedRoads::new();
     while let Some(node) = find_node_to_collapse(graph) {
-        collapse_node(graph, node);
+        collapse_node(graph, node, &mut merged, max_points);
     }
+    merged
 }
 
-fn collapse_node(graph: &mut StableDiGraph<NodeData, EdgeData>, node: NodeIndex) {
+fn collapse_node(
+    graph: &mut StableDiGraph<NodeData, EdgeData>,
+    node: NodeIndex,
+    merged: &mut MergedRoads,
+    max_points: Option<usize>,
+) {
     let data = graph.node_weight(node).unwrap();
     let mut forwards = vec![data.point];
     let mut backwards = Vec::new();
@@ -25,6 +39,7 @@ fn collapse_node(graph: &mut StableDiGraph<NodeData, EdgeData>, node: NodeIndex)
     let end;
 
     let mut to_remove = vec![node];
+    let mut roads = Vec::new();
 
     // Walk forwards until we hit a node we cannot collapse
     let mut distance_forwards = 0.0;
@@ -43,6 +58,7 @@ fn collapse_node(graph: &mut StableDiGraph<NodeData, EdgeData>, node: NodeIndex)
             break;
         }
 
+        roads.extend(take_roads(merged, edge.id(), edge_data));
         distance_forwards += edge_data.distance;
         speed_limit_forwards += edge_data.speed_limit.unwrap_or(0.0) * edge_data.distance;
         forwards.extend(edge_data.polyline.iter().skip(1));
@@ -69,6 +85,7 @@ fn collapse_node(graph: &mut StableDiGraph<NodeData, EdgeData>, node: NodeIndex)
             break;
         }
 
+        roads.extend(take_roads(merged, edge.id(), edge_data));
         distance_backwards += edge_data.distance;
         speed_limit_backwards += edge_data.speed_limit.unwrap_or(0.0) * edge_data.distance;
         backwards.extend(edge_data.polyline.iter().rev().skip(1));
@@ -86,12 +103,12 @@ fn collapse_node(graph: &mut StableDiGraph<NodeData, EdgeData>, node: NodeIndex)
     let start_data = graph.node_weight(start).unwrap();
     let end_data = graph.node_weight(end).unwrap();
 
-    let speed_limit_forwards = speed_limit_forwards / distance_forwards;
-    let speed_limit_backwards = speed_limit_backwards / distance_backwards;
-    let speed_limit = (speed_limit_forwards + speed_limit_backwards) / 2.0;
+    // Both halves are summed weighted by distance, so weigh them by their length together
+    let distance = distance_forwards + distance_backwards;
+    let speed_limit = (speed_limit_forwards + speed_limit_backwards) / distance;
 
-    let edge_data = EdgeData {
-        distance: distance_forwards + distance_backwards,
+    let mut edge_data = EdgeData {
+        distance,
         main_number: prev_edge_data.main_number,
         sub_number: prev_edge_data.sub_number,
         polyline: backwards.into_iter().rev().chain(forwards).collect(),
@@ -101,8 +118,10 @@ fn collapse_node(graph: &mut StableDiGraph<NodeData, EdgeData>, node: NodeIndex)
         original_road_id: -1,
         speed_limit: Some(speed_limit),
     };
+    cap_polyline(&mut edge_data, max_points);
 
-    graph.add_edge(start, end, edge_data);
+    let edge = graph.add_edge(start, end, edge_data);
+    merged.insert(edge, roads);
 
     for node in to_remove {
         graph.remove_node(node);
@@ -144,3 +163,70 @@ fn can_collapse_node(graph: &StableDiGraph<NodeData, EdgeData>, node: NodeIndex)
 
     out_data.main_number == in_data.main_number && out_data.sub_number == in_data.sub_number
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        math::dist,
+        test_graphs::{add_chain, TestGraph},
+    };
+
+    #[test]
+    fn merged_speed_limit_is_weighted_by_length() {
+        let mut graph = TestGraph::default();
+        let nodes = add_chain(
+            &mut graph,
+            &[(0.0, 0.0), (0.0, 0.001), (0.0, 0.003)],
+            1,
+            0.0,
+        );
+        for (pair, speed_limit) in nodes.windows(2).zip([30.0, 60.0]) {
+            let edge = graph.find_edge(pair[0], pair[1]).unwrap();
+            graph[edge].speed_limit = Some(speed_limit);
+        }
+        let lengths = graph
+            .edge_weights()
+            .map(|edge| (edge.distance, edge.speed_limit.unwrap()))
+            .collect::<Vec<_>>();
+        let expected = lengths.iter().map(|(d, s)| d * s).sum::<f64>()
+            / lengths.iter().map(|(d, _)| d).sum::<f64>();
+
+        naive(&mut graph, None);
+
+        assert_eq!(graph.edge_count(), 1);
+        let merged = graph.edge_weights().next().unwrap();
+        assert!((merged.speed_limit.unwrap() - expected).abs() < 1e-9);
+        assert!((expected - 50.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn long_chain_is_capped_as_it_collapses() {
+        // 10 000 edges of about 11m, wiggling 10cm either side of a 111km line
+        let points = (0..=10_000)
+            .map(|idx| {
+                (
+                    if idx % 2 == 0 { 0.0 } else { 0.000001 },
+                    idx as f64 * 0.0001,
+                )
+            })
+            .collect::<Vec<_>>();
+        let mut graph = TestGraph::default();
+        add_chain(&mut graph, &points, 1, 50.0);
+        let road_length = graph.edge_weights().map(|edge| edge.distance).sum::<f64>();
+
+        naive(&mut graph, Some(100));
+
+        assert_eq!(graph.edge_count(), 1);
+        let merged = graph.edge_weights().next().unwrap();
+        assert!(merged.polyline.len() <= 100);
+        assert!((merged.distance - road_length).abs() < 1e-6);
+        // The simplified line still follows the road, short only by the wiggles it dropped
+        let line_length = merged
+            .polyline
+            .windows(2)
+            .map(|segment| dist(segment[0], segment[1]))
+            .sum::<f64>();
+        assert!(line_length <= road_length && line_length > 0.999 * road_length);
+    }
+}
@@ -0,0 +1,192 @@
+use std::collections::HashSet;
+
+use petgraph::{
+    graph::NodeIndex,
+    stable_graph::StableDiGraph,
+    visit::EdgeRef,
+    Direction::{Incoming, Outgoing},
+};
+
+use crate::{
+    math::midpoint,
+    processing::{EdgeData, NodeData},
+};
+
+use super::cap_polyline;
+
+/// Contract every chain of degree two nodes into a single edge, across road numbers and
+/// connectors, so only junctions, dead ends and sensor nodes remain. The contracted edges keep
+/// the summed distance and travel time of the chains they replace, so shortest paths between the
+/// remaining nodes are unchanged. Loops without any remaining node are dropped. The polylines of
+/// contracted edges are simplified to at most **max_points** points.
+pub fn skeleton(graph: &mut StableDiGraph<NodeData, EdgeData>, max_points: Option<usize>) {
+    let retained = graph
+        .node_indices()
+        .filter(|node| is_retained(graph, *node))
+        .collect::<HashSet<_>>();
+
+    let mut contracted = Vec::new();
+    for start in retained.iter().copied() {
+        for edge in graph.edges_directed(start, Outgoing) {
+            // Edges between two retained nodes are kept as they are
+            if retained.contains(&edge.target()) {
+                continue;
+            }
+
+            let mut edges = vec![(edge.weight(), start, edge.target())];
+            let mut prev = start;
+            let mut head = edge.target();
+            while !retained.contains(&head) {
+                // A chain node has one way forward that does not lead back where we came from
+                let Some(next) = graph
+                    .edges_directed(head, Outgoing)
+                    .find(|edge| edge.target() != prev)
+                else {
+                    break;
+                };
+                edges.push((next.weight(), head, next.target()));
+                prev = head;
+                head = next.target();
+            }
+
+            if retained.contains(&head) {
+                let mut data = merge_chain(graph, &edges);
+                cap_polyline(&mut data, max_points);
+                contracted.push((start, head, data));
+            }
+        }
+    }
+
+    let removed = graph
+        .node_indices()
+        .filter(|node| !retained.contains(node))
+        .collect::<Vec<_>>();
+    for node in removed {
+        graph.remove_node(node);
+    }
+    for (start, end, data) in contracted {
+        graph.add_edge(start, end, data);
+    }
+}
+
+/// Whether a node is anything but the middle of a one-way or two-way chain
+fn is_retained(graph: &StableDiGraph<NodeData, EdgeData>, node: NodeIndex) -> bool {
+    if graph[node].has_sensor {
+        return true;
+    }
+
+    let incoming = graph
+        .neighbors_directed(node, Incoming)
+        .collect::<HashSet<_>>();
+    let outgoing = graph
+        .neighbors_directed(node, Outgoing)
+        .collect::<HashSet<_>>();
+    let in_edges = graph.edges_directed(node, Incoming).count();
+    let out_edges = graph.edges_directed(node, Outgoing).count();
+
+    let one_way = in_edges == 1 && out_edges == 1 && incoming != outgoing;
+    let two_way = in_edges == 2 && out_edges == 2 && incoming.len() == 2 && incoming == outgoing;
+    !(one_way || two_way)
+}
+
+/// Merge the edges of a chain, given with their endpoints. The speed limit is the length weighted
+/// harmonic mean of the edges that have one, so the travel time is the sum of the travel times of
+/// the edges when they all have one, and edges without one like connectors do not leave the whole
+/// chain without a speed limit
+fn merge_chain(
+    graph: &StableDiGraph<NodeData, EdgeData>,
+    edges: &[(&EdgeData, NodeIndex, NodeIndex)],
+) -> EdgeData {
+    let (first, start, _) = edges[0];
+    let (_, _, end) = edges[edges.len() - 1];
+    let distance = edges.iter().map(|(edge, _, _)| edge.distance).sum::<f64>();
+    let (limited_distance, limited_time) = edges
+        .iter()
+        .filter_map(|(edge, _, _)| edge.speed_limit.map(|speed| (edge.distance, speed)))
+        .fold((0.0, 0.0), |(total, time), (distance, speed)| {
+            (total + distance, time + distance / speed)
+        });
+    let speed_limit = (limited_time > 0.0).then(|| limited_distance / limited_time);
+
+    // Connectors have no polyline, so use a straight line between their endpoints
+    let mut polyline = vec![graph[start].point];
+    for (edge, _, target) in edges.iter() {
+        if edge.polyline.is_empty() {
+            polyline.push(graph[*target].point);
+        } else {
+            polyline.extend(edge.polyline.iter().skip(1));
+        }
+    }
+
+    EdgeData {
+        distance,
+        main_number: first.main_number,
+        sub_number: first.sub_number,
+        polyline,
+        is_connector: edges.iter().all(|(edge, _, _)| edge.is_connector),
+        midpoint: midpoint(graph[start].point, graph[end].point),
+        direction: first.direction,
+        original_road_id: first.original_road_id,
+        speed_limit,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_graphs::{add_two_way, grid, node, TestGraph},
+        visitor::{distance_field, DistanceMetric},
+    };
+
+    #[test]
+    fn shortest_paths_between_retained_nodes_are_unchanged() {
+        let (mut full, nodes) = grid(3, 4);
+        // A sensor in the middle of the chain around a corner, and a detour off the grid
+        let sensor = nodes[0][1];
+        full[sensor].has_sensor = true;
+        let detour = full.add_node(node(0.003, 0.001));
+        add_two_way(&mut full, nodes[2][0], detour, 3, 30.0);
+        add_two_way(&mut full, detour, nodes[2][2], 3, 30.0);
+
+        let mut skeleton = full.clone();
+        super::skeleton(&mut skeleton, None);
+
+        assert!(skeleton.node_count() < full.node_count());
+        assert!(!skeleton.contains_node(detour));
+        assert!(skeleton.contains_node(sensor));
+        for metric in [DistanceMetric::Space, DistanceMetric::Time] {
+            for source in skeleton.node_indices() {
+                let expected = distance_field(&full, source, metric, true, f64::INFINITY);
+                let actual = distance_field(&skeleton, source, metric, true, f64::INFINITY);
+                for target in skeleton.node_indices() {
+                    assert!((actual[&target] - expected[&target]).abs() < 1e-6);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn chain_through_a_connector_keeps_a_speed_limit() {
+        let mut graph = TestGraph::default();
+        let a = graph.add_node(node(0.0, 0.0));
+        let b = graph.add_node(node(0.0, 0.001));
+        let c = graph.add_node(node(0.0, 0.002));
+        let d = graph.add_node(node(0.0, 0.003));
+        add_two_way(&mut graph, a, b, 1, 30.0);
+        add_two_way(&mut graph, b, c, 1, 60.0);
+        add_two_way(&mut graph, c, d, 1, 0.0);
+        for edge in [
+            graph.find_edge(c, d).unwrap(),
+            graph.find_edge(d, c).unwrap(),
+        ] {
+            graph[edge].is_connector = true;
+            graph[edge].speed_limit = None;
+        }
+
+        skeleton(&mut graph, None);
+
+        let merged = &graph[graph.find_edge(a, d).unwrap()];
+        assert!((merged.speed_limit.unwrap() - 40.0).abs() < 0.01);
+    }
+}
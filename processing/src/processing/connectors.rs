@@ -0,0 +1,155 @@
+use petgraph::{graph::NodeIndex, stable_graph::StableDiGraph};
+
+use crate::output::{Canvas, DrawOptions, Projection};
+
+use super::{EdgeData, NodeData};
+
+/// Why the connect pass did not add a connector between two nodes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+    /// Neither end of the nearby edge is in the heading of the node
+    Heading,
+    /// The nearby edge does not run in the heading of the node, with `--connect-parallel-only`
+    RoadHeading,
+    /// The nodes are already connected by an edge
+    Neighbours,
+}
+
+impl RejectReason {
+    fn color(self) -> &'static str {
+        match self {
+            RejectReason::Heading => "orange",
+            RejectReason::RoadHeading => "purple",
+            RejectReason::Neighbours => "cyan",
+        }
+    }
+}
+
+/// A candidate connection the connect pass considered and rejected
+#[derive(Debug, Clone, Copy)]
+pub struct RejectedConnection {
+    pub from: NodeIndex,
+    pub to: NodeIndex,
+    pub reason: RejectReason,
+}
+
+/// Draw the graph with the connectors highlighted and the road caps they join circled, and the
+/// rejected candidate connections as faint dashed lines colored by reason
+pub fn render_connectors(
+    graph: &StableDiGraph<NodeData, EdgeData>,
+    rejected: &[RejectedConnection],
+    path: &str,
+) {
+    let mut canvas = Canvas::from_graph(4000, graph, Projection::default());
+
+    for edge in graph.edge_weights().filter(|edge| !edge.is_connector) {
+        canvas.draw_polyline(
+            edge.polyline.clone(),
+            DrawOptions {
+                color: "gray".into(),
+                stroke: 1.0,
+                ..Default::default()
+            },
+        );
+    }
+
+    for connection in rejected {
+        let from = graph.node_weight(connection.from).unwrap();
+        let to = graph.node_weight(connection.to).unwrap();
+        canvas.draw_line(
+            from.point,
+            to.point,
+            DrawOptions {
+                color: connection.reason.color().into(),
+                stroke: 0.5,
+                stroke_dasharray: "2,2".into(),
+                ..Default::default()
+            },
+        );
+    }
+
+    for edge in graph.edge_indices() {
+        if !graph.edge_weight(edge).unwrap().is_connector {
+            continue;
+        }
+
+        let (from, to) = graph.edge_endpoints(edge).unwrap();
+        let from = graph.node_weight(from).unwrap();
+        let to = graph.node_weight(to).unwrap();
+        canvas.draw_line(
+            from.point,
+            to.point,
+            DrawOptions {
+                color: "red".into(),
+                stroke: 3.0,
+                ..Default::default()
+            },
+        );
+        if from.is_road_cap {
+            canvas.draw_circle(from.point, "red", 4.0);
+        }
+    }
+
+    canvas.draw_legend(
+        [
+            ("Road", "gray"),
+            ("Connector", "red"),
+            ("Rejected, heading", RejectReason::Heading.color()),
+            ("Rejected, road heading", RejectReason::RoadHeading.color()),
+            (
+                "Rejected, already neighbours",
+                RejectReason::Neighbours.color(),
+            ),
+        ]
+        .into_iter()
+        .map(|(label, color)| (label.to_string(), color.to_string()))
+        .collect(),
+    );
+    canvas.save(path);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        output::convert_point,
+        test_graphs::{add_chain, add_road, temp_path, TestGraph},
+    };
+
+    #[test]
+    fn connector_from_a_road_cap_is_highlighted() {
+        let mut graph = TestGraph::default();
+        let road = add_chain(&mut graph, &[(0.0, 0.0), (0.0, 0.001)], 1, 50.0);
+        let other = add_chain(&mut graph, &[(0.0002, 0.0012), (0.001, 0.0012)], 2, 50.0);
+        let connector = add_road(&mut graph, road[1], other[0], -1, 20.0);
+        graph[connector].is_connector = true;
+        let path = temp_path("connectors.svg");
+
+        render_connectors(&graph, &[], &path);
+        let raw = std::fs::read_to_string(&path).unwrap();
+
+        let size = Canvas::from_graph(4000, &graph, Projection::default()).size;
+        let (x, y) = convert_point(graph[road[1]].point, size);
+        let (to_x, to_y) = convert_point(graph[other[0]].point, size);
+        // The path data is written with less precision than the circle center
+        let highlighted = raw
+            .lines()
+            .filter(|element| element.starts_with("<path"))
+            .filter(|element| element.contains("stroke=\"red\" "))
+            .filter(|element| element.contains("stroke-width=\"3\""))
+            .collect::<Vec<_>>();
+        assert_eq!(highlighted.len(), 1, "{}", raw);
+        let data = highlighted[0].split("d=\"M").nth(1).unwrap();
+        let pixels = data[..data.find('"').unwrap()]
+            .split([',', ' ', 'L'])
+            .filter(|value| !value.is_empty())
+            .map(|value| value.parse::<f64>().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(pixels.len(), 4);
+        for (pixel, expected) in pixels.iter().zip([x, y, to_x, to_y]) {
+            assert!((pixel - expected).abs() < 0.01, "{}", highlighted[0]);
+        }
+        let circle = format!("<circle cx=\"{}\" cy=\"{}\" fill=\"red\" r=\"4\"/>", x, y);
+        assert!(raw.contains(&circle), "No cap circle {} in {}", circle, raw);
+    }
+}
@@ -1,32 +1,51 @@
 use crate::{
-    custom_bfs::Positionable,
+    custom_bfs::{Positionable, RoadSegment},
     math::{geo_distance, midpoint},
     mongo::model::SensorMetadata,
     parse::{Point, RoadDirection},
     progress::Progress,
 };
 
-use std::collections::{HashMap, HashSet};
+use std::{
+    cmp::Ordering,
+    collections::{btree_map::Entry, BTreeMap, HashMap, HashSet},
+    hash::{DefaultHasher, Hash, Hasher},
+};
 
 use clap::{Args, ValueEnum};
 use console::style;
+use indicatif::ProgressBar;
 use kdtree::KdTree;
 use petgraph::{
     graph::NodeIndex,
     prelude::EdgeIndex,
     stable_graph::{StableDiGraph, StableGraph},
-    visit::{Bfs, EdgeRef, IntoEdgeReferences, IntoNodeReferences, VisitMap},
+    unionfind::UnionFind,
+    visit::{Bfs, EdgeRef, IntoEdgeReferences, IntoNodeReferences, NodeIndexable, VisitMap},
     Direction::{Incoming, Outgoing},
 };
-use rayon::iter::{ParallelBridge, ParallelIterator};
+use rayon::iter::{
+    IntoParallelIterator, IntoParallelRefIterator, ParallelBridge, ParallelIterator,
+};
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    math::{angle_average, angle_diff, dist, line_heading, point_line_dist_approx},
+    math::{
+        angle_average, angle_diff, dist, dist_with, line_heading, point_line_dist, DistanceModel,
+    },
     parse::RoadData,
+    util::{find_point, PointQuery, SnapStrategy},
 };
 
 pub mod collapse;
+pub mod connectors;
+mod routing;
+pub mod simplify;
+pub mod trace;
+mod validate;
+
+pub use routing::{NodeTree, RoutingGraph};
+pub use validate::{check_directions, print_report, repair_directions, validate_graph};
 
 #[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct NodeData {
@@ -46,6 +65,12 @@ impl Positionable for NodeData {
     }
 }
 
+impl RoadSegment for EdgeData {
+    fn road_id(&self) -> i32 {
+        self.original_road_id
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct EdgeData {
     pub distance: f64,
@@ -113,6 +138,13 @@ pub struct GraphProcessingOptions {
         default_value = "inf"
     )]
     max_distance_from_sensors: f64,
+    /// Apply the max distance from sensors while reading the roads, so nodes out of range are
+    /// never added to the graph, keeping memory use down on large datasets
+    #[clap(long, default_value = "false", default_missing_value = "true")]
+    filter_on_ingest: bool,
+    /// Merge every road cap into the nodes within this many meters of it. Merging is transitive,
+    /// so nodes near a cap that is itself near another cap end up in one cluster even when they
+    /// are further apart than this
     #[clap(
         short = 'M',
         long,
@@ -143,21 +175,161 @@ pub struct GraphProcessingOptions {
         default_missing_value = "20.0"
     )]
     connect_distance: f64,
+    /// Degrees the direction from a node to either end of a nearby edge may differ from the heading
+    /// of the node for the connect step to link them, higher values link separate roads more
+    /// aggressively, including roads only passing beside or across each other. Road caps connect
+    /// regardless of heading
+    #[clap(long, default_value = "15")]
+    connect_heading_tolerance: f64,
+    /// Also require nearby edges to run within the connect heading tolerance of the node, so the
+    /// connect step only links roads running in the same direction
+    #[clap(long, default_value = "false", default_missing_value = "true")]
+    connect_parallel_only: bool,
+    #[clap(
+        short = 'C',
+        long,
+        default_value = "false",
+        default_missing_value = "true"
+    )]
+    compact: bool,
+    #[clap(long, default_value = "travel")]
+    node_heading: NodeHeading,
+    /// Relabel edges with another direction than their road and remove edges against one-way
+    /// roads, instead of only reporting them
+    #[clap(long, default_value = "false", default_missing_value = "true")]
+    repair_directions: bool,
+    /// Fill in missing speed limits from neighbouring edges of the same road, falling back to this
+    /// speed in km/h
+    #[clap(long, default_missing_value = "50")]
+    default_speed: Option<f64>,
+    /// Speed limit in km/h given to connectors when filling in missing speed limits
+    #[clap(long, default_value = "20")]
+    connector_speed: f64,
+    /// Simplify edge polylines with Douglas-Peucker, dropping points closer than this many meters
+    /// to the simplified line, and measure the edge distance along the simplified polyline
+    #[clap(long)]
+    simplify: Option<f64>,
+    /// Simplify edge polylines with more points than this, both as nodes are collapsed and once
+    /// more at the end
+    #[clap(long)]
+    max_polyline_points: Option<usize>,
+    /// Render the graph after the connect pass to this SVG file, with the added connectors and
+    /// the rejected candidate connections
+    #[clap(long)]
+    render_connectors: Option<String>,
+    /// Keep roads closed to traffic in both directions as one-way edges that routing never
+    /// traverses, so they can still be drawn
+    #[clap(long, default_value = "false", default_missing_value = "true")]
+    keep_forbidden: bool,
+    /// Write the roads that have no edges left in the graph, and the pass that removed them, to this CSV file
+    #[clap(long)]
+    trace_dropped: Option<String>,
+    /// Leave sensors farther than this many meters from their nearest node unassigned
+    #[clap(long)]
+    max_sensor_assign_distance: Option<f64>,
+    /// Earth model the distances of edges are measured on. Lookups of nearby nodes, edges and
+    /// sensors, and the thresholds compared against them, use the great circle distance
+    #[clap(long, default_value = "haversine")]
+    distance_model: DistanceModel,
+}
+
+/// How node headings are derived from the edges around a node
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum NodeHeading {
+    /// Use the outgoing edges in the direction the road is traveled, ignoring the reverse edges of two-way roads
+    Travel,
+    /// Average the headings of all incoming and outgoing edges
+    AllEdges,
 }
 
 #[derive(Debug, Clone, ValueEnum)]
 pub enum NodeCollapse {
     Naive,
     ForwardOnly,
+    Bidirectional,
     None,
 }
 
+/// How many of the sensors too far from the graph to assign are listed by id
+const UNASSIGNED_EXAMPLES: usize = 10;
+
+/// How many of the pairs of identical roads with different lengths are listed by id
+const MISMATCHED_EXAMPLES: usize = 10;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ProcessedGraph {
     pub graph: StableDiGraph<NodeData, EdgeData>,
     pub sensor_store: HashMap<NodeIndex, Vec<SensorMetadata>>,
 }
 
+/// Remove every road with the same coordinates and direction as a later road, keeping the last
+/// copy. Roads are bucketed by a hash of their coordinates so only roads in the same bucket are
+/// compared. Also returns the ids of identical roads with different lengths, and how far apart
+/// their lengths are.
+fn dedup_roads(
+    pb: &ProgressBar,
+    road_data: Vec<RoadData>,
+) -> (Vec<RoadData>, Vec<(i32, i32, f64)>) {
+    let hashes = road_data
+        .par_iter()
+        .map(|road| {
+            let mut hasher = DefaultHasher::new();
+            for point in road.coordinates.iter() {
+                point.latitude.to_bits().hash(&mut hasher);
+                point.longitude.to_bits().hash(&mut hasher);
+            }
+            hasher.finish()
+        })
+        .collect::<Vec<_>>();
+    let mut buckets = HashMap::<u64, Vec<usize>>::new();
+    for (idx, hash) in hashes.into_iter().enumerate() {
+        buckets.entry(hash).or_default().push(idx);
+    }
+
+    let (duplicates, mismatched) = buckets
+        .into_par_iter()
+        .map(|(_, bucket)| {
+            let mut duplicates = Vec::new();
+            let mut mismatched = Vec::new();
+            for (pos, &i) in bucket.iter().enumerate() {
+                let road = &road_data[i];
+                for &j in bucket[pos + 1..].iter() {
+                    let other_road = &road_data[j];
+                    if road.coordinates != other_road.coordinates {
+                        continue;
+                    }
+                    if road.length != other_road.length {
+                        let diff = (road.length - other_road.length).abs();
+                        mismatched.push((road.unique_id, other_road.unique_id, diff));
+                    }
+                    if road.direction == other_road.direction {
+                        duplicates.push(i);
+                        break;
+                    }
+                }
+                pb.inc(1);
+            }
+            (duplicates, mismatched)
+        })
+        .reduce(
+            || (Vec::new(), Vec::new()),
+            |(mut duplicates, mut mismatched), (more_duplicates, more_mismatched)| {
+                duplicates.extend(more_duplicates);
+                mismatched.extend(more_mismatched);
+                (duplicates, mismatched)
+            },
+        );
+
+    let duplicates = duplicates.into_iter().collect::<HashSet<_>>();
+    let unique_roads = road_data
+        .into_iter()
+        .enumerate()
+        .filter(|(idx, _)| !duplicates.contains(idx))
+        .map(|(_, road)| road)
+        .collect();
+    (unique_roads, mismatched)
+}
+
 pub fn process_graph(
     options: GraphProcessingOptions,
     mut road_data: Vec<RoadData>,
@@ -168,141 +340,107 @@ pub fn process_graph(
 
     let mut graph = StableDiGraph::new();
     let mut sensor_store = HashMap::<NodeIndex, Vec<SensorMetadata>>::new();
+    let mut trace = options
+        .trace_dropped
+        .as_ref()
+        .map(|_| trace::DropTrace::new(&road_data));
 
     progress.step_unsized("Calculating middle and range of sensors");
-    let sensor_middle = sensor_data.iter().map(|s| s.point()).fold(
-        Point {
-            latitude: 0.0,
-            longitude: 0.0,
-        },
-        |acc, p| Point {
-            latitude: acc.latitude + p.latitude,
-            longitude: acc.longitude + p.longitude,
-        },
-    );
-    let sensor_middle = Point {
-        latitude: sensor_middle.latitude / sensor_data.len() as f64,
-        longitude: sensor_middle.longitude / sensor_data.len() as f64,
-    };
-    let range = sensor_data
-        .iter()
-        .map(|s| dist(sensor_middle, s.point()))
-        .fold(0.0, f64::max);
-    let range = range + options.max_distance_from_sensors;
+    let (sensor_middle, range) = sensor_extent(&sensor_data, options.max_distance_from_sensors);
     progress.finish(format!(
         "Middle: {:?}, Range: {}",
         style(sensor_middle).bold(),
         style(range).bold()
     ));
 
-    if options.dedup_road_data {
-        progress.step_sized(road_data.len(), "Deduplicating road data");
+    let filter_on_ingest =
+        options.filter_on_ingest && options.max_distance_from_sensors < f64::INFINITY;
+    let sensor_tree = build_sensor_acceleration_structure(sensor_data.iter());
+    let in_range = sensor_range(
+        &sensor_tree,
+        sensor_middle,
+        range,
+        options.max_distance_from_sensors,
+    );
 
-        let mut unique_roads = Vec::new();
+    if filter_on_ingest {
+        progress.step_sized(road_data.len(), "Dropping roads not close to any sensors");
+        let pb = progress.get_pb();
         let len = road_data.len();
-        'outer: for i in 0..len {
-            for j in i..len {
-                if i == j {
-                    continue;
-                }
+        road_data = road_data
+            .into_par_iter()
+            .filter(|road| {
+                pb.inc(1);
+                road.coordinates.iter().any(|point| in_range(*point))
+            })
+            .collect();
+        progress.finish(format!(
+            "Dropped {} of {} roads",
+            style(len - road_data.len()).bold(),
+            len
+        ));
+        if let Some(trace) = trace.as_mut() {
+            let present = road_data.iter().map(|road| road.unique_id).collect();
+            trace.record("sensor distance filter", &present);
+        }
+    }
 
-                let road = &road_data[i];
-                let other_road = &road_data[j];
-
-                if road.coordinates.len() == other_road.coordinates.len() {
-                    let identical = road
-                        .coordinates
-                        .iter()
-                        .zip(other_road.coordinates.iter())
-                        .all(|(a, b)| a == b);
-                    if identical {
-                        if road.length != other_road.length {
-                            let diff = (road.length - other_road.length).abs();
-                            panic!(
-                                "Different lengths {} {} {}",
-                                road.unique_id, other_road.unique_id, diff
-                            );
-                        }
+    if options.dedup_road_data {
+        progress.step_sized(road_data.len(), "Deduplicating road data");
 
-                        if road.direction == other_road.direction {
-                            continue 'outer;
-                        }
-                    }
-                }
-            }
-            unique_roads.push(road_data[i].clone());
-            progress.tick();
-        }
+        let len = road_data.len();
+        let (unique_roads, mismatched) = dedup_roads(progress.get_pb(), road_data);
         road_data = unique_roads;
         progress.finish(format!(
             "Removed {} duplicate roads",
             style(len - road_data.len()).bold()
         ));
+        for (road, other_road, diff) in mismatched.iter().take(MISMATCHED_EXAMPLES) {
+            println!(
+                "{} Roads {} and {} have identical coordinates but lengths {:.2}m apart",
+                style("[!]").bold().yellow(),
+                road,
+                other_road,
+                diff
+            );
+        }
+        if mismatched.len() > MISMATCHED_EXAMPLES {
+            println!(
+                "{} ...and {} more",
+                style("[!]").bold().yellow(),
+                mismatched.len() - MISMATCHED_EXAMPLES
+            );
+        }
     } else {
         progress.step_single("Skipping deduplication of road data");
     }
+    if let Some(trace) = trace.as_mut() {
+        let present = road_data.iter().map(|road| road.unique_id).collect();
+        trace.record("road data deduplication", &present);
+        let present = road_data
+            .iter()
+            .filter(|road| options.keep_forbidden || road.direction != RoadDirection::None)
+            .map(|road| road.unique_id)
+            .collect();
+        trace.record("road direction", &present);
+    }
 
     progress.step_unsized("Adding nodes and edges");
-    let mut skipped = 0;
-    for road in road_data.iter_mut() {
-        let mut prev_node: Option<(NodeIndex, NodeData)> = None;
-
-        if road.direction == RoadDirection::None {
-            continue;
-        }
-
-        if road.direction == RoadDirection::Backward {
-            road.coordinates.reverse()
-        }
-
-        for (idx, point) in road.coordinates.iter().enumerate() {
-            if options.max_distance_from_sensors < f64::INFINITY {
-                let d = dist(sensor_middle, *point);
-                if d > range {
-                    prev_node = None;
-                    skipped += 1;
-                    continue;
-                }
-            }
-
-            let node_data = NodeData {
-                point: *point,
-                direction: road.direction,
-                main_number: road.main_number,
-                sub_number: road.sub_number,
-                original_road_id: road.unique_id,
-                heading: 0.0,
-                is_road_cap: idx == 0 || idx == road.coordinates.len() - 1,
-                has_sensor: false,
-            };
-            let node = graph.add_node(node_data);
-
-            if let Some((prev_idx, prev_data)) = prev_node {
-                let dist = dist(prev_data.point, node_data.point);
-                let edge_data = EdgeData {
-                    distance: dist,
-                    main_number: road.main_number,
-                    sub_number: road.sub_number,
-                    polyline: vec![prev_data.point, node_data.point],
-                    is_connector: false,
-                    midpoint: midpoint(prev_data.point, node_data.point),
-                    direction: direction_from_data(prev_data, node_data),
-                    original_road_id: road.unique_id,
-                    speed_limit: Some(road.speed_limit),
-                };
-                if road.direction == RoadDirection::Both {
-                    let mut rev_edge_data = edge_data.clone();
-                    rev_edge_data.polyline.reverse();
-                    graph.add_edge(node, prev_idx, rev_edge_data);
-                }
-
-                graph.add_edge(prev_idx, node, edge_data);
+    let (skipped, out_of_range_roads, reverse_edges) = add_roads(
+        &mut graph,
+        &mut road_data,
+        options.keep_forbidden,
+        options.distance_model,
+        |point| {
+            if filter_on_ingest {
+                !in_range(point)
+            } else {
+                options.max_distance_from_sensors < f64::INFINITY
+                    && dist(sensor_middle, point) > range
             }
-
-            prev_node = Some((node, node_data));
-        }
-        progress.tick();
-    }
+        },
+        |_| progress.tick(),
+    );
     progress.finish(format!(
         "Added {} nodes and {} edges, skipping {} nodes out of range",
         style(graph.node_count()).bold(),
@@ -310,34 +448,31 @@ pub fn process_graph(
         style(skipped).bold()
     ));
 
-    progress.step_sized(graph.node_count(), "Calculating node headings");
-    for node in graph.clone().node_indices() {
-        let in_edges = graph.edges_directed(node, Incoming);
-        let out_edges = graph.edges_directed(node, Outgoing);
-
-        let mut headings = Vec::new();
-
-        for edge in in_edges.clone() {
-            let endpoints = graph.edge_endpoints(edge.id()).unwrap();
-            let start = graph.node_weight(endpoints.0).unwrap();
-            let end = graph.node_weight(endpoints.1).unwrap();
-            headings.push(line_heading(start.point, end.point));
-        }
-
-        for edge in out_edges.clone() {
-            let endpoints = graph.edge_endpoints(edge.id()).unwrap();
-            let start = graph.node_weight(endpoints.0).unwrap();
-            let end = graph.node_weight(endpoints.1).unwrap();
-            headings.push(line_heading(start.point, end.point));
-        }
+    if let Some(trace) = trace.as_mut() {
+        // Roads left without edges by skipping their points out of range are dropped by the range
+        let mut present = trace.graph_roads(&graph);
+        present.extend(
+            road_data
+                .iter()
+                .map(|road| road.unique_id)
+                .filter(|id| !out_of_range_roads.contains(id)),
+        );
+        trace.record("sensor distance filter", &present);
+        trace.record_graph("adding nodes and edges", &graph);
+    }
 
-        let data = graph.node_weight_mut(node).unwrap();
-        data.heading = angle_average(&headings);
-        progress.tick();
+    progress.step_sized(graph.node_count(), "Calculating node headings");
+    let pb = progress.get_pb();
+    let headings = node_headings(&graph, &reverse_edges, options.node_heading, || pb.inc(1));
+    for (node, heading) in headings {
+        graph.node_weight_mut(node).unwrap().heading = heading;
     }
     progress.finish("Calculated node headings");
 
-    if options.max_distance_from_sensors < f64::INFINITY {
+    if filter_on_ingest {
+        progress
+            .step_single("Skipping removal of nodes not close to any sensors, already filtered");
+    } else if options.max_distance_from_sensors < f64::INFINITY {
         progress.step_sized(
             graph.node_count(),
             format!(
@@ -346,7 +481,6 @@ pub fn process_graph(
             ),
         );
         let pb = progress.get_pb();
-        let sensor_tree = build_sensor_acceleration_structure(sensor_data.iter());
         let to_remove = graph
             .node_indices()
             .par_bridge()
@@ -377,6 +511,9 @@ pub fn process_graph(
     } else {
         progress.step_single("Skipping removal of nodes not close to any sensors");
     }
+    if let Some(trace) = trace.as_mut().filter(|_| !filter_on_ingest) {
+        trace.record_graph("sensor distance filter", &graph);
+    }
 
     if !options.merge_overlap_distance.is_nan() {
         progress.step_sized(
@@ -387,78 +524,53 @@ pub fn process_graph(
             ),
         );
 
-        let node_tree = build_node_acceleration_structure(&graph);
-        let mut removed = HashSet::new();
-        let indices = graph.node_indices().collect::<Vec<_>>();
-        for node in indices {
-            if removed.contains(&node) {
-                progress.tick();
-                continue;
-            }
-            let data = graph.node_weight(node).unwrap().clone();
-
-            if !data.is_road_cap {
-                progress.tick();
-                continue;
-            }
-
-            let borrowed = [data.point.latitude, data.point.longitude];
-            let mut close_iter = node_tree.iter_nearest(&borrowed, &geo_distance).unwrap();
-
-            while let Some((_, (other, other_data))) = close_iter.next() {
-                if node == *other {
-                    continue;
-                }
-
-                let d = dist(data.point, other_data.point);
-                if d <= options.merge_overlap_distance {
-                    let mut edges = Vec::new();
-                    let in_edges = graph.edges_directed(*other, Incoming);
-                    for edge in in_edges {
-                        if !are_neighbours(&graph, edge.source(), node) {
-                            edges.push((edge.source(), node, edge.weight().clone()));
-                        }
-                    }
-                    let out_edges = graph.edges_directed(*other, Outgoing);
-                    for edge in out_edges {
-                        if !are_neighbours(&graph, node, edge.target()) {
-                            edges.push((node, edge.target(), edge.weight().clone()));
-                        }
-                    }
-
-                    graph.remove_node(*other);
-                    removed.insert(*other);
-                    for (from, to, data) in edges {
-                        graph.add_edge(from, to, data);
-                    }
-                } else {
-                    break;
-                }
-            }
-            progress.tick();
-        }
-        progress.finish(format!(
-            "Merged {} overlapping nodes",
-            style(removed.len()).bold()
-        ));
+        let pb = progress.get_pb();
+        let merged =
+            merge_overlapping_nodes(&mut graph, options.merge_overlap_distance, || pb.inc(1));
+        progress.finish(format!("Merged {} overlapping nodes", style(merged).bold()));
+    }
+    if let Some(trace) = trace.as_mut() {
+        trace.record_graph("overlap merging", &graph);
     }
 
     progress.step_sized(sensor_data.len(), "Assigning sensors to nodes");
     let node_tree = build_node_acceleration_structure(&graph);
+    let max_assign_distance = options.max_sensor_assign_distance.unwrap_or(f64::INFINITY);
+    let mut unassigned = Vec::new();
     for sensor in sensor_data {
-        let (_, closest_idx) = find_closest_node(&node_tree, sensor.point());
+        let (distance, closest_idx) = find_closest_node(&node_tree, sensor.point());
+        progress.tick();
+        if distance > max_assign_distance {
+            unassigned.push((sensor.site_id, distance));
+            continue;
+        }
         sensor_store
             .entry(closest_idx)
             .or_insert(Vec::new())
             .push(sensor);
         let data = graph.node_weight_mut(closest_idx).unwrap();
         data.has_sensor = true;
-        progress.tick();
     }
     progress.finish(format!(
-        "Assigned sensors to {} nodes",
-        style(sensor_store.len()).bold()
+        "Assigned sensors to {} nodes, left {} sensors unassigned",
+        style(sensor_store.len()).bold(),
+        style(unassigned.len()).bold()
     ));
+    for (site_id, distance) in unassigned.iter().take(UNASSIGNED_EXAMPLES) {
+        println!(
+            "{} Sensor {} is {:.0}m from the nearest node",
+            style("[!]").bold().yellow(),
+            site_id,
+            distance
+        );
+    }
+    if unassigned.len() > UNASSIGNED_EXAMPLES {
+        println!(
+            "{} ...and {} more",
+            style("[!]").bold().yellow(),
+            unassigned.len() - UNASSIGNED_EXAMPLES
+        );
+    }
 
     progress.step_sized(graph.edge_count(), "Finding longest road segment");
     let mut longest_road_segment = f64::NEG_INFINITY;
@@ -478,9 +590,12 @@ pub fn process_graph(
         progress.step_sized(graph.node_count(), "Connecting individual roads");
         let pb = progress.get_pb();
         let edge_tree = build_edge_acceleration_structure(&graph, None);
+        let record_rejected = options.render_connectors.is_some();
+        let tolerance = options.connect_heading_tolerance;
         let par_iter = graph.node_indices().par_bridge();
-        let to_connect = par_iter
-            .filter_map(|node| {
+        let decisions = par_iter
+            .map(|node| {
+                let mut rejected = Vec::new();
                 let data = graph.node_weight(node).unwrap();
 
                 let in_edges = graph.edges_directed(node, Incoming);
@@ -510,48 +625,38 @@ pub fn process_graph(
                         continue;
                     }
 
-                    let endpoints = graph.edge_endpoints(edge).unwrap();
-                    let start = graph.node_weight(endpoints.0).unwrap();
-                    let end = graph.node_weight(endpoints.1).unwrap();
-
-                    if !is_cap {
-                        // Only allow to connect to roads with the same heading if its not a road cap
-                        let heading = line_heading(start.point, end.point);
-                        if angle_diff(heading, data.heading).abs() > 15.0 {
-                            //continue;
-                        }
-
-                        let s_head = line_heading(data.point, start.point);
-                        let e_head = line_heading(data.point, end.point);
-
-                        let s_head_diff = angle_diff(data.heading, s_head).abs();
-                        let e_head_diff = angle_diff(data.heading, e_head).abs();
-
-                        if s_head_diff > 15.0 && e_head_diff > 15.0 {
-                            continue;
-                        } else if s_head_diff > 15.0 {
-                            return Some((node, endpoints.1));
-                        } else if e_head_diff > 15.0 {
-                            return Some((node, endpoints.0));
-                        } else {
-                            if dist(data.point, start.point) > dist(data.point, end.point) {
-                                return Some((node, endpoints.1));
-                            } else {
-                                return Some((node, endpoints.0));
+                    let endpoint = closest_endpoint(
+                        &graph,
+                        edge,
+                        data,
+                        is_cap,
+                        tolerance,
+                        options.connect_parallel_only,
+                    );
+                    match endpoint {
+                        Ok(to) => return (Some((node, to)), rejected),
+                        Err((to, reason)) => {
+                            if record_rejected {
+                                rejected.push(connectors::RejectedConnection {
+                                    from: node,
+                                    to,
+                                    reason,
+                                });
                             }
                         }
-                    } else {
-                        if dist(data.point, start.point) > dist(data.point, end.point) {
-                            return Some((node, endpoints.1));
-                        } else {
-                            return Some((node, endpoints.0));
-                        }
                     }
                 }
-                return None;
+                return (None, rejected);
             })
             .collect::<Vec<_>>();
 
+        let mut to_connect = Vec::new();
+        let mut rejected = Vec::new();
+        for (connection, node_rejected) in decisions {
+            to_connect.extend(connection);
+            rejected.extend(node_rejected);
+        }
+
         let mut skipped = 0;
         let mut connected = 0;
 
@@ -560,12 +665,19 @@ pub fn process_graph(
             let to_data = graph.node_weight(to).unwrap().clone();
 
             if are_neighbours(&graph, from, to) {
+                if record_rejected {
+                    rejected.push(connectors::RejectedConnection {
+                        from,
+                        to,
+                        reason: connectors::RejectReason::Neighbours,
+                    });
+                }
                 skipped += 1;
                 continue;
             }
             connected += 1;
 
-            let d = dist(from_data.point, to_data.point);
+            let d = dist_with(from_data.point, to_data.point, options.distance_model);
 
             let edge_data = EdgeData {
                 distance: d,
@@ -598,6 +710,15 @@ pub fn process_graph(
             style(connected).bold(),
             style(skipped).bold()
         ));
+
+        if let Some(path) = &options.render_connectors {
+            connectors::render_connectors(&graph, &rejected, path);
+            println!(
+                "Rendered connectors and {} rejected connections to {}",
+                style(rejected.len()).bold(),
+                path
+            );
+        }
     } else {
         progress.step_single("Skipping connection of individual roads");
     }
@@ -641,6 +762,9 @@ pub fn process_graph(
     } else {
         progress.step_single("Skipping removal of disjointed nodes");
     }
+    if let Some(trace) = trace.as_mut() {
+        trace.record_graph("disjoint removal", &graph);
+    }
 
     if options.dedup_edges {
         progress.step_sized(graph.edge_count(), "Removing duplicate edges");
@@ -689,13 +813,32 @@ pub fn process_graph(
     } else {
         progress.step_single("Skipping removal of duplicate edges");
     }
+    if let Some(trace) = trace.as_mut() {
+        trace.record_graph("edge deduplication", &graph);
+    }
+
+    if let Some(default_speed) = options.default_speed {
+        progress.step_unsized("Imputing missing speed limits");
+        let (propagated, defaulted) =
+            impute_speed_limits(&mut graph, default_speed, options.connector_speed);
+        progress.finish(format!(
+            "Propagated {} speed limits from neighbouring edges and defaulted {}",
+            style(propagated).bold(),
+            style(defaulted).bold()
+        ));
+    } else {
+        progress.step_single("Skipping imputation of missing speed limits");
+    }
 
     match options.collapse_nodes {
         NodeCollapse::Naive => {
             progress.step_unsized(format!("Collapsing nodes: {}", style("naive").bold()));
 
             let nodes = graph.node_count();
-            collapse::naive(&mut graph);
+            let merged = collapse::naive(&mut graph, options.max_polyline_points);
+            if let Some(trace) = trace.as_mut() {
+                trace.record_merged(merged);
+            }
 
             progress.finish(format!(
                 "Collapsed {} nodes",
@@ -709,7 +852,27 @@ pub fn process_graph(
             ));
 
             let nodes = graph.node_count();
-            collapse::forward_only(&mut graph);
+            let merged = collapse::forward_only(&mut graph, options.max_polyline_points);
+            if let Some(trace) = trace.as_mut() {
+                trace.record_merged(merged);
+            }
+
+            progress.finish(format!(
+                "Collapsed {} nodes",
+                style(nodes - graph.node_count()).bold()
+            ));
+        }
+        NodeCollapse::Bidirectional => {
+            progress.step_unsized(format!(
+                "Collapsing nodes: {}",
+                style("bidirectional").bold()
+            ));
+
+            let nodes = graph.node_count();
+            let merged = collapse::bidirectional(&mut graph, options.max_polyline_points);
+            if let Some(trace) = trace.as_mut() {
+                trace.record_merged(merged);
+            }
 
             progress.finish(format!(
                 "Collapsed {} nodes",
@@ -720,6 +883,78 @@ pub fn process_graph(
             progress.step_single("Skipping node collapse");
         }
     }
+    if let Some(trace) = trace.as_mut() {
+        trace.record_graph("node collapse", &graph);
+    }
+
+    if options.repair_directions {
+        progress.step_unsized("Repairing edge directions against their roads");
+        let (relabeled, removed) = repair_directions(&mut graph, &road_data);
+        progress.finish(format!(
+            "Relabeled {} edges and removed {} edges against one-way roads",
+            style(relabeled).bold(),
+            style(removed).bold()
+        ));
+        if let Some(trace) = trace.as_mut() {
+            trace.record_graph("direction repair", &graph);
+        }
+    } else {
+        progress.step_unsized("Checking edge directions against their roads");
+        let mismatches = check_directions(&graph, &road_data);
+        progress.finish(format!(
+            "Found {} edges with another direction than their road and {} edges against one-way \
+             roads, repair them with --repair-directions",
+            style(mismatches.relabel.len()).bold(),
+            style(mismatches.against.len()).bold()
+        ));
+    }
+
+    if let Some(epsilon) = options.simplify {
+        progress.step_unsized(format!(
+            "Simplifying polylines with epsilon {}m",
+            style(epsilon).bold()
+        ));
+        let (edges, removed) =
+            simplify::simplify_polylines(&mut graph, epsilon, options.distance_model);
+        progress.finish(format!(
+            "Simplified {} edges, removing {} points",
+            style(edges).bold(),
+            style(removed).bold()
+        ));
+    } else {
+        progress.step_single("Skipping Douglas-Peucker simplification");
+    }
+
+    if let Some(max_points) = options.max_polyline_points {
+        progress.step_sized(
+            graph.edge_count(),
+            format!(
+                "Simplifying polylines to at most {} points",
+                style(max_points).bold()
+            ),
+        );
+        let (edges, removed) = simplify::simplify_graph(&mut graph, max_points);
+        progress.finish(format!(
+            "Simplified {} edges, removing {} points",
+            style(edges).bold(),
+            style(removed).bold()
+        ));
+    } else {
+        progress.step_single("Skipping polyline simplification");
+    }
+
+    if options.compact {
+        progress.step_unsized("Compacting node indices");
+        let (compacted, remap) = compact(&graph);
+        graph = compacted;
+        sensor_store = remap_sensor_store(sensor_store, &remap);
+        progress.finish(format!(
+            "Compacted {} nodes",
+            style(graph.node_count()).bold()
+        ));
+    } else {
+        progress.step_single("Skipping compaction of node indices");
+    }
 
     println!(
         "{:?} Completed processing graph with {} nodes and {} edges remaining",
@@ -728,26 +963,631 @@ pub fn process_graph(
         style(graph.edge_count()).bold()
     );
 
+    if let (Some(trace), Some(path)) = (trace, options.trace_dropped) {
+        trace.save(&path);
+    }
+
     ProcessedGraph {
         graph,
         sensor_store,
     }
 }
 
-fn are_neighbours(graph: &StableDiGraph<NodeData, EdgeData>, a: NodeIndex, b: NodeIndex) -> bool {
-    graph.edges_connecting(a, b).count() > 0
+/// Rough size of the graph [estimate_graph] expects **process_graph** to build
+#[derive(Debug, PartialEq)]
+pub struct GraphEstimate {
+    pub roads: usize,
+    pub nodes: usize,
+    pub edges: usize,
+    pub out_of_range: usize,
+    pub merge_candidates: Option<usize>,
 }
 
-fn build_sensor_acceleration_structure<'a, I: Iterator<Item = &'a SensorMetadata>>(
-    sensors: I,
-) -> KdTree<f64, SensorMetadata, [f64; 2]> {
-    let mut kdtree = KdTree::new(2);
+/// Report roughly how large the graph built from **road_data** would be, and how much the sensor
+/// distance filter and overlap merging would remove, without building it
+pub fn estimate_graph(
+    options: &GraphProcessingOptions,
+    road_data: &[RoadData],
+    sensor_data: &[SensorMetadata],
+) -> GraphEstimate {
+    let mut progress = Progress::new();
 
-    sensors.for_each(|data| {
-        let point = data.point();
-        kdtree
-            .add([point.latitude, point.longitude], data.clone())
-            .unwrap();
+    progress.step_unsized("Calculating middle and range of sensors");
+    let (sensor_middle, range) = sensor_extent(sensor_data, options.max_distance_from_sensors);
+    progress.finish(format!(
+        "Middle: {:?}, Range: {}",
+        style(sensor_middle).bold(),
+        style(range).bold()
+    ));
+
+    let roads = road_data
+        .iter()
+        .filter(|road| options.keep_forbidden || road.direction != RoadDirection::None)
+        .collect::<Vec<_>>();
+    let nodes = roads
+        .iter()
+        .map(|road| road.coordinates.len())
+        .sum::<usize>();
+    let edges = roads
+        .iter()
+        .map(|road| {
+            let segments = road.coordinates.len().saturating_sub(1);
+            if road.direction == RoadDirection::Both {
+                segments * 2
+            } else {
+                segments
+            }
+        })
+        .sum::<usize>();
+
+    progress.step_sized(roads.len(), "Counting nodes not close to any sensors");
+    let sensor_tree = build_sensor_acceleration_structure(sensor_data.iter());
+    let out_of_range = if options.max_distance_from_sensors < f64::INFINITY {
+        let pb = progress.get_pb();
+        roads
+            .par_iter()
+            .map(|road| {
+                pb.inc(1);
+                road.coordinates
+                    .iter()
+                    .filter(|point| {
+                        dist(sensor_middle, **point) > range
+                            || find_closest_sensor(&sensor_tree, **point).0
+                                > options.max_distance_from_sensors
+                    })
+                    .count()
+            })
+            .sum::<usize>()
+    } else {
+        0
+    };
+    progress.finish(format!(
+        "Found {} of {} nodes out of range",
+        style(out_of_range).bold(),
+        nodes
+    ));
+
+    // Only road caps are merged, so count the ones with another node within the overlap distance
+    let merge_candidates = if options.merge_overlap_distance.is_nan() {
+        None
+    } else {
+        progress.step_sized(roads.len(), "Counting road caps to merge");
+        let mut tree = KdTree::new(2);
+        for point in roads.iter().flat_map(|road| road.coordinates.iter()) {
+            tree.add([point.latitude, point.longitude], *point).unwrap();
+        }
+        let pb = progress.get_pb();
+        let candidates = roads
+            .par_iter()
+            .map(|road| {
+                pb.inc(1);
+                [road.coordinates.first(), road.coordinates.last()]
+                    .into_iter()
+                    .flatten()
+                    .filter(|point| {
+                        let p = [point.latitude, point.longitude];
+                        tree.iter_nearest(&p, &geo_distance)
+                            .unwrap()
+                            .nth(1)
+                            .is_some_and(|(_, other)| {
+                                dist(**point, *other) <= options.merge_overlap_distance
+                            })
+                    })
+                    .count()
+            })
+            .sum::<usize>();
+        progress.finish(format!(
+            "Found {} road caps to merge",
+            style(candidates).bold()
+        ));
+        Some(candidates)
+    };
+
+    let estimate = GraphEstimate {
+        roads: roads.len(),
+        nodes,
+        edges,
+        out_of_range,
+        merge_candidates,
+    };
+    println!(
+        "Estimated {} nodes and {} edges from {} roads",
+        style(estimate.nodes).bold(),
+        style(estimate.edges).bold(),
+        style(estimate.roads).bold()
+    );
+    println!(
+        "Estimated {} nodes after removing {} out of range",
+        style(estimate.nodes - estimate.out_of_range).bold(),
+        style(estimate.out_of_range).bold()
+    );
+    if let Some(candidates) = estimate.merge_candidates {
+        println!(
+            "Estimated {} road caps within {}m of another node to merge",
+            style(candidates).bold(),
+            style(options.merge_overlap_distance).bold()
+        );
+    }
+    estimate
+}
+
+/// The middle of the sensors, and the distance from it within which nodes can be at most
+/// **max_distance** from a sensor
+fn sensor_extent(sensor_data: &[SensorMetadata], max_distance: f64) -> (Point, f64) {
+    let sensor_middle = sensor_data.iter().map(|s| s.point()).fold(
+        Point {
+            latitude: 0.0,
+            longitude: 0.0,
+        },
+        |acc, p| Point {
+            latitude: acc.latitude + p.latitude,
+            longitude: acc.longitude + p.longitude,
+        },
+    );
+    let sensor_middle = Point {
+        latitude: sensor_middle.latitude / sensor_data.len() as f64,
+        longitude: sensor_middle.longitude / sensor_data.len() as f64,
+    };
+    let range = sensor_data
+        .iter()
+        .map(|s| dist(sensor_middle, s.point()))
+        .fold(0.0, f64::max);
+    (sensor_middle, range + max_distance)
+}
+
+/// Whether a point is within **max_distance** of a sensor, checking first that it is within the
+/// sensor **range** of their middle
+fn sensor_range<'a>(
+    sensor_tree: &'a KdTree<f64, SensorMetadata, [f64; 2]>,
+    sensor_middle: Point,
+    range: f64,
+    max_distance: f64,
+) -> impl Fn(Point) -> bool + Sync + 'a {
+    move |point| {
+        dist(sensor_middle, point) <= range
+            && find_closest_sensor(sensor_tree, point).0 <= max_distance
+    }
+}
+
+/// Add a node for every point of the roads that is not **out_of_range**, and an edge between
+/// consecutive nodes of each road, calling **tick** with the graph after each road.
+///
+/// Returns the number of skipped points, the roads that had points skipped and the edges added
+/// against the direction of their two-way road.
+fn add_roads(
+    graph: &mut StableDiGraph<NodeData, EdgeData>,
+    road_data: &mut [RoadData],
+    keep_forbidden: bool,
+    model: DistanceModel,
+    out_of_range: impl Fn(Point) -> bool,
+    mut tick: impl FnMut(&StableDiGraph<NodeData, EdgeData>),
+) -> (usize, HashSet<i32>, HashSet<EdgeIndex>) {
+    let mut skipped = 0;
+    let mut out_of_range_roads = HashSet::new();
+    let mut reverse_edges = HashSet::new();
+    for road in road_data.iter_mut() {
+        let mut prev_node: Option<(NodeIndex, NodeData)> = None;
+
+        if road.direction == RoadDirection::None && !keep_forbidden {
+            continue;
+        }
+
+        if road.direction == RoadDirection::Backward {
+            road.coordinates.reverse()
+        }
+
+        for (idx, point) in road.coordinates.iter().enumerate() {
+            if out_of_range(*point) {
+                prev_node = None;
+                skipped += 1;
+                out_of_range_roads.insert(road.unique_id);
+                continue;
+            }
+
+            let node_data = NodeData {
+                point: *point,
+                direction: road.direction,
+                main_number: road.main_number,
+                sub_number: road.sub_number,
+                original_road_id: road.unique_id,
+                heading: 0.0,
+                is_road_cap: idx == 0 || idx == road.coordinates.len() - 1,
+                has_sensor: false,
+            };
+            let node = graph.add_node(node_data);
+
+            if let Some((prev_idx, prev_data)) = prev_node {
+                let dist = dist_with(prev_data.point, node_data.point, model);
+                let edge_data = EdgeData {
+                    distance: dist,
+                    main_number: road.main_number,
+                    sub_number: road.sub_number,
+                    polyline: vec![prev_data.point, node_data.point],
+                    is_connector: false,
+                    midpoint: midpoint(prev_data.point, node_data.point),
+                    direction: direction_from_data(prev_data, node_data),
+                    original_road_id: road.unique_id,
+                    speed_limit: Some(road.speed_limit),
+                };
+                if road.direction == RoadDirection::Both {
+                    let mut rev_edge_data = edge_data.clone();
+                    rev_edge_data.polyline.reverse();
+                    reverse_edges.insert(graph.add_edge(node, prev_idx, rev_edge_data));
+                }
+
+                graph.add_edge(prev_idx, node, edge_data);
+            }
+
+            prev_node = Some((node, node_data));
+        }
+        tick(graph);
+    }
+
+    (skipped, out_of_range_roads, reverse_edges)
+}
+
+/// Remove the nodes outside the box, keeping those just outside that share an edge with a node
+/// inside so roads crossing the boundary are not cut off
+pub fn crop_to_bbox(
+    graph: &mut StableDiGraph<NodeData, EdgeData>,
+    min_lat: f64,
+    max_lat: f64,
+    min_lon: f64,
+    max_lon: f64,
+) {
+    let inside = |node: NodeIndex| {
+        let point = graph[node].point;
+        point.latitude >= min_lat
+            && point.latitude <= max_lat
+            && point.longitude >= min_lon
+            && point.longitude <= max_lon
+    };
+    let to_remove = graph
+        .node_indices()
+        .filter(|node| !inside(*node) && !graph.neighbors_undirected(*node).any(inside))
+        .collect::<Vec<_>>();
+    for node in to_remove {
+        graph.remove_node(node);
+    }
+}
+
+fn has_speed_limit(edge: &EdgeData) -> bool {
+    edge.speed_limit
+        .is_some_and(|speed| speed.is_finite() && speed > 0.0)
+}
+
+/// Fill in missing or non-positive speed limits. Connectors get **connector_speed**, other edges
+/// repeatedly take the mean of the adjacent edges on the same road number that have a speed limit,
+/// and edges on roads without any get **default_speed**. Returns the number of edges filled in
+/// from neighbours and the number given the default, connectors included.
+pub fn impute_speed_limits(
+    graph: &mut StableDiGraph<NodeData, EdgeData>,
+    default_speed: f64,
+    connector_speed: f64,
+) -> (usize, usize) {
+    let mut defaulted = 0;
+    for edge in graph.edge_weights_mut() {
+        if edge.is_connector && !has_speed_limit(edge) {
+            edge.speed_limit = Some(connector_speed);
+            defaulted += 1;
+        }
+    }
+
+    let mut propagated = 0;
+    loop {
+        let imputed = graph
+            .edge_references()
+            .filter(|edge| !has_speed_limit(edge.weight()))
+            .filter_map(|edge| {
+                let data = edge.weight();
+                let speeds = [edge.source(), edge.target()]
+                    .into_iter()
+                    .flat_map(|node| {
+                        graph
+                            .edges_directed(node, Incoming)
+                            .chain(graph.edges_directed(node, Outgoing))
+                    })
+                    .filter(|other| other.id() != edge.id())
+                    .map(|other| other.weight())
+                    .filter(|other| {
+                        !other.is_connector
+                            && other.main_number == data.main_number
+                            && other.sub_number == data.sub_number
+                            && has_speed_limit(other)
+                    })
+                    .filter_map(|other| other.speed_limit)
+                    .collect::<Vec<_>>();
+                if speeds.is_empty() {
+                    None
+                } else {
+                    Some((edge.id(), speeds.iter().sum::<f64>() / speeds.len() as f64))
+                }
+            })
+            .collect::<Vec<_>>();
+
+        if imputed.is_empty() {
+            break;
+        }
+        propagated += imputed.len();
+        for (edge, speed) in imputed {
+            graph[edge].speed_limit = Some(speed);
+        }
+    }
+
+    for edge in graph.edge_weights_mut() {
+        if !has_speed_limit(edge) {
+            edge.speed_limit = Some(default_speed);
+            defaulted += 1;
+        }
+    }
+
+    (propagated, defaulted)
+}
+
+/// Rebuild the graph with contiguous node indices, keeping the relative order of nodes and edges.
+/// Returns the new graph together with a map from old to new node indices.
+pub fn compact(
+    graph: &StableDiGraph<NodeData, EdgeData>,
+) -> (
+    StableDiGraph<NodeData, EdgeData>,
+    HashMap<NodeIndex, NodeIndex>,
+) {
+    let mut compacted = StableDiGraph::with_capacity(graph.node_count(), graph.edge_count());
+    let mut remap = HashMap::with_capacity(graph.node_count());
+
+    for node in graph.node_indices() {
+        let new = compacted.add_node(*graph.node_weight(node).unwrap());
+        remap.insert(node, new);
+    }
+
+    for edge in graph.edge_indices() {
+        let (source, target) = graph.edge_endpoints(edge).unwrap();
+        let data = graph.edge_weight(edge).unwrap().clone();
+        compacted.add_edge(remap[&source], remap[&target], data);
+    }
+
+    (compacted, remap)
+}
+
+/// Move the sensors of **sensor_store** to the new indices of their nodes after [`compact`]
+pub fn remap_sensor_store(
+    sensor_store: HashMap<NodeIndex, Vec<SensorMetadata>>,
+    remap: &HashMap<NodeIndex, NodeIndex>,
+) -> HashMap<NodeIndex, Vec<SensorMetadata>> {
+    sensor_store
+        .into_iter()
+        .map(|(node, sensors)| (remap[&node], sensors))
+        .collect()
+}
+
+/// The heading of every node from the edges around it, picked by **node_heading**, where
+/// **reverse_edges** are the edges added against the drawn direction of two-way roads. **tick** is
+/// called once per node.
+fn node_headings(
+    graph: &StableDiGraph<NodeData, EdgeData>,
+    reverse_edges: &HashSet<EdgeIndex>,
+    node_heading: NodeHeading,
+    tick: impl Fn() + Sync,
+) -> Vec<(NodeIndex, f64)> {
+    graph
+        .node_indices()
+        .par_bridge()
+        .map(|node| {
+            let in_edges = graph.edges_directed(node, Incoming);
+            let out_edges = graph.edges_directed(node, Outgoing);
+
+            let mut headings = Vec::new();
+
+            match node_heading {
+                NodeHeading::AllEdges => {
+                    for edge in in_edges.chain(out_edges) {
+                        let start = graph.node_weight(edge.source()).unwrap();
+                        let end = graph.node_weight(edge.target()).unwrap();
+                        headings.push(line_heading(start.point, end.point));
+                    }
+                }
+                NodeHeading::Travel => {
+                    for edge in out_edges.filter(|e| !reverse_edges.contains(&e.id())) {
+                        let start = graph.node_weight(edge.source()).unwrap();
+                        let end = graph.node_weight(edge.target()).unwrap();
+                        headings.push(line_heading(start.point, end.point));
+                    }
+
+                    // The last node of a road has no outgoing edge, so use the edge leading into it
+                    if headings.is_empty() {
+                        for edge in in_edges.filter(|e| !reverse_edges.contains(&e.id())) {
+                            let start = graph.node_weight(edge.source()).unwrap();
+                            let end = graph.node_weight(edge.target()).unwrap();
+                            headings.push(line_heading(start.point, end.point));
+                        }
+                    }
+                }
+            }
+
+            tick();
+            (node, angle_average(&headings))
+        })
+        .collect()
+}
+
+/// Merge every node within **distance** of a road cap, and the nodes within reach of those, into
+/// one node per cluster, moving their edges over and returning the number of nodes removed.
+///
+/// All clusters are found before the graph is changed, and the node kept and the edge kept when
+/// several connect the same nodes are picked by their data, so the result does not depend on the
+/// order the nodes were added in. **tick** is called once per node.
+fn merge_overlapping_nodes(
+    graph: &mut StableDiGraph<NodeData, EdgeData>,
+    distance: f64,
+    tick: impl Fn() + Sync,
+) -> usize {
+    let node_tree = build_node_acceleration_structure(graph);
+    let pairs = graph
+        .node_references()
+        .par_bridge()
+        .flat_map_iter(|(node, data)| {
+            tick();
+            let mut close = Vec::new();
+            if !data.is_road_cap {
+                return close;
+            }
+            let borrowed = [data.point.latitude, data.point.longitude];
+            for (_, (other, other_data)) in
+                node_tree.iter_nearest(&borrowed, &geo_distance).unwrap()
+            {
+                if dist(data.point, other_data.point) > distance {
+                    break;
+                }
+                if node != *other {
+                    close.push((node, *other));
+                }
+            }
+            close
+        })
+        .collect::<Vec<_>>();
+
+    let mut union_find = UnionFind::new((*graph).node_bound());
+    for (a, b) in pairs {
+        union_find.union(a.index(), b.index());
+    }
+    let mut clusters: HashMap<usize, Vec<NodeIndex>> = HashMap::new();
+    for node in graph.node_indices() {
+        clusters
+            .entry(union_find.find(node.index()))
+            .or_default()
+            .push(node);
+    }
+
+    // The node each merged node is replaced by
+    let mut representatives = HashMap::new();
+    for members in clusters.into_values().filter(|members| members.len() > 1) {
+        let representative = *members
+            .iter()
+            .min_by(|a, b| merge_order(&graph[**a], &graph[**b]))
+            .unwrap();
+        for member in members {
+            if member != representative {
+                representatives.insert(member, representative);
+            }
+        }
+    }
+    let representative = |node| representatives.get(&node).copied().unwrap_or(node);
+
+    let mut moved: BTreeMap<(NodeIndex, NodeIndex), EdgeData> = BTreeMap::new();
+    for edge in graph.edge_references() {
+        if !representatives.contains_key(&edge.source())
+            && !representatives.contains_key(&edge.target())
+        {
+            continue;
+        }
+        let (from, to) = (representative(edge.source()), representative(edge.target()));
+        if from == to {
+            continue;
+        }
+        match moved.entry((from, to)) {
+            Entry::Vacant(entry) => {
+                entry.insert(edge.weight().clone());
+            }
+            Entry::Occupied(mut entry) => {
+                if edge_merge_order(edge.weight(), entry.get()) == Ordering::Less {
+                    entry.insert(edge.weight().clone());
+                }
+            }
+        }
+    }
+
+    for node in representatives.keys() {
+        graph.remove_node(*node);
+    }
+    for ((from, to), mut data) in moved {
+        if !are_neighbours(graph, from, to) {
+            // Start and end the polyline at the nodes the edge was moved to
+            let (start, end) = (graph[from], graph[to]);
+            if let Some(first) = data.polyline.first_mut() {
+                *first = start.point;
+            }
+            if let Some(last) = data.polyline.last_mut() {
+                *last = end.point;
+            }
+            data.midpoint = midpoint(start.point, end.point);
+            data.direction = direction_from_data(start, end);
+            graph.add_edge(from, to, data);
+        }
+    }
+
+    representatives.len()
+}
+
+/// The order nodes are picked to represent their cluster by, road caps first
+fn merge_order(a: &NodeData, b: &NodeData) -> Ordering {
+    b.is_road_cap
+        .cmp(&a.is_road_cap)
+        .then(a.point.latitude.total_cmp(&b.point.latitude))
+        .then(a.point.longitude.total_cmp(&b.point.longitude))
+        .then(a.original_road_id.cmp(&b.original_road_id))
+}
+
+/// The order edges are kept by when merging leaves several between the same nodes
+fn edge_merge_order(a: &EdgeData, b: &EdgeData) -> Ordering {
+    a.original_road_id
+        .cmp(&b.original_road_id)
+        .then(a.distance.total_cmp(&b.distance))
+        .then(a.main_number.cmp(&b.main_number))
+}
+
+/// The endpoint of **edge** that the node with **data** connects to, the closer one for road caps.
+/// Other nodes only connect to an endpoint ahead of them within **tolerance** degrees of their
+/// heading, and with **parallel_only** only to roads heading the same way. Fails with the closer
+/// endpoint and why it can not be connected to
+fn closest_endpoint(
+    graph: &StableDiGraph<NodeData, EdgeData>,
+    edge: EdgeIndex,
+    data: &NodeData,
+    is_cap: bool,
+    tolerance: f64,
+    parallel_only: bool,
+) -> Result<NodeIndex, (NodeIndex, connectors::RejectReason)> {
+    let (start_idx, end_idx) = graph.edge_endpoints(edge).unwrap();
+    let start = graph.node_weight(start_idx).unwrap().point;
+    let end = graph.node_weight(end_idx).unwrap().point;
+    let closer = if dist(data.point, start) > dist(data.point, end) {
+        end_idx
+    } else {
+        start_idx
+    };
+    if is_cap {
+        return Ok(closer);
+    }
+
+    let heading = line_heading(start, end);
+    if parallel_only && angle_diff(heading, data.heading).abs() > tolerance {
+        return Err((closer, connectors::RejectReason::RoadHeading));
+    }
+
+    let start_ahead = angle_diff(data.heading, line_heading(data.point, start)).abs() <= tolerance;
+    let end_ahead = angle_diff(data.heading, line_heading(data.point, end)).abs() <= tolerance;
+    match (start_ahead, end_ahead) {
+        (true, true) => Ok(closer),
+        (true, false) => Ok(start_idx),
+        (false, true) => Ok(end_idx),
+        (false, false) => Err((closer, connectors::RejectReason::Heading)),
+    }
+}
+
+fn are_neighbours(graph: &StableDiGraph<NodeData, EdgeData>, a: NodeIndex, b: NodeIndex) -> bool {
+    graph.edges_connecting(a, b).count() > 0
+}
+
+fn build_sensor_acceleration_structure<'a, I: Iterator<Item = &'a SensorMetadata>>(
+    sensors: I,
+) -> KdTree<f64, SensorMetadata, [f64; 2]> {
+    let mut kdtree = KdTree::new(2);
+
+    sensors.for_each(|data| {
+        let point = data.point();
+        kdtree
+            .add([point.latitude, point.longitude], data.clone())
+            .unwrap();
     });
 
     kdtree
@@ -770,15 +1610,33 @@ fn find_closest_sensor(
 
 pub fn build_node_acceleration_structure(
     graph: &StableGraph<NodeData, EdgeData>,
+) -> KdTree<f64, (NodeIndex, NodeData), [f64; 2]> {
+    build_node_tree_from_points(graph, &node_points(graph))
+}
+
+/// The position of every node, in the order they are added to the node acceleration structure
+pub fn node_points(graph: &StableGraph<NodeData, EdgeData>) -> Vec<(NodeIndex, [f64; 2])> {
+    graph
+        .node_indices()
+        .map(|idx| {
+            let point = graph.node_weight(idx).unwrap().point;
+            (idx, [point.latitude, point.longitude])
+        })
+        .collect()
+}
+
+/// Build the node acceleration structure from **points** made by [`node_points`], adding them in
+/// the same order gives the same tree
+pub fn build_node_tree_from_points(
+    graph: &StableGraph<NodeData, EdgeData>,
+    points: &[(NodeIndex, [f64; 2])],
 ) -> KdTree<f64, (NodeIndex, NodeData), [f64; 2]> {
     let mut kdtree = KdTree::new(2);
 
-    graph.node_indices().for_each(|idx| {
-        let data = graph.node_weight(idx).unwrap();
-        kdtree
-            .add([data.point.latitude, data.point.longitude], (idx, *data))
-            .unwrap();
-    });
+    for (idx, point) in points {
+        let data = graph.node_weight(*idx).unwrap();
+        kdtree.add(*point, (*idx, *data)).unwrap();
+    }
 
     kdtree
 }
@@ -816,6 +1674,19 @@ fn build_edge_acceleration_structure(
     kdtree
 }
 
+/// Resolve every query against the same **tree**, honouring the radius and heading range of each,
+/// with **None** for the queries that match no node
+pub fn nearest_nodes_batch(
+    tree: &KdTree<f64, (NodeIndex, NodeData), [f64; 2]>,
+    queries: &[PointQuery],
+    strategy: SnapStrategy,
+) -> Vec<Option<NodeIndex>> {
+    queries
+        .par_iter()
+        .map(|query| find_point(tree, query, strategy))
+        .collect()
+}
+
 pub fn find_closest_node(
     kdtree: &KdTree<f64, (NodeIndex, NodeData), [f64; 2]>,
     point: Point,
@@ -858,7 +1729,7 @@ where
         let start = graph.node_weight(endpoints.0).unwrap();
         let end = graph.node_weight(endpoints.1).unwrap();
 
-        let actual_dist = point_line_dist_approx(point, start.point, end.point);
+        let actual_dist = point_line_dist(point, start.point, end.point);
         if actual_dist < max_dist {
             let tuple = (*idx, data.clone());
             let group = group_by(&tuple);
@@ -878,3 +1749,530 @@ pub fn direction_from_data(a: NodeData, b: NodeData) -> RoadDirection {
         RoadDirection::Both
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_graphs::{
+        add_chain, add_road, grid, node, point, sensor, temp_path, TestGraph,
+    };
+
+    /// Every edge as its endpoints, road and polyline, in an order independent of the node indices
+    fn edge_summary(graph: &TestGraph) -> Vec<String> {
+        let mut edges = graph
+            .edge_references()
+            .map(|edge| {
+                format!(
+                    "{:?} {:?} {} {:?}",
+                    graph[edge.source()].point,
+                    graph[edge.target()].point,
+                    edge.weight().original_road_id,
+                    edge.weight().polyline
+                )
+            })
+            .collect::<Vec<_>>();
+        edges.sort();
+        edges
+    }
+
+    #[test]
+    fn overlap_merging_does_not_depend_on_input_order() {
+        // Three roads whose caps nearly meet around (0, 0.002)
+        let roads = [
+            vec![(0.0, 0.0), (0.0, 0.001), (0.0, 0.002)],
+            vec![(0.00001, 0.002), (0.001, 0.002), (0.002, 0.002)],
+            vec![(-0.00001, 0.002), (-0.001, 0.002)],
+        ];
+        let merged = |order: &[usize]| {
+            let mut graph = TestGraph::default();
+            for idx in order {
+                add_chain(&mut graph, &roads[*idx], *idx as i32 + 1, 50.0);
+            }
+            let removed = merge_overlapping_nodes(&mut graph, 5.0, || {});
+            (removed, edge_summary(&graph))
+        };
+
+        let (removed, edges) = merged(&[0, 1, 2]);
+        assert_eq!(removed, 2);
+        assert_eq!((removed, edges), merged(&[2, 1, 0]));
+    }
+
+    #[test]
+    fn merged_edges_start_and_end_at_their_new_nodes() {
+        let mut graph = TestGraph::default();
+        add_chain(&mut graph, &[(0.0, 0.0), (0.0, 0.001)], 1, 50.0);
+        add_chain(&mut graph, &[(0.00002, 0.001), (0.001, 0.001)], 2, 50.0);
+
+        assert_eq!(merge_overlapping_nodes(&mut graph, 5.0, || {}), 1);
+        for edge in graph.edge_references() {
+            let polyline = &edge.weight().polyline;
+            assert_eq!(polyline.first(), Some(&graph[edge.source()].point));
+            assert_eq!(polyline.last(), Some(&graph[edge.target()].point));
+        }
+    }
+
+    fn road(unique_id: i32, coordinates: &[(f64, f64)], direction: RoadDirection) -> RoadData {
+        RoadData {
+            direction,
+            main_number: 1,
+            sub_number: 0,
+            coordinates: coordinates
+                .iter()
+                .map(|(latitude, longitude)| point(*latitude, *longitude))
+                .collect(),
+            length: 100.0,
+            unique_id,
+            speed_limit: 50.0,
+        }
+    }
+
+    #[test]
+    fn exact_duplicate_roads_are_removed() {
+        let line = [(0.0, 0.0), (0.0, 0.001)];
+        let roads = vec![
+            road(0, &line, RoadDirection::Forward),
+            road(1, &line, RoadDirection::Forward),
+            road(2, &line, RoadDirection::Backward),
+            road(3, &[(0.0, 0.0), (0.001, 0.0)], RoadDirection::Forward),
+        ];
+
+        let (unique, mismatched) = dedup_roads(&ProgressBar::hidden(), roads);
+
+        let mut ids = unique.iter().map(|road| road.unique_id).collect::<Vec<_>>();
+        ids.sort();
+        assert_eq!(ids, vec![1, 2, 3]);
+        assert!(mismatched.is_empty());
+    }
+
+    #[test]
+    fn tighter_heading_tolerance_prevents_a_connection() {
+        let mut graph = TestGraph::default();
+        let from = graph.add_node(NodeData {
+            heading: 0.0,
+            ..node(0.0, 0.0)
+        });
+        // Ahead of the node, with its closer end about 22 degrees right of its heading
+        let road = add_chain(&mut graph, &[(0.0005, 0.0002), (0.0005, 0.001)], 2, 50.0);
+        let edge = graph.find_edge(road[0], road[1]).unwrap();
+        let connect =
+            |tolerance| closest_endpoint(&graph, edge, &graph[from], false, tolerance, false);
+
+        assert!(matches!(
+            connect(15.0),
+            Err((_, connectors::RejectReason::Heading))
+        ));
+        assert_eq!(connect(30.0), Ok(road[0]));
+    }
+
+    #[derive(clap::Parser)]
+    struct ProcessArgs {
+        #[clap(flatten)]
+        options: GraphProcessingOptions,
+    }
+
+    #[test]
+    fn roads_out_of_sensor_range_are_traced_to_the_filter() {
+        let path = temp_path("dropped.csv");
+        let args = [
+            "process",
+            "--max-distance-from-sensors",
+            "500",
+            "--merge-overlap-distance",
+            "1",
+            "--collapse-nodes",
+            "naive",
+            "--trace-dropped",
+            &path,
+        ];
+        let options = <ProcessArgs as clap::Parser>::parse_from(args).options;
+        // Roads 0 and 2 meet and are collapsed into one edge, road 1 is kilometers from the sensor
+        let roads = vec![
+            road(0, &[(0.0, 0.0), (0.0, 0.001)], RoadDirection::Forward),
+            road(1, &[(0.05, 0.0), (0.05, 0.001)], RoadDirection::Forward),
+            road(2, &[(0.0, 0.001), (0.0, 0.002)], RoadDirection::Forward),
+        ];
+
+        let processed = process_graph(options, roads, vec![sensor(1, 0.0, 0.0)]);
+
+        let raw = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(processed.graph.edge_count(), 1);
+        assert_eq!(
+            raw.lines().collect::<Vec<_>>(),
+            vec![
+                "unique_id,main_number,sub_number,stage",
+                "1,1,0,sensor distance filter"
+            ]
+        );
+    }
+
+    #[test]
+    fn ingest_filter_never_adds_nodes_out_of_range() {
+        let sensors = vec![sensor(1, 0.0, 0.0)];
+        let (sensor_middle, range) = sensor_extent(&sensors, 200.0);
+        let sensor_tree = build_sensor_acceleration_structure(sensors.iter());
+        let in_range = sensor_range(&sensor_tree, sensor_middle, range, 200.0);
+        // Only the first two points of road 0 are within 200m of the sensor, 111m apart, and the
+        // long road 1 is kilometers away
+        let far = (0..100)
+            .map(|idx| (0.05, idx as f64 * 0.001))
+            .collect::<Vec<_>>();
+        let mut roads = vec![
+            road(
+                0,
+                &(0..11)
+                    .map(|idx| (0.0, idx as f64 * 0.001))
+                    .collect::<Vec<_>>(),
+                RoadDirection::Forward,
+            ),
+            road(1, &far, RoadDirection::Both),
+        ];
+        let mut graph = TestGraph::default();
+
+        let (skipped, out_of_range_roads, _) = add_roads(
+            &mut graph,
+            &mut roads,
+            false,
+            DistanceModel::Haversine,
+            |point| !in_range(point),
+            |graph| assert!(graph.node_count() <= 2),
+        );
+
+        assert_eq!(graph.node_count(), 2);
+        assert_eq!(graph.edge_count(), 1);
+        assert_eq!(skipped, 9 + 100);
+        assert_eq!(out_of_range_roads, HashSet::from([0, 1]));
+    }
+
+    #[test]
+    fn crop_removes_nodes_outside_and_keeps_crossing_edges() {
+        let mut graph = TestGraph::default();
+        // Road 1 runs from inside the box across its eastern edge, and on to a node further out
+        let nodes = add_chain(
+            &mut graph,
+            &[(0.0005, 0.0005), (0.0005, 0.0015), (0.0005, 0.0025)],
+            1,
+            50.0,
+        );
+        let outside = add_chain(&mut graph, &[(0.003, 0.003), (0.003, 0.004)], 2, 50.0);
+
+        crop_to_bbox(&mut graph, 0.0, 0.001, 0.0, 0.001);
+
+        assert!(graph.contains_node(nodes[0]));
+        assert!(graph.contains_node(nodes[1]));
+        assert!(graph.find_edge(nodes[0], nodes[1]).is_some());
+        assert!(!graph.contains_node(nodes[2]));
+        assert!(outside.iter().all(|node| !graph.contains_node(*node)));
+        assert_eq!(graph.edge_count(), 1);
+    }
+
+    #[test]
+    fn compaction_keeps_the_topology_and_moves_the_sensors() {
+        let mut graph = TestGraph::default();
+        let removed = add_chain(&mut graph, &[(0.0, 0.0), (0.0, 0.001)], 1, 50.0);
+        let nodes = add_chain(
+            &mut graph,
+            &[(0.001, 0.0), (0.001, 0.001), (0.001, 0.002)],
+            2,
+            50.0,
+        );
+        removed.iter().for_each(|node| {
+            graph.remove_node(*node);
+        });
+        let sensor_store = HashMap::from([(nodes[1], vec![sensor(7, 0.001, 0.001)])]);
+
+        let (compacted, remap) = compact(&graph);
+        let sensor_store = remap_sensor_store(sensor_store, &remap);
+
+        assert_eq!(compacted.node_bound(), compacted.node_count());
+        assert_eq!(edge_summary(&compacted), edge_summary(&graph));
+        for node in nodes.iter() {
+            assert_eq!(compacted[remap[node]].point, graph[*node].point);
+        }
+        assert_eq!(sensor_store[&remap[&nodes[1]]][0].site_id, 7);
+        assert_eq!(compacted[remap[&nodes[1]]].point, point(0.001, 0.001));
+    }
+
+    #[test]
+    fn two_way_through_node_heads_in_the_travel_direction() {
+        let mut graph = TestGraph::default();
+        let nodes = [(0.0, 0.0), (0.0, 0.001), (0.0, 0.002)]
+            .map(|(lat, lon)| graph.add_node(node(lat, lon)));
+        let mut reverse_edges = HashSet::new();
+        for pair in nodes.windows(2) {
+            add_road(&mut graph, pair[0], pair[1], 1, 50.0);
+            reverse_edges.insert(add_road(&mut graph, pair[1], pair[0], 1, 50.0));
+        }
+        let heading_of = |node_heading| {
+            node_headings(&graph, &reverse_edges, node_heading, || {})
+                .into_iter()
+                .find(|(node, _)| *node == nodes[1])
+                .unwrap()
+                .1
+        };
+
+        let east = line_heading(graph[nodes[0]].point, graph[nodes[2]].point);
+        let travel = heading_of(NodeHeading::Travel);
+        let all_edges = heading_of(NodeHeading::AllEdges);
+
+        assert!(angle_diff(travel, east).abs() < 1e-6);
+        // Opposing edges cancel out, leaving a heading unrelated to the road
+        assert!(angle_diff(all_edges, east).abs() > 1.0);
+    }
+
+    #[test]
+    fn parallel_headings_match_the_headings_of_each_node() {
+        let mut graph = TestGraph::default();
+        let nodes = add_chain(
+            &mut graph,
+            &[
+                (0.0, 0.0),
+                (0.001, 0.0),
+                (0.002, 0.001),
+                (0.002, 0.003),
+                (0.001, 0.004),
+            ],
+            1,
+            50.0,
+        );
+
+        let mut headings = node_headings(&graph, &HashSet::new(), NodeHeading::Travel, || {});
+        headings.sort_by_key(|(node, _)| *node);
+
+        // Each node heads to the next one, and the last one on from the one before it
+        let heading =
+            |from: NodeIndex, to: NodeIndex| line_heading(graph[from].point, graph[to].point);
+        let mut expected = nodes
+            .windows(2)
+            .map(|pair| (pair[0], heading(pair[0], pair[1])))
+            .collect::<Vec<_>>();
+        expected.push((nodes[4], heading(nodes[3], nodes[4])));
+        assert_eq!(headings.len(), expected.len());
+        for ((node, heading), (expected_node, expected_heading)) in headings.iter().zip(expected) {
+            assert_eq!(*node, expected_node);
+            assert!(angle_diff(*heading, expected_heading).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn missing_speed_limit_is_the_mean_of_its_neighbours() {
+        let mut graph = TestGraph::default();
+        let nodes = add_chain(
+            &mut graph,
+            &[(0.0, 0.0), (0.0, 0.001), (0.0, 0.002), (0.0, 0.003)],
+            1,
+            50.0,
+        );
+        let edges = nodes
+            .windows(2)
+            .map(|pair| graph.find_edge(pair[0], pair[1]).unwrap())
+            .collect::<Vec<_>>();
+        graph[edges[0]].speed_limit = Some(40.0);
+        graph[edges[1]].speed_limit = None;
+        graph[edges[2]].speed_limit = Some(60.0);
+        let other = add_chain(&mut graph, &[(0.001, 0.0), (0.001, 0.001)], 2, 50.0);
+        let unknown = graph.find_edge(other[0], other[1]).unwrap();
+        graph[unknown].speed_limit = None;
+
+        let (propagated, defaulted) = impute_speed_limits(&mut graph, 30.0, 10.0);
+
+        assert_eq!((propagated, defaulted), (1, 1));
+        assert_eq!(graph[edges[1]].speed_limit, Some(50.0));
+        // A road without any speed limit falls back to the default
+        assert_eq!(graph[unknown].speed_limit, Some(30.0));
+    }
+
+    #[test]
+    fn batch_queries_without_a_match_are_none() {
+        let (graph, nodes) = grid(2, 2);
+        let tree = build_node_acceleration_structure(&graph);
+        let corner = graph[nodes[1][1]].point;
+        let queries = [
+            PointQuery::new(corner.latitude, corner.longitude, 20.0, -45.0..45.0),
+            // Far from every node
+            PointQuery::new(1.0, 1.0, 20.0, -45.0..45.0),
+            // On a node, but every node heads north
+            PointQuery::new(corner.latitude, corner.longitude, 20.0, 135.0..225.0),
+        ];
+
+        let matches = nearest_nodes_batch(&tree, &queries, SnapStrategy::ProximityFirst);
+
+        assert_eq!(matches, vec![Some(nodes[1][1]), None, None]);
+    }
+
+    #[test]
+    fn kept_forbidden_roads_are_never_traveled() {
+        use crate::visitor::{shortest_path, DistanceMetric, SearchStrategy};
+
+        let process = |keep_forbidden: bool| {
+            let mut args = vec!["process", "--merge-overlap-distance", "1"];
+            if keep_forbidden {
+                args.push("--keep-forbidden");
+            }
+            let options = <ProcessArgs as clap::Parser>::parse_from(args).options;
+            // A forbidden shortcut next to a detour between the same two points
+            let roads = vec![
+                road(0, &[(0.0, 0.0), (0.0, 0.002)], RoadDirection::None),
+                road(1, &[(0.0, 0.0), (0.001, 0.001)], RoadDirection::Forward),
+                road(2, &[(0.001, 0.001), (0.0, 0.002)], RoadDirection::Forward),
+            ];
+            process_graph(options, roads, vec![sensor(1, 0.0, 0.0)]).graph
+        };
+
+        let dropped = process(false);
+        let graph = process(true);
+
+        assert!(graph.edge_count() > dropped.edge_count());
+        let forbidden = graph
+            .edge_references()
+            .filter(|edge| edge.weight().direction == RoadDirection::None)
+            .map(|edge| edge.id())
+            .collect::<HashSet<_>>();
+        assert!(!forbidden.is_empty());
+        let tree = build_node_acceleration_structure(&graph);
+        let (_, start) = find_closest_node(&tree, point(0.0, 0.0));
+        let (_, end) = find_closest_node(&tree, point(0.0, 0.002));
+        let path = shortest_path(
+            &graph,
+            vec![start, end],
+            DistanceMetric::Space,
+            1.0,
+            SearchStrategy::Dijkstra,
+        )
+        .unwrap();
+        assert!(path.nodes.windows(2).all(|pair| graph
+            .edges_connecting(pair[0], pair[1])
+            .all(|edge| !forbidden.contains(&edge.id()))));
+        assert!(path
+            .nodes
+            .iter()
+            .any(|node| graph[*node].point.latitude > 0.0005));
+    }
+
+    #[test]
+    fn dry_run_estimate_matches_the_nodes_of_a_real_run() {
+        let options = |max_distance: &str| {
+            let args = [
+                "process",
+                "--max-distance-from-sensors",
+                max_distance,
+                "--merge-overlap-distance",
+                "nan",
+            ];
+            <ProcessArgs as clap::Parser>::parse_from(args).options
+        };
+        // Two roads by the sensor, one kilometers away and one closed to traffic
+        let roads = || {
+            vec![
+                road(
+                    0,
+                    &[(0.0, 0.0), (0.0, 0.001), (0.0, 0.002)],
+                    RoadDirection::Forward,
+                ),
+                road(1, &[(0.001, 0.0), (0.001, 0.001)], RoadDirection::Both),
+                road(2, &[(0.05, 0.0), (0.05, 0.001)], RoadDirection::Forward),
+                road(3, &[(0.002, 0.0), (0.002, 0.001)], RoadDirection::None),
+            ]
+        };
+        let sensors = || vec![sensor(1, 0.0, 0.0)];
+
+        let estimate = estimate_graph(&options("500"), &roads(), &sensors());
+        assert_eq!(
+            estimate,
+            GraphEstimate {
+                roads: 3,
+                nodes: 7,
+                edges: 5,
+                out_of_range: 2,
+                merge_candidates: None,
+            }
+        );
+
+        let unfiltered = process_graph(options("inf"), roads(), sensors()).graph;
+        assert_eq!(unfiltered.node_count(), estimate.nodes);
+        assert_eq!(unfiltered.edge_count(), estimate.edges);
+        let filtered = process_graph(options("500"), roads(), sensors()).graph;
+        assert_eq!(
+            filtered.node_count(),
+            estimate.nodes - estimate.out_of_range
+        );
+    }
+
+    #[test]
+    fn sensors_beyond_the_assign_distance_are_left_unassigned() {
+        let process = |max_assign_distance: Option<&str>| {
+            let mut args = vec!["process", "--merge-overlap-distance", "nan"];
+            if let Some(distance) = max_assign_distance {
+                args.extend(["--max-sensor-assign-distance", distance]);
+            }
+            let options = <ProcessArgs as clap::Parser>::parse_from(args).options;
+            let roads = vec![road(0, &[(0.0, 0.0), (0.0, 0.001)], RoadDirection::Forward)];
+            // One sensor beside the road and one kilometers away
+            let sensors = vec![sensor(1, 0.0001, 0.0005), sensor(2, 0.02, 0.0)];
+            let mut site_ids = process_graph(options, roads, sensors)
+                .sensor_store
+                .into_values()
+                .flatten()
+                .map(|sensor| sensor.site_id)
+                .collect::<Vec<_>>();
+            site_ids.sort();
+            site_ids
+        };
+
+        assert_eq!(process(None), vec![1, 2]);
+        assert_eq!(process(Some("100")), vec![1]);
+    }
+
+    #[test]
+    fn single_thread_processing_matches_the_default_pool() {
+        let process = || {
+            let args = [
+                "process",
+                "--max-distance-from-sensors",
+                "2000",
+                "--merge-overlap-distance",
+                "5",
+                "--connect-distance",
+                "30",
+                "--simplify",
+                "1",
+            ];
+            let options = <ProcessArgs as clap::Parser>::parse_from(args).options;
+            // A crossing, a road ending just short of another, and a road out of range
+            let roads = vec![
+                road(
+                    0,
+                    &[(0.0, 0.0), (0.0, 0.001), (0.0, 0.002)],
+                    RoadDirection::Both,
+                ),
+                road(
+                    1,
+                    &[(-0.001, 0.001), (0.0, 0.001), (0.001, 0.001)],
+                    RoadDirection::Forward,
+                ),
+                road(2, &[(0.001, 0.0), (0.001, 0.0009)], RoadDirection::Backward),
+                road(
+                    3,
+                    &[(0.00002, 0.002), (0.001, 0.003)],
+                    RoadDirection::Forward,
+                ),
+                road(4, &[(0.1, 0.0), (0.1, 0.001)], RoadDirection::Both),
+            ];
+            let processed = process_graph(options, roads, vec![sensor(1, 0.0, 0.001)]);
+            let mut points = processed
+                .graph
+                .node_weights()
+                .map(|data| format!("{:?}", data.point))
+                .collect::<Vec<_>>();
+            points.sort();
+            (points, edge_summary(&processed.graph))
+        };
+        let single = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .unwrap();
+
+        let default = process();
+        assert!(!default.1.is_empty());
+        assert_eq!(single.install(process), default);
+    }
+}
@@ -0,0 +1,209 @@
+use std::{cell::OnceCell, ops::Range};
+
+use kdtree::KdTree;
+use petgraph::{graph::NodeIndex, stable_graph::StableDiGraph};
+
+use crate::{
+    math::{dist, geo_distance},
+    parse::Point,
+    util::{find_point, PointQuery, QueryError, SnapStrategy},
+};
+
+use super::{
+    build_node_acceleration_structure, find_closest_node, nearest_nodes_batch, EdgeData, NodeData,
+};
+
+pub type NodeTree = KdTree<f64, (NodeIndex, NodeData), [f64; 2]>;
+
+/// A graph together with a node acceleration structure, which is built the first time a node is
+/// looked up and reused for every lookup after that.
+pub struct RoutingGraph {
+    graph: StableDiGraph<NodeData, EdgeData>,
+    tree: OnceCell<NodeTree>,
+}
+
+impl RoutingGraph {
+    pub fn new(graph: StableDiGraph<NodeData, EdgeData>) -> Self {
+        RoutingGraph {
+            graph,
+            tree: OnceCell::new(),
+        }
+    }
+
+    /// Use **tree** built from the nodes of **graph**, such as one read from a cached index,
+    /// instead of building it on the first lookup
+    pub fn with_tree(graph: StableDiGraph<NodeData, EdgeData>, tree: NodeTree) -> Self {
+        RoutingGraph {
+            graph,
+            tree: OnceCell::from(tree),
+        }
+    }
+
+    pub fn graph(&self) -> &StableDiGraph<NodeData, EdgeData> {
+        &self.graph
+    }
+
+    /// Mutable access to the graph, the cached tree is dropped and rebuilt on the next lookup
+    pub fn graph_mut(&mut self) -> &mut StableDiGraph<NodeData, EdgeData> {
+        self.tree.take();
+        &mut self.graph
+    }
+
+    pub fn into_graph(self) -> StableDiGraph<NodeData, EdgeData> {
+        self.graph
+    }
+
+    pub fn tree(&self) -> &NodeTree {
+        self.tree
+            .get_or_init(|| build_node_acceleration_structure(&self.graph))
+    }
+
+    /// The closest node to **point** and its distance in meters
+    pub fn nearest_node(&self, point: Point) -> (f64, NodeIndex) {
+        find_closest_node(self.tree(), point)
+    }
+
+    /// The closest node within **radius** meters of **point** with a heading in **heading_range**
+    pub fn nearest_node_with_heading(
+        &self,
+        point: Point,
+        heading_range: &Range<f64>,
+        radius: f64,
+    ) -> Option<NodeIndex> {
+        self.tree()
+            .iter_nearest(&[point.latitude, point.longitude], &geo_distance)
+            .unwrap()
+            .take_while(|(dist, _)| *dist <= radius)
+            .find(|(_, (_, data))| heading_range.contains(&data.heading))
+            .map(|(_, (idx, _))| *idx)
+    }
+
+    pub fn find_point(&self, query: &PointQuery, strategy: SnapStrategy) -> Option<NodeIndex> {
+        find_point(self.tree(), query, strategy)
+    }
+
+    /// Resolve a query to a node, or describe the closest node if it has no matching node
+    pub fn resolve_point(
+        &self,
+        query: &PointQuery,
+        strategy: SnapStrategy,
+    ) -> Result<NodeIndex, QueryError> {
+        self.find_point(query, strategy)
+            .ok_or_else(|| self.query_error(query))
+    }
+
+    /// Resolve every query to a node, failing on the first one without a matching node
+    pub fn find_points(
+        &self,
+        queries: &[PointQuery],
+        strategy: SnapStrategy,
+    ) -> Result<Vec<NodeIndex>, QueryError> {
+        nearest_nodes_batch(self.tree(), queries, strategy)
+            .into_iter()
+            .zip(queries)
+            .map(|(node, query)| node.ok_or_else(|| self.query_error(query)))
+            .collect()
+    }
+
+    fn query_error(&self, query: &PointQuery) -> QueryError {
+        let closest = self
+            .tree()
+            .nearest(
+                &[query.point.latitude, query.point.longitude],
+                1,
+                &geo_distance,
+            )
+            .unwrap()
+            .first()
+            .map(|(_, (_, data))| (dist(data.point, query.point), data.heading));
+        QueryError {
+            query: query.clone(),
+            closest,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_graphs::{diamond, grid, point};
+
+    #[test]
+    fn too_tight_radius_names_the_closest_node() {
+        let (graph, [a, ..]) = diamond();
+        let routing = RoutingGraph::new(graph);
+        let query = PointQuery::new(0.0005, 0.0, 10.0, -180.0..180.0);
+
+        let error = routing
+            .resolve_point(&query, SnapStrategy::ProximityFirst)
+            .unwrap_err();
+
+        let (distance, heading) = error.closest.unwrap();
+        assert!((distance - dist(routing.graph()[a].point, query.point)).abs() < 1e-9);
+        assert!(distance > query.radius);
+        assert_eq!(heading, routing.graph()[a].heading);
+        assert!(error
+            .to_string()
+            .contains(&format!("the closest node is {:.1}m away", distance)));
+    }
+
+    #[test]
+    fn tree_is_built_once_and_finds_what_the_inline_lookups_did() {
+        let (mut graph, _) = grid(5, 5);
+        for (idx, node) in graph.node_weights_mut().enumerate() {
+            node.heading = (idx * 37 % 360) as f64 - 180.0;
+        }
+        let queries = [(0.0012, 0.0031), (0.0041, 0.0002), (0.0025, 0.0025)]
+            .into_iter()
+            .flat_map(|(latitude, longitude)| {
+                [-180.0..180.0, 0.0..90.0, -90.0..-45.0].map(|heading| PointQuery {
+                    point: point(latitude, longitude),
+                    radius: 500.0,
+                    heading,
+                    snap: None,
+                })
+            })
+            .collect::<Vec<_>>();
+        let routing = RoutingGraph::new(graph.clone());
+        assert!(routing.tree.get().is_none());
+
+        let tree = routing.tree() as *const NodeTree;
+        let found = routing.find_points(&queries, SnapStrategy::ProximityFirst);
+        let nearest = queries
+            .iter()
+            .map(|query| {
+                let with_heading =
+                    routing.nearest_node_with_heading(query.point, &query.heading, f64::INFINITY);
+                (routing.nearest_node(query.point), with_heading)
+            })
+            .collect::<Vec<_>>();
+        assert!(std::ptr::eq(tree, routing.tree()));
+
+        // Each lookup as the modes did it before, on a tree built for that lookup alone
+        let inline_found = queries
+            .iter()
+            .map(|query| {
+                let tree = build_node_acceleration_structure(&graph);
+                find_point(&tree, query, SnapStrategy::ProximityFirst)
+            })
+            .collect::<Option<Vec<_>>>();
+        let inline_nearest = queries
+            .iter()
+            .map(|query| {
+                let tree = build_node_acceleration_structure(&graph);
+                let with_heading = tree
+                    .iter_nearest(
+                        &[query.point.latitude, query.point.longitude],
+                        &geo_distance,
+                    )
+                    .unwrap()
+                    .find(|(_, (_, data))| query.heading.contains(&data.heading))
+                    .map(|(_, (idx, _))| *idx);
+                (find_closest_node(&tree, query.point), with_heading)
+            })
+            .collect::<Vec<_>>();
+        assert!(inline_found.is_some());
+        assert_eq!(found.ok(), inline_found);
+        assert_eq!(nearest, inline_nearest);
+    }
+}
@@ -0,0 +1,168 @@
+use petgraph::stable_graph::StableDiGraph;
+
+use crate::{
+    math::{dist_with, point_line_dist, DistanceModel},
+    parse::Point,
+};
+
+use super::{EdgeData, NodeData};
+
+/// Simplify **points** with the Douglas-Peucker algorithm, dropping every point closer than
+/// **epsilon** meters to the line between the points kept around it. The endpoints are always kept.
+pub fn douglas_peucker(points: &[Point], epsilon: f64) -> Vec<Point> {
+    if points.len() < 3 {
+        return points.to_vec();
+    }
+
+    let mut keep = vec![false; points.len()];
+    keep[0] = true;
+    keep[points.len() - 1] = true;
+
+    let mut stack = vec![(0, points.len() - 1)];
+    while let Some((start, end)) = stack.pop() {
+        let (farthest, distance) = (start + 1..end)
+            .map(|idx| {
+                let distance = point_line_dist(points[idx], points[start], points[end]).abs();
+                (idx, distance)
+            })
+            .fold((start, 0.0), |best, candidate| {
+                if candidate.1 > best.1 {
+                    candidate
+                } else {
+                    best
+                }
+            });
+
+        if distance > epsilon {
+            keep[farthest] = true;
+            stack.push((start, farthest));
+            stack.push((farthest, end));
+        }
+    }
+
+    points
+        .iter()
+        .zip(keep)
+        .filter(|(_, keep)| *keep)
+        .map(|(point, _)| *point)
+        .collect()
+}
+
+/// Simplify **points** with a growing epsilon until at most **max_points** are left
+pub fn simplify_polyline(points: &[Point], max_points: usize) -> Vec<Point> {
+    let max_points = max_points.max(2);
+    let mut epsilon = 1.0;
+    let mut simplified = douglas_peucker(points, epsilon);
+    while simplified.len() > max_points {
+        epsilon *= 2.0;
+        simplified = douglas_peucker(&simplified, epsilon);
+    }
+    simplified
+}
+
+/// Simplify every edge polyline with more than **max_points** points. The distance of the edges
+/// is kept, as it is the length of the road and not of the simplified line.
+///
+/// Returns the number of simplified edges and the number of points removed.
+pub fn simplify_graph(
+    graph: &mut StableDiGraph<NodeData, EdgeData>,
+    max_points: usize,
+) -> (usize, usize) {
+    let mut edges = 0;
+    let mut removed = 0;
+    for edge in graph.edge_weights_mut() {
+        if edge.polyline.len() <= max_points {
+            continue;
+        }
+
+        let simplified = simplify_polyline(&edge.polyline, max_points);
+        edges += 1;
+        removed += edge.polyline.len() - simplified.len();
+        edge.polyline = simplified;
+    }
+
+    (edges, removed)
+}
+
+/// Simplify every edge polyline with the Douglas-Peucker algorithm, dropping points closer than
+/// **epsilon** meters to the simplified line. Unlike **simplify_graph** the distance of the
+/// simplified edges is measured along the new polyline, on the earth **model**.
+///
+/// Returns the number of simplified edges and the number of points removed.
+pub fn simplify_polylines(
+    graph: &mut StableDiGraph<NodeData, EdgeData>,
+    epsilon: f64,
+    model: DistanceModel,
+) -> (usize, usize) {
+    let mut edges = 0;
+    let mut removed = 0;
+    for edge in graph.edge_weights_mut() {
+        let simplified = douglas_peucker(&edge.polyline, epsilon);
+        if simplified.len() == edge.polyline.len() {
+            continue;
+        }
+
+        edges += 1;
+        removed += edge.polyline.len() - simplified.len();
+        edge.distance = simplified
+            .windows(2)
+            .map(|segment| dist_with(segment[0], segment[1], model))
+            .sum();
+        edge.polyline = simplified;
+    }
+
+    (edges, removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_graphs::{add_road, node, point, TestGraph};
+
+    #[test]
+    fn nearly_straight_polyline_collapses_to_its_endpoints() {
+        // Wiggling about a meter either side of a 1km line
+        let points = (0..=10)
+            .map(|idx| {
+                point(
+                    if idx % 2 == 0 { 0.0 } else { 0.00001 },
+                    idx as f64 * 0.0009,
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let simplified = douglas_peucker(&points, 5.0);
+
+        assert_eq!(simplified, vec![points[0], points[10]]);
+    }
+
+    #[test]
+    fn sharp_corner_is_kept_and_the_distance_follows_it() {
+        let mut graph = TestGraph::default();
+        let (a, b) = (
+            graph.add_node(node(0.0, 0.0)),
+            graph.add_node(node(0.001, 0.001)),
+        );
+        let edge = add_road(&mut graph, a, b, 1, 50.0);
+        // East along the equator, then a right angle north, with a point halfway along each leg
+        let corner = point(0.0, 0.001);
+        graph[edge].polyline = vec![
+            point(0.0, 0.0),
+            point(0.0, 0.0005),
+            corner,
+            point(0.0005, 0.001),
+            point(0.001, 0.001),
+        ];
+
+        let (edges, removed) = simplify_polylines(&mut graph, 5.0, DistanceModel::Haversine);
+
+        assert_eq!((edges, removed), (1, 2));
+        assert_eq!(
+            graph[edge].polyline,
+            vec![point(0.0, 0.0), corner, point(0.001, 0.001)]
+        );
+        let legs = dist_with(point(0.0, 0.0), corner, DistanceModel::Haversine)
+            + dist_with(corner, point(0.001, 0.001), DistanceModel::Haversine);
+        assert!((graph[edge].distance - legs).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,121 @@
+use std::collections::{HashMap, HashSet};
+
+use console::style;
+use petgraph::{
+    stable_graph::StableDiGraph,
+    visit::{EdgeRef, IntoEdgeReferences},
+};
+
+use crate::parse::RoadData;
+
+use super::{collapse::MergedRoads, EdgeData, NodeData};
+
+/// A road from the input that has no edges left in the graph
+#[derive(Debug, Clone)]
+pub struct DroppedRoad {
+    pub unique_id: i32,
+    pub main_number: i32,
+    pub sub_number: i32,
+    /// The processing pass that removed the last of the road
+    pub stage: String,
+}
+
+/// Keeps track of which input roads are still part of the graph between processing passes
+pub struct DropTrace {
+    remaining: HashMap<i32, (i32, i32)>,
+    dropped: Vec<DroppedRoad>,
+    /// The roads merged into edges by node collapse, which only keep the id of the first one
+    merged: MergedRoads,
+}
+
+impl DropTrace {
+    pub fn new(roads: &[RoadData]) -> Self {
+        DropTrace {
+            remaining: roads
+                .iter()
+                .map(|road| (road.unique_id, (road.main_number, road.sub_number)))
+                .collect(),
+            dropped: Vec::new(),
+            merged: MergedRoads::new(),
+        }
+    }
+
+    /// Mark every remaining road not in **present** as dropped by **stage**
+    pub fn record(&mut self, stage: &str, present: &HashSet<i32>) {
+        let mut dropped = self
+            .remaining
+            .iter()
+            .filter(|(id, _)| !present.contains(id))
+            .map(|(id, (main_number, sub_number))| DroppedRoad {
+                unique_id: *id,
+                main_number: *main_number,
+                sub_number: *sub_number,
+                stage: stage.to_string(),
+            })
+            .collect::<Vec<_>>();
+        dropped.sort_by_key(|road| road.unique_id);
+
+        for road in dropped.iter() {
+            self.remaining.remove(&road.unique_id);
+        }
+        self.dropped.extend(dropped);
+    }
+
+    /// Attribute the edges added by node collapse to every road in **merged** from now on
+    pub fn record_merged(&mut self, merged: MergedRoads) {
+        self.merged = merged;
+    }
+
+    /// Mark every remaining road without any edge in **graph** as dropped by **stage**
+    pub fn record_graph(&mut self, stage: &str, graph: &StableDiGraph<NodeData, EdgeData>) {
+        let present = self.graph_roads(graph);
+        self.record(stage, &present);
+    }
+
+    /// The roads with an edge in **graph**
+    pub fn graph_roads(&self, graph: &StableDiGraph<NodeData, EdgeData>) -> HashSet<i32> {
+        graph
+            .edge_references()
+            .filter(|edge| !edge.weight().is_connector)
+            .flat_map(|edge| match self.merged.get(&edge.id()) {
+                Some(roads) => roads.clone(),
+                None => vec![edge.weight().original_road_id],
+            })
+            .collect()
+    }
+
+    /// Print the number of dropped roads per stage and write all of them to **path** as CSV
+    pub fn save(&self, path: &str) {
+        let mut stages: Vec<(&str, usize)> = Vec::new();
+        for road in self.dropped.iter() {
+            match stages.iter_mut().find(|(stage, _)| *stage == road.stage) {
+                Some((_, count)) => *count += 1,
+                None => stages.push((&road.stage, 1)),
+            }
+        }
+        for (stage, count) in stages {
+            println!("Dropped {} roads at {}", style(count).bold(), stage);
+        }
+
+        let mut writer = csv::Writer::from_path(path).expect("Failed to open file");
+        writer
+            .write_record(["unique_id", "main_number", "sub_number", "stage"])
+            .expect("Failed to write header");
+        for road in self.dropped.iter() {
+            writer
+                .write_record([
+                    road.unique_id.to_string(),
+                    road.main_number.to_string(),
+                    road.sub_number.to_string(),
+                    road.stage.clone(),
+                ])
+                .expect("Failed to write record");
+        }
+        writer.flush().expect("Failed to flush writer");
+        println!(
+            "Wrote {} dropped roads to {}",
+            style(self.dropped.len()).bold(),
+            path
+        );
+    }
+}
@@ -0,0 +1,280 @@
+use std::collections::HashMap;
+
+use console::style;
+use petgraph::{
+    graph::NodeIndex,
+    prelude::EdgeIndex,
+    stable_graph::StableDiGraph,
+    visit::{EdgeRef, IntoEdgeReferences},
+};
+
+use crate::parse::{Point, RoadData, RoadDirection};
+
+use super::{EdgeData, NodeData};
+
+/// A problem in a processed graph that can break routing or drawing
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GraphIssue {
+    /// A node no edge leads to or from
+    IsolatedNode(NodeIndex),
+    /// A road edge with fewer than two points in its polyline
+    ShortPolyline(EdgeIndex),
+    /// An edge with a distance that is NaN or infinite
+    NonFiniteDistance(EdgeIndex, f64),
+    /// An edge from a node to itself
+    SelfLoop(EdgeIndex),
+}
+
+impl GraphIssue {
+    /// Whether the issue breaks routing or drawing, rather than only wasting space
+    pub fn is_fatal(&self) -> bool {
+        !matches!(self, GraphIssue::IsolatedNode(_))
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            GraphIssue::IsolatedNode(_) => "isolated nodes",
+            GraphIssue::ShortPolyline(_) => "road edges with fewer than two polyline points",
+            GraphIssue::NonFiniteDistance(_, _) => "edges with a non-finite distance",
+            GraphIssue::SelfLoop(_) => "self-loops",
+        }
+    }
+}
+
+impl std::fmt::Display for GraphIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GraphIssue::IsolatedNode(node) => write!(f, "Node {} has no edges", node.index()),
+            GraphIssue::ShortPolyline(edge) => {
+                write!(
+                    f,
+                    "Edge {} has fewer than two polyline points",
+                    edge.index()
+                )
+            }
+            GraphIssue::NonFiniteDistance(edge, distance) => {
+                write!(f, "Edge {} has distance {}", edge.index(), distance)
+            }
+            GraphIssue::SelfLoop(edge) => write!(f, "Edge {} is a self-loop", edge.index()),
+        }
+    }
+}
+
+/// Find every [`GraphIssue`] in the graph, nodes first and then edges, in index order
+pub fn validate_graph(graph: &StableDiGraph<NodeData, EdgeData>) -> Vec<GraphIssue> {
+    let mut issues = graph
+        .node_indices()
+        .filter(|node| graph.neighbors_undirected(*node).next().is_none())
+        .map(GraphIssue::IsolatedNode)
+        .collect::<Vec<_>>();
+
+    for edge in graph.edge_references() {
+        let data = edge.weight();
+        if !data.is_connector && data.polyline.len() < 2 {
+            issues.push(GraphIssue::ShortPolyline(edge.id()));
+        }
+        if !data.distance.is_finite() {
+            issues.push(GraphIssue::NonFiniteDistance(edge.id(), data.distance));
+        }
+        if edge.source() == edge.target() {
+            issues.push(GraphIssue::SelfLoop(edge.id()));
+        }
+    }
+
+    issues
+}
+
+/// Print how many issues there are of each kind, with the first few of each as examples
+pub fn print_report(issues: &[GraphIssue], examples: usize) {
+    let mut kinds: Vec<(&str, Vec<&GraphIssue>)> = Vec::new();
+    for issue in issues {
+        match kinds.iter_mut().find(|(kind, _)| *kind == issue.kind()) {
+            Some((_, issues)) => issues.push(issue),
+            None => kinds.push((issue.kind(), vec![issue])),
+        }
+    }
+
+    for (kind, issues) in kinds {
+        let severity = if issues[0].is_fatal() {
+            style("[error]").bold().red()
+        } else {
+            style("[warning]").bold().yellow()
+        };
+        println!("{} {} {}", severity, style(issues.len()).bold(), kind);
+        for issue in issues.iter().take(examples) {
+            println!("    {}", issue);
+        }
+        if issues.len() > examples {
+            println!("    ...and {} more", issues.len() - examples);
+        }
+    }
+}
+
+/// Road edges that disagree with the direction their original road can be traveled in
+#[derive(Debug, Default)]
+pub struct DirectionMismatches {
+    /// Edges labeled with another direction than their road, with the direction of the road
+    pub relabel: Vec<(EdgeIndex, RoadDirection)>,
+    /// Edges running against a one-way road
+    pub against: Vec<EdgeIndex>,
+}
+
+/// Find the road edges that disagree with the direction their original road can be traveled in.
+/// The roads must have the coordinates of backward roads reversed, as when adding them to the
+/// graph, so one-way roads are traveled in the order of their coordinates. Edges whose endpoints
+/// are not on their road, like connectors, are not checked for running against their road.
+pub fn check_directions(
+    graph: &StableDiGraph<NodeData, EdgeData>,
+    road_data: &[RoadData],
+) -> DirectionMismatches {
+    let roads = road_data
+        .iter()
+        .map(|road| (road.unique_id, road))
+        .collect::<HashMap<_, _>>();
+    let mut positions: HashMap<i32, HashMap<(u64, u64), usize>> = HashMap::new();
+    let key = |point: &Point| (point.latitude.to_bits(), point.longitude.to_bits());
+
+    let mut mismatches = DirectionMismatches::default();
+    for edge in graph.edge_references() {
+        let data = edge.weight();
+        if data.is_connector {
+            continue;
+        }
+        let Some(road) = roads.get(&data.original_road_id) else {
+            continue;
+        };
+
+        if data.direction != road.direction {
+            mismatches.relabel.push((edge.id(), road.direction));
+        }
+
+        if matches!(
+            road.direction,
+            RoadDirection::Forward | RoadDirection::Backward
+        ) {
+            let positions = positions.entry(road.unique_id).or_insert_with(|| {
+                road.coordinates
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, point)| (key(point), idx))
+                    .collect()
+            });
+            let first = data.polyline.first().and_then(|p| positions.get(&key(p)));
+            let last = data.polyline.last().and_then(|p| positions.get(&key(p)));
+            if let (Some(first), Some(last)) = (first, last) {
+                if first > last {
+                    mismatches.against.push(edge.id());
+                }
+            }
+        }
+    }
+
+    mismatches
+}
+
+/// Make every road edge agree with the direction its original road can be traveled in, as found
+/// by [`check_directions`], relabeling edges with another direction and removing edges that run
+/// against a one-way road. Returns how many edges were relabeled and removed.
+pub fn repair_directions(
+    graph: &mut StableDiGraph<NodeData, EdgeData>,
+    road_data: &[RoadData],
+) -> (usize, usize) {
+    let mismatches = check_directions(graph, road_data);
+    for (edge, direction) in mismatches.relabel.iter() {
+        graph[*edge].direction = *direction;
+    }
+    for edge in mismatches.against.iter() {
+        graph.remove_edge(*edge);
+    }
+    (mismatches.relabel.len(), mismatches.against.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_graphs::{add_chain, add_road, edge, grid, node, TestGraph},
+        visitor::{shortest_path, DistanceMetric, SearchStrategy},
+    };
+
+    #[test]
+    fn forward_only_road_can_not_be_traveled_backward() {
+        let mut graph = TestGraph::default();
+        let nodes = add_chain(&mut graph, &[(0.0, 0.0), (0.0, 0.001)], 1, 50.0);
+        let (start, end) = (nodes[0], nodes[1]);
+        // Edges added both ways, as for a road wrongly labeled as two-way
+        add_road(&mut graph, end, start, 1, 50.0);
+        let road = RoadData {
+            direction: RoadDirection::Forward,
+            main_number: 1,
+            sub_number: 0,
+            coordinates: vec![graph[start].point, graph[end].point],
+            length: 111.0,
+            unique_id: 1,
+            speed_limit: 50.0,
+        };
+
+        let mismatches = check_directions(&graph, std::slice::from_ref(&road));
+        assert_eq!(mismatches.against.len(), 1);
+        assert_eq!(graph.edge_count(), 2);
+
+        assert_eq!(repair_directions(&mut graph, &[road]).1, 1);
+
+        let route = |from, to| {
+            shortest_path(
+                &graph,
+                vec![from, to],
+                DistanceMetric::Space,
+                1.0,
+                SearchStrategy::Dijkstra,
+            )
+            .unwrap()
+        };
+        assert!(route(start, end).complete);
+        assert!(!route(end, start).complete);
+    }
+
+    #[test]
+    fn grid_with_a_connector_has_no_issues() {
+        let (mut graph, nodes) = grid(2, 2);
+        // Connectors have no polyline of their own
+        let data = EdgeData {
+            polyline: Vec::new(),
+            is_connector: true,
+            ..edge(graph[nodes[0][0]], graph[nodes[1][1]], 3, 20.0)
+        };
+        graph.add_edge(nodes[0][0], nodes[1][1], data);
+
+        assert_eq!(validate_graph(&graph), Vec::new());
+    }
+
+    #[test]
+    fn each_kind_of_issue_is_found() {
+        let mut graph = TestGraph::default();
+        let nodes = add_chain(
+            &mut graph,
+            &[(0.0, 0.0), (0.0, 0.001), (0.0, 0.002)],
+            1,
+            50.0,
+        );
+        let isolated = graph.add_node(node(0.001, 0.0));
+        let short = add_road(&mut graph, nodes[2], nodes[0], 2, 50.0);
+        graph[short].polyline.truncate(1);
+        let nan = add_road(&mut graph, nodes[1], nodes[0], 3, 50.0);
+        graph[nan].distance = f64::NAN;
+        let self_loop = add_road(&mut graph, nodes[1], nodes[1], 4, 50.0);
+
+        let issues = validate_graph(&graph);
+
+        assert_eq!(issues.len(), 4);
+        assert_eq!(issues[0], GraphIssue::IsolatedNode(isolated));
+        assert!(issues.contains(&GraphIssue::ShortPolyline(short)));
+        assert!(issues.iter().any(
+            |issue| matches!(issue, GraphIssue::NonFiniteDistance(edge, distance)
+                if *edge == nan && distance.is_nan())
+        ));
+        assert!(issues.contains(&GraphIssue::SelfLoop(self_loop)));
+        assert!(!issues[0].is_fatal());
+        assert!(issues[1..].iter().all(GraphIssue::is_fatal));
+    }
+}
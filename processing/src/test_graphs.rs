@@ -0,0 +1,207 @@
+//! Small hand built graphs for unit tests
+
+use std::{
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use petgraph::{
+    graph::{EdgeIndex, NodeIndex},
+    stable_graph::StableDiGraph,
+};
+
+use crate::{
+    math::{dist, midpoint},
+    mongo::model::{Location, MeasurementSide, SensorMetadata, VehicleType},
+    parse::{Point, RoadDirection},
+    processing::{direction_from_data, EdgeData, NodeData},
+};
+
+/// Degrees between neighbouring nodes of a [`grid`], roughly 110m
+pub const GRID_SPACING: f64 = 0.001;
+
+pub type TestGraph = StableDiGraph<NodeData, EdgeData>;
+
+pub fn point(latitude: f64, longitude: f64) -> Point {
+    Point {
+        latitude,
+        longitude,
+    }
+}
+
+/// A node in the middle of two-way road 1
+pub fn node(latitude: f64, longitude: f64) -> NodeData {
+    NodeData {
+        point: point(latitude, longitude),
+        direction: RoadDirection::Both,
+        main_number: 1,
+        sub_number: 0,
+        original_road_id: 1,
+        heading: 0.0,
+        is_road_cap: false,
+        has_sensor: false,
+    }
+}
+
+/// A straight edge of **road_id** from **from** to **to**, with the speed limit in km/h
+pub fn edge(from: NodeData, to: NodeData, road_id: i32, speed_limit: f64) -> EdgeData {
+    EdgeData {
+        distance: dist(from.point, to.point),
+        main_number: road_id,
+        sub_number: 0,
+        polyline: vec![from.point, to.point],
+        is_connector: false,
+        midpoint: midpoint(from.point, to.point),
+        direction: direction_from_data(from, to),
+        original_road_id: road_id,
+        speed_limit: Some(speed_limit),
+    }
+}
+
+/// Add a one-way road from **from** to **to**
+pub fn add_road(
+    graph: &mut TestGraph,
+    from: NodeIndex,
+    to: NodeIndex,
+    road_id: i32,
+    speed_limit: f64,
+) -> EdgeIndex {
+    let data = edge(graph[from], graph[to], road_id, speed_limit);
+    graph.add_edge(from, to, data)
+}
+
+/// Two one-way routes from **a** to **d**, a short one through **b** and a longer one through
+/// **c**, returned as [a, b, c, d]
+pub fn diamond() -> (TestGraph, [NodeIndex; 4]) {
+    let mut graph = TestGraph::default();
+    let a = graph.add_node(node(0.0, 0.0));
+    let b = graph.add_node(node(0.001, 0.001));
+    let c = graph.add_node(node(-0.002, 0.001));
+    let d = graph.add_node(node(0.0, 0.002));
+    add_road(&mut graph, a, b, 1, 50.0);
+    add_road(&mut graph, b, d, 1, 50.0);
+    add_road(&mut graph, a, c, 2, 50.0);
+    add_road(&mut graph, c, d, 2, 50.0);
+    (graph, [a, b, c, d])
+}
+
+/// Add a one-way road through **points** as latitude and longitude pairs, with caps at both ends
+pub fn add_chain(
+    graph: &mut TestGraph,
+    points: &[(f64, f64)],
+    road_id: i32,
+    speed_limit: f64,
+) -> Vec<NodeIndex> {
+    let nodes = points
+        .iter()
+        .enumerate()
+        .map(|(idx, (latitude, longitude))| {
+            graph.add_node(NodeData {
+                main_number: road_id,
+                original_road_id: road_id,
+                is_road_cap: idx == 0 || idx == points.len() - 1,
+                ..node(*latitude, *longitude)
+            })
+        })
+        .collect::<Vec<_>>();
+    for pair in nodes.windows(2) {
+        add_road(graph, pair[0], pair[1], road_id, speed_limit);
+    }
+    nodes
+}
+
+/// Add a two-way road between **a** and **b**
+pub fn add_two_way(
+    graph: &mut TestGraph,
+    a: NodeIndex,
+    b: NodeIndex,
+    road_id: i32,
+    speed_limit: f64,
+) {
+    add_road(graph, a, b, road_id, speed_limit);
+    add_road(graph, b, a, road_id, speed_limit);
+}
+
+/// A **rows** by **cols** grid of two-way 50 km/h roads, indexed by row then column, with row 0
+/// furthest south and column 0 furthest west. Rows are road 1 and columns road 2.
+pub fn grid(rows: usize, cols: usize) -> (TestGraph, Vec<Vec<NodeIndex>>) {
+    let mut graph = TestGraph::default();
+    let nodes = (0..rows)
+        .map(|row| {
+            (0..cols)
+                .map(|col| {
+                    graph.add_node(node(row as f64 * GRID_SPACING, col as f64 * GRID_SPACING))
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    for row in 0..rows {
+        for col in 0..cols {
+            if col + 1 < cols {
+                add_two_way(&mut graph, nodes[row][col], nodes[row][col + 1], 1, 50.0);
+            }
+            if row + 1 < rows {
+                add_two_way(&mut graph, nodes[row][col], nodes[row + 1][col], 2, 50.0);
+            }
+        }
+    }
+
+    (graph, nodes)
+}
+
+/// A sensor counting any vehicle at **latitude**, **longitude**
+pub fn sensor(site_id: i32, latitude: f64, longitude: f64) -> SensorMetadata {
+    SensorMetadata {
+        mongo_id: None,
+        site_id,
+        location: Location {
+            _type: "Point".to_string(),
+            coordinates: [longitude, latitude],
+        },
+        measurement_side: MeasurementSide::Unknown,
+        vehicle_type: VehicleType::AnyVehicle,
+        specific_lane: 1,
+        period: 60,
+    }
+}
+
+/// A path for **name** in a fresh temporary directory, which is removed along with anything
+/// written next to the path when dropped
+pub fn temp_path(name: &str) -> TempPath {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let dir = std::env::temp_dir().join(format!(
+        "processing-{}-{}",
+        std::process::id(),
+        COUNT.fetch_add(1, Ordering::Relaxed)
+    ));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name).to_string_lossy().into_owned();
+    TempPath { dir, path }
+}
+
+pub struct TempPath {
+    dir: PathBuf,
+    path: String,
+}
+
+impl Deref for TempPath {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.path
+    }
+}
+
+impl AsRef<Path> for TempPath {
+    fn as_ref(&self) -> &Path {
+        self.path.as_ref()
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
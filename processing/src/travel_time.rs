@@ -1,14 +1,28 @@
+use std::collections::HashMap;
+
+use clap::ValueEnum;
 use mongodb::bson::DateTime;
+use petgraph::{
+    graph::{EdgeIndex, NodeIndex},
+    stable_graph::StableDiGraph,
+    visit::{EdgeRef, IntoEdgeReferences},
+};
 
 use crate::{
-    mongo::{client::async_client::AsyncMongoClient, model::VehicleType},
-    processing::ProcessedGraph,
-    visitor::{convert_kmh_to_ms, Path},
+    mongo::{
+        client::async_client::{AsyncMongoClient, DataAggregation},
+        model::{DataPoint, VehicleType},
+    },
+    processing::{EdgeData, NodeData, ProcessedGraph},
+    visitor::{convert_kmh_to_ms, distance_time_dynamic, HourlySpeeds, Path},
 };
 
 pub struct DataPointFilter {
     pub timestamp: Option<i64>,
     pub max_age: Option<i64>,
+    /// Estimate the speed variance of each sensor over this many milliseconds before the timestamp
+    pub variance_window: Option<i64>,
+    pub aggregation: DataAggregation,
 }
 
 impl Default for DataPointFilter {
@@ -16,29 +30,287 @@ impl Default for DataPointFilter {
         Self {
             timestamp: None,
             max_age: None,
+            variance_window: None,
+            aggregation: DataAggregation::default(),
+        }
+    }
+}
+
+/// How the speed is assumed to change between two consecutive sensors
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SpeedInterpolation {
+    /// Travel the whole segment at the mean of both sensor speeds
+    Harmonic,
+    /// Travel the whole segment at the speed of the upstream sensor
+    Nearest,
+    /// The speed changes linearly over the distance between the sensors
+    LinearTime,
+}
+
+impl SpeedInterpolation {
+    /// The time in seconds to travel **distance** meters between two sensors measuring
+    /// **start_speed** and **end_speed** in km/h
+    pub fn travel_time(self, distance: f64, start_speed: f64, end_speed: f64) -> f64 {
+        let start_speed = convert_kmh_to_ms(start_speed);
+        let end_speed = convert_kmh_to_ms(end_speed);
+        match self {
+            SpeedInterpolation::Harmonic => 2.0 * distance / (start_speed + end_speed),
+            SpeedInterpolation::Nearest => distance / start_speed,
+            SpeedInterpolation::LinearTime => {
+                // Integral of 1 / v(x) over the segment, with v(x) linear from start to end speed
+                if (end_speed - start_speed).abs() < 1e-9 {
+                    distance / start_speed
+                } else {
+                    distance * (end_speed / start_speed).ln() / (end_speed - start_speed)
+                }
+            }
+        }
+    }
+}
+
+/// The z-score bounding the central 90% of a normal distribution
+const Z_90: f64 = 1.6448536;
+
+/// Travel times along paths from the historical speeds of the sensors at an hour of the day, each
+/// edge traveled at the speed of the nearest sensor before it on the path
+pub struct TimeDependentMetric<'a> {
+    graph: &'a StableDiGraph<NodeData, EdgeData>,
+    profiles: HashMap<NodeIndex, HourlySpeeds>,
+}
+
+impl<'a> TimeDependentMetric<'a> {
+    /// Combine the profiles of the sensors at each sensor node, keyed by site id, into one profile
+    /// per node by averaging the sensors with data for each hour
+    pub fn new(graph: &'a ProcessedGraph, site_profiles: &HashMap<i32, HourlySpeeds>) -> Self {
+        let profiles = graph
+            .sensor_store
+            .iter()
+            .map(|(node, sensors)| {
+                let mut profile = [None; 24];
+                for (hour, speed) in profile.iter_mut().enumerate() {
+                    let speeds = sensors
+                        .iter()
+                        .filter_map(|sensor| site_profiles.get(&sensor.site_id))
+                        .filter_map(|profile| profile[hour])
+                        .collect::<Vec<_>>();
+                    if !speeds.is_empty() {
+                        *speed = Some(speeds.iter().sum::<f64>() / speeds.len() as f64);
+                    }
+                }
+                (*node, profile)
+            })
+            .collect();
+
+        Self {
+            graph: &graph.graph,
+            profiles,
+        }
+    }
+
+    /// The time in seconds to travel **path** at **hour**, in UTC
+    pub fn route_time(&self, path: &Path, hour: usize) -> f64 {
+        let mut upstream = None;
+        let mut time = 0.0;
+        for nodes in path.nodes.windows(2) {
+            if let Some(profile) = self.profiles.get(&nodes[0]) {
+                upstream = Some(profile);
+            }
+            let edge = self
+                .graph
+                .edges_connecting(nodes[0], nodes[1])
+                .next()
+                .unwrap();
+            time += distance_time_dynamic(
+                &self.graph[nodes[0]],
+                &self.graph[nodes[1]],
+                edge.weight(),
+                upstream,
+                hour,
+            );
+        }
+        time
+    }
+}
+
+/// The live speed in km/h of every edge to or from a sensor node with data, averaging the sensors
+/// of each node and the nodes of each edge, for routing with
+/// [`crate::visitor::live_distance_fn`]
+pub fn live_edge_speeds(
+    graph: &ProcessedGraph,
+    data: &HashMap<i32, DataPoint>,
+    vehicle_type: VehicleType,
+) -> HashMap<EdgeIndex, f64> {
+    let node_speeds = graph
+        .sensor_store
+        .iter()
+        .filter_map(|(node, sensors)| {
+            let speeds = sensors
+                .iter()
+                .filter(|sensor| sensor.vehicle_type == vehicle_type)
+                .filter_map(|sensor| data.get(&sensor.site_id))
+                .map(|data_point| data_point.average_speed)
+                .filter(|speed| *speed > 0.0)
+                .collect::<Vec<_>>();
+            if speeds.is_empty() {
+                None
+            } else {
+                Some((*node, speeds.iter().sum::<f64>() / speeds.len() as f64))
+            }
+        })
+        .collect::<HashMap<_, _>>();
+
+    let mut edge_speeds = HashMap::new();
+    for edge in graph.graph.edge_references() {
+        let speeds = [edge.source(), edge.target()]
+            .iter()
+            .filter_map(|node| node_speeds.get(node))
+            .collect::<Vec<_>>();
+        if !speeds.is_empty() {
+            let speed = speeds.iter().copied().sum::<f64>() / speeds.len() as f64;
+            edge_speeds.insert(edge.id(), speed);
+        }
+    }
+    edge_speeds
+}
+
+/// Computes travel times along paths from the speeds at sensor nodes, with speed overrides
+/// layered on top so modified scenarios can be evaluated without cloning the graph
+pub struct TravelTimeCalculator<'a> {
+    graph: &'a StableDiGraph<NodeData, EdgeData>,
+    overrides: HashMap<NodeIndex, f64>,
+}
+
+impl<'a> TravelTimeCalculator<'a> {
+    pub fn new(graph: &'a StableDiGraph<NodeData, EdgeData>) -> Self {
+        TravelTimeCalculator {
+            graph,
+            overrides: HashMap::new(),
+        }
+    }
+
+    /// Use **speed** in km/h at **node** instead of the measured speed
+    pub fn set_override(&mut self, node: NodeIndex, speed: f64) {
+        self.overrides.insert(node, speed);
+    }
+
+    /// The distance along **path** and the speed in km/h of every node with a measured or
+    /// overridden speed, and the length of the whole path
+    pub fn measurements(
+        &self,
+        path: &Path,
+        speeds: &HashMap<NodeIndex, f64>,
+    ) -> (Vec<(NodeIndex, f64, f64)>, f64) {
+        let mut distance = 0.0;
+        let mut measurements = Vec::new();
+        let mut prev_node = None;
+
+        for node in &path.nodes {
+            if let Some(prev_node) = prev_node {
+                let edge = self
+                    .graph
+                    .edges_connecting(prev_node, *node)
+                    .next()
+                    .unwrap();
+                distance += edge.weight().distance;
+            }
+
+            if let Some(speed) = self.overrides.get(node).or_else(|| speeds.get(node)) {
+                measurements.push((*node, distance, *speed));
+            }
+
+            prev_node = Some(*node);
         }
+
+        (measurements, distance)
     }
 }
 
 pub struct LiveRouteResults {
     pub travel_time: f64,
+    /// Approximate standard deviation of the travel time, if a variance window was given
+    pub travel_time_std_dev: Option<f64>,
+    /// Travel time at the historical speeds of the sensors at the same hour, if requested
+    pub historical_travel_time: Option<f64>,
     pub total_flow_rate: f64,
     pub average_flow_rate: f64,
     pub average_speed: f64,
     pub sensor_count: usize,
 }
 
+impl LiveRouteResults {
+    /// Results for a step without sensor data, with a NaN travel time so the step is still written
+    /// but stands out from the measured ones
+    pub fn missing() -> Self {
+        LiveRouteResults {
+            travel_time: f64::NAN,
+            travel_time_std_dev: None,
+            historical_travel_time: None,
+            total_flow_rate: 0.0,
+            average_flow_rate: f64::NAN,
+            average_speed: f64::NAN,
+            sensor_count: 0,
+        }
+    }
+
+    /// The 90% interval of the travel time, assuming it is normally distributed
+    pub fn interval_90(&self) -> Option<(f64, f64)> {
+        self.travel_time_std_dev.map(|std_dev| {
+            (
+                (self.travel_time - Z_90 * std_dev).max(0.0),
+                self.travel_time + Z_90 * std_dev,
+            )
+        })
+    }
+}
+
+/// Why a live travel time could not be calculated
+#[derive(Debug, Clone)]
+pub enum TravelTimeError {
+    /// None of the sensors along the path had data between the two timestamps, in milliseconds
+    NoSensorData { from: i64, to: i64 },
+    /// Querying the sensor data failed
+    Mongo(mongodb::error::Error),
+}
+
+impl std::fmt::Display for TravelTimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TravelTimeError::NoSensorData { from, to } => write!(
+                f,
+                "No sensor data found for path between {} and {}",
+                DateTime::from_millis(*from),
+                DateTime::from_millis(*to)
+            ),
+            TravelTimeError::Mongo(error) => write!(f, "Failed to query sensor data: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for TravelTimeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TravelTimeError::NoSensorData { .. } => None,
+            TravelTimeError::Mongo(error) => Some(error),
+        }
+    }
+}
+
+impl From<mongodb::error::Error> for TravelTimeError {
+    fn from(error: mongodb::error::Error) -> Self {
+        TravelTimeError::Mongo(error)
+    }
+}
+
 pub async fn calculate_live_travel_time(
     graph: &ProcessedGraph,
     path: &Path,
     mongo: &AsyncMongoClient,
     filter: DataPointFilter,
     vehicle_type: Option<VehicleType>,
-) -> LiveRouteResults {
-    let ProcessedGraph {
-        graph,
-        sensor_store,
-    } = graph;
+    interpolation: SpeedInterpolation,
+    speed_overrides: &HashMap<i32, f64>,
+) -> Result<LiveRouteResults, TravelTimeError> {
+    let sensor_store = &graph.sensor_store;
 
     let mut passed_sensors = Vec::new();
     for node in &path.nodes {
@@ -57,33 +329,69 @@ pub async fn calculate_live_travel_time(
     let max_age = filter.max_age.unwrap_or(timestamp);
 
     let data = mongo
-        .get_sensor_data_at(passed_sensors.into_iter(), timestamp, max_age)
-        .await
-        .expect("Failed to get sensor data");
+        .get_sensor_data_at(
+            passed_sensors.iter().copied(),
+            timestamp,
+            max_age,
+            filter.aggregation,
+        )
+        .await?;
+
+    let speed_std_devs = match filter.variance_window {
+        Some(window) => Some(
+            mongo
+                .get_sensor_speed_std_dev(passed_sensors.iter().copied(), timestamp, window)
+                .await?,
+        ),
+        None => None,
+    };
 
-    let mut distance = 0.0;
-    let mut measurements_distance = Vec::new();
+    live_travel_time_from(
+        graph,
+        path,
+        &data,
+        speed_std_devs.as_ref(),
+        interpolation,
+        speed_overrides,
+    )
+    .ok_or(TravelTimeError::NoSensorData {
+        from: timestamp - max_age,
+        to: timestamp,
+    })
+}
+
+/// The live travel time along **path** from the latest **data** of each sensor by site id, or
+/// `None` when none of the sensors along the path have data
+fn live_travel_time_from(
+    graph: &ProcessedGraph,
+    path: &Path,
+    data: &HashMap<i32, DataPoint>,
+    speed_std_devs: Option<&HashMap<i32, f64>>,
+    interpolation: SpeedInterpolation,
+    speed_overrides: &HashMap<i32, f64>,
+) -> Option<LiveRouteResults> {
+    let ProcessedGraph {
+        graph,
+        sensor_store,
+    } = graph;
+
+    let mut calculator = TravelTimeCalculator::new(graph);
+    let mut node_speeds = HashMap::new();
+    let mut node_std_devs = HashMap::new();
     let mut total_flow_rate = 0.0;
     let mut total_average_flow = 0.0;
     let mut average_flows_count = 0;
     let mut sensor_count = 0;
 
-    let mut prev_node = None;
-
     for node in &path.nodes {
-        let edge_length = if let Some(prev_node) = prev_node {
-            let edge = graph.edges_connecting(prev_node, *node).next().unwrap();
-            edge.weight().distance
-        } else {
-            0.0
-        };
-
-        distance += edge_length;
-
         let node_data = graph.node_weight(*node).unwrap();
         if node_data.has_sensor {
             let sensors = sensor_store.get(node).unwrap();
             let site_ids = sensors.iter().map(|s| s.site_id).collect::<Vec<_>>();
+            if let Some(speed) = site_ids.iter().find_map(|id| speed_overrides.get(id)) {
+                calculator.set_override(*node, *speed);
+            }
+
             let (sum, count) = site_ids
                 .iter()
                 .filter_map(|id| data.get(id))
@@ -106,20 +414,62 @@ pub async fn calculate_live_travel_time(
             sensor_count += count;
 
             if count > 0 {
-                measurements_distance.push((distance, average_speed));
+                node_speeds.insert(*node, average_speed);
+
+                // The average of independent speeds has the root of the summed variances over n
+                let variance = site_ids
+                    .iter()
+                    .filter(|id| data.contains_key(id))
+                    .filter_map(|id| speed_std_devs?.get(id))
+                    .map(|std_dev| std_dev * std_dev)
+                    .sum::<f64>();
+                node_std_devs.insert(*node, variance.sqrt() / count as f64);
             }
         }
-
-        prev_node = Some(*node);
     }
 
-    if measurements_distance.is_empty() {
-        println!("No sensor data found for path");
-        println!("At timestamp: {}", DateTime::from_millis(timestamp));
-        println!("Max age: {}", DateTime::from_millis(timestamp - max_age));
+    let (measurements, distance) = calculator.measurements(path, &node_speeds);
+    if measurements.is_empty() {
+        return None;
     }
 
-    let mut iter = measurements_distance.iter();
+    let measurements_distance = measurements
+        .iter()
+        .map(|(_, distance, speed)| (*distance, *speed))
+        .collect::<Vec<_>>();
+    let travel_time = route_travel_time(&measurements_distance, distance, interpolation);
+    let travel_time_std_dev = speed_std_devs.map(|_| {
+        let measurement_std_devs = measurements
+            .iter()
+            .map(|(node, _, _)| node_std_devs.get(node).copied().unwrap_or(0.0))
+            .collect::<Vec<_>>();
+        route_travel_time_std_dev(
+            &measurements_distance,
+            &measurement_std_devs,
+            distance,
+            interpolation,
+        )
+    });
+
+    Some(LiveRouteResults {
+        travel_time,
+        travel_time_std_dev,
+        historical_travel_time: None,
+        total_flow_rate,
+        average_flow_rate: total_average_flow / average_flows_count as f64,
+        average_speed: distance / travel_time,
+        sensor_count,
+    })
+}
+
+/// The time in seconds to travel **distance** meters past the **measurements**, given as the
+/// distance along the route and the speed in km/h at each sensor
+pub fn route_travel_time(
+    measurements: &[(f64, f64)],
+    distance: f64,
+    interpolation: SpeedInterpolation,
+) -> f64 {
+    let mut iter = measurements.iter();
     let mut prev = iter.next().unwrap();
     // Calculate the travel time from the start of the path to the first sensor
     let mut travel_time = prev.0 / convert_kmh_to_ms(prev.1);
@@ -129,8 +479,7 @@ pub async fn calculate_live_travel_time(
         let (prev_distance, prev_speed) = prev;
         let (next_distance, next_speed) = next;
         let distance = next_distance - prev_distance;
-        let time = 2.0 * distance / convert_kmh_to_ms(prev_speed + next_speed);
-        travel_time += time;
+        travel_time += interpolation.travel_time(distance, *prev_speed, *next_speed);
         prev = next;
     }
 
@@ -139,11 +488,282 @@ pub async fn calculate_live_travel_time(
     let distance = distance - prev_distance;
     travel_time += distance / convert_kmh_to_ms(*prev_speed);
 
-    LiveRouteResults {
-        travel_time,
-        total_flow_rate,
-        average_flow_rate: total_average_flow / average_flows_count as f64,
-        average_speed: distance / travel_time,
-        sensor_count,
+    travel_time
+}
+
+/// Propagate the speed standard deviation of each measurement to the travel time, treating the
+/// measurements as independent and the travel time as linear around the measured speeds
+fn route_travel_time_std_dev(
+    measurements: &[(f64, f64)],
+    std_devs: &[f64],
+    distance: f64,
+    interpolation: SpeedInterpolation,
+) -> f64 {
+    let mut shifted = measurements.to_vec();
+    let variance = std_devs
+        .iter()
+        .enumerate()
+        .map(|(idx, std_dev)| {
+            let speed = measurements[idx].1;
+            let step = (speed * 1e-3).max(1e-6);
+
+            shifted[idx].1 = speed + step;
+            let above = route_travel_time(&shifted, distance, interpolation);
+            shifted[idx].1 = speed - step;
+            let below = route_travel_time(&shifted, distance, interpolation);
+            shifted[idx].1 = speed;
+
+            let derivative = (above - below) / (2.0 * step);
+            (derivative * std_dev).powi(2)
+        })
+        .sum::<f64>();
+
+    variance.sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mongo::{client::MongoOptions, model::SensorMetadata},
+        test_graphs::{add_chain, diamond, sensor, TestGraph},
+    };
+
+    /// The latest data point of a sensor measuring **average_speed** in km/h
+    fn data_point(average_speed: f64) -> DataPoint {
+        DataPoint {
+            mongo_id: None,
+            original_id: mongodb::bson::oid::ObjectId::new(),
+            sensor_id: mongodb::bson::oid::ObjectId::new(),
+            time: DateTime::from_millis(0),
+            flow_rate: 600.0,
+            average_speed,
+        }
+    }
+
+    /// The width of the 90% interval of a route past two sensors whose speeds vary by **std_dev**
+    fn interval_width(std_dev: f64) -> f64 {
+        let measurements = [(500.0, 50.0), (1500.0, 70.0)];
+        let interpolation = SpeedInterpolation::Harmonic;
+        let results = LiveRouteResults {
+            travel_time: route_travel_time(&measurements, 2000.0, interpolation),
+            travel_time_std_dev: Some(route_travel_time_std_dev(
+                &measurements,
+                &[std_dev, std_dev],
+                2000.0,
+                interpolation,
+            )),
+            ..LiveRouteResults::missing()
+        };
+        let (low, high) = results.interval_90().unwrap();
+        high - low
+    }
+
+    #[test]
+    fn higher_variance_widens_the_interval() {
+        assert!(interval_width(2.0) > 0.0);
+        assert!(interval_width(5.0) > interval_width(2.0));
+    }
+
+    #[test]
+    fn interpolations_order_times_over_a_segment_speeding_up() {
+        // 1km from a sensor at 36 km/h to one at 108 km/h, so 10 m/s to 30 m/s
+        let time =
+            |interpolation: SpeedInterpolation| interpolation.travel_time(1000.0, 36.0, 108.0);
+
+        let harmonic = time(SpeedInterpolation::Harmonic);
+        let linear_time = time(SpeedInterpolation::LinearTime);
+        let nearest = time(SpeedInterpolation::Nearest);
+
+        assert!((harmonic - 50.0).abs() < 1e-9);
+        assert!((linear_time - 1000.0 * 3.0_f64.ln() / 20.0).abs() < 1e-9);
+        assert!((nearest - 100.0).abs() < 1e-9);
+        assert!(harmonic < linear_time && linear_time < nearest);
+    }
+
+    #[test]
+    fn overrides_match_modified_speeds_and_clear_back() {
+        let (graph, [a, b, _, d]) = diamond();
+        let path = Path {
+            nodes: vec![a, b, d],
+            length: 0.0,
+            complete: true,
+            missed: vec![],
+        };
+        let speeds = HashMap::from([(a, 50.0), (b, 50.0), (d, 70.0)]);
+        // The speeds as the graph would have them if it was cloned and modified
+        let mut modified_speeds = speeds.clone();
+        modified_speeds.insert(b, 20.0);
+
+        let mut calculator = TravelTimeCalculator::new(&graph);
+        let unmodified = calculator.measurements(&path, &speeds);
+        calculator.set_override(b, 20.0);
+        let overridden = calculator.measurements(&path, &speeds);
+        calculator.overrides.clear();
+        let cleared = calculator.measurements(&path, &speeds);
+
+        let cloned = TravelTimeCalculator::new(&graph).measurements(&path, &modified_speeds);
+        assert_eq!(overridden, cloned);
+        assert_eq!(cleared, unmodified);
+        assert_ne!(overridden, unmodified);
+    }
+
+    #[test]
+    fn afternoon_congestion_slows_the_route_down() {
+        let mut graph = TestGraph::default();
+        let nodes = add_chain(
+            &mut graph,
+            &[(0.0, 0.0), (0.0, 0.005), (0.0, 0.01)],
+            1,
+            50.0,
+        );
+        let graph = ProcessedGraph {
+            graph,
+            sensor_store: HashMap::from([
+                (nodes[0], vec![sensor(1, 0.0, 0.0)]),
+                (nodes[1], vec![sensor(2, 0.0, 0.005)]),
+            ]),
+        };
+        // Both sensors only have data for the afternoon rush hour
+        let congested = |speed| {
+            let mut profile = [None; 24];
+            profile[16] = Some(speed);
+            profile
+        };
+        let site_profiles = HashMap::from([(1, congested(20.0)), (2, congested(30.0))]);
+        let path = Path {
+            nodes,
+            length: 0.0,
+            complete: true,
+            missed: vec![],
+        };
+
+        let metric = TimeDependentMetric::new(&graph, &site_profiles);
+        let free_flow = metric.route_time(&path, 3);
+        let afternoon = metric.route_time(&path, 16);
+
+        let half = graph.graph.edge_weights().next().unwrap().distance;
+        assert!((free_flow - 2.0 * half / convert_kmh_to_ms(50.0)).abs() < 1e-6);
+        let expected = half / convert_kmh_to_ms(20.0) + half / convert_kmh_to_ms(30.0);
+        assert!((afternoon - expected).abs() < 1e-6);
+        assert!(afternoon > free_flow);
+    }
+
+    #[test]
+    fn congested_sensor_on_the_fast_route_picks_the_alternative() {
+        use crate::visitor::{live_distance_fn, shortest_path_with};
+
+        let (graph, [a, b, c, d]) = diamond();
+        let graph = ProcessedGraph {
+            sensor_store: HashMap::from([(b, vec![sensor(1, 0.001, 0.001)])]),
+            graph,
+        };
+        let route = |speed: f64| {
+            let data = HashMap::from([(1, data_point(speed))]);
+            let speeds = live_edge_speeds(&graph, &data, VehicleType::AnyVehicle);
+            assert_eq!(speeds.len(), 2);
+            shortest_path_with(&graph.graph, vec![a, d], live_distance_fn(speeds, 1.0))
+                .unwrap()
+                .nodes
+        };
+
+        assert_eq!(route(50.0), vec![a, b, d]);
+        assert_eq!(route(5.0), vec![a, c, d]);
+    }
+
+    #[test]
+    fn route_without_sensor_data_falls_back_to_missing_results() {
+        let mut graph = TestGraph::default();
+        let nodes = add_chain(
+            &mut graph,
+            &[(0.0, 0.0), (0.0, 0.005), (0.0, 0.01)],
+            1,
+            50.0,
+        );
+        for node in &nodes[..2] {
+            graph[*node].has_sensor = true;
+        }
+        let graph = ProcessedGraph {
+            graph,
+            sensor_store: HashMap::from([
+                (nodes[0], vec![sensor(1, 0.0, 0.0)]),
+                (nodes[1], vec![sensor(2, 0.0, 0.005)]),
+            ]),
+        };
+        let path = Path {
+            nodes,
+            length: 0.0,
+            complete: true,
+            missed: vec![],
+        };
+        let travel_time = |site_id: i32| {
+            let data = HashMap::from([(site_id, data_point(50.0))]);
+            live_travel_time_from(
+                &graph,
+                &path,
+                &data,
+                None,
+                SpeedInterpolation::Harmonic,
+                &HashMap::new(),
+            )
+        };
+
+        let measured = travel_time(1).unwrap();
+        assert!(measured.travel_time.is_finite());
+        assert_eq!(measured.sensor_count, 1);
+
+        // Only a sensor off the path has data in the window
+        assert!(travel_time(3).is_none());
+        let missing = LiveRouteResults::missing();
+        assert!(missing.travel_time.is_nan());
+        assert_eq!(missing.sensor_count, 0);
+    }
+
+    #[test]
+    fn unreachable_server_is_an_error_not_a_panic() {
+        let (graph, [a, b, _, d]) = diamond();
+        let point = graph[b].point;
+        let sensor = SensorMetadata {
+            mongo_id: Some(mongodb::bson::oid::ObjectId::new()),
+            ..sensor(1, point.latitude, point.longitude)
+        };
+        let graph = ProcessedGraph {
+            graph,
+            sensor_store: HashMap::from([(b, vec![sensor])]),
+        };
+        let path = Path {
+            nodes: vec![a, b, d],
+            length: 0.0,
+            complete: true,
+            missed: vec![],
+        };
+        let options = MongoOptions {
+            // Nothing listens on the discard port
+            uri: "mongodb://127.0.0.1:9".into(),
+            db: "exjobb".into(),
+            raw_sensor_data_collection: "raw".into(),
+            sensors_collection: "sensors".into(),
+            data_points_collection: "sensordata".into(),
+            speed_profiles_collection: "speed_profiles".into(),
+            timeout_ms: 200,
+            max_retries: 0,
+        };
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+
+        let result = runtime.block_on(async {
+            let client = AsyncMongoClient::new(options).await.unwrap();
+            calculate_live_travel_time(
+                &graph,
+                &path,
+                &client,
+                DataPointFilter::default(),
+                None,
+                SpeedInterpolation::Harmonic,
+                &HashMap::new(),
+            )
+            .await
+        });
+
+        assert!(matches!(result, Err(TravelTimeError::Mongo(_))));
     }
 }
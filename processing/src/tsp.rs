@@ -0,0 +1,116 @@
+/// Held-Karp is exact but takes O(2^n * n^2) time, so only use it for up to this many stops
+const MAX_EXACT_STOPS: usize = 12;
+
+/// The order to visit every stop in with the lowest total cost, starting at the first stop and
+/// ending anywhere. **costs** is indexed by from and to stop and does not need to be symmetric,
+/// unreachable pairs are infinite. Exact for few stops, otherwise nearest neighbour improved with
+/// 2-opt.
+pub fn open_path_order(costs: &[Vec<f64>]) -> Vec<usize> {
+    let n = costs.len();
+    if n <= 2 {
+        return (0..n).collect();
+    }
+    if n <= MAX_EXACT_STOPS {
+        held_karp(costs)
+    } else {
+        let mut order = nearest_neighbour(costs);
+        two_opt(costs, &mut order);
+        order
+    }
+}
+
+fn path_cost(costs: &[Vec<f64>], order: &[usize]) -> f64 {
+    order.windows(2).map(|pair| costs[pair[0]][pair[1]]).sum()
+}
+
+fn held_karp(costs: &[Vec<f64>]) -> Vec<usize> {
+    let n = costs.len();
+    let full = 1 << n;
+    // best[mask][last] is the cheapest path from stop 0 through the stops in mask ending at last
+    let mut best = vec![vec![f64::INFINITY; n]; full];
+    let mut prev = vec![vec![usize::MAX; n]; full];
+    best[1][0] = 0.0;
+
+    for mask in 1..full {
+        if mask & 1 == 0 {
+            continue;
+        }
+        for last in 0..n {
+            let cost = best[mask][last];
+            if mask & (1 << last) == 0 || cost.is_infinite() {
+                continue;
+            }
+            for next in 0..n {
+                if mask & (1 << next) != 0 {
+                    continue;
+                }
+                let next_mask = mask | (1 << next);
+                let next_cost = cost + costs[last][next];
+                if next_cost < best[next_mask][next] {
+                    best[next_mask][next] = next_cost;
+                    prev[next_mask][next] = last;
+                }
+            }
+        }
+    }
+
+    let mut mask = full - 1;
+    let Some(mut last) = (1..n)
+        .filter(|last| best[mask][*last].is_finite())
+        .min_by(|a, b| best[mask][*a].total_cmp(&best[mask][*b]))
+    else {
+        // No order reaches every stop, keep the given one
+        return (0..n).collect();
+    };
+
+    let mut order = vec![last];
+    while last != 0 {
+        let before = prev[mask][last];
+        mask &= !(1 << last);
+        last = before;
+        order.push(last);
+    }
+    order.reverse();
+    order
+}
+
+fn nearest_neighbour(costs: &[Vec<f64>]) -> Vec<usize> {
+    let n = costs.len();
+    let mut visited = vec![false; n];
+    let mut order = vec![0];
+    visited[0] = true;
+
+    while order.len() < n {
+        let last = *order.last().unwrap();
+        let next = (0..n)
+            .filter(|next| !visited[*next])
+            .min_by(|a, b| costs[last][*a].total_cmp(&costs[last][*b]))
+            .unwrap();
+        visited[next] = true;
+        order.push(next);
+    }
+    order
+}
+
+/// Reverse segments of the path while that lowers its cost, never moving the first stop
+fn two_opt(costs: &[Vec<f64>], order: &mut [usize]) {
+    let mut best_cost = path_cost(costs, order);
+    let mut improved = true;
+    while improved {
+        improved = false;
+        for i in 1..order.len() - 1 {
+            for j in i + 1..order.len() {
+                // Costs may be asymmetric, so the whole path is rescored rather than only the
+                // edges at the ends of the reversed segment
+                order[i..=j].reverse();
+                let cost = path_cost(costs, order);
+                if cost < best_cost {
+                    best_cost = cost;
+                    improved = true;
+                } else {
+                    order[i..=j].reverse();
+                }
+            }
+        }
+    }
+}
@@ -1,7 +1,13 @@
 use std::{io::Error, ops::Range};
 
-use crate::args::deserialize_f64_null_as_infinity;
-use clap::Args;
+use crate::{
+    args::deserialize_f64_null_as_infinity,
+    math::{angle_diff, geo_distance},
+    processing::{self, EdgeData, NodeData},
+};
+use clap::{Args, ValueEnum};
+use kdtree::KdTree;
+use petgraph::{graph::NodeIndex, stable_graph::StableDiGraph};
 use serde::{Deserialize, Serialize};
 
 use crate::parse;
@@ -16,8 +22,107 @@ pub struct PointQuery {
     pub radius: f64,
     #[clap(short, long, default_value = "-180..180", value_parser = range_from_str)]
     pub heading: Range<f64>,
+    /// Overrides the snapping strategy of the command for this query
+    #[clap(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub snap: Option<SnapStrategy>,
 }
 
+/// How a [`PointQuery`] is snapped to a node in the graph
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SnapStrategy {
+    /// The closest node within the radius whose heading is in range
+    #[default]
+    ProximityFirst,
+    /// The node within the radius whose heading is closest to the middle of the range
+    HeadingFirst,
+    /// The node within the radius minimizing its distance in meters plus **heading_weight** times
+    /// its heading error in degrees, which defaults to the radius over 180 so both count equally
+    /// at their extremes. With **any_heading** nodes outside the heading range are candidates too
+    Weighted {
+        #[serde(default)]
+        heading_weight: Option<f64>,
+        #[serde(default)]
+        any_heading: bool,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum SnapMode {
+    ProximityFirst,
+    HeadingFirst,
+    Weighted,
+}
+
+/// The snapping strategy of a command, see [`SnapStrategy`]
+#[derive(Debug, Clone, Copy, Args)]
+#[clap(about = None, long_about = None)]
+pub struct SnapOptions {
+    #[clap(long = "snap", value_name = "SNAP", default_value = "proximity-first")]
+    mode: SnapMode,
+    /// Meters a degree of heading error weighs as when snapping weighted
+    #[clap(long)]
+    heading_weight: Option<f64>,
+    /// Let weighted snapping pick nodes with a heading outside the range of the query
+    #[clap(long, default_value = "false", default_missing_value = "true")]
+    any_heading: bool,
+}
+
+impl SnapOptions {
+    pub fn strategy(&self) -> SnapStrategy {
+        match self.mode {
+            SnapMode::ProximityFirst => SnapStrategy::ProximityFirst,
+            SnapMode::HeadingFirst => SnapStrategy::HeadingFirst,
+            SnapMode::Weighted => SnapStrategy::Weighted {
+                heading_weight: self.heading_weight,
+                any_heading: self.any_heading,
+            },
+        }
+    }
+}
+
+/// How much more connector edges between roads cost than other edges when routing
+#[derive(Debug, Clone, Copy, Args)]
+#[clap(about = None, long_about = None)]
+pub struct PenaltyOptions {
+    /// Factor the cost of connector edges between roads is multiplied by when routing
+    #[clap(long, default_value = "1.0")]
+    pub connector_penalty: f64,
+}
+
+/// A query that did not snap to any node, with the distance in meters and heading of the closest
+/// node regardless of radius and heading, if the graph has any nodes
+#[derive(Debug, Clone)]
+pub struct QueryError {
+    pub query: PointQuery,
+    pub closest: Option<(f64, f64)>,
+}
+
+impl std::fmt::Display for QueryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "No node within {}m of ({}, {}) with a heading in {}..{}",
+            self.query.radius,
+            self.query.point.latitude,
+            self.query.point.longitude,
+            self.query.heading.start,
+            self.query.heading.end
+        )?;
+        match self.closest {
+            Some((distance, heading)) => write!(
+                f,
+                ", the closest node is {:.1}m away with heading {:.1}",
+                distance, heading
+            ),
+            None => write!(f, ", the graph has no nodes"),
+        }
+    }
+}
+
+impl std::error::Error for QueryError {}
+
 fn range_from_str(s: &str) -> Result<Range<f64>, Error> {
     let mut parts = s.split("..");
     let start = parts.next().unwrap().parse().unwrap();
@@ -34,6 +139,266 @@ impl PointQuery {
             },
             radius,
             heading,
+            snap: None,
+        }
+    }
+
+    /// How far off `heading` is from the middle of the heading range, in degrees
+    fn heading_error(&self, heading: f64) -> f64 {
+        if self.heading.end - self.heading.start >= 360.0 {
+            return 0.0;
+        }
+        let middle = (self.heading.start + self.heading.end) / 2.0;
+        angle_diff(heading, middle).abs()
+    }
+}
+
+/// Find the node a query snaps to, or **None** if no node within the radius has a matching heading,
+/// which weighted snapping with **any_heading** does not require.
+///
+/// Queries without a finite radius snap proximity first, since every node in the graph would
+/// otherwise be a candidate, unless weighted with an explicit heading weight, where the search
+/// stops once the distance alone exceeds the best score.
+pub fn find_point(
+    tree: &KdTree<f64, (NodeIndex, NodeData), [f64; 2]>,
+    query: &PointQuery,
+    strategy: SnapStrategy,
+) -> Option<NodeIndex> {
+    let strategy = query.snap.unwrap_or(strategy);
+    let any_heading = matches!(
+        strategy,
+        SnapStrategy::Weighted {
+            any_heading: true,
+            ..
+        }
+    );
+    let p = [query.point.latitude, query.point.longitude];
+    let candidates = tree
+        .iter_nearest(&p, &geo_distance)
+        .unwrap()
+        .take_while(|(dist, _)| *dist <= query.radius)
+        .filter(|(_, (_, data))| any_heading || query.heading.contains(&data.heading));
+
+    let heading_weight = match strategy {
+        SnapStrategy::Weighted {
+            heading_weight: Some(weight),
+            ..
+        } => Some(weight),
+        SnapStrategy::Weighted { .. } if query.radius.is_finite() => Some(query.radius / 180.0),
+        _ => None,
+    };
+    if strategy == SnapStrategy::ProximityFirst
+        || (heading_weight.is_none() && !query.radius.is_finite())
+    {
+        return candidates.map(|(_, (idx, _))| *idx).next();
+    }
+
+    let score = |dist: f64, data: &NodeData| match heading_weight {
+        Some(weight) => dist + weight * query.heading_error(data.heading),
+        None => query.heading_error(data.heading),
+    };
+
+    // Candidates are visited closest first, so ties are resolved in favour of proximity
+    let mut best: Option<(f64, NodeIndex)> = None;
+    for (dist, (idx, data)) in candidates {
+        // A weighted candidate can not beat the best one once its distance alone is higher
+        if heading_weight.is_some() && best.is_some_and(|(best_score, _)| dist > best_score) {
+            break;
+        }
+        let score = score(dist, data);
+        if best.is_none_or(|(best_score, _)| score < best_score) {
+            best = Some((score, *idx));
         }
     }
+    best.map(|(_, idx)| idx)
+}
+
+/// A latitude and longitude box, parsed from `minlat,maxlat,minlon,maxlon`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BoundingBox {
+    pub min_lat: f64,
+    pub max_lat: f64,
+    pub min_lon: f64,
+    pub max_lon: f64,
+}
+
+impl std::str::FromStr for BoundingBox {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values = s
+            .split(',')
+            .map(|v| v.trim().parse::<f64>().map_err(|e| format!("{}: {}", v, e)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let [min_lat, max_lat, min_lon, max_lon] = values[..] else {
+            return Err(format!("Expected minlat,maxlat,minlon,maxlon, got {}", s));
+        };
+        if min_lat > max_lat || min_lon > max_lon {
+            return Err(format!("Minimum is larger than maximum in {}", s));
+        }
+        Ok(BoundingBox {
+            min_lat,
+            max_lat,
+            min_lon,
+            max_lon,
+        })
+    }
+}
+
+/// Crop the graph to a box before drawing or routing on it
+#[derive(Debug, Clone, Copy, Args)]
+#[clap(about = None, long_about = None)]
+pub struct CropOptions {
+    /// Only use the graph within minlat,maxlat,minlon,maxlon
+    #[clap(long)]
+    pub bbox: Option<BoundingBox>,
+}
+
+impl CropOptions {
+    /// Remove the nodes outside the box if set, as [`processing::crop_to_bbox`] does
+    pub fn crop(&self, graph: &mut StableDiGraph<NodeData, EdgeData>) {
+        if let Some(bbox) = self.bbox {
+            processing::crop_to_bbox(
+                graph,
+                bbox.min_lat,
+                bbox.max_lat,
+                bbox.min_lon,
+                bbox.max_lon,
+            );
+        }
+    }
+}
+
+/// The spacing in degrees between the lines of a graticule
+#[derive(Debug, Clone, Copy)]
+pub struct GraticuleStep {
+    pub lat_step: f64,
+    pub lon_step: f64,
+}
+
+impl std::str::FromStr for GraticuleStep {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values = s
+            .split(',')
+            .map(|v| v.trim().parse::<f64>().map_err(|e| format!("{}: {}", v, e)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let [lat_step, lon_step] = values[..] else {
+            return Err(format!("Expected lat_step,lon_step, got {}", s));
+        };
+        if !(lat_step > 0.0 && lon_step > 0.0) {
+            return Err(format!("Steps must be positive, got {}", s));
+        }
+        Ok(GraticuleStep { lat_step, lon_step })
+    }
+}
+
+/// How many columns and rows to split a drawing into, parsed from `cols,rows`
+#[derive(Debug, Clone, Copy)]
+pub struct TileGrid {
+    pub cols: u32,
+    pub rows: u32,
+}
+
+impl std::str::FromStr for TileGrid {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values = s
+            .split(',')
+            .map(|v| v.trim().parse::<u32>().map_err(|e| format!("{}: {}", v, e)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let [cols, rows] = values[..] else {
+            return Err(format!("Expected cols,rows, got {}", s));
+        };
+        if cols == 0 || rows == 0 {
+            return Err(format!("Need at least one column and row, got {}", s));
+        }
+        Ok(TileGrid { cols, rows })
+    }
+}
+
+/// Append **suffix** to the file name of **path**, before the extension
+pub fn suffixed_path(path: &str, suffix: &str) -> String {
+    let path = std::path::Path::new(path);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let file_name = match path.extension() {
+        Some(extension) => format!("{}_{}.{}", stem, suffix, extension.to_string_lossy()),
+        None => format!("{}_{}", stem, suffix),
+    };
+    path.with_file_name(file_name)
+        .to_string_lossy()
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        processing::build_node_acceleration_structure,
+        test_graphs::{node, TestGraph},
+    };
+
+    /// A node 22m north of the origin heading 30 degrees, and one 55m north heading 0 degrees
+    fn heading_graph() -> (TestGraph, NodeIndex, NodeIndex) {
+        let mut graph = TestGraph::default();
+        let near = graph.add_node(NodeData {
+            heading: 30.0,
+            ..node(0.0002, 0.0)
+        });
+        let far = graph.add_node(NodeData {
+            heading: 0.0,
+            ..node(0.0005, 0.0)
+        });
+        (graph, near, far)
+    }
+
+    #[test]
+    fn heading_first_prefers_heading_over_proximity() {
+        let (graph, near, far) = heading_graph();
+        let tree = build_node_acceleration_structure(&graph);
+        let query = PointQuery::new(0.0, 0.0, 100.0, -45.0..45.0);
+
+        assert_eq!(
+            find_point(&tree, &query, SnapStrategy::ProximityFirst),
+            Some(near)
+        );
+        assert_eq!(
+            find_point(&tree, &query, SnapStrategy::HeadingFirst),
+            Some(far)
+        );
+    }
+
+    #[test]
+    fn weighted_snapping_prefers_a_closer_node_slightly_off_heading() {
+        let mut graph = TestGraph::default();
+        // 11m away and 8 degrees off, and 55m away and straight on
+        let close = graph.add_node(NodeData {
+            heading: 8.0,
+            ..node(0.0001, 0.0)
+        });
+        let far = graph.add_node(NodeData {
+            heading: 0.0,
+            ..node(0.0005, 0.0)
+        });
+        let tree = build_node_acceleration_structure(&graph);
+        let query = PointQuery::new(0.0, 0.0, 100.0, -10.0..10.0);
+        let weighted = |any_heading| SnapStrategy::Weighted {
+            heading_weight: None,
+            any_heading,
+        };
+
+        assert_eq!(
+            find_point(&tree, &query, SnapStrategy::HeadingFirst),
+            Some(far)
+        );
+        assert_eq!(find_point(&tree, &query, weighted(false)), Some(close));
+
+        // Outside the heading range the close node is only a candidate with any heading
+        graph[close].heading = 30.0;
+        let tree = build_node_acceleration_structure(&graph);
+        assert_eq!(find_point(&tree, &query, weighted(false)), Some(far));
+        assert_eq!(find_point(&tree, &query, weighted(true)), Some(close));
+    }
 }
@@ -1,11 +1,26 @@
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{BinaryHeap, HashMap, HashSet},
+    sync::Arc,
+};
+
 use clap::ValueEnum;
-use petgraph::{graph::NodeIndex, stable_graph::StableDiGraph};
+use petgraph::{
+    graph::{EdgeIndex, NodeIndex},
+    stable_graph::StableDiGraph,
+    visit::{EdgeRef, VisitMap},
+    Direction::Incoming,
+};
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    custom_bfs::CustomBfs,
-    math::geo_distance,
+    custom_bfs::{CustomBfs, EdgeDistanceFn},
+    math::MIN_MODEL_RATIO,
+    mongo::model::SensorMetadata,
+    parse::{Point, RoadDirection},
     processing::{EdgeData, NodeData},
+    tsp,
 };
 
 pub struct Path {
@@ -15,9 +30,88 @@ pub struct Path {
     pub missed: Vec<NodeIndex>,
 }
 
-struct SubPath {
-    nodes: Vec<NodeIndex>,
-    length: f64,
+impl Path {
+    /// The geometry of the path, following the polylines of the edges it travels along
+    pub fn to_polyline(&self, graph: &StableDiGraph<NodeData, EdgeData>) -> Vec<Point> {
+        let mut points = Vec::new();
+        if let Some(start) = self.nodes.first() {
+            points.push(graph.node_weight(*start).unwrap().point);
+        }
+
+        for pair in self.nodes.windows(2) {
+            let edge = graph.edges_connecting(pair[0], pair[1]).next().unwrap();
+            let data = edge.weight();
+            if data.polyline.is_empty() {
+                // Connectors have no geometry of their own
+                points.push(graph.node_weight(pair[1]).unwrap().point);
+            } else {
+                points.extend(data.polyline.iter().skip(1).cloned());
+            }
+        }
+
+        points
+    }
+
+    /// The data of every edge the path travels along, in order
+    pub fn edges<'a>(
+        &'a self,
+        graph: &'a StableDiGraph<NodeData, EdgeData>,
+    ) -> impl Iterator<Item = &'a EdgeData> + 'a {
+        self.nodes.windows(2).map(|pair| {
+            graph
+                .edges_connecting(pair[0], pair[1])
+                .next()
+                .unwrap()
+                .weight()
+        })
+    }
+}
+
+/// Speed limit in km/h assumed for edges before the first one with a known speed limit
+pub const DEFAULT_SPEED_LIMIT: f64 = 50.0;
+
+/// The length weighted average speed limit of **edges** in km/h, edges without a speed limit
+/// take the speed limit of the edge before them
+pub fn average_speed_limit<'a, I: IntoIterator<Item = &'a EdgeData>>(edges: I) -> f64 {
+    let mut previous_speed_limit = DEFAULT_SPEED_LIMIT;
+    let (weighted, distance) = edges
+        .into_iter()
+        .fold((0.0, 0.0), |(weighted, distance), data| {
+            let speed_limit = data.speed_limit.unwrap_or(previous_speed_limit);
+            previous_speed_limit = speed_limit;
+            (
+                weighted + speed_limit * data.distance,
+                distance + data.distance,
+            )
+        });
+
+    if distance > 0.0 {
+        weighted / distance
+    } else {
+        0.0
+    }
+}
+
+/// A path between two nodes, including both of them
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubPath {
+    pub nodes: Vec<NodeIndex>,
+    pub length: f64,
+}
+
+// Sub paths are ordered by their length only, so candidates can be kept in a heap
+impl Eq for SubPath {}
+
+impl PartialOrd for SubPath {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for SubPath {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.length.total_cmp(&other.length)
+    }
 }
 
 #[derive(Debug, ValueEnum, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
@@ -35,6 +129,16 @@ impl DistanceMetric {
     }
 }
 
+/// The algorithm used to find the shortest path between two nodes
+#[derive(Debug, ValueEnum, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SearchStrategy {
+    Dijkstra,
+    /// Dijkstra guided by the straight line distance to the target
+    AStar,
+    /// Dijkstra from both the start and the end, meeting in the middle
+    Bidirectional,
+}
+
 impl DistanceMetric {
     pub fn to_function(self) -> fn(&NodeData, &NodeData, &EdgeData) -> f64 {
         match self {
@@ -44,28 +148,85 @@ impl DistanceMetric {
     }
 }
 
+/// The cost of traveling an edge in **metric**, with connectors costing **connector_penalty** times
+/// as much so routes avoid hopping between roads over the connectors made when processing the graph
+pub fn route_cost(
+    metric: DistanceMetric,
+    connector_penalty: f64,
+) -> EdgeDistanceFn<NodeData, EdgeData> {
+    let distance_fn = metric.to_function();
+    Arc::new(move |_, from, to, edge| {
+        let distance = distance_fn(from, to, edge);
+        if edge.is_connector {
+            distance * connector_penalty
+        } else {
+            distance
+        }
+    })
+}
+
+/// A search from **start** measuring edges by **cost**
+fn route_search(
+    graph: &StableDiGraph<NodeData, EdgeData>,
+    start: NodeIndex,
+    cost: &EdgeDistanceFn<NodeData, EdgeData>,
+) -> CustomBfs<NodeData, EdgeData> {
+    CustomBfs::new(graph, start, distance_space).with_edge_distance(cost.clone())
+}
+
 pub fn shortest_path(
     graph: &StableDiGraph<NodeData, EdgeData>,
     points: Vec<NodeIndex>,
     metric: DistanceMetric,
+    connector_penalty: f64,
+    strategy: SearchStrategy,
+) -> Option<Path> {
+    let cost = route_cost(metric, connector_penalty);
+    let heuristic_scale = match strategy {
+        SearchStrategy::Dijkstra | SearchStrategy::Bidirectional => None,
+        SearchStrategy::AStar => heuristic_scale(graph, metric),
+    };
+
+    join_sub_paths(&points, |start, end, arrived_from| match strategy {
+        SearchStrategy::Bidirectional => {
+            bidirectional_shortest_path(graph, start, end, &cost, arrived_from)
+        }
+        _ => shortest_path_singular(graph, start, end, &cost, heuristic_scale, arrived_from),
+    })
+}
+
+/// Like [`shortest_path`] with Dijkstra, but measuring every edge with **distance_fn**
+pub fn shortest_path_with(
+    graph: &StableDiGraph<NodeData, EdgeData>,
+    points: Vec<NodeIndex>,
+    distance_fn: EdgeDistanceFn<NodeData, EdgeData>,
+) -> Option<Path> {
+    join_sub_paths(&points, |start, end, arrived_from| {
+        let mut search =
+            CustomBfs::new(graph, start, distance_time).with_edge_distance(distance_fn.clone());
+        search.arrived_from = arrived_from;
+        let (length, nodes) = search.path_to(graph, end)?;
+        Some(SubPath { nodes, length })
+    })
+}
+
+/// Join the sub paths between each point and the next into one path, with **find** given the start
+/// and end of each sub path and the node the start was arrived at from. Points without a sub path
+/// to them are skipped and recorded as missed.
+fn join_sub_paths(
+    points: &[NodeIndex],
+    mut find: impl FnMut(NodeIndex, NodeIndex, Option<NodeIndex>) -> Option<SubPath>,
 ) -> Option<Path> {
     let mut path = Vec::new();
     let mut length = 0.0;
 
-    let distance_fn = match metric {
-        DistanceMetric::Space => distance_space,
-        DistanceMetric::Time => distance_time,
-    };
-
     let mut iter = points.iter();
     let mut start = iter.next()?;
 
     let mut complete = true;
     let mut missed = Vec::new();
     for end in iter {
-        let p = shortest_path_singular(graph, *start, *end, distance_fn);
-
-        let p = if let Some(p) = p {
+        let p = if let Some(p) = find(*start, *end, path.last().copied()) {
             p
         } else {
             missed.push(*end);
@@ -73,7 +234,8 @@ pub fn shortest_path(
             continue;
         };
 
-        path.extend(p.nodes.iter());
+        // The end is added as the start of the next sub path
+        path.extend(p.nodes.iter().take(p.nodes.len() - 1));
         length += p.length;
         start = end;
     }
@@ -92,34 +254,487 @@ fn shortest_path_singular(
     graph: &StableDiGraph<NodeData, EdgeData>,
     start: NodeIndex,
     end: NodeIndex,
-    distance_fn: fn(&NodeData, &NodeData, &EdgeData) -> f64,
+    cost: &EdgeDistanceFn<NodeData, EdgeData>,
+    heuristic_scale: Option<f64>,
+    arrived_from: Option<NodeIndex>,
 ) -> Option<SubPath> {
-    let mut search = CustomBfs::new(graph, start, distance_fn);
-    while let Some((idx, dist, path)) = search.next(&graph) {
-        if idx == end {
-            return Some(SubPath {
-                nodes: path,
-                length: dist,
-            });
+    let mut search = route_search(graph, start, cost);
+    search.arrived_from = arrived_from;
+    if let Some(scale) = heuristic_scale {
+        let target = graph.node_weight(end).unwrap().point;
+        search = search.with_heuristic(target, scale);
+    }
+    let (length, nodes) = search.path_to(graph, end)?;
+    Some(SubPath { nodes, length })
+}
+
+/// Find the shortest path from **start** to **end** by searching forward from the start and
+/// backward from the end at the same time, stopping once the two searches can no longer find a
+/// shorter connection than the best one found so far. Like the single search it does not turn back
+/// along the same road, at the start arrived at from **arrived_from** or where the halves meet.
+pub fn bidirectional_shortest_path(
+    graph: &StableDiGraph<NodeData, EdgeData>,
+    start: NodeIndex,
+    end: NodeIndex,
+    cost: &EdgeDistanceFn<NodeData, EdgeData>,
+    arrived_from: Option<NodeIndex>,
+) -> Option<SubPath> {
+    let mut forward = route_search(graph, start, cost);
+    forward.arrived_from = arrived_from;
+    let mut backward = route_search(graph, end, cost);
+
+    // Whether going on from **via** to the next node of the backward path turns back towards
+    // **previous**, the node before **via**
+    let turns_back = |forward: &CustomBfs<NodeData, EdgeData>,
+                      backward: &CustomBfs<NodeData, EdgeData>,
+                      previous: Option<&NodeIndex>,
+                      via: NodeIndex| {
+        let Some(&next) = backward.paths.get(&via).and_then(|path| path.last()) else {
+            return false;
+        };
+        graph
+            .edges_connecting(via, next)
+            .all(|edge| forward.is_uturn(graph, previous, next, (next, via), edge.weight()))
+    };
+
+    // The length of the best path, and the edge where the forward and backward halves meet
+    let mut best: Option<(f64, NodeIndex, NodeIndex)> = None;
+    fn offer(
+        best: &mut Option<(f64, NodeIndex, NodeIndex)>,
+        length: f64,
+        from: NodeIndex,
+        to: NodeIndex,
+    ) {
+        if best.is_none_or(|(best_length, _, _)| length < best_length) {
+            *best = Some((length, from, to));
+        }
+    }
+
+    let mut first = true;
+    loop {
+        let forward_top = forward.peek_distance().unwrap_or(f64::INFINITY);
+        let backward_top = backward.peek_distance().unwrap_or(f64::INFINITY);
+        let best_length = best.map_or(f64::INFINITY, |(length, _, _)| length);
+        // Both searches must have visited their start before they can meet
+        if !first && forward_top + backward_top >= best_length {
+            break;
+        }
+
+        if first || forward_top <= backward_top {
+            if let Some((idx, distance, path)) = forward.next(graph) {
+                if let Some(remaining) = backward.distances.get(&idx) {
+                    if !turns_back(&forward, &backward, path.last(), idx) {
+                        offer(&mut best, distance + remaining, idx, idx);
+                    }
+                }
+                for edge in graph.edges(idx) {
+                    let target = edge.target();
+                    if let Some(remaining) = backward.distances.get(&target) {
+                        let arrival = (target, idx);
+                        if forward.is_uturn(graph, path.last(), target, arrival, edge.weight())
+                            || turns_back(&forward, &backward, Some(&idx), target)
+                        {
+                            continue;
+                        }
+                        let from = graph.node_weight(idx).unwrap();
+                        let to = graph.node_weight(target).unwrap();
+                        let length = cost(edge.id(), from, to, edge.weight());
+                        if length.is_finite() {
+                            offer(&mut best, distance + length + remaining, idx, target);
+                        }
+                    }
+                }
+            }
+        }
+
+        if first || backward_top < forward_top {
+            if let Some((idx, distance, _)) = backward.next_reverse(graph) {
+                if let Some(traveled) = forward.distances.get(&idx) {
+                    let previous = forward.paths.get(&idx).and_then(|path| path.last());
+                    if !turns_back(&forward, &backward, previous, idx) {
+                        offer(&mut best, traveled + distance, idx, idx);
+                    }
+                }
+                for edge in graph.edges_directed(idx, Incoming) {
+                    let source = edge.source();
+                    if let Some(traveled) = forward.distances.get(&source) {
+                        let previous = forward.paths.get(&source).and_then(|path| path.last());
+                        if forward.is_uturn(graph, previous, idx, (idx, source), edge.weight())
+                            || turns_back(&forward, &backward, Some(&source), idx)
+                        {
+                            continue;
+                        }
+                        let from = graph.node_weight(source).unwrap();
+                        let to = graph.node_weight(idx).unwrap();
+                        let length = cost(edge.id(), from, to, edge.weight());
+                        if length.is_finite() {
+                            offer(&mut best, traveled + length + distance, source, idx);
+                        }
+                    }
+                }
+            }
+        }
+
+        first = false;
+    }
+
+    let (length, from, to) = best?;
+    let mut nodes = forward.paths.get(&from).unwrap().clone();
+    nodes.push(from);
+    if to != from {
+        nodes.push(to);
+    }
+    // The backward path runs from the end towards the meeting node
+    nodes.extend(backward.paths.get(&to).unwrap().iter().rev());
+
+    Some(SubPath { nodes, length })
+}
+
+/// The distance from **source** to every node within **max_range** of it, following edges in
+/// either direction unless **directed**. A NaN range does not limit the search.
+pub fn distance_field(
+    graph: &StableDiGraph<NodeData, EdgeData>,
+    source: NodeIndex,
+    metric: DistanceMetric,
+    directed: bool,
+    max_range: f64,
+) -> HashMap<NodeIndex, f64> {
+    let mut search = CustomBfs::new(graph, source, metric.to_function());
+    let next_func = if directed {
+        CustomBfs::next
+    } else {
+        CustomBfs::next_undirected
+    };
+    while let Some((node, distance, _)) = next_func(&mut search, graph) {
+        if distance > max_range {
+            search.distances.remove(&node);
+            break;
+        }
+    }
+    search.distances
+}
+
+/// The distance from every sensor node to every other sensor node it can reach, keyed by the
+/// start and end node.
+pub fn sensor_distance_matrix(
+    graph: &StableDiGraph<NodeData, EdgeData>,
+    sensor_store: &HashMap<NodeIndex, Vec<SensorMetadata>>,
+    metric: DistanceMetric,
+) -> HashMap<(NodeIndex, NodeIndex), f64> {
+    let sensor_nodes = sensor_store.keys().copied().collect::<Vec<_>>();
+    distances_between(graph, &sensor_nodes, metric)
+}
+
+/// The distance from every one of **nodes** to every other one it can reach, keyed by the start
+/// and end node. Runs one search per node, which stops once all the other nodes are settled.
+pub fn distances_between(
+    graph: &StableDiGraph<NodeData, EdgeData>,
+    nodes: &[NodeIndex],
+    metric: DistanceMetric,
+) -> HashMap<(NodeIndex, NodeIndex), f64> {
+    let distance_fn = metric.to_function();
+
+    nodes
+        .par_iter()
+        .flat_map_iter(|start| {
+            let mut remaining = nodes.iter().copied().collect::<HashSet<_>>();
+            let mut search = CustomBfs::new(graph, *start, distance_fn);
+            let mut distances = Vec::new();
+            while let Some((node, distance, _)) = search.next(graph) {
+                if remaining.remove(&node) {
+                    distances.push(((*start, node), distance));
+                    if remaining.is_empty() {
+                        break;
+                    }
+                }
+            }
+            distances
+        })
+        .collect()
+}
+
+/// Reorder **points** into the visiting order with the shortest total distance, keeping the first
+/// point as the start and ending at whichever point is best.
+pub fn optimize_visit_order(
+    graph: &StableDiGraph<NodeData, EdgeData>,
+    points: &[NodeIndex],
+    metric: DistanceMetric,
+) -> Vec<NodeIndex> {
+    let distances = distances_between(graph, points, metric);
+    let costs = points
+        .iter()
+        .map(|from| {
+            points
+                .iter()
+                .map(|to| {
+                    distances
+                        .get(&(*from, *to))
+                        .copied()
+                        .unwrap_or(f64::INFINITY)
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    tsp::open_path_order(&costs)
+        .into_iter()
+        .map(|idx| points[idx])
+        .collect()
+}
+
+/// Find up to **k** loopless paths from **start** to **end** with distinct node sequences, shortest
+/// first, using Yen's algorithm.
+pub fn k_shortest_paths(
+    graph: &StableDiGraph<NodeData, EdgeData>,
+    start: NodeIndex,
+    end: NodeIndex,
+    metric: DistanceMetric,
+    connector_penalty: f64,
+    k: usize,
+) -> Vec<SubPath> {
+    let cost = route_cost(metric, connector_penalty);
+
+    let mut found = Vec::new();
+    match shortest_path_singular(graph, start, end, &cost, None, None) {
+        Some(path) => found.push(path),
+        None => return found,
+    }
+    let mut candidates = BinaryHeap::new();
+
+    while found.len() < k {
+        let previous = found.last().unwrap().nodes.clone();
+
+        for i in 0..previous.len() - 1 {
+            let spur = previous[i];
+            let root = &previous[..=i];
+
+            let mut search = route_search(graph, spur, &cost);
+            // Do not branch off the same way as an already found path with the same root
+            for path in found
+                .iter()
+                .filter(|p: &&SubPath| p.nodes.starts_with(root))
+            {
+                if let Some(next) = path.nodes.get(i + 1) {
+                    let edges = graph.edges_connecting(spur, *next).map(|edge| edge.id());
+                    search.excluded_edges.extend(edges);
+                }
+            }
+            // Keep the path loopless by never revisiting the root
+            for node in &root[..i] {
+                search.discovered.visit(*node);
+            }
+
+            while let Some((idx, dist, path)) = search.next(graph) {
+                if idx != end {
+                    continue;
+                }
+
+                let mut nodes = root[..i].to_vec();
+                nodes.extend(path);
+                nodes.push(end);
+                let candidate = SubPath {
+                    length: path_length(graph, &nodes[..=i], &cost) + dist,
+                    nodes,
+                };
+                let known = found.iter().any(|p| p.nodes == candidate.nodes)
+                    || candidates
+                        .iter()
+                        .any(|Reverse(p): &Reverse<SubPath>| p.nodes == candidate.nodes);
+                if !known {
+                    candidates.push(Reverse(candidate));
+                }
+                break;
+            }
+        }
+
+        match candidates.pop() {
+            Some(Reverse(path)) => found.push(path),
+            None => break,
         }
     }
 
-    None
+    found
+}
+
+/// The length of a sequence of nodes, taking the shortest edge between each pair
+fn path_length(
+    graph: &StableDiGraph<NodeData, EdgeData>,
+    nodes: &[NodeIndex],
+    cost: &EdgeDistanceFn<NodeData, EdgeData>,
+) -> f64 {
+    nodes
+        .windows(2)
+        .map(|pair| {
+            let from = graph.node_weight(pair[0]).unwrap();
+            let to = graph.node_weight(pair[1]).unwrap();
+            graph
+                .edges_connecting(pair[0], pair[1])
+                .map(|edge| cost(edge.id(), from, to, edge.weight()))
+                .fold(f64::INFINITY, f64::min)
+        })
+        .sum()
+}
+
+/// The factor converting straight line distance in meters into a lower bound of the metric,
+/// or **None** if no such bound exists. The straight line is the great circle distance, while the
+/// edges may have been measured on another [`crate::math::DistanceModel`], so the factor leaves
+/// room for the models to differ.
+fn heuristic_scale(
+    graph: &StableDiGraph<NodeData, EdgeData>,
+    metric: DistanceMetric,
+) -> Option<f64> {
+    let scale = match metric {
+        DistanceMetric::Space => Some(1.0),
+        DistanceMetric::Time => {
+            // Nothing can be traveled faster than the highest speed limit in the graph
+            let max_speed = graph
+                .edge_weights()
+                .filter_map(|edge| edge.speed_limit)
+                .fold(0.0, f64::max);
+            if max_speed > 0.0 {
+                Some(1.0 / convert_kmh_to_ms(max_speed))
+            } else {
+                None
+            }
+        }
+    };
+    scale.map(|scale| scale * MIN_MODEL_RATIO)
 }
 
-fn distance_space(from: &NodeData, to: &NodeData, _edge: &EdgeData) -> f64 {
-    let from = [from.point.latitude, from.point.longitude];
-    let to = [to.point.latitude, to.point.longitude];
-    geo_distance(&from, &to)
+// The length of the road the edge follows, not the straight line between its endpoints, which
+// undercounts every curved edge and every edge collapsed from a chain of nodes. Edges of roads
+// closed in both directions are only kept for drawing, and are never traversed
+fn distance_space(_from: &NodeData, _to: &NodeData, edge: &EdgeData) -> f64 {
+    if edge.direction == RoadDirection::None {
+        return f64::INFINITY;
+    }
+    edge.distance
 }
 
+// Edges without a positive speed limit are impassable, instead of producing inf or NaN times that
+// break the ordering of the search
 fn distance_time(_from: &NodeData, _to: &NodeData, edge: &EdgeData) -> f64 {
-    let speed_kmh = edge.speed_limit.unwrap_or(0.0);
+    if edge.direction == RoadDirection::None {
+        return f64::INFINITY;
+    }
+    let speed_kmh = match edge.speed_limit {
+        Some(speed_kmh) if speed_kmh > 0.0 => speed_kmh,
+        _ => return f64::INFINITY,
+    };
     let speed = speed_kmh * 1000.0 / 3600.0;
     let distance = edge.distance;
     distance / speed
 }
 
+/// Average speed in km/h for each hour of the day in UTC, with **None** for hours without data
+pub type HourlySpeeds = [Option<f64>; 24];
+
+/// The time to travel an edge at the historical speed of the nearest upstream sensor at **hour**,
+/// given as its **profile**, falling back to the speed limit if there is no speed for that hour
+pub fn distance_time_dynamic(
+    from: &NodeData,
+    to: &NodeData,
+    edge: &EdgeData,
+    profile: Option<&HourlySpeeds>,
+    hour: usize,
+) -> f64 {
+    match profile.and_then(|profile| profile[hour % 24]) {
+        Some(speed_kmh) if speed_kmh > 0.0 && edge.direction != RoadDirection::None => {
+            edge.distance / convert_kmh_to_ms(speed_kmh)
+        }
+        _ => distance_time(from, to, edge),
+    }
+}
+
+/// Measure edges by the time to travel them at the live speed in km/h in **speeds**, falling back
+/// to the speed limit for edges without one, with connectors costing **connector_penalty** times
+/// as much
+pub fn live_distance_fn(
+    speeds: HashMap<EdgeIndex, f64>,
+    connector_penalty: f64,
+) -> EdgeDistanceFn<NodeData, EdgeData> {
+    Arc::new(move |edge, from, to, data| {
+        let time = match speeds.get(&edge) {
+            Some(speed_kmh) if *speed_kmh > 0.0 && data.direction != RoadDirection::None => {
+                data.distance / convert_kmh_to_ms(*speed_kmh)
+            }
+            _ => distance_time(from, to, data),
+        };
+        if data.is_connector {
+            time * connector_penalty
+        } else {
+            time
+        }
+    })
+}
+
+/// A contiguous run of a route along the same numbered road
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Leg {
+    pub main_number: i32,
+    pub sub_number: i32,
+    pub start_distance: f64,
+    pub end_distance: f64,
+    pub length: f64,
+    /// The speed limit covering the largest part of the leg
+    pub speed_limit: Option<f64>,
+}
+
+/// Group the edges of a path into legs of contiguous `main_number`/`sub_number` runs.
+/// Connector edges are attributed to the leg they are part of.
+pub fn route_legs(graph: &StableDiGraph<NodeData, EdgeData>, path: &Path) -> Vec<Leg> {
+    // The length of the leg driven at each speed limit
+    type SpeedLimitShares = Vec<(Option<f64>, f64)>;
+    let mut legs: Vec<(Leg, SpeedLimitShares)> = Vec::new();
+    let mut distance = 0.0;
+
+    for pair in path.nodes.windows(2) {
+        let edge = graph.edges_connecting(pair[0], pair[1]).next().unwrap();
+        let data = edge.weight();
+
+        let same_road = legs.last().is_some_and(|(leg, _)| {
+            data.is_connector
+                || (leg.main_number == data.main_number && leg.sub_number == data.sub_number)
+        });
+        if !same_road {
+            legs.push((
+                Leg {
+                    main_number: data.main_number,
+                    sub_number: data.sub_number,
+                    start_distance: distance,
+                    end_distance: distance,
+                    length: 0.0,
+                    speed_limit: None,
+                },
+                Vec::new(),
+            ));
+        }
+
+        distance += data.distance;
+        let (leg, speed_limits) = legs.last_mut().unwrap();
+        leg.end_distance = distance;
+        leg.length += data.distance;
+        match speed_limits
+            .iter_mut()
+            .find(|(limit, _)| *limit == data.speed_limit)
+        {
+            Some((_, length)) => *length += data.distance,
+            None => speed_limits.push((data.speed_limit, data.distance)),
+        }
+    }
+
+    legs.into_iter()
+        .map(|(mut leg, speed_limits)| {
+            leg.speed_limit = speed_limits
+                .into_iter()
+                .filter(|(limit, _)| limit.is_some())
+                .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+                .and_then(|(limit, _)| limit);
+            leg
+        })
+        .collect()
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TravelTime {
     pub time: f64,
@@ -133,3 +748,344 @@ pub fn convert_ms_to_kmh(speed: f64) -> f64 {
 pub fn convert_kmh_to_ms(speed: f64) -> f64 {
     speed * 1000.0 / 3600.0
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        math::{dist, dist_with, DistanceModel},
+        test_graphs::{add_road, add_two_way, diamond, edge, grid, node, point, sensor, TestGraph},
+    };
+
+    #[test]
+    fn average_speed_limit_is_weighted_by_edge_length() {
+        let mut graph = TestGraph::default();
+        let [a, b, c] = [0.0, 0.001, 0.004].map(|longitude| graph.add_node(node(0.0, longitude)));
+        let first = add_road(&mut graph, a, b, 1, 30.0);
+        let second = add_road(&mut graph, b, c, 1, 90.0);
+        graph[first].distance = 100.0;
+        graph[second].distance = 300.0;
+        let path = Path {
+            nodes: vec![a, b, c],
+            length: 0.0,
+            complete: true,
+            missed: vec![],
+        };
+        let mut average = |speed_limits: [Option<f64>; 2]| {
+            graph[first].speed_limit = speed_limits[0];
+            graph[second].speed_limit = speed_limits[1];
+            average_speed_limit(path.edges(&graph))
+        };
+
+        assert_eq!(average([Some(30.0), Some(90.0)]), 75.0);
+        // Edges without a speed limit take the one before them, or the default on the first edge
+        assert_eq!(average([Some(30.0), None]), 30.0);
+        assert_eq!(
+            average([None, Some(90.0)]),
+            (DEFAULT_SPEED_LIMIT * 100.0 + 90.0 * 300.0) / 400.0
+        );
+    }
+
+    #[test]
+    fn k_shortest_paths_are_found_shortest_first() {
+        let (graph, [a, b, c, d]) = diamond();
+
+        let paths = k_shortest_paths(&graph, a, d, DistanceMetric::Space, 1.0, 3);
+
+        let nodes = paths
+            .iter()
+            .map(|path| path.nodes.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(nodes, vec![vec![a, b, d], vec![a, c, d]]);
+        assert!(paths[0].length < paths[1].length);
+    }
+
+    #[test]
+    fn zero_speed_edges_are_not_traveled() {
+        let (mut graph, [a, b, c, d]) = diamond();
+        let edge = graph.find_edge(b, d).unwrap();
+        graph[edge].speed_limit = Some(0.0);
+
+        let path = shortest_path(
+            &graph,
+            vec![a, d],
+            DistanceMetric::Time,
+            1.0,
+            SearchStrategy::Dijkstra,
+        )
+        .unwrap();
+
+        assert_eq!(path.nodes, vec![a, c, d]);
+        assert!(path.length.is_finite());
+    }
+
+    #[test]
+    fn connector_penalty_forces_the_longer_path() {
+        let (mut graph, [a, b, c, d]) = diamond();
+        for (from, to) in [(a, b), (b, d)] {
+            let edge = graph.find_edge(from, to).unwrap();
+            graph[edge].is_connector = true;
+        }
+        let route = |penalty| {
+            shortest_path(
+                &graph,
+                vec![a, d],
+                DistanceMetric::Space,
+                penalty,
+                SearchStrategy::Dijkstra,
+            )
+            .unwrap()
+            .nodes
+        };
+
+        assert_eq!(route(1.0), vec![a, b, d]);
+        assert_eq!(route(3.0), vec![a, c, d]);
+    }
+
+    #[test]
+    fn a_star_finds_the_same_paths_as_dijkstra() {
+        let (mut graph, nodes) = grid(4, 5);
+        // Lengthen every edge by a different amount so no two paths are equally short
+        for edge in graph.edge_indices().collect::<Vec<_>>() {
+            graph[edge].distance += (edge.index() as f64 + 2.0).sqrt();
+        }
+        let nodes = nodes.concat();
+
+        for metric in [DistanceMetric::Space, DistanceMetric::Time] {
+            for start in nodes.iter() {
+                for end in nodes.iter().filter(|end| *end != start) {
+                    let route = |strategy| {
+                        shortest_path(&graph, vec![*start, *end], metric, 1.0, strategy).unwrap()
+                    };
+                    let dijkstra = route(SearchStrategy::Dijkstra);
+                    let a_star = route(SearchStrategy::AStar);
+                    assert_eq!(a_star.nodes, dijkstra.nodes);
+                    assert!((a_star.length - dijkstra.length).abs() < 1e-9);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn bidirectional_search_matches_dijkstra_on_a_grid() {
+        let (graph, grid_nodes) = grid(4, 5);
+        let nodes = grid_nodes.concat();
+        let length = |points: Vec<NodeIndex>, strategy| {
+            shortest_path(&graph, points, DistanceMetric::Space, 1.0, strategy)
+                .unwrap()
+                .length
+        };
+
+        for start in nodes.iter() {
+            for end in nodes.iter().filter(|end| *end != start) {
+                let points = vec![*start, *end];
+                let dijkstra = length(points.clone(), SearchStrategy::Dijkstra);
+                let bidirectional = length(points, SearchStrategy::Bidirectional);
+                assert!((dijkstra - bidirectional).abs() < 1e-6);
+            }
+        }
+
+        // Going on from a point must not turn back where the previous sub path arrived from
+        let points = vec![grid_nodes[0][0], grid_nodes[0][3], grid_nodes[0][1]];
+        let dijkstra = length(points.clone(), SearchStrategy::Dijkstra);
+        let bidirectional = length(points, SearchStrategy::Bidirectional);
+        assert!((dijkstra - bidirectional).abs() < 1e-6);
+    }
+
+    #[test]
+    fn route_from_one_road_onto_another_is_two_legs() {
+        let mut graph = TestGraph::default();
+        let nodes = (0..4)
+            .map(|idx| graph.add_node(node(idx as f64 * 0.001, 0.0)))
+            .collect::<Vec<_>>();
+        let edges = [
+            add_road(&mut graph, nodes[0], nodes[1], 1, 50.0),
+            add_road(&mut graph, nodes[1], nodes[2], 1, 50.0),
+            add_road(&mut graph, nodes[2], nodes[3], 2, 70.0),
+        ]
+        .map(|edge| graph[edge].distance);
+        let path = Path {
+            nodes,
+            length: 0.0,
+            complete: true,
+            missed: vec![],
+        };
+
+        let legs = route_legs(&graph, &path);
+
+        assert_eq!(legs.len(), 2);
+        assert_eq!((legs[0].main_number, legs[1].main_number), (1, 2));
+        assert!((legs[0].length - (edges[0] + edges[1])).abs() < 1e-9);
+        assert!((legs[1].length - edges[2]).abs() < 1e-9);
+        assert_eq!(legs[1].start_distance, legs[0].end_distance);
+        assert_eq!(
+            (legs[0].speed_limit, legs[1].speed_limit),
+            (Some(50.0), Some(70.0))
+        );
+    }
+
+    #[test]
+    fn sensor_distances_follow_the_road_directions() {
+        // A two-way road from the first sensor to the second, then a one-way road to the third
+        let mut graph = TestGraph::default();
+        let nodes = (0..3)
+            .map(|idx| graph.add_node(node(0.0, idx as f64 * 0.001)))
+            .collect::<Vec<_>>();
+        add_two_way(&mut graph, nodes[0], nodes[1], 1, 50.0);
+        let last = add_road(&mut graph, nodes[1], nodes[2], 2, 50.0);
+        let sensor_store = nodes
+            .iter()
+            .enumerate()
+            .map(|(idx, node)| (*node, vec![sensor(idx as i32, 0.0, idx as f64 * 0.001)]))
+            .collect::<HashMap<_, _>>();
+        let first = graph.find_edge(nodes[0], nodes[1]).unwrap();
+        let (first, last) = (graph[first].distance, graph[last].distance);
+
+        let matrix = sensor_distance_matrix(&graph, &sensor_store, DistanceMetric::Space);
+        let distance = |from: usize, to: usize| matrix.get(&(nodes[from], nodes[to])).copied();
+
+        assert_eq!(distance(0, 1), distance(1, 0));
+        assert!((distance(0, 1).unwrap() - first).abs() < 1e-9);
+        assert!((distance(1, 2).unwrap() - last).abs() < 1e-9);
+        assert!((distance(0, 2).unwrap() - (first + last)).abs() < 1e-9);
+        assert_eq!(distance(2, 1), None);
+        assert_eq!(distance(2, 0), None);
+    }
+
+    #[test]
+    fn optimized_visit_order_shortens_a_zigzag() {
+        let (graph, nodes) = grid(2, 6);
+        let row = &nodes[0];
+        let zigzag = vec![row[0], row[5], row[1], row[4]];
+        let length = |points: Vec<NodeIndex>| {
+            shortest_path(
+                &graph,
+                points,
+                DistanceMetric::Space,
+                1.0,
+                SearchStrategy::Dijkstra,
+            )
+            .unwrap()
+            .length
+        };
+
+        let optimized = optimize_visit_order(&graph, &zigzag, DistanceMetric::Space);
+
+        assert_eq!(optimized, vec![row[0], row[1], row[4], row[5]]);
+        assert!(length(optimized) < length(zigzag));
+    }
+
+    #[test]
+    fn distance_field_matches_the_search_distances() {
+        let (graph, nodes) = grid(3, 4);
+        let source = nodes[1][1];
+        let searched = |directed: bool| {
+            let mut search = CustomBfs::new(&graph, source, distance_space);
+            if directed {
+                while search.next(&graph).is_some() {}
+            } else {
+                while search.next_undirected(&graph).is_some() {}
+            }
+            search.distances
+        };
+
+        // Between two and three blocks, so only the nodes at most two edges from the source
+        let block = graph[graph.find_edge(nodes[0][0], nodes[0][1]).unwrap()].distance;
+        let range = 2.5 * block;
+
+        for directed in [true, false] {
+            let all = searched(directed);
+            assert_eq!(all.len(), graph.node_count());
+            assert_eq!(
+                distance_field(&graph, source, DistanceMetric::Space, directed, f64::NAN),
+                all
+            );
+
+            let limited = distance_field(&graph, source, DistanceMetric::Space, directed, range);
+            let expected = all
+                .into_iter()
+                .filter(|(_, distance)| *distance <= range)
+                .collect::<HashMap<_, _>>();
+            assert_eq!(limited, expected);
+            assert!(limited.len() < graph.node_count());
+        }
+    }
+
+    #[test]
+    fn directed_distance_field_only_follows_one_way_roads() {
+        let (graph, [a, b, c, d]) = diamond();
+
+        let directed = distance_field(&graph, b, DistanceMetric::Space, true, f64::NAN);
+        let undirected = distance_field(&graph, b, DistanceMetric::Space, false, f64::NAN);
+
+        let mut reached = directed.keys().copied().collect::<Vec<_>>();
+        reached.sort();
+        assert_eq!(reached, vec![b, d]);
+        assert!(undirected.contains_key(&a) && undirected.contains_key(&c));
+    }
+
+    #[test]
+    fn space_metric_follows_the_road_not_the_straight_line() {
+        let mut graph = TestGraph::default();
+        let a = graph.add_node(node(0.0, 0.0));
+        let b = graph.add_node(node(0.0, 0.002));
+        let c = graph.add_node(node(0.0005, 0.001));
+        // A direct road bending far north, longer than the detour through c
+        let bend = point(0.002, 0.001);
+        let direct = graph.add_edge(
+            a,
+            b,
+            EdgeData {
+                distance: dist(graph[a].point, bend) + dist(bend, graph[b].point),
+                polyline: vec![graph[a].point, bend, graph[b].point],
+                ..edge(graph[a], graph[b], 1, 50.0)
+            },
+        );
+        let detour = [
+            add_road(&mut graph, a, c, 2, 50.0),
+            add_road(&mut graph, c, b, 2, 50.0),
+        ]
+        .map(|edge| graph[edge].distance);
+        assert!(dist(graph[a].point, graph[b].point) < detour.iter().sum::<f64>());
+        assert!(detour.iter().sum::<f64>() < graph[direct].distance);
+
+        let path = shortest_path(
+            &graph,
+            vec![a, b],
+            DistanceMetric::Space,
+            1.0,
+            SearchStrategy::Dijkstra,
+        )
+        .unwrap();
+
+        assert_eq!(path.nodes, vec![a, c, b]);
+        assert!((path.length - detour.iter().sum::<f64>()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn a_star_matches_dijkstra_on_edges_measured_by_vincenty() {
+        let (mut graph, nodes) = grid(5, 5);
+        // Far north, where the ellipsoid and the sphere differ the most along the meridians
+        for node in graph.node_weights_mut() {
+            node.point.latitude += 70.0;
+        }
+        for edge in graph.edge_indices().collect::<Vec<_>>() {
+            let (from, to) = graph.edge_endpoints(edge).unwrap();
+            let (from, to) = (graph[from].point, graph[to].point);
+            graph[edge].distance = dist_with(from, to, DistanceModel::Vincenty);
+        }
+        let route = |from, to, strategy| {
+            shortest_path(&graph, vec![from, to], DistanceMetric::Space, 1.0, strategy)
+                .unwrap()
+                .length
+        };
+
+        for from in nodes.concat() {
+            for to in nodes.concat() {
+                let dijkstra = route(from, to, SearchStrategy::Dijkstra);
+                let a_star = route(from, to, SearchStrategy::AStar);
+                assert!((dijkstra - a_star).abs() < 1e-6);
+            }
+        }
+    }
+}
@@ -0,0 +1,29 @@
+//! Runs the built binary to check how commands fail on bad input
+
+use std::process::Command;
+
+#[test]
+fn shortest_path_with_missing_graph_fails_without_panicking() {
+    let input =
+        std::env::temp_dir().join(format!("processing-{}-missing.json", std::process::id()));
+
+    let output = Command::new(env!("CARGO_BIN_EXE_processing"))
+        .args([
+            "shortest-path",
+            "--query-file",
+            "./queries/query1.json",
+            "--input",
+        ])
+        .arg(&input)
+        .output()
+        .unwrap();
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+    assert!(
+        stderr.contains(&format!("Failed to read graph from {}", input.display())),
+        "{}",
+        stderr
+    );
+}